@@ -1,11 +1,17 @@
 use crate::Error;
 use anyhow::bail;
 use serde::{Deserialize, Serialize};
-use shared::{chmod, ensure_dirs_exist, Cidr, IoErrorContext, Peer, WrappedIoError};
+use shared::{
+    chmod, ensure_dirs_exist, interface_config::InterfaceInfo, Cidr, IoErrorContext, Peer,
+    WrappedIoError,
+};
 use std::{
+    collections::HashMap,
     fs::{File, OpenOptions},
     io::{self, Read, Seek, SeekFrom, Write},
+    net::SocketAddr,
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 use wireguard_control::InterfaceName;
 
@@ -19,7 +25,27 @@ pub struct DataStore {
 #[serde(tag = "version")]
 pub enum Contents {
     #[serde(rename = "1")]
-    V1 { peers: Vec<Peer>, cidrs: Vec<Cidr> },
+    V1 {
+        peers: Vec<Peer>,
+        cidrs: Vec<Cidr>,
+        /// The listen port the interface last had, reused if it has to be recreated.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        listen_port: Option<u16>,
+        /// The MTU last applied to the interface, after taking peers' MTU hints into account.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mtu: Option<u32>,
+        /// Server-reported endpoints that failed their probe, by public key.
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        failed_endpoints: HashMap<String, FailedEndpoint>,
+    },
+}
+
+/// An endpoint that nothing answered on, so that it isn't applied (and probed) again on every
+/// fetch for as long as the server keeps reporting it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailedEndpoint {
+    pub endpoint: SocketAddr,
+    pub failed_at: SystemTime,
 }
 
 impl DataStore {
@@ -48,6 +74,9 @@ impl DataStore {
         let contents = serde_json::from_str(&json).unwrap_or_else(|_| Contents::V1 {
             peers: vec![],
             cidrs: vec![],
+            listen_port: None,
+            mtu: None,
+            failed_endpoints: HashMap::new(),
         });
 
         Ok(Self { file, contents })
@@ -57,6 +86,35 @@ impl DataStore {
         data_dir.join(interface.to_string()).with_extension("json")
     }
 
+    /// Marks a network paused by `down --keep-interface`. It's a separate file rather than
+    /// part of the store so that a running daemon's writes to the store can't undo it.
+    fn paused_path(data_dir: &Path, interface: &InterfaceName) -> PathBuf {
+        data_dir
+            .join(interface.to_string())
+            .with_extension("paused")
+    }
+
+    pub fn pause(data_dir: &Path, interface: &InterfaceName) -> Result<(), WrappedIoError> {
+        ensure_dirs_exist(&[data_dir])?;
+        let path = Self::paused_path(data_dir, interface);
+        File::create(&path).with_path(&path)?;
+        Ok(())
+    }
+
+    /// Returns whether the network was paused.
+    pub fn resume(data_dir: &Path, interface: &InterfaceName) -> Result<bool, WrappedIoError> {
+        let path = Self::paused_path(data_dir, interface);
+        match std::fs::remove_file(&path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).with_path(&path),
+        }
+    }
+
+    pub fn is_paused(data_dir: &Path, interface: &InterfaceName) -> bool {
+        Self::paused_path(data_dir, interface).exists()
+    }
+
     fn _open(
         data_dir: &Path,
         interface: &InterfaceName,
@@ -132,6 +190,56 @@ impl DataStore {
         }
     }
 
+    pub fn listen_port(&self) -> Option<u16> {
+        match &self.contents {
+            Contents::V1 { listen_port, .. } => *listen_port,
+        }
+    }
+
+    pub fn set_listen_port(&mut self, new_listen_port: Option<u16>) {
+        match &mut self.contents {
+            Contents::V1 {
+                ref mut listen_port,
+                ..
+            } => *listen_port = new_listen_port,
+        }
+    }
+
+    /// The port to bring a recreated interface up with: its configured one, or else the one
+    /// it last had, unless `persist` is off.
+    pub fn recreation_listen_port(&self, interface: &InterfaceInfo, persist: bool) -> Option<u16> {
+        interface.startup_listen_port(self.listen_port().filter(|_| persist))
+    }
+
+    pub fn mtu(&self) -> Option<u32> {
+        match &self.contents {
+            Contents::V1 { mtu, .. } => *mtu,
+        }
+    }
+
+    pub fn set_mtu(&mut self, new_mtu: Option<u32>) {
+        match &mut self.contents {
+            Contents::V1 { ref mut mtu, .. } => *mtu = new_mtu,
+        }
+    }
+
+    pub fn failed_endpoints(&self) -> &HashMap<String, FailedEndpoint> {
+        match &self.contents {
+            Contents::V1 {
+                failed_endpoints, ..
+            } => failed_endpoints,
+        }
+    }
+
+    pub fn set_failed_endpoints(&mut self, new_failed_endpoints: HashMap<String, FailedEndpoint>) {
+        match &mut self.contents {
+            Contents::V1 {
+                ref mut failed_endpoints,
+                ..
+            } => *failed_endpoints = new_failed_endpoints,
+        }
+    }
+
     pub fn write(&mut self) -> Result<(), io::Error> {
         self.file.seek(SeekFrom::Start(0))?;
         self.file.set_len(0)?;
@@ -149,19 +257,12 @@ mod tests {
     lazy_static! {
         static ref BASE_PEERS: Vec<Peer> = vec![Peer {
             id: 0,
-            contents: PeerContents {
-                name: "blah".parse().unwrap(),
-                ip: "10.0.0.1".parse().unwrap(),
-                cidr_id: 1,
-                public_key: "abc".to_string(),
-                endpoint: None,
-                is_admin: false,
-                is_disabled: false,
-                is_redeemed: true,
-                persistent_keepalive_interval: None,
-                invite_expires: None,
-                candidates: vec![],
-            }
+            contents: PeerContents::new(
+                "blah".parse().unwrap(),
+                "10.0.0.1".parse().unwrap(),
+                1,
+                "abc".to_string()
+            )
         }];
         static ref BASE_CIDRS: Vec<Cidr> = vec![Cidr {
             id: 1,
@@ -174,7 +275,7 @@ mod tests {
     }
 
     fn setup_basic_store(dir: &Path) {
-        let mut store = DataStore::open_with_path(&dir.join("peer_store.json"), true).unwrap();
+        let mut store = DataStore::open_with_path(dir.join("peer_store.json"), true).unwrap();
 
         println!("{:?}", store);
         assert_eq!(0, store.peers().len());
@@ -189,7 +290,7 @@ mod tests {
     fn test_sanity() {
         let dir = tempfile::tempdir().unwrap();
         setup_basic_store(dir.path());
-        let store = DataStore::open_with_path(&dir.path().join("peer_store.json"), false).unwrap();
+        let store = DataStore::open_with_path(dir.path().join("peer_store.json"), false).unwrap();
         assert_eq!(store.peers(), &*BASE_PEERS);
         assert_eq!(store.cidrs(), &*BASE_CIDRS);
     }
@@ -199,7 +300,7 @@ mod tests {
         let dir = tempfile::tempdir().unwrap();
         setup_basic_store(dir.path());
         let mut store =
-            DataStore::open_with_path(&dir.path().join("peer_store.json"), false).unwrap();
+            DataStore::open_with_path(dir.path().join("peer_store.json"), false).unwrap();
 
         // Should work, since peer is unmodified.
         store.update_peers(&BASE_PEERS).unwrap();
@@ -211,12 +312,89 @@ mod tests {
         assert!(store.update_peers(&modified).is_err());
     }
 
+    #[test]
+    fn test_listen_port_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("peer_store.json");
+
+        // Stores written before the listen port was recorded still open.
+        std::fs::write(&path, r#"{"version": "1", "peers": [], "cidrs": []}"#).unwrap();
+        let mut store = DataStore::open_with_path(&path, false).unwrap();
+        assert_eq!(store.listen_port(), None);
+        assert_eq!(store.mtu(), None);
+
+        assert!(store.failed_endpoints().is_empty());
+
+        let failed: HashMap<_, _> = [(
+            "abc".to_string(),
+            FailedEndpoint {
+                endpoint: "1.1.1.1:51820".parse().unwrap(),
+                failed_at: SystemTime::UNIX_EPOCH,
+            },
+        )]
+        .into();
+        store.set_listen_port(Some(51820));
+        store.set_mtu(Some(1380));
+        store.set_failed_endpoints(failed.clone());
+        store.write().unwrap();
+        let store = DataStore::open_with_path(&path, false).unwrap();
+        assert_eq!(store.listen_port(), Some(51820));
+        assert_eq!(store.mtu(), Some(1380));
+        assert_eq!(store.failed_endpoints(), &failed);
+    }
+
+    #[test]
+    fn test_recreation_reuses_listen_port() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("peer_store.json");
+        let interface = |listen_port, random_listen_port| InterfaceInfo {
+            network_name: "test".into(),
+            address: "10.0.0.2/24".parse().unwrap(),
+            private_key: String::new(),
+            listen_port,
+            random_listen_port,
+            allowed_endpoint_ports: Default::default(),
+            hosts_domain: None,
+            mtu: None,
+            name_template: None,
+        };
+
+        // The first fetch records the random port WireGuard picked.
+        let mut store = DataStore::open_with_path(&path, true).unwrap();
+        assert_eq!(
+            store.recreation_listen_port(&interface(None, false), true),
+            None
+        );
+        store.set_listen_port(Some(41234));
+        store.write().unwrap();
+        drop(store);
+
+        // The interface is gone (ex. after a reboot) and gets recreated with that port.
+        let store = DataStore::open_with_path(&path, false).unwrap();
+        assert_eq!(
+            store.recreation_listen_port(&interface(None, false), true),
+            Some(41234)
+        );
+        assert_eq!(
+            store.recreation_listen_port(&interface(None, false), false),
+            None
+        );
+        assert_eq!(
+            store.recreation_listen_port(&interface(None, true), true),
+            None
+        );
+        assert_eq!(
+            store.recreation_listen_port(&interface(Some(51820), false), true),
+            Some(51820)
+        );
+    }
+
     #[test]
     fn test_peer_persistence() {
         let dir = tempfile::tempdir().unwrap();
         setup_basic_store(dir.path());
         let mut store =
-            DataStore::open_with_path(&dir.path().join("peer_store.json"), false).unwrap();
+            DataStore::open_with_path(dir.path().join("peer_store.json"), false).unwrap();
 
         // Should work, since peer is unmodified.
         store.update_peers(&[]).unwrap();
@@ -230,4 +408,21 @@ mod tests {
             .collect::<Vec<_>>();
         assert_eq!(store.peers(), &new_peers);
     }
+
+    #[test]
+    fn test_pause() {
+        let dir = tempfile::tempdir().unwrap();
+        let interface = "evilcorp".parse().unwrap();
+        assert!(!DataStore::is_paused(dir.path(), &interface));
+        assert!(!DataStore::resume(dir.path(), &interface).unwrap());
+
+        DataStore::pause(dir.path(), &interface).unwrap();
+        assert!(DataStore::is_paused(dir.path(), &interface));
+        assert!(!DataStore::is_paused(
+            dir.path(),
+            &"othernet".parse().unwrap()
+        ));
+        assert!(DataStore::resume(dir.path(), &interface).unwrap());
+        assert!(!DataStore::is_paused(dir.path(), &interface));
+    }
 }
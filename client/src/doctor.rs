@@ -0,0 +1,121 @@
+//! Diagnostics that need to watch an interface for a while, ex. peers whose handshakes happen
+//! suspiciously often.
+//!
+//! WireGuard rekeys a session every two minutes, so a healthy peer's completed handshakes
+//! are spaced at least that far apart. Peers that handshake much more often usually have a
+//! flapping endpoint, or share a key with another machine that keeps taking the session over.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime},
+};
+
+/// How often the interface's peers are read while watching handshakes. Handshakes can only
+/// be told apart if they're at least this far apart.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeThresholds {
+    /// Handshakes closer together than this are suspicious.
+    pub min_interval: Duration,
+    /// How many suspicious intervals a peer can have before it's flagged, so that a single
+    /// reconnect (ex. after roaming) doesn't count as flapping.
+    pub max_short_intervals: usize,
+}
+
+/// A peer whose handshakes came more often than the [`HandshakeThresholds`] allow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlappingPeer {
+    pub public_key: String,
+    pub handshakes: usize,
+    pub short_intervals: usize,
+    pub shortest_interval: Duration,
+}
+
+/// The distinct handshake times seen for each peer across successive reads of an interface.
+#[derive(Debug, Default)]
+pub struct HandshakeTracker {
+    handshakes: BTreeMap<String, Vec<SystemTime>>,
+}
+
+impl HandshakeTracker {
+    /// Record one read of the interface's peers' public keys and last handshake times.
+    pub fn observe(&mut self, peers: impl IntoIterator<Item = (String, Option<SystemTime>)>) {
+        for (public_key, last_handshake) in peers {
+            let handshake = match last_handshake {
+                Some(handshake) => handshake,
+                None => continue,
+            };
+            let seen = self.handshakes.entry(public_key).or_default();
+            if seen.last().is_none_or(|last| handshake > *last) {
+                seen.push(handshake);
+            }
+        }
+    }
+
+    pub fn flapping(&self, thresholds: &HandshakeThresholds) -> Vec<FlappingPeer> {
+        self.handshakes
+            .iter()
+            .filter_map(|(public_key, handshakes)| {
+                let intervals: Vec<Duration> = handshakes
+                    .windows(2)
+                    .filter_map(|pair| pair[1].duration_since(pair[0]).ok())
+                    .collect();
+                let short = intervals
+                    .iter()
+                    .filter(|interval| **interval < thresholds.min_interval)
+                    .count();
+                if short <= thresholds.max_short_intervals {
+                    return None;
+                }
+                Some(FlappingPeer {
+                    public_key: public_key.clone(),
+                    handshakes: handshakes.len(),
+                    short_intervals: short,
+                    shortest_interval: intervals.iter().min().copied()?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake_flapping() {
+        let start = SystemTime::now();
+        let at = |secs| Some(start + Duration::from_secs(secs));
+        let thresholds = HandshakeThresholds {
+            min_interval: Duration::from_secs(60),
+            max_short_intervals: 1,
+        };
+
+        let mut tracker = HandshakeTracker::default();
+        // Reads that see the same handshake again don't count as new handshakes.
+        for (healthy, flapping) in [(0, 0), (0, 10), (120, 20), (120, 30), (240, 30)] {
+            tracker.observe([
+                ("healthy".to_string(), at(healthy)),
+                ("flapping".to_string(), at(flapping)),
+                ("idle".to_string(), None),
+            ]);
+        }
+
+        assert_eq!(
+            tracker.flapping(&thresholds),
+            vec![FlappingPeer {
+                public_key: "flapping".into(),
+                handshakes: 4,
+                short_intervals: 3,
+                shortest_interval: Duration::from_secs(10),
+            }]
+        );
+
+        let lenient = HandshakeThresholds {
+            max_short_intervals: 3,
+            ..thresholds
+        };
+        assert_eq!(tracker.flapping(&lenient), vec![]);
+    }
+}
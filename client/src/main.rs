@@ -1,36 +1,59 @@
+// `ureq::Error` is large, but it only comes back from network requests, where moving it
+// around costs nothing next to the request itself.
+#![allow(clippy::result_large_err)]
+
 use anyhow::{anyhow, bail};
 use clap::{AppSettings, Args, IntoApp, Parser, Subcommand};
 use colored::*;
 use dialoguer::{Confirm, Input};
 use hostsfile::HostsBuilder;
 use indoc::eprintdoc;
+use ipnet::IpNet;
+use serde::Serialize;
 use shared::{
     get_local_addrs,
-    interface_config::InterfaceConfig,
+    interface_config::{InterfaceConfig, ServerInfo},
     prompts,
+    reachability::{shared_endpoint_groups, ReachabilityDelta},
+    spec::{NetworkSpec, SpecChange},
     wg::{DeviceExt, PeerInfoExt},
     AddCidrOpts, AddDeleteAssociationOpts, AddPeerOpts, Association, AssociationContents, Cidr,
-    CidrTree, DeleteCidrOpts, Endpoint, EndpointContents, InstallOpts, Interface, IoErrorContext,
-    ListenPortOpts, NatOpts, NetworkOpts, OverrideEndpointOpts, Peer, RedeemContents,
-    RenamePeerOpts, State, WrappedIoError, REDEEM_TRANSITION_WAIT,
+    CidrTree, CidrUtilization, DeleteCidrOpts, DuplicateIp, Endpoint, EndpointContents,
+    EndpointSource, FeatureFlagContents, FeatureFlags, HandshakeReport, InstallOpts, Interface,
+    IoErrorContext, ListenPortOpts, MaintenanceContents, MtuHint, MtuHintContents, NatOpts,
+    NetworkOpts, NextIpsRequest, OverrideEndpointOpts, Peer, PeerContents, RedeemContents,
+    RenameCidrOpts, RenamePeerOpts, ReportedMetadata, State, Timestring, WrappedIoError,
+    API_VERSION_2, MAX_HANDSHAKE_REPORT_BATCH, PERSISTENT_KEEPALIVE_INTERVAL_SECS,
+    REDEEM_TRANSITION_WAIT,
 };
 use std::{
-    fmt, io,
-    net::SocketAddr,
+    collections::HashMap,
+    fs::OpenOptions,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::OpenOptionsExt,
     path::{Path, PathBuf},
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
-use wireguard_control::{Device, DeviceUpdate, InterfaceName, PeerConfigBuilder, PeerInfo};
+use wireguard_control::{Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder, PeerInfo};
 
 mod data_store;
+mod doctor;
 mod nat;
+mod peer_import;
+mod secret_store;
 mod util;
 
-use data_store::DataStore;
+use data_store::{DataStore, FailedEndpoint};
+use doctor::{HandshakeThresholds, HandshakeTracker};
 use nat::NatTraverse;
+use secret_store::SecretStoreKind;
 use shared::{wg, Error};
-use util::{human_duration, human_size, Api};
+use util::{
+    human_duration, human_size, interface_statuses, local_metadata, send_batched, udp_bound_addrs,
+    with_retries, Api, Backoff, Debouncer, InterfaceStatus,
+};
 
 use crate::util::all_installed;
 
@@ -63,6 +86,45 @@ struct Opts {
     #[clap(short, long, default_value = "/var/lib/innernet")]
     data_dir: PathBuf,
 
+    /// An fwmark to keep set on interfaces, only re-applied when the live value differs.
+    /// If unset, any fwmark set out-of-band is left alone.
+    #[clap(long)]
+    fwmark: Option<u32>,
+
+    /// Don't reuse an interface's last listen port when recreating it without a configured
+    /// one (see set-listen-port), letting it pick a new random port instead. Can also be set
+    /// per interface with `random-listen-port = true` in its config file.
+    #[clap(long)]
+    no_persist_listen_port: bool,
+
+    /// After updating an interface, read its peers back and fail if any of their allowed IPs
+    /// differ from what was applied.
+    #[clap(long)]
+    verify_allowed_ips: bool,
+
+    /// Don't report this machine's OS, hostname, innernet version, and WireGuard backend
+    /// to the server when fetching.
+    #[clap(long)]
+    no_report_metadata: bool,
+
+    /// When fetching, report the latest handshake with each peer to the server, so that it
+    /// can tell which peers are online even if they don't talk to it directly (ex. on a
+    /// gateway)
+    #[clap(long)]
+    report_handshakes: bool,
+
+    /// How many peers' handshakes to report per request
+    #[clap(long, default_value_t = MAX_HANDSHAKE_REPORT_BATCH, requires = "report-handshakes")]
+    report_batch_size: usize,
+
+    /// How many handshake report requests to have in flight at once
+    #[clap(long, default_value = "4", requires = "report-handshakes")]
+    report_concurrency: usize,
+
+    /// Where network private keys are loaded from and stored to.
+    #[clap(long, default_value_t, possible_values = SecretStoreKind::variants())]
+    secret_store: SecretStoreKind,
+
     #[clap(flatten)]
     network: NetworkOpts,
 }
@@ -80,7 +142,7 @@ struct HostsOpt {
 
 impl From<HostsOpt> for Option<PathBuf> {
     fn from(opt: HostsOpt) -> Self {
-        (!opt.no_write_hosts).then(|| opt.hosts_path)
+        (!opt.no_write_hosts).then_some(opt.hosts_path)
     }
 }
 
@@ -102,6 +164,14 @@ enum Command {
         nat: NatOpts,
     },
 
+    /// Generate a WireGuard keypair offline, to be registered with 'innernet-server pre-register'
+    ///
+    /// The private key is written to a new file, and the public key printed to stdout.
+    GenerateKeypair {
+        /// Where to write the private key
+        private_key_file: PathBuf,
+    },
+
     /// Enumerate all innernet connections
     #[clap(alias = "list")]
     Show {
@@ -113,9 +183,21 @@ enum Command {
         #[clap(short, long)]
         tree: bool,
 
+        /// Print the peers as JSON
+        #[clap(long, conflicts_with_all = &["short", "tree"])]
+        json: bool,
+
         interface: Option<Interface>,
     },
 
+    /// List the WireGuard interfaces on this host alongside the installed innernet networks,
+    /// including interfaces innernet doesn't manage and networks that aren't up
+    Interfaces {
+        /// Print the interfaces as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
     /// Bring up your local interface, and update it with latest peer list
     Up {
         /// Enable daemon mode i.e. keep the process running, while fetching
@@ -123,10 +205,39 @@ enum Command {
         #[clap(short, long)]
         daemon: bool,
 
-        /// Keep fetching the latest peer list at the specified interval in
-        /// seconds. Valid only in daemon mode
-        #[clap(long, default_value = "60")]
-        interval: u64,
+        /// Keep fetching the latest peer list at the specified interval, like "30s" or "5m"
+        /// (plain numbers are seconds). Valid only in daemon mode
+        #[clap(long, default_value = "60s")]
+        interval: Timestring,
+
+        /// After failed fetches, the interval doubles up to this long, going back to
+        /// --interval once a fetch succeeds. Valid only in daemon mode
+        #[clap(long, default_value = "15m")]
+        max_interval: Timestring,
+
+        /// Randomly lengthen or shorten each interval by up to this percentage, so
+        /// clients don't all fetch at once. Valid only in daemon mode
+        #[clap(
+            long,
+            value_name = "PERCENT",
+            default_value = "10",
+            parse(try_from_str = util::parse_jitter)
+        )]
+        interval_jitter: u8,
+
+        /// Merge syncs triggered within this many seconds of each other (by the interval, or
+        /// by sending the daemon SIGHUP) into a single run. Valid only in daemon mode
+        #[clap(long, default_value = "5")]
+        coalesce_window: u64,
+
+        /// Only print warnings and errors
+        #[clap(short, long)]
+        quiet: bool,
+
+        /// Exit with status 2 if no peers or CIDRs changed (and no interface had to be
+        /// brought up). Not valid in daemon mode
+        #[clap(long, conflicts_with = "daemon")]
+        exit_code: bool,
 
         #[clap(flatten)]
         hosts: HostsOpt,
@@ -141,6 +252,21 @@ enum Command {
     Fetch {
         interface: Interface,
 
+        /// Keep running, fetching every --interval until stopped with SIGTERM or SIGINT.
+        /// Stopping leaves the interface up
+        #[clap(long = "loop")]
+        run_loop: bool,
+
+        /// How often to fetch, like "30s" or "5m" (plain numbers are seconds). Valid only
+        /// with --loop
+        #[clap(long, default_value = "60s")]
+        interval: Timestring,
+
+        /// After failed fetches, the interval doubles up to this long, going back to
+        /// --interval once a fetch succeeds. Valid only with --loop
+        #[clap(long, default_value = "15m")]
+        max_interval: Timestring,
+
         #[clap(flatten)]
         hosts: HostsOpt,
 
@@ -155,10 +281,24 @@ enum Command {
         /// Bypass confirmation
         #[clap(long)]
         yes: bool,
+
+        #[clap(flatten)]
+        hosts: HostsOpt,
     },
 
     /// Bring down the interface (equivalent to 'wg-quick down <interface>')
-    Down { interface: Interface },
+    Down {
+        interface: Interface,
+
+        /// Only stop managing the network: leave the WireGuard interface up, but remove
+        /// its hosts entries and have running 'up --daemon' processes skip it. A later
+        /// 'innernet up' adopts the existing interface again
+        #[clap(long)]
+        keep_interface: bool,
+
+        #[clap(flatten)]
+        hosts: HostsOpt,
+    },
 
     /// Add a new peer
     ///
@@ -171,6 +311,15 @@ enum Command {
 
         #[clap(flatten)]
         sub_opts: AddPeerOpts,
+
+        /// Invite every peer listed in a TOML file of [[peer]] tables, or a CSV file, with a
+        /// name, cidr, and optionally ip and admin. Invitations are saved as '<name>.toml'
+        #[clap(
+            long,
+            value_name = "FILE",
+            conflicts_with_all = &["name", "ip", "auto-ip", "cidr", "admin", "save-config"]
+        )]
+        from_file: Option<PathBuf>,
     },
 
     /// Rename a peer
@@ -194,6 +343,19 @@ enum Command {
         sub_opts: AddCidrOpts,
     },
 
+    /// Rename a CIDR
+    ///
+    /// By default, you'll be prompted interactively to select a CIDR, but you can
+    /// also specify all the options in the command, eg:
+    ///
+    /// --name 'engineers' --new-name 'engineering'
+    RenameCidr {
+        interface: Interface,
+
+        #[clap(flatten)]
+        sub_opts: RenameCidrOpts,
+    },
+
     /// Delete a CIDR
     DeleteCidr {
         interface: Interface,
@@ -209,6 +371,29 @@ enum Command {
         /// Display CIDRs in tree format
         #[clap(short, long)]
         tree: bool,
+
+        /// Fetch and show how many of each CIDR's addresses are in use (requires admin)
+        #[clap(short, long)]
+        utilization: bool,
+
+        /// Print the CIDRs as JSON
+        #[clap(long, conflicts_with = "tree")]
+        json: bool,
+    },
+
+    /// Show the next free IPs in a CIDR, in the order they would be assigned
+    NextIps {
+        interface: Interface,
+
+        /// The CIDR name (eg. 'engineers')
+        cidr: String,
+
+        /// The number of IPs to return
+        count: usize,
+
+        /// Reserve the IPs for this many seconds so that other next-ips calls skip them
+        #[clap(long)]
+        reserve: Option<u64>,
     },
 
     /// Disable an enabled peer
@@ -236,6 +421,91 @@ enum Command {
     /// List existing assocations between CIDRs
     ListAssociations { interface: Interface },
 
+    /// Preview which peers would gain or lose reachability from an association change,
+    /// without making it
+    SimulateAssociation {
+        interface: Interface,
+
+        /// Simulate associating the two CIDRs
+        #[clap(long, conflicts_with = "delete", required_unless_present = "delete")]
+        add: bool,
+
+        /// Simulate deleting the association between the two CIDRs
+        #[clap(long)]
+        delete: bool,
+
+        /// The first CIDR of the association
+        cidr1: String,
+
+        /// The second CIDR of the association
+        cidr2: String,
+
+        /// Print the changes as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Print the network's CIDRs, associations and peers as a declarative spec (TOML by
+    /// default), for use with apply-spec
+    Export {
+        interface: Interface,
+
+        /// Print the spec as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Reconcile the server with a spec from export (TOML, or JSON if the file ends in
+    /// .json), creating and deleting CIDRs and associations and updating or disabling peers
+    /// in a single transaction. New peers still have to be invited with add-peer
+    ApplySpec {
+        file: PathBuf,
+
+        /// Only print the changes that would be made, after checking them with the server
+        #[clap(long)]
+        dry_run: bool,
+    },
+
+    /// Group peers by the public IP the server observes them connecting from, and flag
+    /// those that are allowed to reach each other but would depend on NAT hairpinning
+    SharedEndpoints {
+        interface: Interface,
+
+        /// Print the groups as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Check for IP addresses assigned to more than one peer, which breaks routing to them
+    CheckIps {
+        interface: Interface,
+
+        /// Print the conflicts as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Hint the path MTU to a peer, so that peers that can reach it lower their interface MTU
+    /// to fit. Leave out the MTU to clear the hint
+    SetMtuHint {
+        interface: Interface,
+
+        /// Name of the peer
+        peer: String,
+
+        /// Largest packet that fits the path to the peer
+        mtu: Option<u32>,
+    },
+
+    /// List the peers' MTU hints
+    ListMtuHints {
+        interface: Interface,
+
+        /// Print the hints as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
     /// Set the local listen port.
     SetListenPort {
         interface: Interface,
@@ -252,6 +522,97 @@ enum Command {
         sub_opts: OverrideEndpointOpts,
     },
 
+    /// Show or set the server's read-only maintenance mode
+    ///
+    /// While read-only, the server keeps serving the network state to peers
+    /// but rejects all changes (new peers, redemptions, endpoint updates, etc).
+    Maintenance {
+        interface: Interface,
+
+        /// Turn read-only mode on or off. Without this, the current mode is shown
+        #[clap(long)]
+        read_only: Option<bool>,
+    },
+
+    /// Show or change the network-wide feature flags that clients adjust their behavior by
+    Features {
+        interface: Interface,
+
+        /// Turn a flag on for every client in the network
+        #[clap(long, value_name = "FLAG", possible_values = FeatureFlags::NAMES)]
+        enable: Vec<String>,
+
+        /// Turn a flag off for every client in the network
+        #[clap(long, value_name = "FLAG", possible_values = FeatureFlags::NAMES)]
+        disable: Vec<String>,
+
+        /// Print the flags as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Smoke-test the enrollment pipeline of a network's server
+    ///
+    /// Issues a throwaway invitation, redeems it with a temporary key over a
+    /// temporary interface, checks that it's listed as redeemed alongside the
+    /// network state, and then deletes it again. Requires admin access.
+    SelftestServer {
+        interface: Interface,
+
+        /// The CIDR to create the test peer in (defaults to the first one with a free IP)
+        #[clap(long)]
+        cidr: Option<String>,
+    },
+
+    /// Look up which peer holds an innernet IP address
+    ///
+    /// Searches the peers known to this machine as of the last fetch. Only admins know about
+    /// every peer, so for other peers an address that isn't found may still be assigned.
+    Whois {
+        interface: Interface,
+
+        /// The IPv4 or IPv6 address to look up
+        ip: IpAddr,
+
+        /// Print the result as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// List the OS, hostname, innernet version, and WireGuard backend last reported by each peer
+    ListPeerMetadata {
+        interface: Interface,
+
+        /// Print the metadata as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Show the local underlay socket details of a network
+    ///
+    /// Reports the interface's listen port, fwmark, and the local addresses its
+    /// UDP socket is bound to, for configuring firewalls or debugging NAT.
+    SocketInfo { interface: Interface },
+
+    /// Watch a network's interface for a while and flag problems that only show up over
+    /// time, such as peers that handshake abnormally often (flapping endpoints, or a key
+    /// that's in use on more than one machine)
+    Doctor {
+        interface: Interface,
+
+        /// How long to watch the interface for, in seconds
+        #[clap(long, default_value_t = 150)]
+        duration: u64,
+
+        /// Flag peers whose handshakes come closer together than this many seconds
+        #[clap(long, default_value_t = 60)]
+        min_handshake_interval: u64,
+
+        /// How many too-close handshakes to tolerate per peer before flagging it
+        #[clap(long, default_value_t = 1)]
+        max_short_handshakes: usize,
+    },
+
     /// Generate shell completion scripts
     Completions {
         #[clap(arg_enum)]
@@ -259,35 +620,26 @@ enum Command {
     },
 }
 
-/// Application-level error.
-#[derive(Debug, Clone)]
-pub(crate) struct ClientError(String);
-
-impl fmt::Display for ClientError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
-    }
-}
-
-impl std::error::Error for ClientError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
-    }
+/// The tag of the interface's section of the hosts file, so that each network's peers are
+/// rewritten (and removed) independently of the others'.
+fn hosts_tag(interface: &InterfaceName) -> String {
+    format!("innernet {}", interface)
 }
 
 fn update_hosts_file(
     interface: &InterfaceName,
     hosts_path: PathBuf,
+    domain: Option<&str>,
     peers: &[Peer],
 ) -> Result<(), WrappedIoError> {
     log::info!("updating {} with the latest peers.", "/etc/hosts".yellow());
 
-    let mut hosts_builder = HostsBuilder::new(format!("innernet {}", interface));
-    for peer in peers {
-        hosts_builder.add_hostname(
-            peer.contents.ip,
-            &format!("{}.{}.wg", peer.contents.name, interface),
-        );
+    let mut hosts_builder = HostsBuilder::new(hosts_tag(interface));
+    let peers = peers
+        .iter()
+        .map(|peer| (peer.contents.ip, &*peer.contents.name));
+    for (ip, hostnames) in util::peer_hostnames(interface, domain, peers) {
+        hosts_builder.add_hostnames(ip, hostnames);
     }
     if let Err(e) = hosts_builder.write_to(&hosts_path).with_path(hosts_path) {
         log::warn!("failed to update hosts ({})", e);
@@ -304,16 +656,41 @@ fn install(
     nat: &NatOpts,
 ) -> Result<(), Error> {
     shared::ensure_dirs_exist(&[&opts.config_dir])?;
-    let config = InterfaceConfig::from_file(invite)?;
+    let mut config = InterfaceConfig::from_file(invite)?;
+    if install_opts.hosts_domain.is_some() {
+        config.interface.hosts_domain = install_opts.hosts_domain.clone();
+    }
+    if opts.network.mtu.is_some() {
+        config.interface.mtu = opts.network.mtu;
+    }
+    if install_opts.name_template.is_some() {
+        config.interface.name_template = install_opts.name_template.clone();
+    }
+    let is_bootstrap = config.interface.private_key.is_empty();
+    match (is_bootstrap, &install_opts.private_key_file) {
+        (true, None) => bail!(
+            "{} is a bootstrap file for a pre-registered peer, install it with --private-key-file.",
+            invite.display()
+        ),
+        (false, Some(_)) => bail!(
+            "--private-key-file is only for bootstrap files, {} is an invitation.",
+            invite.display()
+        ),
+        _ => {},
+    }
 
+    let default_name = match &config.interface.name_template {
+        Some(template) => template.derive(&config.interface.network_name)?.to_string(),
+        None => config.interface.network_name.clone(),
+    };
     let iface = if install_opts.default_name {
-        config.interface.network_name.clone()
+        default_name
     } else if let Some(ref iface) = install_opts.name {
         iface.clone()
     } else {
         Input::with_theme(&*prompts::THEME)
             .with_prompt("Interface name")
-            .default(config.interface.network_name.clone())
+            .default(default_name)
             .interact()?
     };
 
@@ -336,7 +713,26 @@ fn install(
         );
     }
 
-    redeem_invite(&iface, config, target_conf, opts.network).map_err(|e| {
+    // Check before redeeming anything, since a redeemed invitation can't be used again.
+    if opts.secret_store.open(&opts.config_dir).is_read_only() {
+        bail!(
+            "the {} secret store is read-only, so it can't hold the private key of a new \
+             network. Install with the file secret store instead.",
+            opts.secret_store
+        );
+    }
+
+    let installed = match &install_opts.private_key_file {
+        Some(key_file) => install_bootstrap(&iface, config, target_conf, opts, key_file),
+        None => redeem_invite(
+            &iface,
+            config,
+            target_conf,
+            opts,
+            install_opts.redeem_retries,
+        ),
+    };
+    installed.map_err(|e| {
         log::error!("failed to start the interface: {}.", e);
         log::info!("bringing down the interface.");
         if let Err(e) = wg::down(&iface, opts.network.backend) {
@@ -363,7 +759,7 @@ fn install(
     if install_opts.delete_invite
         || Confirm::with_theme(&*prompts::THEME)
             .wait_for_newline(true)
-            .with_prompt(&format!(
+            .with_prompt(format!(
                 "Delete invitation file \"{}\" now? (It's no longer needed)",
                 invite.to_string_lossy().yellow()
             ))
@@ -429,12 +825,63 @@ fn install(
     Ok(())
 }
 
+/// Install a bootstrap file from `innernet-server pre-register` with the private key the
+/// peer generated offline. The public key is already registered, so there's nothing to redeem.
+fn install_bootstrap(
+    iface: &InterfaceName,
+    mut config: InterfaceConfig,
+    target_conf: PathBuf,
+    opts: &Opts,
+    key_file: &Path,
+) -> Result<(), Error> {
+    let private_key = std::fs::read_to_string(key_file).with_path(key_file)?;
+    let private_key = Key::from_base64(private_key.trim())?;
+
+    config.interface.private_key = private_key.to_base64();
+    config.write_to_path(&target_conf, false, Some(0o600))?;
+    if opts.secret_store != SecretStoreKind::File {
+        opts.secret_store
+            .open(&opts.config_dir)
+            .store_private_key(iface, &private_key)?;
+    }
+    log::info!(
+        "Installed pre-registered key {}. Copied config to {}.\n",
+        private_key.get_public().to_base64(),
+        target_conf.to_string_lossy().yellow()
+    );
+
+    Ok(())
+}
+
+fn generate_keypair(private_key_file: &Path) -> Result<(), Error> {
+    let keypair = wireguard_control::KeyPair::generate();
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(private_key_file)
+        .with_path(private_key_file)?;
+    writeln!(file, "{}", keypair.private.to_base64()).with_path(private_key_file)?;
+
+    log::info!(
+        "private key written to {}.",
+        private_key_file.to_string_lossy().yellow()
+    );
+    println!("{}", keypair.public.to_base64());
+    Ok(())
+}
+
+/// The wait before the first retry of a failed redemption, doubling with each retry.
+const REDEEM_RETRY_DELAY: Duration = Duration::from_secs(1);
+
 fn redeem_invite(
     iface: &InterfaceName,
     mut config: InterfaceConfig,
     target_conf: PathBuf,
-    network: NetworkOpts,
+    opts: &Opts,
+    retries: u32,
 ) -> Result<(), Error> {
+    let network = opts.network;
     log::info!("bringing up interface {}.", iface.as_str_lossy().yellow());
     let resolved_endpoint = config
         .server
@@ -452,9 +899,13 @@ fn redeem_invite(
             resolved_endpoint,
         )),
         network,
+        network.mtu_or(config.interface.mtu),
     )
     .with_str(iface.to_string())?;
 
+    let api = Api::new(&config.server);
+    api.check_api_version()?;
+
     log::info!("Generating new keypair.");
     let keypair = wireguard_control::KeyPair::generate();
 
@@ -462,16 +913,35 @@ fn redeem_invite(
         "Registering keypair with server (at {}).",
         &config.server.internal_endpoint
     );
-    Api::new(&config.server).http_form(
-        "POST",
-        "/user/redeem",
-        RedeemContents {
-            public_key: keypair.public.to_base64(),
-        },
-    )?;
+    // The server only consumes the invitation once redemption fully succeeds, so transient
+    // failures are safe to retry.
+    let mut response_lost = false;
+    with_retries(retries, REDEEM_RETRY_DELAY, || {
+        let result = api.http_form::<_, ()>(
+            "POST",
+            "/user/redeem",
+            RedeemContents {
+                public_key: keypair.public.to_base64(),
+            },
+        );
+        response_lost |= matches!(result, Err(ureq::Error::Transport(_)));
+        result
+    })
+    .map_err(|e| match e {
+        ureq::Error::Status(401 | 410, _) if response_lost => anyhow!(
+            "the server rejected the invitation after an earlier attempt went unanswered. \
+             It may have been redeemed by that attempt, in which case ask an admin for a new invitation."
+        ),
+        e => e.into(),
+    })?;
 
     config.interface.private_key = keypair.private.to_base64();
     config.write_to_path(&target_conf, false, Some(0o600))?;
+    if opts.secret_store != SecretStoreKind::File {
+        opts.secret_store
+            .open(&opts.config_dir)
+            .store_private_key(iface, &keypair.private)?;
+    }
     log::info!(
         "New keypair registered. Copied config to {}.\n",
         target_conf.to_string_lossy().yellow()
@@ -487,46 +957,227 @@ fn redeem_invite(
     Ok(())
 }
 
+/// The exit status of `up --exit-code` when nothing changed.
+const NO_CHANGES_EXIT_CODE: i32 = 2;
+
+/// Returns whether any interface changed (see [`fetch`]).
 fn up(
     interface: Option<Interface>,
     opts: &Opts,
-    loop_interval: Option<Duration>,
+    mut backoff: Option<Backoff>,
+    coalesce_window: Duration,
     hosts_path: Option<PathBuf>,
     nat: &NatOpts,
-) -> Result<(), Error> {
+) -> Result<bool, Error> {
+    let mut debouncer = Debouncer::new(coalesce_window);
+    let mut changed = false;
+    let mut first_run = true;
     loop {
         let interfaces = match &interface {
             Some(iface) => vec![iface.clone()],
             None => all_installed(&opts.config_dir)?,
         };
 
+        let mut failed = false;
         for iface in interfaces {
-            fetch(&*iface, opts, true, hosts_path.clone(), nat)?;
+            // Running 'up' resumes networks paused by 'down --keep-interface', but a
+            // daemon that was already running leaves them be.
+            if !first_run {
+                if DataStore::is_paused(&opts.data_dir, &iface) {
+                    log::debug!("{} is paused, skipping.", iface);
+                    continue;
+                }
+            } else if DataStore::resume(&opts.data_dir, &iface)? {
+                log::info!("resuming management of {}.", iface.as_str_lossy().yellow());
+            }
+            match fetch(&iface, opts, true, hosts_path.clone(), nat) {
+                Ok(fetch_changed) => changed |= fetch_changed,
+                // The daemon keeps going, backing off until the server is reachable again.
+                Err(e) if backoff.is_some() => {
+                    log::error!("failed to fetch {}: {}", iface, e);
+                    failed = true;
+                },
+                Err(e) => return Err(e),
+            }
         }
+        first_run = false;
 
-        match loop_interval {
-            Some(interval) => thread::sleep(interval),
+        let backoff = match &mut backoff {
+            Some(backoff) => backoff,
             None => break,
+        };
+        if failed {
+            backoff.failed();
+        } else {
+            backoff.succeeded();
+        }
+        let delay = backoff.delay(util::random_fraction());
+        if failed {
+            log::info!("retrying in {}s.", delay.as_secs());
+        }
+        match wait_for_sync(&mut debouncer, Instant::now() + delay, coalesce_window) {
+            Some(triggers) if triggers > 1 => {
+                log::debug!("coalesced {} sync triggers into one sync.", triggers);
+            },
+            Some(_) => {},
+            None => {
+                log::info!("shutting down.");
+                break;
+            },
         }
     }
 
-    Ok(())
+    Ok(changed)
+}
+
+/// How often the daemon checks for sync triggers while it waits.
+const SYNC_TRIGGER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Wait until a sync is triggered, either by its schedule or by SIGHUP, and then for the rest
+/// of its coalescing window. Returns how many triggers the sync covers, or `None` if a
+/// shutdown was requested. The scheduled trigger opens its window early, so that it still
+/// syncs at `scheduled`.
+fn wait_for_sync(debouncer: &mut Debouncer, scheduled: Instant, window: Duration) -> Option<usize> {
+    let mut scheduled = Some(scheduled);
+    loop {
+        let now = Instant::now();
+        if scheduled.is_some_and(|at| now + window >= at) {
+            debouncer.trigger(now);
+            scheduled = None;
+        }
+        if util::take_sync_request() {
+            log::info!("sync requested.");
+            debouncer.trigger(now);
+        }
+        if let Some(triggers) = debouncer.take_due(now) {
+            return Some(triggers);
+        }
+        if !util::sleep_unless_shutdown(SYNC_TRIGGER_POLL_INTERVAL) {
+            return None;
+        }
+    }
+}
+
+/// How much `fetch --loop` randomly lengthens or shortens each interval by.
+const FETCH_LOOP_INTERVAL_JITTER: f64 = 0.1;
+
+/// Fetch `interface` on the schedule `backoff` sets until asked to shut down, which leaves
+/// the interface as it is.
+fn fetch_loop(
+    interface: &InterfaceName,
+    opts: &Opts,
+    mut backoff: Backoff,
+    hosts_path: Option<PathBuf>,
+    nat: &NatOpts,
+) -> Result<(), Error> {
+    util::handle_shutdown_signals();
+    loop {
+        match fetch(interface, opts, false, hosts_path.clone(), nat) {
+            Ok(_) => backoff.succeeded(),
+            Err(e) => {
+                log::error!("failed to fetch {}: {}", interface, e);
+                backoff.failed();
+            },
+        }
+        let delay = backoff.delay(util::random_fraction());
+        log::debug!("fetching {} again in {}s.", interface, delay.as_secs());
+        if !util::sleep_unless_shutdown(delay) {
+            log::info!("shutting down, leaving {} up.", interface);
+            return Ok(());
+        }
+    }
+}
+
+/// WireGuard's MTU is per-interface, so lower it to fit the smallest MTU hint of any visible
+/// peer (or raise it back once those hints are gone). Returns the MTU the interface now has.
+fn update_mtu(
+    interface: &InterfaceName,
+    config: &InterfaceConfig,
+    opts: &Opts,
+    api: &Api,
+    store: &DataStore,
+    peers: &[Peer],
+    interface_up: bool,
+) -> Result<u32, Error> {
+    let configured = opts.network.mtu_or(config.interface.mtu);
+    let applied = if interface_up {
+        store.mtu().unwrap_or(configured)
+    } else {
+        configured
+    };
+    // Servers only speaking API version 1 don't have MTU hints.
+    let hints = api
+        .supports_api_version(API_VERSION_2)
+        .and_then(|supported| match supported {
+            true => api.http("GET", "/user/mtu-hints"),
+            false => Ok(vec![]),
+        });
+    let hints: Vec<MtuHint> = match hints {
+        Ok(hints) => hints,
+        // Keep the MTU as it is rather than keep the peers from being synced.
+        Err(e) => {
+            log::warn!("failed to fetch MTU hints, keeping MTU {}: {}", applied, e);
+            return Ok(applied);
+        },
+    };
+    let endpoint_ip = |peer_id| {
+        peers
+            .iter()
+            .find(|peer| peer.id == peer_id)
+            .and_then(|peer| peer.endpoint.as_ref())
+            .and_then(|endpoint| endpoint.resolve().ok())
+            .map(|addr| addr.ip())
+    };
+    let mtu = MtuHint::effective_mtu(configured, &hints, endpoint_ip);
+
+    if mtu != applied {
+        log::info!(
+            "updating MTU: {} => {} ({} peer{} with MTU hints)",
+            applied,
+            mtu,
+            hints.len(),
+            if hints.len() == 1 { "" } else { "s" },
+        );
+        DeviceUpdate::new()
+            .set_mtu(mtu)
+            .apply(interface, opts.network.backend)
+            .with_str(interface.to_string())?;
+    }
+    if mtu < 1280 && config.interface.address.addr().is_ipv6() {
+        log::warn!(
+            "MTU {} is below the IPv6 minimum of 1280, IPv6 traffic over {} will likely fail.",
+            mtu,
+            interface
+        );
+    }
+    Ok(mtu)
 }
 
+/// Returns whether anything changed: the interface had to be brought up, or its peers,
+/// fwmark, or the network's CIDRs were updated.
 fn fetch(
     interface: &InterfaceName,
     opts: &Opts,
     bring_up_interface: bool,
     hosts_path: Option<PathBuf>,
     nat: &NatOpts,
-) -> Result<(), Error> {
+) -> Result<bool, Error> {
     let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let private_key = opts
+        .secret_store
+        .open(&opts.config_dir)
+        .load_private_key(interface)?
+        .to_base64();
     let interface_up = match Device::list(opts.network.backend) {
         Ok(interfaces) => interfaces.iter().any(|name| name == interface),
         _ => false,
     };
 
-    if !interface_up {
+    let mut store = DataStore::open_or_create(&opts.data_dir, interface)?;
+    if interface_up {
+        let device = Device::get(interface, opts.network.backend)?;
+        wg::ensure_interface_owned(interface, device.public_key.as_ref(), &private_key)?;
+    } else {
         if !bring_up_interface {
             bail!(
                 "Interface is not up. Use 'innernet up {}' instead",
@@ -543,17 +1194,23 @@ fn fetch(
             .external_endpoint
             .resolve()
             .with_str(config.server.external_endpoint.to_string())?;
+        // Without a configured port, keep the one the interface had before (even across
+        // reboots, since it's kept in the data store) so that NAT mappings and firewall rules
+        // for it stay valid.
+        let listen_port =
+            store.recreation_listen_port(&config.interface, !opts.no_persist_listen_port);
         wg::up(
             interface,
-            &config.interface.private_key,
+            &private_key,
             config.interface.address,
-            config.interface.listen_port,
+            listen_port,
             Some((
                 &config.server.public_key,
                 config.server.internal_endpoint.ip(),
                 resolved_endpoint,
             )),
             opts.network,
+            opts.network.mtu_or(config.interface.mtu),
         )
         .with_str(interface.to_string())?;
     }
@@ -562,12 +1219,46 @@ fn fetch(
         "fetching state for {} from server...",
         interface.as_str_lossy().yellow()
     );
-    let mut store = DataStore::open_or_create(&opts.data_dir, interface)?;
     let api = Api::new(&config.server);
-    let State { peers, cidrs } = api.http("GET", "/user/state")?;
+    let State { peers, cidrs } = api.http_streaming("GET", "/user/state")?;
+    let mtu = update_mtu(interface, &config, opts, &api, &store, &peers, interface_up)?;
+    // Servers only speaking API version 1 don't have feature flags or take reports.
+    let extended_api = match api.supports_api_version(API_VERSION_2) {
+        Ok(supported) => supported,
+        Err(e) => {
+            log::warn!(
+                "failed to fetch the server's API version, assuming 1: {}",
+                e
+            );
+            false
+        },
+    };
+    let features: FeatureFlags = match extended_api.then(|| api.http("GET", "/user/features")) {
+        Some(Ok(features)) => features,
+        None => {
+            log::debug!("server doesn't support feature flags, using the defaults.");
+            FeatureFlags::default()
+        },
+        // Feature discovery should never keep the peers from being synced.
+        Some(Err(e)) => {
+            log::warn!("failed to fetch feature flags, using the defaults: {}", e);
+            FeatureFlags::default()
+        },
+    };
+    log::debug!("network feature flags: {:?}", features);
 
     let device = Device::get(interface, opts.network.backend)?;
-    let modifications = device.diff(&peers);
+    // What gets applied can differ from what the server reported, ex. for endpoints that
+    // aren't allowed, or that recently failed their probe.
+    let mut applied_peers = peers.clone();
+    nat::retain_allowed_endpoints(&mut applied_peers, &config.interface.allowed_endpoint_ports);
+    let mut failed_endpoints = nat::skip_failed_endpoints(
+        &mut applied_peers,
+        &device.peers,
+        store.failed_endpoints(),
+        SystemTime::now(),
+    );
+    let modifications = device.diff(&applied_peers);
 
     let updates = modifications
         .iter()
@@ -576,25 +1267,67 @@ fn fetch(
         .map(PeerConfigBuilder::from)
         .collect::<Vec<_>>();
 
-    if !updates.is_empty() || !interface_up {
-        DeviceUpdate::new()
-            .add_peers(&updates)
+    let fwmark = wg::fwmark_update(device.fwmark, opts.fwmark);
+    if let Some(fwmark) = fwmark {
+        log::info!(
+            "updating fwmark: {} => {}",
+            device.fwmark.unwrap_or(0),
+            fwmark
+        );
+    }
+
+    let interface_changed = !updates.is_empty() || !interface_up || fwmark.is_some();
+    let mut failed_probes = HashMap::new();
+    if interface_changed {
+        let mut update = DeviceUpdate::new().add_peers(&updates);
+        if let Some(fwmark) = fwmark {
+            update = update.set_fwmark(fwmark);
+        }
+        update
             .apply(interface, opts.network.backend)
             .with_str(interface.to_string())?;
 
+        if nat.probe_endpoints {
+            failed_probes = nat::probe_endpoints(
+                interface,
+                opts.network.backend,
+                &device,
+                &peers,
+                nat.endpoint_probe_timeout(),
+            )?;
+            let failed_at = SystemTime::now();
+            failed_endpoints.extend(failed_probes.iter().map(|(public_key, failed)| {
+                let failed = FailedEndpoint {
+                    endpoint: failed.endpoint,
+                    failed_at,
+                };
+                (public_key.clone(), failed)
+            }));
+        }
+
+        if opts.verify_allowed_ips {
+            verify_allowed_ips(interface, opts, &peers)?;
+        }
+
         if let Some(path) = hosts_path {
-            update_hosts_file(interface, path, &peers)?;
+            update_hosts_file(interface, path, config.interface.hosts_domain(), &peers)?;
         }
 
-        println!();
+        if log::log_enabled!(log::Level::Info) {
+            println!();
+        }
         log::info!("updated interface {}\n", interface.as_str_lossy().yellow());
     } else {
         log::info!("{}", "peers are already up to date".green());
     }
     let interface_updated_time = Instant::now();
 
+    let changed = interface_changed || store.cidrs() != &cidrs[..];
     store.set_cidrs(cidrs);
     store.update_peers(&peers)?;
+    store.set_listen_port(device.listen_port);
+    store.set_mtu(Some(mtu));
+    store.set_failed_endpoints(failed_endpoints);
     store.write().with_str(interface.to_string())?;
 
     let candidates: Vec<Endpoint> = get_local_addrs()?
@@ -613,19 +1346,94 @@ fn fetch(
         Err(ureq::Error::Status(404, _)) => {
             log::warn!("your network is using an old version of innernet-server that doesn't support NAT traversal candidate reporting.")
         },
+        Err(ureq::Error::Status(503, _)) => {
+            log::warn!("the server is in read-only maintenance mode, skipping NAT traversal candidate reporting.")
+        },
         Err(e) => return Err(e.into()),
         _ => {},
     }
     log::debug!("candidates successfully reported");
 
+    if !extended_api {
+        log::debug!("the server doesn't support handshake or metadata reporting, skipping.");
+    } else if opts.report_handshakes || features.report_handshakes {
+        let reports: Vec<HandshakeReport> = device
+            .peers
+            .iter()
+            .filter_map(|info| {
+                let peer = peers
+                    .iter()
+                    .find(|peer| peer.public_key == info.config.public_key.to_base64())?;
+                let last_handshake = info
+                    .stats
+                    .last_handshake_time?
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .ok()?;
+                Some(HandshakeReport {
+                    peer_id: peer.id,
+                    last_handshake: last_handshake.as_secs(),
+                })
+            })
+            .collect();
+        log::info!("reporting handshakes with {} peer(s)", reports.len());
+        let batch_size = opts.report_batch_size.min(MAX_HANDSHAKE_REPORT_BATCH);
+        match send_batched(
+            &config.server,
+            "PUT",
+            "/user/handshakes",
+            &reports,
+            batch_size,
+            opts.report_concurrency,
+        ) {
+            Err(ureq::Error::Status(503, _)) => {
+                log::warn!(
+                    "the server is in read-only maintenance mode, skipping handshake reporting."
+                )
+            },
+            // Like metadata, the reports are optional and shouldn't keep the rest of the fetch
+            // (ex. NAT traversal) from running, notably when they're rate limited.
+            Err(e) => log::warn!("failed to report handshakes: {}", e),
+            _ => {},
+        }
+    }
+
+    if extended_api && !opts.no_report_metadata && features.report_metadata {
+        let metadata = local_metadata(opts.network.backend);
+        log::debug!("reporting metadata: {:?}", metadata);
+        match api.http_form::<_, ()>("PUT", "/user/metadata", &metadata) {
+            Err(ureq::Error::Status(503, _)) => {
+                log::debug!(
+                    "the server is in read-only maintenance mode, skipping metadata reporting."
+                )
+            },
+            // The peers are already synced, which is what matters; metadata is informational.
+            Err(e) => log::warn!("failed to report metadata: {}", e),
+            _ => {},
+        }
+    }
+
     if nat.no_nat_traversal {
         log::debug!("NAT traversal explicitly disabled, not attempting.");
+    } else if !features.nat_traversal {
+        log::debug!("NAT traversal disabled by the network's feature flags, not attempting.");
     } else {
-        let mut nat_traverse = NatTraverse::new(interface, opts.network.backend, &modifications)?;
+        let mut nat_traverse = NatTraverse::new(
+            interface,
+            opts.network.backend,
+            &modifications,
+            &nat.nat_candidate_weights,
+            &config.interface.allowed_endpoint_ports,
+            nat.nat_candidate_timeout(),
+            &failed_probes,
+        )?;
 
         // Give time for handshakes with recently changed endpoints to complete before attempting traversal.
         if !nat_traverse.is_finished() {
-            thread::sleep(nat::STEP_INTERVAL - interface_updated_time.elapsed());
+            thread::sleep(
+                nat_traverse
+                    .step_interval()
+                    .saturating_sub(interface_updated_time.elapsed()),
+            );
         }
         loop {
             if nat_traverse.is_finished() {
@@ -639,10 +1447,67 @@ fn fetch(
         }
     }
 
+    Ok(changed)
+}
+
+/// Read the interface back after an update, making sure every peer ended up with
+/// exactly the allowed IPs that were sent.
+fn verify_allowed_ips(interface: &InterfaceName, opts: &Opts, peers: &[Peer]) -> Result<(), Error> {
+    let device = Device::get(interface, opts.network.backend)?;
+    let mismatches = device.allowed_ip_mismatches(peers);
+    if mismatches.is_empty() {
+        log::debug!("verified allowed IPs of all peers on {}.", interface);
+        return Ok(());
+    }
+
+    for mismatch in &mismatches {
+        log::error!("{}", mismatch);
+    }
+    bail!(
+        "{} peer(s) on {} don't have the allowed IPs that were applied",
+        mismatches.len(),
+        interface
+    )
+}
+
+fn remove_hosts_section(interface: &InterfaceName, hosts_path: PathBuf) {
+    // Writing the section without any hostnames removes it, leaving other networks' be.
+    if let Err(e) = HostsBuilder::new(hosts_tag(interface))
+        .write_to(&hosts_path)
+        .with_path(hosts_path)
+    {
+        log::warn!("failed to remove the network from hosts ({})", e);
+    }
+}
+
+fn down(
+    interface: &InterfaceName,
+    opts: &Opts,
+    keep_interface: bool,
+    hosts_path: Option<PathBuf>,
+) -> Result<(), Error> {
+    if !keep_interface {
+        return wg::down(interface, opts.network.backend);
+    }
+
+    DataStore::pause(&opts.data_dir, interface)?;
+    if let Some(hosts_path) = hosts_path {
+        remove_hosts_section(interface, hosts_path);
+    }
+    log::info!(
+        "stopped managing {}, its interface is left up. Run '{}' to resume.",
+        interface.as_str_lossy().yellow(),
+        format!("innernet up {}", interface).yellow()
+    );
     Ok(())
 }
 
-fn uninstall(interface: &InterfaceName, opts: &Opts, yes: bool) -> Result<(), Error> {
+fn uninstall(
+    interface: &InterfaceName,
+    opts: &Opts,
+    yes: bool,
+    hosts_path: Option<PathBuf>,
+) -> Result<(), Error> {
     let config = InterfaceConfig::get_path(&opts.config_dir, interface);
     let data = DataStore::get_path(&opts.data_dir, interface);
 
@@ -655,7 +1520,7 @@ fn uninstall(interface: &InterfaceName, opts: &Opts, yes: bool) -> Result<(), Er
 
     if yes
         || Confirm::with_theme(&*prompts::THEME)
-            .with_prompt(&format!(
+            .with_prompt(format!(
                 "Permanently delete network \"{}\"?",
                 interface.as_str_lossy().yellow()
             ))
@@ -673,6 +1538,12 @@ fn uninstall(interface: &InterfaceName, opts: &Opts, yes: bool) -> Result<(), Er
             .with_path(&data)
             .map_err(|e| log::warn!("{}", e.to_string().yellow()))
             .ok();
+        DataStore::resume(&opts.data_dir, interface)
+            .map_err(|e| log::warn!("{}", e.to_string().yellow()))
+            .ok();
+        if let Some(hosts_path) = hosts_path {
+            remove_hosts_section(interface, hosts_path);
+        }
         log::info!(
             "network {} is uninstalled.",
             interface.as_str_lossy().yellow()
@@ -690,7 +1561,14 @@ fn add_cidr(interface: &InterfaceName, opts: &Opts, sub_opts: AddCidrOpts) -> Re
 
     if let Some(cidr_request) = prompts::add_cidr(&cidrs, &sub_opts)? {
         log::info!("Creating CIDR...");
-        let cidr: Cidr = api.http_form("POST", "/admin/cidrs", cidr_request)?;
+        let cidr: Cidr = match api.http_form("POST", "/admin/cidrs", cidr_request) {
+            // The server explains why it rejected the CIDR, ex. the sibling it overlaps.
+            Err(ureq::Error::Status(400, response)) => match response.into_string() {
+                Ok(reason) if !reason.is_empty() => bail!("{}", reason),
+                _ => bail!("The server rejected the CIDR."),
+            },
+            result => result?,
+        };
 
         eprintdoc!(
             "
@@ -710,6 +1588,29 @@ fn add_cidr(interface: &InterfaceName, opts: &Opts, sub_opts: AddCidrOpts) -> Re
     Ok(())
 }
 
+fn rename_cidr(
+    interface: &InterfaceName,
+    opts: &Opts,
+    sub_opts: RenameCidrOpts,
+) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    log::info!("Fetching CIDRs");
+    let api = Api::new(&server);
+    let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
+
+    if let Some((id, cidr_request)) = prompts::rename_cidr(&cidrs, &sub_opts)? {
+        api.require_api_version(API_VERSION_2, "renaming CIDRs")?;
+        log::info!("Renaming CIDR...");
+        let cidr: Cidr = api.http_form("PUT", &format!("/admin/cidrs/{}", id), cidr_request)?;
+        log::info!("CIDR renamed to {}.", cidr.name.bold());
+    } else {
+        log::info!("exited without renaming CIDR.");
+    }
+
+    Ok(())
+}
+
 fn delete_cidr(
     interface: &InterfaceName,
     opts: &Opts,
@@ -725,28 +1626,117 @@ fn delete_cidr(
     let cidr_id = prompts::delete_cidr(&cidrs, &peers, &sub_opts)?;
 
     println!("Deleting CIDR...");
-    let _ = api.http("DELETE", &*format!("/admin/cidrs/{}", cidr_id))?;
+    api.http::<()>("DELETE", &format!("/admin/cidrs/{}", cidr_id))?;
 
     println!("CIDR deleted.");
 
     Ok(())
 }
 
-fn list_cidrs(interface: &InterfaceName, opts: &Opts, tree: bool) -> Result<(), Error> {
+/// CIDRs this full or more are highlighted by `list-cidrs --utilization`.
+const CIDR_USAGE_HIGHLIGHT_PERCENT: f64 = 90.0;
+
+fn list_cidrs(
+    interface: &InterfaceName,
+    opts: &Opts,
+    tree: bool,
+    utilization: bool,
+    json: bool,
+) -> Result<(), Error> {
     let data_store = DataStore::open(&opts.data_dir, interface)?;
-    if tree {
+    let usage: Vec<CidrUtilization> = if utilization {
+        let InterfaceConfig { server, .. } =
+            InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+        let api = Api::new(&server);
+        api.require_api_version(API_VERSION_2, "CIDR utilization")?;
+        api.http("GET", "/admin/cidrs/utilization")?
+    } else {
+        vec![]
+    };
+    let usage_of = |cidr: &Cidr| usage.iter().find(|u| u.cidr_id == cidr.id);
+
+    if json {
+        let cidrs: Vec<_> = data_store
+            .cidrs()
+            .iter()
+            .map(|cidr| {
+                let mut value = serde_json::to_value(cidr)?;
+                if let Some(usage) = usage_of(cidr) {
+                    value["used"] = usage.used.into();
+                    value["total"] = usage.total.into();
+                }
+                Ok(value)
+            })
+            .collect::<Result<_, serde_json::Error>>()?;
+        println!("{}", serde_json::to_string_pretty(&cidrs)?);
+    } else if tree {
         let cidr_tree = CidrTree::new(data_store.cidrs());
         colored::control::set_override(false);
         print_tree(&cidr_tree, &[], 0);
         colored::control::unset_override();
     } else {
         for cidr in data_store.cidrs() {
-            println!("{} {}", cidr.cidr, cidr.name);
+            match usage_of(cidr) {
+                Some(usage) => {
+                    let summary =
+                        format!("{}/{} ({:.1}%)", usage.used, usage.total, usage.percent());
+                    let summary = if usage.percent() >= CIDR_USAGE_HIGHLIGHT_PERCENT {
+                        summary.red()
+                    } else {
+                        summary.dimmed()
+                    };
+                    println!("{} {} {}", cidr.cidr, cidr.name, summary);
+                },
+                None => println!("{} {}", cidr.cidr, cidr.name),
+            }
         }
     }
     Ok(())
 }
 
+fn next_ips(
+    interface: &InterfaceName,
+    opts: &Opts,
+    cidr_name: &str,
+    count: usize,
+    reserve: Option<u64>,
+) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let api = Api::new(&server);
+    api.require_api_version(API_VERSION_2, "reserving IPs")?;
+
+    log::info!("Fetching CIDRs");
+    let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
+    let cidr = cidrs
+        .iter()
+        .find(|cidr| cidr.name == cidr_name)
+        .ok_or_else(|| anyhow!("No CIDR named \"{}\" exists.", cidr_name))?;
+
+    let request = NextIpsRequest {
+        count,
+        reserve_secs: reserve,
+    };
+    let ips: Vec<IpAddr> =
+        match api.http_form("POST", &format!("/admin/cidrs/{}/next-ips", cidr.id), request) {
+            Err(ureq::Error::Status(400, _)) => bail!(
+                "CIDR \"{}\" can't supply {} free IPs (it may not be a leaf CIDR, or the reservation is too long).",
+                cidr.name,
+                count
+            ),
+            result => result?,
+        };
+
+    for ip in ips {
+        println!("{}", ip);
+    }
+    if let Some(secs) = reserve {
+        log::info!("reserved for {}s.", secs);
+    }
+
+    Ok(())
+}
+
 fn add_peer(interface: &InterfaceName, opts: &Opts, sub_opts: AddPeerOpts) -> Result<(), Error> {
     let InterfaceConfig { server, .. } =
         InterfaceConfig::from_interface(&opts.config_dir, interface)?;
@@ -755,13 +1745,21 @@ fn add_peer(interface: &InterfaceName, opts: &Opts, sub_opts: AddPeerOpts) -> Re
     log::info!("Fetching CIDRs");
     let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
     log::info!("Fetching peers");
-    let peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
+    // Deleted peers still hold on to their names and IPs.
+    let peers: Vec<Peer> = api.http("GET", "/admin/peers?include_deleted=true")?;
     let cidr_tree = CidrTree::new(&cidrs[..]);
 
     if let Some(result) = prompts::add_peer(&peers, &cidr_tree, &sub_opts)? {
         let (peer_request, keypair, target_path, mut target_file) = result;
         log::info!("Creating peer...");
-        let peer: Peer = api.http_form("POST", "/admin/peers", peer_request)?;
+        let peer: Peer = match api.http_form("POST", "/admin/peers", peer_request) {
+            // Another admin may have taken the IP since the peers were fetched.
+            Err(ureq::Error::Status(409, response)) => match response.into_string() {
+                Ok(reason) if !reason.is_empty() => bail!("{}", reason),
+                _ => bail!("The peer's IP is already taken."),
+            },
+            result => result?,
+        };
         let server_peer = peers.iter().find(|p| p.id == 1).unwrap();
         prompts::write_peer_invitation(
             (&mut target_file, &target_path),
@@ -779,6 +1777,127 @@ fn add_peer(interface: &InterfaceName, opts: &Opts, sub_opts: AddPeerOpts) -> Re
     Ok(())
 }
 
+fn add_peers_from_file(
+    interface: &InterfaceName,
+    opts: &Opts,
+    path: &Path,
+    sub_opts: AddPeerOpts,
+) -> Result<(), Error> {
+    let contents = std::fs::read_to_string(path).with_path(path)?;
+    let rows = peer_import::parse(path, &contents)?;
+    if rows.is_empty() {
+        bail!("{} doesn't list any peers.", path.display());
+    }
+
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let api = Api::new(&server);
+    log::info!("Fetching CIDRs");
+    let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
+    log::info!("Fetching peers");
+    // Deleted peers still hold on to their names and IPs.
+    let peers: Vec<Peer> = api.http("GET", "/admin/peers?include_deleted=true")?;
+    let cidr_tree = CidrTree::new(&cidrs[..]);
+    let server_peer = peers.iter().find(|p| p.id == 1).unwrap();
+
+    let placed = peer_import::place(rows, &cidr_tree, &peers, sub_opts.ip_order);
+    let valid = placed
+        .iter()
+        .filter(|(_, placement)| placement.is_ok())
+        .count();
+    if valid > 0
+        && !sub_opts.yes
+        && !Confirm::with_theme(&*prompts::THEME)
+            .with_prompt(format!(
+                "Create {} of the {} peers in {}?",
+                valid,
+                placed.len(),
+                path.display()
+            ))
+            .default(false)
+            .wait_for_newline(true)
+            .interact()?
+    {
+        log::info!("exited without creating peers.");
+        return Ok(());
+    }
+    let invite_expires: Duration = match sub_opts.invite_expires {
+        Some(invite_expires) => invite_expires.into(),
+        None => Duration::from_secs(14 * 24 * 60 * 60),
+    };
+
+    let mut created = vec![];
+    let mut failed = vec![];
+    for (row, placement) in placed {
+        let result = placement.and_then(|placement| {
+            let target_path = format!("{}.toml", row.name);
+            let mut target_file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&target_path)
+                .with_path(&target_path)?;
+            let (peer_request, keypair) = prompts::invited_peer(
+                row.name.clone(),
+                placement.ip,
+                placement.cidr.id,
+                row.admin,
+                invite_expires,
+            );
+            let peer: Peer = match api.http_form("POST", "/admin/peers", peer_request) {
+                Ok(peer) => peer,
+                Err(e) => {
+                    std::fs::remove_file(&target_path).ok();
+                    return Err(match e {
+                        ureq::Error::Status(400 | 409, response) => {
+                            anyhow!("{}", response.into_string().unwrap_or_default())
+                        },
+                        e => e.into(),
+                    });
+                },
+            };
+            prompts::write_peer_invitation(
+                (&mut target_file, &target_path),
+                interface,
+                &peer,
+                server_peer,
+                &cidr_tree,
+                keypair,
+                &server.internal_endpoint,
+            )?;
+            Ok((peer, target_path))
+        });
+        match result {
+            Ok(peer) => created.push(peer),
+            Err(e) => failed.push((row.name, e)),
+        }
+    }
+
+    println!();
+    for (peer, target_path) in &created {
+        println!(
+            "  {} {} ({}) -> {}",
+            "+".green(),
+            peer.name.bold(),
+            peer.ip,
+            target_path
+        );
+    }
+    for (name, e) in &failed {
+        println!("  {} {}: {}", "x".red(), name.bold(), e);
+    }
+    println!();
+    if !failed.is_empty() {
+        bail!(
+            "{} of {} peers couldn't be added.",
+            failed.len(),
+            created.len() + failed.len()
+        );
+    }
+    log::info!("added {} peers.", created.len());
+
+    Ok(())
+}
+
 fn rename_peer(
     interface: &InterfaceName,
     opts: &Opts,
@@ -801,7 +1920,7 @@ fn rename_peer(
             .next()
             .ok_or_else(|| anyhow!("Peer not found."))?;
 
-        let _ = api.http_form("PUT", &format!("/admin/peers/{}", id), peer_request)?;
+        api.http_form::<_, ()>("PUT", &format!("/admin/peers/{}", id), peer_request)?;
         log::info!("Peer renamed.");
     } else {
         log::info!("exited without renaming peer.");
@@ -820,12 +1939,13 @@ fn enable_or_disable_peer(
     let api = Api::new(&server);
 
     log::info!("Fetching peers.");
-    let peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
+    // Enabling a deleted peer restores it.
+    let peers: Vec<Peer> = api.http("GET", "/admin/peers?include_deleted=true")?;
 
     if let Some(peer) = prompts::enable_or_disable_peer(&peers[..], enable)? {
         let Peer { id, mut contents } = peer;
         contents.is_disabled = !enable;
-        api.http_form("PUT", &format!("/admin/peers/{}", id), contents)?;
+        api.http_form::<_, ()>("PUT", &format!("/admin/peers/{}", id), contents)?;
     } else {
         log::info!("exiting without disabling peer.");
     }
@@ -863,7 +1983,7 @@ fn add_association(
         return Ok(());
     };
 
-    api.http_form(
+    api.http_form::<_, ()>(
         "POST",
         "/admin/associations",
         AssociationContents {
@@ -892,7 +2012,7 @@ fn delete_association(
     if let Some(association) =
         prompts::delete_association(&associations[..], &cidrs[..], &sub_opts)?
     {
-        api.http("DELETE", &format!("/admin/associations/{}", association.id))?;
+        api.http::<()>("DELETE", &format!("/admin/associations/{}", association.id))?;
     } else {
         log::info!("exiting without adding association.");
     }
@@ -910,24 +2030,373 @@ fn list_associations(interface: &InterfaceName, opts: &Opts) -> Result<(), Error
     log::info!("Fetching associations");
     let associations: Vec<Association> = api.http("GET", "/admin/associations")?;
 
-    for association in associations {
+    for association in associations {
+        println!(
+            "{}: {} <=> {}",
+            association.id,
+            &cidrs
+                .iter()
+                .find(|c| c.id == association.cidr_id_1)
+                .unwrap()
+                .name
+                .yellow(),
+            &cidrs
+                .iter()
+                .find(|c| c.id == association.cidr_id_2)
+                .unwrap()
+                .name
+                .yellow()
+        );
+    }
+
+    Ok(())
+}
+
+fn fetch_spec(api: &Api, interface: &InterfaceName) -> Result<NetworkSpec, Error> {
+    log::info!("Fetching CIDRs");
+    let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
+    log::info!("Fetching peers");
+    let peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
+    log::info!("Fetching associations");
+    let associations: Vec<Association> = api.http("GET", "/admin/associations")?;
+    NetworkSpec::from_state(interface.to_string(), &cidrs, &peers, &associations)
+}
+
+fn export(interface: &InterfaceName, opts: &Opts, json: bool) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let spec = fetch_spec(&Api::new(&server), interface)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&spec)?);
+    } else {
+        print!("{}", toml::to_string(&spec)?);
+    }
+    Ok(())
+}
+
+fn apply_spec(path: &Path, opts: &Opts, dry_run: bool) -> Result<(), Error> {
+    let contents = std::fs::read_to_string(path).with_path(path)?;
+    let spec: NetworkSpec = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&contents)?
+    } else {
+        toml::from_str(&contents)?
+    };
+    let interface: Interface = spec.network.parse()?;
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, &interface)?;
+    let api = Api::new(&server);
+    api.require_api_version(API_VERSION_2, "applying specs")?;
+
+    // Plan locally first, since the server can only say whether a spec was rejected, not why.
+    let planned = spec.changes_from(&fetch_spec(&api, &interface)?)?;
+    if planned.is_empty() {
+        log::info!(
+            "{} already matches the spec.",
+            interface.as_str_lossy().yellow()
+        );
+        return Ok(());
+    }
+
+    let endpoint = if dry_run {
+        "/admin/spec/plan"
+    } else {
+        "/admin/spec"
+    };
+    let changes: Vec<SpecChange> = api.http_form("POST", endpoint, &spec)?;
+    for change in &changes {
+        println!("{}", change);
+    }
+    if dry_run {
+        log::info!("dry run: {} change(s) not applied.", changes.len());
+    } else {
+        log::info!(
+            "applied {} change(s) to {}.",
+            changes.len(),
+            interface.as_str_lossy().yellow()
+        );
+    }
+    Ok(())
+}
+
+fn shared_endpoints(interface: &InterfaceName, opts: &Opts, json: bool) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let api = Api::new(&server);
+
+    log::info!("Fetching CIDRs");
+    let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
+    log::info!("Fetching peers");
+    let peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
+    log::info!("Fetching associations");
+    let associations: Vec<(i64, i64)> = api
+        .http::<Vec<Association>>("GET", "/admin/associations")?
+        .iter()
+        .map(|a| (a.cidr_id_1, a.cidr_id_2))
+        .collect();
+
+    let groups = shared_endpoint_groups(&peers, &cidrs, &associations);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&groups)?);
+        return Ok(());
+    }
+
+    if groups.is_empty() {
+        println!("No peers share an observed endpoint IP.");
+    }
+    for group in &groups {
+        println!(
+            "{}: {}",
+            group.ip.to_string().bold(),
+            group
+                .peers
+                .iter()
+                .map(|name| name.yellow().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        for pair in &group.needs_hairpin {
+            println!(
+                "  {} {} <=> {} needs NAT hairpinning or a relay (no LAN candidates)",
+                "!".red(),
+                pair.peer1.yellow(),
+                pair.peer2.yellow()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn check_ips(interface: &InterfaceName, opts: &Opts, json: bool) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let api = Api::new(&server);
+
+    log::info!("Fetching peers");
+    let peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
+
+    let duplicates = DuplicateIp::find(&peers);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&duplicates)?);
+    } else if duplicates.is_empty() {
+        println!("No IP is assigned to more than one peer.");
+    } else {
+        for duplicate in &duplicates {
+            println!(
+                "{} {} is assigned to {}",
+                "!".red(),
+                duplicate.ip.to_string().bold(),
+                duplicate
+                    .peers
+                    .iter()
+                    .map(|name| name.yellow().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+    }
+
+    if !duplicates.is_empty() {
+        bail!(
+            "{} IP address(es) assigned to more than one peer.",
+            duplicates.len()
+        );
+    }
+    Ok(())
+}
+
+fn set_mtu_hint(
+    interface: &InterfaceName,
+    opts: &Opts,
+    peer_name: &str,
+    mtu: Option<u32>,
+) -> Result<(), Error> {
+    if let Some(mtu) = mtu {
+        if !MtuHint::is_valid_mtu(mtu) {
+            bail!("MTU must be between {} and {}.", MtuHint::MIN, MtuHint::MAX);
+        }
+    }
+
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let api = Api::new(&server);
+
+    api.require_api_version(API_VERSION_2, "MTU hints")?;
+
+    log::info!("Fetching peers");
+    let peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
+    let peer = peers
+        .into_iter()
+        .find(|peer| &*peer.name == peer_name)
+        .ok_or_else(|| anyhow!("No peer named \"{}\".", peer_name))?;
+
+    api.http_form::<_, ()>(
+        "PUT",
+        &format!("/admin/peers/{}/mtu-hint", peer.id),
+        MtuHintContents { mtu },
+    )?;
+    match mtu {
+        Some(mtu) => log::info!("Set MTU hint of {} to {}.", peer.name.yellow(), mtu),
+        None => log::info!("Cleared MTU hint of {}.", peer.name.yellow()),
+    }
+    Ok(())
+}
+
+fn list_mtu_hints(interface: &InterfaceName, opts: &Opts, json: bool) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let api = Api::new(&server);
+    api.require_api_version(API_VERSION_2, "MTU hints")?;
+
+    log::info!("Fetching peers");
+    let peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
+    log::info!("Fetching MTU hints");
+    let hints: Vec<MtuHint> = api.http("GET", "/admin/peers/mtu-hints")?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&hints)?);
+        return Ok(());
+    }
+
+    for hint in &hints {
+        match peers.iter().find(|peer| peer.id == hint.peer_id) {
+            Some(peer) => println!("{} {} {}", peer.name.yellow(), peer.ip, hint.mtu),
+            None => println!("{} {}", format!("#{}", hint.peer_id).yellow(), hint.mtu),
+        }
+    }
+    if hints.is_empty() {
+        log::info!("No peers have MTU hints.");
+    }
+
+    Ok(())
+}
+
+fn list_peer_metadata(interface: &InterfaceName, opts: &Opts, json: bool) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let api = Api::new(&server);
+    api.require_api_version(API_VERSION_2, "peer metadata")?;
+
+    log::info!("Fetching peers");
+    let mut peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
+    log::info!("Fetching peer metadata");
+    let metadata: Vec<ReportedMetadata> = api.http("GET", "/admin/peers/metadata")?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&metadata)?);
+        return Ok(());
+    }
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Something is horribly wrong with system time.")
+        .as_secs();
+    peers.sort_by(|a, b| a.name.cmp(&b.name));
+    for peer in peers.iter().filter(|peer| !peer.is_disabled) {
+        let reported = metadata.iter().find(|m| m.peer_id == peer.id);
+        match reported {
+            Some(ReportedMetadata {
+                reported_at,
+                metadata,
+                ..
+            }) => {
+                let field = |value: &Option<String>| value.clone().unwrap_or_else(|| "?".into());
+                println!(
+                    "{} {} v{} {} {} {} ({})",
+                    peer.name.yellow(),
+                    peer.ip,
+                    field(&metadata.version),
+                    field(&metadata.os),
+                    field(&metadata.backend),
+                    field(&metadata.hostname).dimmed(),
+                    human_duration(Duration::from_secs(now.saturating_sub(*reported_at))),
+                );
+            },
+            None => println!(
+                "{} {} {}",
+                peer.name.yellow(),
+                peer.ip,
+                "(never reported)".dimmed()
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+fn simulate_association(
+    interface: &InterfaceName,
+    opts: &Opts,
+    delete: bool,
+    cidr1: &str,
+    cidr2: &str,
+    json: bool,
+) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let api = Api::new(&server);
+
+    log::info!("Fetching CIDRs");
+    let cidrs: Vec<Cidr> = api.http("GET", "/admin/cidrs")?;
+    log::info!("Fetching peers");
+    let peers: Vec<Peer> = api.http("GET", "/admin/peers")?;
+    log::info!("Fetching associations");
+    let associations: Vec<Association> = api.http("GET", "/admin/associations")?;
+
+    let find_cidr = |name: &str| {
+        cidrs
+            .iter()
+            .find(|c| c.name == name)
+            .map(|c| c.id)
+            .ok_or_else(|| anyhow!("can't find cidr '{}'", name))
+    };
+    let (id1, id2) = (find_cidr(cidr1)?, find_cidr(cidr2)?);
+    let is_change = |&(a, b): &(i64, i64)| (a, b) == (id1, id2) || (a, b) == (id2, id1);
+
+    let before: Vec<(i64, i64)> = associations
+        .iter()
+        .map(|a| (a.cidr_id_1, a.cidr_id_2))
+        .collect();
+    let mut after = before.clone();
+    if delete {
+        if !before.iter().any(is_change) {
+            bail!("'{}' and '{}' aren't associated", cidr1, cidr2);
+        }
+        after.retain(|pair| !is_change(pair));
+    } else {
+        if before.iter().any(is_change) {
+            bail!("'{}' and '{}' are already associated", cidr1, cidr2);
+        }
+        after.push((id1, id2));
+    }
+
+    let delta = ReachabilityDelta::between(&peers, &cidrs, &before, &after);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&delta)?);
+        return Ok(());
+    }
+
+    for pair in &delta.gained {
         println!(
-            "{}: {} <=> {}",
-            association.id,
-            &cidrs
-                .iter()
-                .find(|c| c.id == association.cidr_id_1)
-                .unwrap()
-                .name
-                .yellow(),
-            &cidrs
-                .iter()
-                .find(|c| c.id == association.cidr_id_2)
-                .unwrap()
-                .name
-                .yellow()
+            "{} {} <=> {}",
+            "+".green(),
+            pair.peer1.yellow(),
+            pair.peer2.yellow()
         );
     }
+    for pair in &delta.lost {
+        println!(
+            "{} {} <=> {}",
+            "-".red(),
+            pair.peer1.yellow(),
+            pair.peer2.yellow()
+        );
+    }
+    println!(
+        "{} peer pair(s) would gain reachability, {} would lose it.",
+        delta.gained.len(),
+        delta.lost.len()
+    );
 
     Ok(())
 }
@@ -966,7 +2435,7 @@ fn override_endpoint(
     };
 
     let endpoint_contents = if sub_opts.unset {
-        prompts::unset_override_endpoint(&sub_opts)?.then(|| EndpointContents::Unset)
+        prompts::unset_override_endpoint(&sub_opts)?.then_some(EndpointContents::Unset)
     } else {
         let endpoint = prompts::override_endpoint(&sub_opts, port)?;
         endpoint.map(EndpointContents::Set)
@@ -974,7 +2443,7 @@ fn override_endpoint(
 
     if let Some(contents) = endpoint_contents {
         log::info!("requesting endpoint update...");
-        Api::new(&config.server).http_form("PUT", "/user/endpoint", contents)?;
+        Api::new(&config.server).http_form::<_, ()>("PUT", "/user/endpoint", contents)?;
         log::info!(
             "endpoint override {}",
             if sub_opts.unset { "unset" } else { "set" }
@@ -986,7 +2455,461 @@ fn override_endpoint(
     Ok(())
 }
 
-fn show(opts: &Opts, short: bool, tree: bool, interface: Option<Interface>) -> Result<(), Error> {
+fn maintenance(
+    interface: &InterfaceName,
+    opts: &Opts,
+    read_only: Option<bool>,
+) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let api = Api::new(&server);
+    api.require_api_version(API_VERSION_2, "maintenance mode")?;
+
+    let status: MaintenanceContents = match read_only {
+        Some(read_only) => api.http_form(
+            "PUT",
+            "/admin/maintenance",
+            MaintenanceContents { read_only },
+        )?,
+        None => api.http("GET", "/admin/maintenance")?,
+    };
+    println!(
+        "{} is {}.",
+        interface.to_string().yellow(),
+        if status.read_only {
+            "in read-only maintenance mode".red()
+        } else {
+            "accepting changes".green()
+        }
+    );
+
+    Ok(())
+}
+
+fn features(
+    interface: &InterfaceName,
+    opts: &Opts,
+    enable: &[String],
+    disable: &[String],
+    json: bool,
+) -> Result<(), Error> {
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let api = Api::new(&server);
+    api.require_api_version(API_VERSION_2, "feature flags")?;
+
+    let mut flags: FeatureFlags = api.http("GET", "/admin/features")?;
+    let changes = enable
+        .iter()
+        .map(|name| (name, true))
+        .chain(disable.iter().map(|name| (name, false)));
+    for (name, enabled) in changes {
+        flags = api.http_form(
+            "PUT",
+            "/admin/features",
+            FeatureFlagContents {
+                name: name.clone(),
+                enabled,
+            },
+        )?;
+        log::info!(
+            "Turned {} {} for {}.",
+            name.yellow(),
+            if enabled { "on" } else { "off" },
+            interface
+        );
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&flags)?);
+        return Ok(());
+    }
+    for name in FeatureFlags::NAMES {
+        let enabled = flags.get(name) == Some(true);
+        println!(
+            "{} {}",
+            name.yellow(),
+            if enabled { "on".green() } else { "off".red() }
+        );
+    }
+
+    Ok(())
+}
+
+fn whois(interface: &InterfaceName, opts: &Opts, ip: IpAddr, json: bool) -> Result<(), Error> {
+    let config = InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let store = DataStore::open(&opts.data_dir, interface)?;
+    let peer = store.peers().iter().find(|peer| peer.ip == ip);
+    let cidr = peer.and_then(|peer| store.cidrs().iter().find(|c| c.id == peer.cidr_id));
+    // Non-admins are only sent the peers they can reach, so a miss doesn't mean the address
+    // is free.
+    let sees_every_peer = store
+        .peers()
+        .iter()
+        .any(|peer| peer.ip == config.interface.address.addr() && peer.is_admin);
+
+    if json {
+        let result = match peer {
+            Some(peer) => serde_json::json!({
+                "ip": ip,
+                "assigned": true,
+                "peer": {
+                    "id": peer.id,
+                    "name": peer.name,
+                    "public_key": peer.public_key,
+                    "cidr": cidr.map(|c| &c.name),
+                    "is_disabled": peer.is_disabled,
+                },
+            }),
+            None if sees_every_peer => serde_json::json!({ "ip": ip, "assigned": false }),
+            None => serde_json::json!({ "ip": ip, "assigned": null, "visible": false }),
+        };
+        println!("{}", serde_json::to_string_pretty(&result)?);
+        return Ok(());
+    }
+
+    match peer {
+        Some(peer) => {
+            println!(
+                "{}: {}",
+                "peer".green().bold(),
+                peer.name.to_string().green()
+            );
+            println!("  {}: {}", "ip".bold(), peer.ip);
+            println!("  {}: {}", "public key".bold(), peer.public_key);
+            if let Some(cidr) = cidr {
+                println!("  {}: {}", "cidr".bold(), cidr);
+            }
+            if peer.is_disabled {
+                println!("  {}", "disabled".red());
+            }
+        },
+        None if sees_every_peer => println!("{} is unassigned.", ip.to_string().yellow()),
+        None => println!(
+            "{} is not visible to this peer (only admins can see every peer).",
+            ip.to_string().yellow()
+        ),
+    }
+
+    Ok(())
+}
+
+fn doctor(
+    interface: &InterfaceName,
+    opts: &Opts,
+    duration: Duration,
+    thresholds: HandshakeThresholds,
+) -> Result<(), Error> {
+    let store = DataStore::open(&opts.data_dir, interface)?;
+    log::info!(
+        "watching {} for {}s...",
+        interface.as_str_lossy().yellow(),
+        duration.as_secs()
+    );
+
+    let mut tracker = HandshakeTracker::default();
+    let start = Instant::now();
+    loop {
+        let device = Device::get(interface, opts.network.backend)?;
+        tracker.observe(device.peers.iter().map(|peer| {
+            (
+                peer.config.public_key.to_base64(),
+                peer.stats.last_handshake_time,
+            )
+        }));
+        if start.elapsed() >= duration {
+            break;
+        }
+        thread::sleep(doctor::POLL_INTERVAL);
+    }
+
+    let flapping = tracker.flapping(&thresholds);
+    if flapping.is_empty() {
+        println!("{}", "No problems found.".green());
+        return Ok(());
+    }
+    for peer in &flapping {
+        let name = store
+            .peers()
+            .iter()
+            .find(|p| p.public_key == peer.public_key)
+            .map_or_else(|| peer.public_key.clone(), |p| p.name.to_string());
+        println!(
+            "{} {} handshaked {} times, {} of them within {}s of the previous one (closest: {}s)",
+            "!".red(),
+            name.yellow(),
+            peer.handshakes,
+            peer.short_intervals,
+            thresholds.min_interval.as_secs(),
+            peer.shortest_interval.as_secs()
+        );
+    }
+    println!(
+        "Frequent handshakes usually mean the peer's endpoint keeps changing, or its key is \
+         in use on more than one machine."
+    );
+    bail!(
+        "{} peer(s) are handshaking abnormally often.",
+        flapping.len()
+    );
+}
+
+fn socket_info(interface: &InterfaceName, opts: &Opts) -> Result<(), Error> {
+    let device = Device::get(interface, opts.network.backend)?;
+
+    println!(
+        "{}: {}",
+        "network".green().bold(),
+        device.name.to_string().green()
+    );
+    println!("  {}: {}", "backend".bold(), device.backend);
+    match device.listen_port {
+        Some(port) => println!("  {}: {}", "listening port".bold(), port),
+        None => println!("  {}: {}", "listening port".bold(), "none".dimmed()),
+    }
+    match device.fwmark {
+        Some(fwmark) => println!("  {}: {:#x}", "fwmark".bold(), fwmark),
+        None => println!("  {}: {}", "fwmark".bold(), "none".dimmed()),
+    }
+
+    let bound = device.listen_port.map(udp_bound_addrs).unwrap_or_default();
+    if bound.is_empty() {
+        println!("  {}: {}", "bound to".bold(), "unknown".dimmed());
+    } else {
+        println!("  {}:", "bound to".bold());
+        for addr in bound {
+            println!("    {}", addr);
+        }
+    }
+
+    Ok(())
+}
+
+/// How long the throwaway invitation from `selftest-server` stays valid. If cleanup
+/// fails, the server's expired invite sweeper removes it after this.
+const SELFTEST_INVITE_TTL: Duration = Duration::from_secs(60);
+
+/// Redeem a `selftest-server` invitation the way `install` would, returning the public key
+/// it was redeemed with.
+///
+/// The server tells peers apart by the tunnel address their requests come from, so this
+/// brings up a temporary interface with the invited IP and, for the duration of the
+/// request, routes only the server's address through it.
+fn redeem_selftest_invite(
+    interface: &InterfaceName,
+    server: &ServerInfo,
+    network: NetworkOpts,
+    invite_keypair: &wireguard_control::KeyPair,
+    ip: IpAddr,
+) -> Result<Key, Error> {
+    let resolved_endpoint = server
+        .external_endpoint
+        .resolve()
+        .with_str(server.external_endpoint.to_string())?;
+    wg::up(
+        interface,
+        &invite_keypair.private.to_base64(),
+        IpNet::from(ip),
+        None,
+        Some((
+            &server.public_key,
+            server.internal_endpoint.ip(),
+            resolved_endpoint,
+        )),
+        NetworkOpts {
+            no_routing: true,
+            ..network
+        },
+        network.mtu_or(None),
+    )
+    .with_str(interface.to_string())?;
+
+    let redeem = || -> Result<Key, Error> {
+        wg::add_route(interface, IpNet::from(server.internal_endpoint.ip()))
+            .with_str(interface.to_string())?;
+        let keypair = wireguard_control::KeyPair::generate();
+        Api::new(server).http_form::<_, ()>(
+            "POST",
+            "/user/redeem",
+            RedeemContents {
+                public_key: keypair.public.to_base64(),
+            },
+        )?;
+        Ok(keypair.public)
+    };
+    let result = redeem();
+    // Removing the interface removes its route too, handing the server back to the
+    // network's own interface.
+    if let Err(e) = wg::down(interface, network.backend) {
+        log::warn!("failed to remove {}: {}", interface, e);
+    }
+    result
+}
+
+fn selftest_server(
+    interface: &InterfaceName,
+    opts: &Opts,
+    cidr_name: Option<String>,
+) -> Result<(), Error> {
+    fn step<T>(name: &str, result: Result<T, Error>) -> Result<T, Error> {
+        match &result {
+            Ok(_) => println!("{} {}", "[ok]".green(), name),
+            Err(e) => println!("{} {}: {}", "[failed]".red(), name, e),
+        }
+        result
+    }
+
+    let InterfaceConfig { server, .. } =
+        InterfaceConfig::from_interface(&opts.config_dir, interface)?;
+    let api = Api::new(&server);
+    step(
+        "check the server's API version",
+        api.require_api_version(API_VERSION_2, "self-tests"),
+    )?;
+
+    let cidrs: Vec<Cidr> = step(
+        "fetch CIDRs",
+        api.http("GET", "/admin/cidrs").map_err(Into::into),
+    )?;
+    let allocate = || {
+        let candidates = cidrs
+            .iter()
+            .filter(|cidr| cidr_name.as_ref().is_none_or(|name| &cidr.name == name));
+        for cidr in candidates {
+            let request = NextIpsRequest {
+                count: 1,
+                reserve_secs: Some(SELFTEST_INVITE_TTL.as_secs()),
+            };
+            let path = format!("/admin/cidrs/{}/next-ips", cidr.id);
+            match api.http_form::<_, Vec<IpAddr>>("POST", &path, request) {
+                Ok(ips) => {
+                    if let Some(ip) = ips.first() {
+                        return Ok((cidr, *ip));
+                    }
+                },
+                // CIDRs with children and full CIDRs are refused, so try the next one.
+                Err(ureq::Error::Status(400, _)) => {},
+                Err(e) => return Err(Error::from(e).context(format!("CIDR {}", cidr.name))),
+            }
+        }
+        Err(anyhow!("no eligible CIDR has a free IP"))
+    };
+    let (cidr, ip) = step("allocate an IP", allocate())?;
+
+    let keypair = wireguard_control::KeyPair::generate();
+    let suffix = keypair.public.as_bytes()[..4]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    let name = format!("selftest-{}", suffix);
+    // Interface names are limited to 15 characters.
+    let selftest_interface: InterfaceName = format!("selftest{}", &suffix[..4]).parse()?;
+    let peer_request = PeerContents {
+        name: name.parse().map_err(|e: &str| anyhow!(e))?,
+        ip,
+        cidr_id: cidr.id,
+        public_key: keypair.public.to_base64(),
+        endpoint: None,
+        persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
+        is_admin: false,
+        is_disabled: false,
+        is_redeemed: false,
+        invite_expires: Some(SystemTime::now() + SELFTEST_INVITE_TTL),
+        candidates: vec![],
+        extra_allowed_ips: vec![],
+        deleted_at: None,
+    };
+    let peer: Peer = step(
+        &format!("create invitation for {} ({}) in {}", name, ip, cidr.name),
+        api.http_form("POST", "/admin/peers", peer_request)
+            .map_err(Into::into),
+    )?;
+
+    let redeemed = step(
+        &format!("redeem invitation over {}", selftest_interface),
+        redeem_selftest_invite(&selftest_interface, &server, opts.network, &keypair, ip),
+    );
+    let checks = redeemed.and_then(|new_key| {
+        step(
+            "list peers",
+            api.http::<Vec<Peer>>("GET", "/admin/peers")
+                .map_err(Error::from)
+                .and_then(|peers| match peers.iter().find(|p| p.id == peer.id) {
+                    Some(p) if p.is_redeemed && p.public_key == new_key.to_base64() => Ok(()),
+                    Some(_) => Err(anyhow!("{} isn't listed as redeemed", name)),
+                    None => Err(anyhow!("{} is missing from the peer list", name)),
+                }),
+        )?;
+        step(
+            "fetch network state",
+            api.http::<State>("GET", "/user/state").map_err(Into::into),
+        )
+    });
+
+    let cleanup = step(
+        &format!("delete {}", name),
+        api.http::<()>("DELETE", &format!("/admin/peers/{}", peer.id))
+            .map_err(Into::into),
+    );
+
+    checks?;
+    cleanup?;
+    println!(
+        "server at {} passed the enrollment self-test.",
+        server.internal_endpoint
+    );
+    Ok(())
+}
+
+/// A peer of a running network, as printed by `show --json`.
+#[derive(Debug, Serialize)]
+struct PeerSummary {
+    interface: String,
+    name: String,
+    ip: IpAddr,
+    public_key: String,
+    cidr: Option<String>,
+    /// The endpoint WireGuard is using, falling back to the one the server knows of.
+    endpoint: Option<String>,
+    /// The UNIX time of the latest handshake, or null if there hasn't been one. Stats are
+    /// null for this host's own peer.
+    last_handshake: Option<u64>,
+    rx_bytes: Option<u64>,
+    tx_bytes: Option<u64>,
+}
+
+impl PeerSummary {
+    fn new(interface: &InterfaceName, state: &PeerState, cidrs: &[Cidr]) -> Self {
+        let PeerState { peer, info } = state;
+        Self {
+            interface: interface.to_string(),
+            name: peer.name.to_string(),
+            ip: peer.ip,
+            public_key: peer.public_key.clone(),
+            cidr: cidrs
+                .iter()
+                .find(|cidr| cidr.id == peer.cidr_id)
+                .map(|cidr| cidr.name.clone()),
+            endpoint: info
+                .and_then(|info| info.config.endpoint.map(|endpoint| endpoint.to_string()))
+                .or_else(|| peer.endpoint.as_ref().map(ToString::to_string)),
+            last_handshake: info
+                .and_then(|info| info.stats.last_handshake_time)
+                .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map(|since_epoch| since_epoch.as_secs()),
+            rx_bytes: info.map(|info| info.stats.rx_bytes),
+            tx_bytes: info.map(|info| info.stats.tx_bytes),
+        }
+    }
+}
+
+fn show(
+    opts: &Opts,
+    short: bool,
+    tree: bool,
+    json: bool,
+    interface: Option<Interface>,
+) -> Result<(), Error> {
     let interfaces = interface.map_or_else(
         || Device::list(opts.network.backend),
         |interface| Ok(vec![*interface]),
@@ -1009,11 +2932,12 @@ fn show(opts: &Opts, short: bool, tree: bool, interface: Option<Interface>) -> R
         })
         .collect::<Result<Vec<_>, _>>()?;
 
-    if devices.is_empty() {
+    if devices.is_empty() && !json {
         log::info!("No innernet networks currently running.");
         return Ok(());
     }
 
+    let mut summaries = vec![];
     for (device_info, store) in devices {
         let public_key = match &device_info.public_key {
             Some(key) => key.to_base64(),
@@ -1052,9 +2976,17 @@ fn show(opts: &Opts, short: bool, tree: bool, interface: Option<Interface>) -> R
             info: None,
         });
 
-        print_interface(&device_info, short || tree)?;
         peer_states.sort_by_key(|peer| peer.peer.ip);
+        if json {
+            summaries.extend(
+                peer_states
+                    .iter()
+                    .map(|state| PeerSummary::new(&device_info.name, state, cidrs)),
+            );
+            continue;
+        }
 
+        print_interface(&device_info, short || tree)?;
         if tree {
             let cidr_tree = CidrTree::new(cidrs);
             print_tree(&cidr_tree, &peer_states, 1);
@@ -1064,6 +2996,10 @@ fn show(opts: &Opts, short: bool, tree: bool, interface: Option<Interface>) -> R
             }
         }
     }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+    }
     Ok(())
 }
 
@@ -1086,6 +3022,98 @@ fn print_tree(cidr: &CidrTree, peers: &[PeerState], level: usize) {
     }
 }
 
+#[derive(Debug, Serialize)]
+struct InterfaceSummary {
+    name: String,
+    status: InterfaceStatus,
+    /// The network's name and address, for interfaces managed by innernet.
+    network: Option<String>,
+    address: Option<IpNet>,
+    listen_port: Option<u16>,
+    /// The number of peers configured on the interface if it's up, otherwise the number of
+    /// enabled peers from the last fetch.
+    peers: Option<usize>,
+}
+
+fn interfaces(opts: &Opts, json: bool) -> Result<(), Error> {
+    let present = Device::list(opts.network.backend)?;
+    let installed = match all_installed(&opts.config_dir) {
+        Ok(installed) => installed.iter().map(|interface| **interface).collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e.into()),
+    };
+
+    let summaries = interface_statuses(&present, &installed)
+        .into_iter()
+        .map(|(name, status)| {
+            let device = match status {
+                InterfaceStatus::Down => None,
+                _ => Some(Device::get(&name, opts.network.backend).with_str(name.as_str_lossy())?),
+            };
+            let config = match status {
+                InterfaceStatus::Unmanaged => None,
+                _ => Some(InterfaceConfig::from_interface(&opts.config_dir, &name)?),
+            };
+            let store_peers = || {
+                DataStore::open(&opts.data_dir, &name)
+                    .ok()
+                    .map(|store| store.peers().iter().filter(|p| !p.is_disabled).count())
+            };
+            Ok(InterfaceSummary {
+                name: name.to_string(),
+                status,
+                network: config
+                    .as_ref()
+                    .map(|config| config.interface.network_name.clone()),
+                address: config.as_ref().map(|config| config.interface.address),
+                listen_port: device.as_ref().map_or_else(
+                    || {
+                        config
+                            .as_ref()
+                            .and_then(|config| config.interface.listen_port)
+                    },
+                    |device| device.listen_port,
+                ),
+                peers: device
+                    .as_ref()
+                    .map(|device| device.peer_count())
+                    .or_else(store_peers),
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+        return Ok(());
+    }
+
+    if summaries.is_empty() {
+        println!("No WireGuard interfaces or innernet networks found.");
+    }
+    for summary in &summaries {
+        let status = match summary.status {
+            InterfaceStatus::Up => "up".green(),
+            InterfaceStatus::Down => "down".red(),
+            InterfaceStatus::Unmanaged => "not managed by innernet".dimmed(),
+        };
+        println!("{} ({})", summary.name.bold(), status);
+        if let Some(network) = &summary.network {
+            println!("  {}: {}", "network".bold(), network);
+        }
+        if let Some(address) = &summary.address {
+            println!("  {}: {}", "address".bold(), address);
+        }
+        if let Some(listen_port) = summary.listen_port {
+            println!("  {}: {}", "listening port".bold(), listen_port);
+        }
+        if let Some(peers) = summary.peers {
+            println!("  {}: {}", "peers".bold(), peers);
+        }
+    }
+
+    Ok(())
+}
+
 fn print_interface(device_info: &Device, short: bool) -> Result<(), Error> {
     if short {
         let listen_port_str = device_info
@@ -1144,10 +3172,15 @@ fn print_peer(peer: &PeerState, short: bool, level: usize) {
         println_pad!(pad, "  {}: {}", "ip".bold(), peer.ip);
         if let Some(info) = info {
             if let Some(endpoint) = info.config.endpoint {
-                println_pad!(pad, "  {}: {}", "endpoint".bold(), endpoint);
+                println_pad!(
+                    pad,
+                    "  {}: {} ({})",
+                    "endpoint".bold(),
+                    endpoint,
+                    EndpointSource::of(peer, endpoint).to_string().dimmed(),
+                );
             }
-            if let Some(last_handshake) = info.stats.last_handshake_time {
-                let duration = last_handshake.elapsed().expect("horrible clock problem");
+            if let Some(duration) = info.stats.time_since_handshake() {
                 println_pad!(
                     pad,
                     "  {}: {}",
@@ -1199,6 +3232,7 @@ fn run(opts: &Opts) -> Result<(), Error> {
     let command = opts.command.clone().unwrap_or(Command::Show {
         short: false,
         tree: false,
+        json: false,
         interface: None,
     });
 
@@ -1209,35 +3243,90 @@ fn run(opts: &Opts) -> Result<(), Error> {
             install_opts,
             nat,
         } => install(opts, &invite, hosts.into(), install_opts, &nat)?,
+        Command::GenerateKeypair { private_key_file } => generate_keypair(&private_key_file)?,
         Command::Show {
             short,
             tree,
+            json,
             interface,
-        } => show(opts, short, tree, interface)?,
+        } => show(opts, short, tree, json, interface)?,
+        Command::Interfaces { json } => interfaces(opts, json)?,
         Command::Fetch {
             interface,
+            run_loop,
+            interval,
+            max_interval,
             hosts,
             nat,
-        } => fetch(&interface, opts, false, hosts.into(), &nat)?,
+        } => {
+            if run_loop {
+                let backoff = Backoff::new(
+                    interval.into(),
+                    max_interval.into(),
+                    FETCH_LOOP_INTERVAL_JITTER,
+                );
+                fetch_loop(&interface, opts, backoff, hosts.into(), &nat)?;
+            } else {
+                fetch(&interface, opts, false, hosts.into(), &nat)?;
+            }
+        },
         Command::Up {
             interface,
             daemon,
             hosts,
             nat,
             interval,
-        } => up(
+            max_interval,
+            interval_jitter,
+            coalesce_window,
+            quiet,
+            exit_code,
+        } => {
+            if quiet {
+                log::set_max_level(log::LevelFilter::Warn);
+            }
+            if daemon {
+                util::handle_shutdown_signals();
+                util::handle_sync_signal();
+            }
+            let changed = up(
+                interface,
+                opts,
+                daemon.then(|| {
+                    Backoff::new(
+                        interval.into(),
+                        max_interval.into(),
+                        f64::from(interval_jitter) / 100.0,
+                    )
+                }),
+                Duration::from_secs(coalesce_window),
+                hosts.into(),
+                &nat,
+            )?;
+            if exit_code && !changed {
+                std::process::exit(NO_CHANGES_EXIT_CODE);
+            }
+        },
+        Command::Down {
             interface,
-            opts,
-            daemon.then(|| Duration::from_secs(interval)),
-            hosts.into(),
-            &nat,
-        )?,
-        Command::Down { interface } => wg::down(&interface, opts.network.backend)?,
-        Command::Uninstall { interface, yes } => uninstall(&interface, opts, yes)?,
+            keep_interface,
+            hosts,
+        } => down(&interface, opts, keep_interface, hosts.into())?,
+        Command::Uninstall {
+            interface,
+            yes,
+            hosts,
+        } => uninstall(&interface, opts, yes, hosts.into())?,
         Command::AddPeer {
             interface,
             sub_opts,
+            from_file: None,
         } => add_peer(&interface, opts, sub_opts)?,
+        Command::AddPeer {
+            interface,
+            sub_opts,
+            from_file: Some(path),
+        } => add_peers_from_file(&interface, opts, &path, sub_opts)?,
         Command::RenamePeer {
             interface,
             sub_opts,
@@ -1246,11 +3335,26 @@ fn run(opts: &Opts) -> Result<(), Error> {
             interface,
             sub_opts,
         } => add_cidr(&interface, opts, sub_opts)?,
+        Command::RenameCidr {
+            interface,
+            sub_opts,
+        } => rename_cidr(&interface, opts, sub_opts)?,
         Command::DeleteCidr {
             interface,
             sub_opts,
         } => delete_cidr(&interface, opts, sub_opts)?,
-        Command::ListCidrs { interface, tree } => list_cidrs(&interface, opts, tree)?,
+        Command::ListCidrs {
+            interface,
+            tree,
+            utilization,
+            json,
+        } => list_cidrs(&interface, opts, tree, utilization, json)?,
+        Command::NextIps {
+            interface,
+            cidr,
+            count,
+            reserve,
+        } => next_ips(&interface, opts, &cidr, count, reserve)?,
         Command::DisablePeer { interface } => enable_or_disable_peer(&interface, opts, false)?,
         Command::EnablePeer { interface } => enable_or_disable_peer(&interface, opts, true)?,
         Command::AddAssociation {
@@ -1262,6 +3366,24 @@ fn run(opts: &Opts) -> Result<(), Error> {
             sub_opts,
         } => delete_association(&interface, opts, sub_opts)?,
         Command::ListAssociations { interface } => list_associations(&interface, opts)?,
+        Command::SimulateAssociation {
+            interface,
+            delete,
+            cidr1,
+            cidr2,
+            json,
+            ..
+        } => simulate_association(&interface, opts, delete, &cidr1, &cidr2, json)?,
+        Command::Export { interface, json } => export(&interface, opts, json)?,
+        Command::ApplySpec { file, dry_run } => apply_spec(&file, opts, dry_run)?,
+        Command::SharedEndpoints { interface, json } => shared_endpoints(&interface, opts, json)?,
+        Command::CheckIps { interface, json } => check_ips(&interface, opts, json)?,
+        Command::SetMtuHint {
+            interface,
+            peer,
+            mtu,
+        } => set_mtu_hint(&interface, opts, &peer, mtu)?,
+        Command::ListMtuHints { interface, json } => list_mtu_hints(&interface, opts, json)?,
         Command::SetListenPort {
             interface,
             sub_opts,
@@ -1274,6 +3396,40 @@ fn run(opts: &Opts) -> Result<(), Error> {
         } => {
             override_endpoint(&interface, opts, sub_opts)?;
         },
+        Command::Maintenance {
+            interface,
+            read_only,
+        } => maintenance(&interface, opts, read_only)?,
+        Command::Features {
+            interface,
+            enable,
+            disable,
+            json,
+        } => features(&interface, opts, &enable, &disable, json)?,
+        Command::SelftestServer { interface, cidr } => selftest_server(&interface, opts, cidr)?,
+        Command::Whois {
+            interface,
+            ip,
+            json,
+        } => whois(&interface, opts, ip, json)?,
+        Command::ListPeerMetadata { interface, json } => {
+            list_peer_metadata(&interface, opts, json)?
+        },
+        Command::SocketInfo { interface } => socket_info(&interface, opts)?,
+        Command::Doctor {
+            interface,
+            duration,
+            min_handshake_interval,
+            max_short_handshakes,
+        } => doctor(
+            &interface,
+            opts,
+            Duration::from_secs(duration),
+            HandshakeThresholds {
+                min_interval: Duration::from_secs(min_handshake_interval),
+                max_short_intervals: max_short_handshakes,
+            },
+        )?,
         Command::Completions { shell } => {
             let mut app = Opts::command();
             let app_name = app.get_name().to_string();
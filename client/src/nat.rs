@@ -3,21 +3,28 @@
 //! Doesn't follow the specific ICE protocol, but takes great inspiration from RFC 8445
 //! and applies it to a protocol more specific to innernet.
 
-use std::time::{Duration, Instant};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant, SystemTime},
+};
 
+use crate::data_store::FailedEndpoint;
 use anyhow::Error;
 use shared::{
     wg::{DeviceExt, PeerInfoExt},
-    Endpoint, Peer, PeerDiff,
+    CandidateKind, CandidateWeights, Endpoint, EndpointPortPolicy, Peer, PeerDiff,
+};
+use wireguard_control::{
+    Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder, PeerInfo,
 };
-use wireguard_control::{Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder};
-
-pub const STEP_INTERVAL: Duration = Duration::from_secs(5);
 
 pub struct NatTraverse<'a> {
     interface: &'a InterfaceName,
     backend: Backend,
     remaining: Vec<Peer>,
+    /// How long each candidate gets to complete a handshake.
+    step_interval: Duration,
 }
 
 impl<'a> NatTraverse<'a> {
@@ -25,11 +32,29 @@ impl<'a> NatTraverse<'a> {
         interface: &'a InterfaceName,
         backend: Backend,
         diffs: &[PeerDiff],
+        weights: &CandidateWeights,
+        ports: &EndpointPortPolicy,
+        step_interval: Duration,
+        failed_probes: &HashMap<String, FailedProbe>,
     ) -> Result<Self, Error> {
         // Filter out removed peers from diffs list.
         let mut remaining: Vec<_> = diffs.iter().filter_map(|diff| diff.new).cloned().collect();
 
         for peer in &mut remaining {
+            // Peers whose new endpoint failed its probe are reset to their previous one instead.
+            if let Some(failed) = failed_probes.get(&peer.public_key) {
+                peer.endpoint = Some(failed.previous.into());
+            }
+
+            for candidate in ports.retain_allowed(&mut peer.candidates) {
+                log::warn!(
+                    "ignoring candidate {} of peer {}: port isn't allowed by this network's policy ({}).",
+                    candidate,
+                    peer.name,
+                    ports
+                );
+            }
+
             // Limit reported alternative candidates to 10.
             peer.candidates.truncate(10);
 
@@ -37,11 +62,14 @@ impl<'a> NatTraverse<'a> {
             let endpoint = peer.endpoint.clone();
             peer.candidates
                 .retain(|addr| Some(addr) != endpoint.as_ref());
+
+            order_candidates(peer, weights);
         }
         let mut nat_traverse = Self {
             interface,
             backend,
             remaining,
+            step_interval,
         };
         nat_traverse.refresh_remaining()?;
         Ok(nat_traverse)
@@ -55,9 +83,16 @@ impl<'a> NatTraverse<'a> {
         self.remaining.len()
     }
 
+    pub fn step_interval(&self) -> Duration {
+        self.step_interval
+    }
+
     /// Refreshes the current state of candidate traversal attempts, returning
     /// the peers that have been exhausted of all options (not included are
     /// peers that have successfully connected, or peers removed from the interface).
+    ///
+    /// Peers that were recently connected are never traversed in the first place, so a peer
+    /// counting as recently connected here means its handshake time advanced.
     fn refresh_remaining(&mut self) -> Result<Vec<Peer>, Error> {
         let device = Device::get(self.interface, self.backend)?;
         // Remove connected and missing peers
@@ -111,7 +146,7 @@ impl<'a> NatTraverse<'a> {
             .apply(self.interface, self.backend)?;
 
         let start = Instant::now();
-        while start.elapsed() < STEP_INTERVAL {
+        while start.elapsed() < self.step_interval {
             self.refresh_remaining()?;
             if self.is_finished() {
                 log::debug!("NAT traverser is finished!");
@@ -123,6 +158,34 @@ impl<'a> NatTraverse<'a> {
     }
 }
 
+/// Sort a peer's candidates so that popping from the end yields them from
+/// highest to lowest weight, keeping the reported order for equal weights.
+///
+/// The server-reported endpoint is slotted in by its own weight so that
+/// higher-weighted candidates are attempted before it. If it would be the
+/// last attempt anyway it's left out, as exhausted peers are reset to it.
+fn order_candidates(peer: &mut Peer, weights: &CandidateWeights) {
+    let mut weighted: Vec<_> = peer
+        .candidates
+        .drain(..)
+        .map(|candidate| (weights.weight(CandidateKind::of(&candidate)), candidate))
+        .collect();
+    if let Some(endpoint) = &peer.endpoint {
+        weighted.push((weights.observed, endpoint.clone()));
+    }
+
+    weighted.sort_by_key(|(weight, _)| std::cmp::Reverse(*weight));
+    if weighted.last().map(|(_, candidate)| candidate) == peer.endpoint.as_ref() {
+        weighted.pop();
+    }
+
+    peer.candidates = weighted
+        .into_iter()
+        .rev()
+        .map(|(_, candidate)| candidate)
+        .collect();
+}
+
 /// Return a PeerConfigBuilder if an endpoint exists and resolves successfully.
 fn set_endpoint(public_key: &str, endpoint: Option<&Endpoint>) -> Option<PeerConfigBuilder> {
     endpoint
@@ -131,3 +194,385 @@ fn set_endpoint(public_key: &str, endpoint: Option<&Endpoint>) -> Option<PeerCon
             PeerConfigBuilder::new(&Key::from_base64(public_key).unwrap()).set_endpoint(addr)
         })
 }
+
+/// WireGuard only initiates a new handshake once a session is this old, so a probe can't tell
+/// anything about peers with a younger session.
+const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+
+/// How long an endpoint that failed its probe is left alone before being tried again.
+const FAILED_ENDPOINT_RETRY: Duration = Duration::from_secs(60 * 60);
+
+/// A peer whose endpoint was just changed, and what to go back to if it doesn't handshake over
+/// the new one.
+#[derive(Debug, Clone, PartialEq)]
+struct EndpointProbe {
+    public_key: Key,
+    /// Where to send a packet to get WireGuard to initiate a handshake.
+    ip: Option<IpAddr>,
+    endpoint: SocketAddr,
+    previous: SocketAddr,
+    started: SystemTime,
+}
+
+impl EndpointProbe {
+    /// Only a handshake made after the probe started, with the new endpoint still in place,
+    /// shows that it works. Inbound traffic arrives whatever endpoint is configured, and
+    /// WireGuard roaming to wherever a handshake came from would overwrite it anyway.
+    fn is_complete(&self, info: &PeerInfo) -> bool {
+        info.config.endpoint == Some(self.endpoint)
+            && matches!(info.stats.last_handshake_time, Some(time) if time >= self.started)
+    }
+}
+
+/// A probed endpoint nothing handshook over, and the endpoint its peer was set back to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailedProbe {
+    pub endpoint: SocketAddr,
+    pub previous: SocketAddr,
+}
+
+/// Peers in `after` whose endpoint differs from the one they had in `before`. Peers that
+/// didn't have an endpoint before have nothing to go back to, and peers with a session younger
+/// than [`REKEY_AFTER_TIME`] wouldn't handshake again, so neither are probed.
+fn endpoint_probes(before: &[PeerInfo], after: &[PeerInfo], now: SystemTime) -> Vec<EndpointProbe> {
+    after
+        .iter()
+        .filter_map(|info| {
+            let old = before
+                .iter()
+                .find(|old| old.config.public_key == info.config.public_key)?;
+            let previous = old.config.endpoint?;
+            let endpoint = info.config.endpoint?;
+            if endpoint == previous || !old.stats.is_stale(REKEY_AFTER_TIME) {
+                return None;
+            }
+            Some(EndpointProbe {
+                public_key: info.config.public_key.clone(),
+                ip: info.config.allowed_ips.first().map(|ip| ip.address),
+                endpoint,
+                previous,
+                started: now,
+            })
+        })
+        .collect()
+}
+
+/// Send a packet to a peer's address inside the network, which makes WireGuard initiate a
+/// handshake with its current endpoint. The port doesn't matter, nothing needs to answer.
+fn poke(ip: IpAddr) {
+    let unspecified = match ip {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let sent = UdpSocket::bind((unspecified, 0)).and_then(|socket| socket.send_to(&[], (ip, 9)));
+    if let Err(e) = sent {
+        log::debug!("failed to send probe packet to {}: {}", ip, e);
+    }
+}
+
+/// Probe the endpoints that changed since `before` by waiting up to `timeout` for a handshake
+/// over them. Peers without one are set back to their previous endpoint, and returned by public
+/// key so they aren't reset to the new one later on.
+pub fn probe_endpoints(
+    interface: &InterfaceName,
+    backend: Backend,
+    before: &Device,
+    peers: &[Peer],
+    timeout: Duration,
+) -> Result<HashMap<String, FailedProbe>, Error> {
+    let mut probes = endpoint_probes(
+        &before.peers,
+        &Device::get(interface, backend)?.peers,
+        SystemTime::now(),
+    );
+    if probes.is_empty() {
+        return Ok(HashMap::new());
+    }
+    log::info!("probing {} changed peer endpoint(s)...", probes.len());
+    for ip in probes.iter().filter_map(|probe| probe.ip) {
+        poke(ip);
+    }
+
+    let start = Instant::now();
+    loop {
+        let device = Device::get(interface, backend)?;
+        probes.retain(|probe| {
+            !matches!(device.find_peer(&probe.public_key), Some(info) if probe.is_complete(info))
+        });
+        if probes.is_empty() || start.elapsed() >= timeout {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    if probes.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let name = |public_key: &Key| {
+        let public_key = public_key.to_base64();
+        peers
+            .iter()
+            .find(|peer| peer.public_key == public_key)
+            .map_or(public_key, |peer| peer.name.to_string())
+    };
+    for probe in &probes {
+        log::warn!(
+            "no handshake with peer {} over its new endpoint {}, keeping {}.",
+            name(&probe.public_key),
+            probe.endpoint,
+            probe.previous
+        );
+    }
+    let updates: Vec<_> = probes
+        .iter()
+        .map(|probe| PeerConfigBuilder::new(&probe.public_key).set_endpoint(probe.previous))
+        .collect();
+    DeviceUpdate::new()
+        .add_peers(&updates)
+        .apply(interface, backend)?;
+
+    Ok(probes
+        .into_iter()
+        .map(|probe| {
+            let failed = FailedProbe {
+                endpoint: probe.endpoint,
+                previous: probe.previous,
+            };
+            (probe.public_key.to_base64(), failed)
+        })
+        .collect())
+}
+
+/// Leave out peers' endpoints whose ports aren't allowed by this network's policy, so they're
+/// never applied to the interface (their candidates are filtered by [`NatTraverse`]).
+pub fn retain_allowed_endpoints(peers: &mut [Peer], ports: &EndpointPortPolicy) {
+    for peer in peers {
+        if let Some(endpoint) = peer.endpoint.take() {
+            if ports.allows(endpoint.port()) {
+                peer.endpoint = Some(endpoint);
+            } else {
+                log::warn!(
+                    "ignoring endpoint {} of peer {}: port isn't allowed by this network's policy ({}).",
+                    endpoint,
+                    peer.name,
+                    ports
+                );
+            }
+        }
+    }
+}
+
+/// Keep the endpoints that recently failed their probe from being applied (and probed, and
+/// reverted) again on every fetch, by leaving those peers on the endpoint they currently have.
+/// Returns the failures that still apply: ones the server stopped reporting, or that are due
+/// to be retried, are dropped.
+pub fn skip_failed_endpoints(
+    peers: &mut [Peer],
+    current: &[PeerInfo],
+    failed: &HashMap<String, FailedEndpoint>,
+    now: SystemTime,
+) -> HashMap<String, FailedEndpoint> {
+    let mut still_failed = HashMap::new();
+    for peer in peers {
+        let failure = match failed.get(&peer.public_key) {
+            Some(failure) => failure,
+            None => continue,
+        };
+        let is_due = matches!(
+            now.duration_since(failure.failed_at),
+            Ok(age) if age >= FAILED_ENDPOINT_RETRY
+        );
+        let resolved = peer.endpoint.as_ref().and_then(|e| e.resolve().ok());
+        if is_due || resolved != Some(failure.endpoint) {
+            continue;
+        }
+        let current = current
+            .iter()
+            .find(|info| info.config.public_key.to_base64() == peer.public_key)
+            .and_then(|info| info.config.endpoint);
+        log::debug!(
+            "not applying endpoint {} of peer {} again, it recently failed its probe.",
+            failure.endpoint,
+            peer.name
+        );
+        peer.endpoint = current.map(Into::into);
+        still_failed.insert(peer.public_key.clone(), failure.clone());
+    }
+    still_failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::PeerContents;
+    use wireguard_control::PeerStats;
+
+    fn peer(endpoint: Option<&str>, candidates: &[&str]) -> Peer {
+        Peer {
+            id: 1,
+            contents: PeerContents {
+                endpoint: endpoint.map(|e| e.parse().unwrap()),
+                candidates: candidates.iter().map(|c| c.parse().unwrap()).collect(),
+                ..PeerContents::new(
+                    "peer1".parse().unwrap(),
+                    "10.0.0.1".parse().unwrap(),
+                    1,
+                    "4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=".to_owned(),
+                )
+            },
+        }
+    }
+
+    fn attempt_order(mut peer: Peer) -> Vec<String> {
+        let mut order = vec![];
+        while let Some(candidate) = peer.candidates.pop() {
+            order.push(candidate.to_string());
+        }
+        order
+    }
+
+    #[test]
+    fn test_default_candidate_order() {
+        let mut peer = peer(
+            Some("8.8.8.8:51820"),
+            &[
+                "1.1.1.1:51820",
+                "192.168.1.2:51820",
+                "1.0.0.1:51820",
+                "10.0.0.2:51820",
+            ],
+        );
+        order_candidates(&mut peer, &CandidateWeights::default());
+        assert_eq!(
+            attempt_order(peer),
+            vec![
+                "192.168.1.2:51820",
+                "10.0.0.2:51820",
+                "1.1.1.1:51820",
+                "1.0.0.1:51820"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_weighted_candidate_order() {
+        let weights = "lan=1,public=2,observed=3".parse().unwrap();
+        let mut peer = peer(
+            Some("8.8.8.8:51820"),
+            &["192.168.1.2:51820", "1.1.1.1:51820"],
+        );
+        order_candidates(&mut peer, &weights);
+        assert_eq!(
+            attempt_order(peer),
+            vec!["8.8.8.8:51820", "1.1.1.1:51820", "192.168.1.2:51820"]
+        );
+    }
+
+    #[test]
+    fn test_endpoint_probes() {
+        let now = SystemTime::now();
+        let keys: Vec<_> = (0..5)
+            .map(|_| wireguard_control::KeyPair::generate().public)
+            .collect();
+        let info = |i: usize, endpoint: Option<&str>, last_handshake_time: Option<SystemTime>| {
+            let mut builder = PeerConfigBuilder::new(&keys[i])
+                .add_allowed_ip(format!("10.0.0.{}", i + 1).parse().unwrap(), 32);
+            if let Some(endpoint) = endpoint {
+                builder = builder.set_endpoint(endpoint.parse().unwrap());
+            }
+            PeerInfo {
+                config: builder.into_peer_config(),
+                stats: PeerStats {
+                    last_handshake_time,
+                    rx_bytes: 148,
+                    ..Default::default()
+                },
+            }
+        };
+        let old_handshake = Some(now - Duration::from_secs(600));
+        let live_session = Some(now - Duration::from_secs(30));
+        let before = [
+            info(0, Some("1.1.1.1:51820"), old_handshake),
+            info(1, Some("2.2.2.2:51820"), old_handshake),
+            info(2, None, None),
+            info(4, Some("5.5.5.5:51820"), live_session),
+        ];
+        let after = [
+            info(0, Some("1.1.1.2:51820"), old_handshake),
+            // Unchanged endpoints, peers without a previous one, and peers whose session isn't
+            // due to be rekeyed aren't probed.
+            info(1, Some("2.2.2.2:51820"), old_handshake),
+            info(2, Some("3.3.3.3:51820"), None),
+            info(3, Some("4.4.4.4:51820"), None),
+            info(4, Some("5.5.5.6:51820"), live_session),
+        ];
+
+        let probes = endpoint_probes(&before, &after, now);
+        assert_eq!(
+            probes,
+            vec![EndpointProbe {
+                public_key: keys[0].clone(),
+                ip: Some("10.0.0.1".parse().unwrap()),
+                endpoint: "1.1.1.2:51820".parse().unwrap(),
+                previous: "1.1.1.1:51820".parse().unwrap(),
+                started: now,
+            }]
+        );
+        // Traffic arriving without a new handshake proves nothing.
+        assert!(!probes[0].is_complete(&after[0]));
+        assert!(probes[0].is_complete(&info(0, Some("1.1.1.2:51820"), Some(now))));
+        // Nor does a handshake that roamed the peer to another endpoint.
+        assert!(!probes[0].is_complete(&info(0, Some("9.9.9.9:51820"), Some(now))));
+    }
+
+    #[test]
+    fn test_skip_failed_endpoints() {
+        let now = SystemTime::now();
+        let key = Key::from_base64("4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=").unwrap();
+        let current = [PeerInfo {
+            config: PeerConfigBuilder::new(&key)
+                .set_endpoint("1.1.1.1:51820".parse().unwrap())
+                .into_peer_config(),
+            stats: Default::default(),
+        }];
+        let failed = |endpoint: &str, age: u64| {
+            let failed = FailedEndpoint {
+                endpoint: endpoint.parse().unwrap(),
+                failed_at: now - Duration::from_secs(age),
+            };
+            [(key.to_base64(), failed)].into()
+        };
+
+        // A recently failed endpoint is left alone, staying on the current one.
+        let mut peers = [peer(Some("1.1.1.2:51820"), &[])];
+        let recent = failed("1.1.1.2:51820", 60);
+        assert_eq!(
+            skip_failed_endpoints(&mut peers, &current, &recent, now),
+            recent
+        );
+        assert_eq!(peers[0].endpoint, Some("1.1.1.1:51820".parse().unwrap()));
+
+        // Failures are forgotten once the server reports another endpoint, or they're due to
+        // be retried.
+        for failed in [failed("1.1.1.3:51820", 60), failed("1.1.1.2:51820", 7200)] {
+            let mut peers = [peer(Some("1.1.1.2:51820"), &[])];
+            assert!(skip_failed_endpoints(&mut peers, &current, &failed, now).is_empty());
+            assert_eq!(peers[0].endpoint, Some("1.1.1.2:51820".parse().unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_disallowed_endpoint_isnt_applied() {
+        let ports = EndpointPortPolicy(vec!["51820".parse().unwrap()]);
+        let mut peers = [
+            peer(Some("1.1.1.1:51820"), &[]),
+            peer(Some("1.1.1.1:22"), &[]),
+        ];
+        retain_allowed_endpoints(&mut peers, &ports);
+        let applied: Vec<_> = peers
+            .iter()
+            .map(|peer| PeerConfigBuilder::from(peer).into_peer_config().endpoint)
+            .collect();
+        assert_eq!(applied, vec![Some("1.1.1.1:51820".parse().unwrap()), None]);
+    }
+}
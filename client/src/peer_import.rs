@@ -0,0 +1,265 @@
+//! Inviting many peers at once with `add-peer --from-file`. Every row is checked against the
+//! network (and the rows before it) up front, so one bad row doesn't stop the others.
+
+use anyhow::{anyhow, bail, Error};
+use serde::Deserialize;
+use shared::{Cidr, CidrTree, Hostname, IpAssignment, IpOrder, Peer};
+use std::{collections::HashSet, net::IpAddr, path::Path};
+
+/// A peer to invite, as listed in the file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PeerImport {
+    pub name: Hostname,
+    pub cidr: String,
+    pub ip: Option<IpAddr>,
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// Where a row's peer will be created.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placement {
+    pub cidr: Cidr,
+    pub ip: IpAddr,
+}
+
+#[derive(Deserialize)]
+struct ImportFile {
+    #[serde(default, rename = "peer")]
+    peers: Vec<PeerImport>,
+}
+
+/// Parse a CSV file (by its `.csv` extension) with a header row naming the `name`, `cidr`,
+/// `ip` and `admin` columns, or otherwise a TOML file of `[[peer]]` tables with those keys.
+pub fn parse(path: &Path, contents: &str) -> Result<Vec<PeerImport>, Error> {
+    if path.extension().is_some_and(|ext| ext == "csv") {
+        parse_csv(contents)
+    } else {
+        Ok(toml::from_str::<ImportFile>(contents)?.peers)
+    }
+}
+
+fn parse_csv(contents: &str) -> Result<Vec<PeerImport>, Error> {
+    let mut lines = contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+    let header: Vec<_> = match lines.next() {
+        Some((_, header)) => header.split(',').map(str::trim).collect(),
+        None => return Ok(vec![]),
+    };
+    let column = |name: &str| header.iter().position(|column| *column == name);
+    let (name, cidr) = match (column("name"), column("cidr")) {
+        (Some(name), Some(cidr)) => (name, cidr),
+        _ => bail!("the CSV header must have \"name\" and \"cidr\" columns."),
+    };
+    let (ip, admin) = (column("ip"), column("admin"));
+
+    lines
+        .map(|(line_number, line)| {
+            let fields: Vec<_> = line.split(',').map(str::trim).collect();
+            let field = |column: Option<usize>| {
+                column
+                    .and_then(|column| fields.get(column))
+                    .copied()
+                    .filter(|field| !field.is_empty())
+            };
+            let row = || -> Result<PeerImport, Error> {
+                Ok(PeerImport {
+                    name: field(Some(name))
+                        .ok_or_else(|| anyhow!("missing name"))?
+                        .parse()
+                        .map_err(|e: &str| anyhow!(e))?,
+                    cidr: field(Some(cidr))
+                        .ok_or_else(|| anyhow!("missing cidr"))?
+                        .to_string(),
+                    ip: field(ip).map(str::parse).transpose()?,
+                    admin: field(admin).map(str::parse).transpose()?.unwrap_or(false),
+                })
+            };
+            row().map_err(|e| anyhow!("line {}: {}", line_number, e))
+        })
+        .collect()
+}
+
+/// Check each row against the network's CIDRs and existing `peers`, which should include
+/// deleted ones since they keep their names and IPs. Rows without an IP get one by `order`.
+pub fn place(
+    rows: Vec<PeerImport>,
+    cidr_tree: &CidrTree,
+    peers: &[Peer],
+    order: IpOrder,
+) -> Vec<(PeerImport, Result<Placement, Error>)> {
+    let leaves = cidr_tree.leaves();
+    let mut names: HashSet<String> = peers.iter().map(|peer| peer.name.to_string()).collect();
+    // Addresses given to earlier rows, on top of the ones existing peers cover.
+    let mut ips: HashSet<IpAddr> = HashSet::new();
+
+    rows.into_iter()
+        .map(|row| {
+            let placement = (|| {
+                if names.contains(&*row.name) {
+                    bail!("a peer named \"{}\" already exists.", row.name);
+                }
+                let cidr = leaves
+                    .iter()
+                    .find(|cidr| cidr.name == row.cidr)
+                    .ok_or_else(|| anyhow!("no leaf CIDR named \"{}\" exists.", row.cidr))?;
+                let assignment = row.ip.map_or_else(|| order.into(), IpAssignment::Specific);
+                let ip = assignment.assign(&cidr.cidr, |ip| {
+                    ips.contains(ip) || peers.iter().any(|peer| peer.covers(ip))
+                })?;
+                Ok(Placement {
+                    cidr: cidr.clone(),
+                    ip,
+                })
+            })();
+            if let Ok(placement) = &placement {
+                names.insert(row.name.to_string());
+                ips.insert(placement.ip);
+            }
+            (row, placement)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::{CidrContents, PeerContents};
+
+    fn cidr(id: i64, name: &str, cidr: &str, parent: Option<i64>) -> Cidr {
+        Cidr {
+            id,
+            contents: CidrContents {
+                name: name.to_string(),
+                cidr: cidr.parse().unwrap(),
+                parent,
+            },
+        }
+    }
+
+    fn row(name: &str, cidr: &str, ip: Option<&str>) -> PeerImport {
+        PeerImport {
+            name: name.parse().unwrap(),
+            cidr: cidr.to_string(),
+            ip: ip.map(|ip| ip.parse().unwrap()),
+            admin: false,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let toml = r#"
+            [[peer]]
+            name = "laptop"
+            cidr = "engineers"
+
+            [[peer]]
+            name = "phone"
+            cidr = "engineers"
+            ip = "10.0.1.9"
+            admin = true
+        "#;
+        let csv = "
+            name, cidr, ip, admin
+            # Comments and blank lines are skipped.
+
+            laptop, engineers, ,
+            phone, engineers, 10.0.1.9, true
+        ";
+        let expected = vec![
+            row("laptop", "engineers", None),
+            PeerImport {
+                admin: true,
+                ..row("phone", "engineers", Some("10.0.1.9"))
+            },
+        ];
+        assert_eq!(parse(Path::new("peers.toml"), toml).unwrap(), expected);
+        assert_eq!(parse(Path::new("peers.csv"), csv).unwrap(), expected);
+
+        assert!(parse(Path::new("peers.csv"), "name,ip\nlaptop,10.0.1.9").is_err());
+        let error = parse(
+            Path::new("peers.csv"),
+            "name,cidr\nlaptop,engineers\nNOT OK,x",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.starts_with("line 3:"), "{}", error);
+    }
+
+    #[test]
+    fn test_place() {
+        let cidrs = [
+            cidr(1, "root", "10.0.0.0/16", None),
+            cidr(2, "engineers", "10.0.1.0/30", Some(1)),
+            cidr(3, "ops", "10.0.2.0/24", Some(1)),
+        ];
+        let cidr_tree = CidrTree::new(&cidrs);
+        let peers = [
+            Peer {
+                id: 1,
+                contents: PeerContents::new(
+                    "existing".parse().unwrap(),
+                    "10.0.1.1".parse().unwrap(),
+                    2,
+                    "abc".to_string(),
+                ),
+            },
+            Peer {
+                id: 2,
+                contents: PeerContents {
+                    extra_allowed_ips: vec!["10.0.2.0/30".parse().unwrap()],
+                    ..PeerContents::new(
+                        "gateway".parse().unwrap(),
+                        "10.0.3.1".parse().unwrap(),
+                        1,
+                        "def".to_string(),
+                    )
+                },
+            },
+        ];
+
+        let placed = place(
+            vec![
+                row("a", "engineers", None),
+                row("existing", "ops", None),
+                row("b", "engineers", None),
+                row("c", "root", None),
+                row("d", "ops", Some("10.0.2.7")),
+                row("e", "ops", Some("10.0.2.7")),
+                row("a", "ops", None),
+                row("f", "ops", None),
+                row("g", "ops", Some("10.0.2.2")),
+            ],
+            &cidr_tree,
+            &peers,
+            IpOrder::Lowest,
+        );
+        let results: Vec<_> = placed
+            .iter()
+            .map(|(row, placement)| {
+                let placement = placement.as_ref().map_err(ToString::to_string);
+                (
+                    &*row.name,
+                    placement.map(|placement| placement.ip.to_string()),
+                )
+            })
+            .collect();
+        assert_eq!(results[0], ("a", Ok("10.0.1.2".into())));
+        // Named like an existing peer.
+        assert!(results[1].1.is_err());
+        // The /30 only has two assignable IPs, and both are taken now.
+        assert!(results[2].1.is_err());
+        // Peers can only go in leaf CIDRs.
+        assert!(results[3].1.is_err());
+        assert_eq!(results[4], ("d", Ok("10.0.2.7".into())));
+        // The IP and the name were taken by earlier rows.
+        assert!(results[5].1.is_err());
+        assert!(results[6].1.is_err());
+        // The lowest IPs are routed to the gateway.
+        assert_eq!(results[7], ("f", Ok("10.0.2.4".into())));
+        assert!(results[8].1.is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//! Pluggable storage for the WireGuard private key of each installed network.
+//!
+//! By default the private key lives in the network's config file, but anything
+//! implementing [`SecretStore`] (ex. a secrets manager or KMS) can be swapped in.
+
+use anyhow::{anyhow, bail, Error};
+use shared::interface_config::InterfaceConfig;
+use std::{fmt, path::Path, str::FromStr};
+use wireguard_control::{InterfaceName, Key};
+
+pub trait SecretStore {
+    /// Load the private key for an installed network.
+    fn load_private_key(&self, network: &InterfaceName) -> Result<Key, Error>;
+
+    /// Persist a (new) private key for an installed network.
+    fn store_private_key(&self, network: &InterfaceName, key: &Key) -> Result<(), Error>;
+
+    /// Whether [`SecretStore::store_private_key`] always fails, which rules the store out
+    /// for anything that generates a new key.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+/// The built-in secret stores, selectable with `--secret-store`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SecretStoreKind {
+    #[default]
+    File,
+    Env,
+}
+
+impl SecretStoreKind {
+    pub fn variants() -> &'static [&'static str] {
+        &["file", "env"]
+    }
+
+    pub fn open<'a>(&self, config_dir: &'a Path) -> Box<dyn SecretStore + 'a> {
+        match self {
+            Self::File => Box::new(FileSecretStore::new(config_dir)),
+            Self::Env => Box::new(EnvSecretStore),
+        }
+    }
+}
+
+impl FromStr for SecretStoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "file" => Ok(Self::File),
+            "env" => Ok(Self::Env),
+            _ => Err(format!("valid values: {}.", Self::variants().join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for SecretStoreKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File => write!(f, "file"),
+            Self::Env => write!(f, "env"),
+        }
+    }
+}
+
+/// Keeps the private key in the network's config file (`<config-dir>/<network>.conf`).
+pub struct FileSecretStore<'a> {
+    config_dir: &'a Path,
+}
+
+impl<'a> FileSecretStore<'a> {
+    pub fn new(config_dir: &'a Path) -> Self {
+        Self { config_dir }
+    }
+}
+
+impl<'a> SecretStore for FileSecretStore<'a> {
+    fn load_private_key(&self, network: &InterfaceName) -> Result<Key, Error> {
+        let config = InterfaceConfig::from_interface(self.config_dir, network)?;
+        Ok(Key::from_base64(&config.interface.private_key)?)
+    }
+
+    fn store_private_key(&self, network: &InterfaceName, key: &Key) -> Result<(), Error> {
+        let mut config = InterfaceConfig::from_interface(self.config_dir, network)?;
+        config.interface.private_key = key.to_base64();
+        config.write_to_interface(self.config_dir, network)?;
+        Ok(())
+    }
+}
+
+/// Reads the private key from the `INNERNET_PRIVATE_KEY_<NETWORK>` environment variable,
+/// where `<NETWORK>` is the uppercased network name with dashes replaced by underscores.
+///
+/// This store is read-only, since a process can't persist its environment.
+pub struct EnvSecretStore;
+
+impl EnvSecretStore {
+    pub fn var_name(network: &InterfaceName) -> String {
+        format!(
+            "INNERNET_PRIVATE_KEY_{}",
+            network
+                .as_str_lossy()
+                .to_ascii_uppercase()
+                .replace('-', "_")
+        )
+    }
+}
+
+impl SecretStore for EnvSecretStore {
+    fn load_private_key(&self, network: &InterfaceName) -> Result<Key, Error> {
+        let var = Self::var_name(network);
+        let value = std::env::var(&var).map_err(|_| anyhow!("{} is not set", var))?;
+        Ok(Key::from_base64(value.trim())?)
+    }
+
+    fn store_private_key(&self, network: &InterfaceName, _key: &Key) -> Result<(), Error> {
+        bail!(
+            "the env secret store is read-only, set {} manually",
+            Self::var_name(network)
+        )
+    }
+
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::interface_config::{InterfaceInfo, ServerInfo};
+
+    fn write_config(config_dir: &Path, network: &InterfaceName, key: &Key) {
+        InterfaceConfig {
+            interface: InterfaceInfo {
+                network_name: network.to_string(),
+                address: "10.0.0.2/24".parse().unwrap(),
+                private_key: key.to_base64(),
+                listen_port: None,
+                random_listen_port: false,
+                allowed_endpoint_ports: Default::default(),
+                hosts_domain: None,
+                mtu: None,
+                name_template: None,
+            },
+            server: ServerInfo {
+                public_key: Key::generate_private().get_public().to_base64(),
+                external_endpoint: "1.1.1.1:51820".parse().unwrap(),
+                internal_endpoint: "10.0.0.1:51820".parse().unwrap(),
+            },
+        }
+        .write_to_interface(config_dir, network)
+        .unwrap();
+    }
+
+    #[test]
+    fn test_file_store_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let network = "secret-test".parse().unwrap();
+        let original = Key::generate_private();
+        write_config(dir.path(), &network, &original);
+
+        let store = FileSecretStore::new(dir.path());
+        assert!(!store.is_read_only());
+        assert_eq!(store.load_private_key(&network).unwrap(), original);
+
+        let rotated = Key::generate_private();
+        store.store_private_key(&network, &rotated).unwrap();
+        assert_eq!(store.load_private_key(&network).unwrap(), rotated);
+    }
+
+    #[test]
+    fn test_env_store() {
+        let network = "env-test".parse().unwrap();
+        let key = Key::generate_private();
+        assert_eq!(
+            EnvSecretStore::var_name(&network),
+            "INNERNET_PRIVATE_KEY_ENV_TEST"
+        );
+
+        assert!(EnvSecretStore.load_private_key(&network).is_err());
+        std::env::set_var(EnvSecretStore::var_name(&network), key.to_base64());
+        assert_eq!(EnvSecretStore.load_private_key(&network).unwrap(), key);
+        assert!(EnvSecretStore.is_read_only());
+        assert!(EnvSecretStore.store_private_key(&network, &key).is_err());
+    }
+}
@@ -3,9 +3,34 @@ use colored::*;
 use indoc::eprintdoc;
 use log::{Level, LevelFilter};
 use serde::{de::DeserializeOwned, Serialize};
-use shared::{interface_config::ServerInfo, Interface, PeerDiff, INNERNET_PUBKEY_HEADER};
-use std::{ffi::OsStr, io, path::Path, time::Duration};
-use ureq::{Agent, AgentBuilder};
+use shared::{
+    interface_config::ServerInfo, ApiVersion, Interface, PeerDiff, PeerMetadata, API_VERSION_MAX,
+    API_VERSION_MIN, INNERNET_PUBKEY_HEADER, INNERNET_SERVER_TIME_HEADER,
+};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashSet,
+    ffi::OsStr,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use ureq::{Agent, AgentBuilder, Response};
+use wireguard_control::{Backend, InterfaceName};
+
+/// Clock differences with the server beyond this start causing confusing invite
+/// expiry and peer "last handshake" information.
+const CLOCK_SKEW_WARNING_THRESHOLD: Duration = Duration::from_secs(60);
+
+/// Retries of each batch sent by [`send_batched`], and the delay before the first one.
+const BATCH_RETRIES: u32 = 3;
+const BATCH_RETRY_DELAY: Duration = Duration::from_millis(500);
 
 static LOGGER: Logger = Logger;
 struct Logger;
@@ -59,6 +84,170 @@ pub fn init_logger(verbosity: u64) {
     log::set_logger(&LOGGER).unwrap();
 }
 
+/// Coalesces sync triggers that fire in quick succession (ex. several events on resume
+/// from sleep) so that they result in a single sync run, once `window` has passed since
+/// the first of them.
+pub struct Debouncer {
+    window: Duration,
+    /// When the first pending trigger fired, and how many have fired since.
+    pending: Option<(Instant, usize)>,
+}
+
+impl Debouncer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: None,
+        }
+    }
+
+    pub fn trigger(&mut self, now: Instant) {
+        let (_, count) = self.pending.get_or_insert((now, 0));
+        *count += 1;
+    }
+
+    /// If the pending triggers' window has passed at `now`, clear them and return how many
+    /// there were, to be handled with a single run.
+    pub fn take_due(&mut self, now: Instant) -> Option<usize> {
+        match self.pending {
+            Some((first, count)) if now >= first + self.window => {
+                self.pending = None;
+                Some(count)
+            },
+            _ => None,
+        }
+    }
+}
+
+/// The delay between daemon syncs, which doubles after every failed sync (up to `max`)
+/// and goes back to `base` after a successful one. Every delay is randomly stretched or
+/// shrunk by up to `jitter`, so clients don't all retry in lockstep after an outage, but is
+/// never shorter than [`Backoff::MIN_DELAY`] so the daemon can't spin.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    /// A fraction of the delay, between 0 and 1.
+    jitter: f64,
+    failures: u32,
+}
+
+impl Backoff {
+    pub const MIN_DELAY: Duration = Duration::from_secs(1);
+
+    pub fn new(base: Duration, max: Duration, jitter: f64) -> Self {
+        Self {
+            base,
+            max: max.max(base),
+            jitter: jitter.clamp(0.0, 1.0),
+            failures: 0,
+        }
+    }
+
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+
+    pub fn failed(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    /// The next delay, for a `random` number between 0 and 1.
+    pub fn delay(&self, random: f64) -> Duration {
+        let delay = self
+            .base
+            .checked_mul(2u32.saturating_pow(self.failures.min(31)))
+            .map_or(self.max, |delay| delay.min(self.max));
+        delay
+            .mul_f64(1.0 + self.jitter * (2.0 * random - 1.0))
+            .max(Self::MIN_DELAY)
+    }
+}
+
+/// Parse an interval jitter percentage, which has to stay below 100 so that intervals can't
+/// shrink to nothing.
+pub fn parse_jitter(percent: &str) -> Result<u8, String> {
+    match percent.parse::<u8>().map_err(|e| e.to_string())? {
+        percent if percent < 100 => Ok(percent),
+        percent => Err(format!("{}% is too much, it has to be below 100%", percent)),
+    }
+}
+
+/// A random number between 0 and 1, which is good enough for jitter.
+pub fn random_fraction() -> f64 {
+    use std::{
+        collections::hash_map::RandomState,
+        hash::{BuildHasher, Hasher},
+    };
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(signal: libc::c_int) {
+    if SHUTDOWN_REQUESTED.swap(true, Ordering::SeqCst) {
+        // Asked twice, ex. because the first request is stuck behind a hung fetch: give up on
+        // stopping cleanly. Both calls are async-signal-safe.
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+}
+
+/// Install `handler` without `SA_RESTART`, so that blocking syscalls the signal interrupts fail
+/// with `EINTR` rather than carrying on as if nothing happened.
+fn install_signal_handler(signal: libc::c_int, handler: extern "C" fn(libc::c_int)) {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, std::ptr::null_mut());
+    }
+}
+
+/// Make SIGTERM and SIGINT ask a long-running command to stop once it's done with what it's
+/// doing (see [`sleep_unless_shutdown`]), rather than killing it in the middle of an update.
+/// A second signal stops it right away.
+pub fn handle_shutdown_signals() {
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        install_signal_handler(signal, request_shutdown);
+    }
+}
+
+static SYNC_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_sync(_signal: libc::c_int) {
+    SYNC_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Make SIGHUP ask a daemon to sync right away (see [`take_sync_request`]).
+pub fn handle_sync_signal() {
+    install_signal_handler(libc::SIGHUP, request_sync);
+}
+
+/// Whether a sync was requested since the last call.
+pub fn take_sync_request() -> bool {
+    SYNC_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Sleep for `duration`, returning false right away if a shutdown was requested.
+pub fn sleep_unless_shutdown(duration: Duration) -> bool {
+    const STEP: Duration = Duration::from_millis(100);
+    let deadline = Instant::now() + duration;
+    loop {
+        if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            return false;
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return true;
+        }
+        std::thread::sleep(remaining.min(STEP));
+    }
+}
+
 pub fn human_duration(duration: Duration) -> String {
     match duration.as_secs() {
         n if n < 1 => "just now".cyan().to_string(),
@@ -102,8 +291,19 @@ pub fn human_size(bytes: u64) -> String {
     }
 }
 
+/// The OS error code behind `e`, looking through errors that wrap one (ex. netlink requests
+/// annotated with the operation that failed).
+fn os_error_code(e: &io::Error) -> Option<i32> {
+    e.raw_os_error().or_else(|| {
+        e.get_ref()?
+            .source()?
+            .downcast_ref::<io::Error>()?
+            .raw_os_error()
+    })
+}
+
 pub fn permissions_helptext(config_dir: &Path, data_dir: &Path, e: &io::Error) {
-    if e.raw_os_error() == Some(1) {
+    if os_error_code(e) == Some(1) {
         let current_exe = std::env::current_exe()
             .ok()
             .map(|s| s.to_string_lossy().to_string())
@@ -170,11 +370,232 @@ pub fn print_peer_diff(store: &DataStore, diff: &PeerDiff) {
     }
 }
 
+/// The local addresses of UDP sockets bound to `port`, as listed in `/proc/net/udp` and
+/// `/proc/net/udp6`. Returns nothing on platforms without procfs.
+pub fn udp_bound_addrs(port: u16) -> Vec<SocketAddr> {
+    ["/proc/net/udp", "/proc/net/udp6"]
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .flat_map(|contents| parse_proc_net_udp(&contents, port))
+        .collect()
+}
+
+/// Parse the local addresses bound to `port` out of a `/proc/net/udp{,6}` table.
+///
+/// Addresses are printed as hex 32-bit words in host byte order.
+fn parse_proc_net_udp(contents: &str, port: u16) -> Vec<SocketAddr> {
+    fn parse_addr(addr: &str) -> Option<IpAddr> {
+        let words = (0..addr.len() / 8)
+            .map(|i| u32::from_str_radix(addr.get(i * 8..i * 8 + 8)?, 16).ok())
+            .collect::<Option<Vec<_>>>()?;
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_ne_bytes()).collect();
+        match bytes.len() {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+            16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+            _ => None,
+        }
+    }
+
+    contents
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let (addr, local_port) = line.split_whitespace().nth(1)?.split_once(':')?;
+            let local_port = u16::from_str_radix(local_port, 16).ok()?;
+            if local_port != port {
+                return None;
+            }
+            Some(SocketAddr::new(parse_addr(addr)?, local_port))
+        })
+        .collect()
+}
+
+/// Whether a failed request might succeed if sent again, because the server or network
+/// hiccuped rather than the server rejecting the request itself.
+pub fn is_transient(error: &ureq::Error) -> bool {
+    match error {
+        ureq::Error::Status(code, _) => *code >= 500,
+        ureq::Error::Transport(transport) => matches!(
+            transport.kind(),
+            ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed | ureq::ErrorKind::Io
+        ),
+    }
+}
+
+/// Run `request`, retrying up to `retries` times on transient errors with exponential
+/// backoff starting at `base_delay`.
+pub fn with_retries<T>(
+    retries: u32,
+    base_delay: Duration,
+    mut request: impl FnMut() -> Result<T, ureq::Error>,
+) -> Result<T, ureq::Error> {
+    let mut attempt = 0;
+    loop {
+        match request() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                let delay = base_delay * 2u32.saturating_pow(attempt);
+                log::warn!("request failed ({}), retrying in {:?}.", e, delay);
+                std::thread::sleep(delay);
+                attempt += 1;
+            },
+            result => return result,
+        }
+    }
+}
+
+/// Send `items` to the server in batches of up to `batch_size`, with up to `concurrency`
+/// requests in flight at once. Each batch is retried on transient failures, so the endpoint
+/// must handle receiving one more than once.
+pub fn send_batched<T: Serialize + Sync>(
+    server: &ServerInfo,
+    verb: &str,
+    endpoint: &str,
+    items: &[T],
+    batch_size: usize,
+    concurrency: usize,
+) -> Result<(), ureq::Error> {
+    let batches = Mutex::new(items.chunks(batch_size.max(1)));
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..concurrency.max(1))
+            .map(|_| {
+                scope.spawn(|| {
+                    let api = Api::new(server);
+                    loop {
+                        let batch = batches
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .next();
+                        match batch {
+                            Some(batch) => with_retries(BATCH_RETRIES, BATCH_RETRY_DELAY, || {
+                                api.http_form::<_, ()>(verb, endpoint, batch)
+                            })?,
+                            None => break Ok(()),
+                        }
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("batch sender panicked"))
+    })
+}
+
+/// What this client reports about itself to the server on fetch, with each field cut
+/// down to what the server accepts.
+pub fn local_metadata(backend: Backend) -> PeerMetadata {
+    fn bounded(value: &str) -> String {
+        let value = value.trim();
+        let mut end = value.len().min(PeerMetadata::MAX_FIELD_LEN);
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        value[..end].to_string()
+    }
+
+    let os = std::fs::read_to_string("/etc/os-release")
+        .ok()
+        .and_then(|contents| os_release_name(&contents))
+        .unwrap_or_else(|| std::env::consts::OS.to_string());
+    let hostname = Command::new("hostname")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .filter(|hostname| !hostname.trim().is_empty());
+
+    PeerMetadata {
+        os: Some(bounded(&os)),
+        version: Some(bounded(env!("CARGO_PKG_VERSION"))),
+        hostname: hostname.as_deref().map(bounded),
+        backend: Some(bounded(&backend.to_string())),
+    }
+}
+
+/// The human-readable distribution name from an `/etc/os-release` file.
+fn os_release_name(contents: &str) -> Option<String> {
+    contents
+        .lines()
+        .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+        .map(|name| name.trim_matches('"').to_string())
+        .filter(|name| !name.is_empty())
+}
+
+/// How a WireGuard interface or installed innernet network relates to the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InterfaceStatus {
+    /// An installed innernet network whose interface is up.
+    Up,
+    /// An installed innernet network whose interface isn't up.
+    Down,
+    /// A WireGuard interface that innernet doesn't manage.
+    Unmanaged,
+}
+
+/// The hosts file entries for an interface's peers, given as `(ip, name)`: each peer is
+/// `<name>.<interface>.wg`, and also `<name>.<domain>` with a domain. Names are unique within a
+/// network, but if one still appears twice (hostnames are case-insensitive) only its first
+/// peer is written, instead of two conflicting entries.
+pub fn peer_hostnames<'a>(
+    interface: &InterfaceName,
+    domain: Option<&str>,
+    peers: impl IntoIterator<Item = (IpAddr, &'a str)>,
+) -> Vec<(IpAddr, Vec<String>)> {
+    let mut seen = HashSet::new();
+    peers
+        .into_iter()
+        .filter(|(ip, name)| {
+            let first = seen.insert(name.to_lowercase());
+            if !first {
+                log::warn!(
+                    "not writing duplicate peer name \"{}\" ({}) to hosts.",
+                    name,
+                    ip
+                );
+            }
+            first
+        })
+        .map(|(ip, name)| {
+            let mut hostnames = vec![format!("{}.{}.wg", name, interface)];
+            if let Some(domain) = domain {
+                hostnames.push(format!("{}.{}", name, domain));
+            }
+            (ip, hostnames)
+        })
+        .collect()
+}
+
+/// Cross-references the WireGuard interfaces `present` on the host with the networks
+/// `installed` in innernet's config directory, sorted by name.
+pub fn interface_statuses(
+    present: &[InterfaceName],
+    installed: &[InterfaceName],
+) -> Vec<(InterfaceName, InterfaceStatus)> {
+    let mut statuses: Vec<_> = installed
+        .iter()
+        .map(|name| {
+            let status = if present.contains(name) {
+                InterfaceStatus::Up
+            } else {
+                InterfaceStatus::Down
+            };
+            (*name, status)
+        })
+        .chain(
+            present
+                .iter()
+                .filter(|name| !installed.contains(name))
+                .map(|name| (*name, InterfaceStatus::Unmanaged)),
+        )
+        .collect();
+    statuses.sort_by_key(|(name, _)| name.to_string());
+    statuses
+}
+
 pub fn all_installed(config_dir: &Path) -> Result<Vec<Interface>, std::io::Error> {
     // All errors are bubbled up when enumerating a directory
-    let entries: Vec<_> = std::fs::read_dir(config_dir)?
-        .into_iter()
-        .collect::<Result<_, _>>()?;
+    let entries: Vec<_> = std::fs::read_dir(config_dir)?.collect::<Result<_, _>>()?;
 
     let installed: Vec<_> = entries
         .into_iter()
@@ -200,6 +621,8 @@ pub fn all_installed(config_dir: &Path) -> Result<Vec<Interface>, std::io::Error
 pub struct Api<'a> {
     agent: Agent,
     server: &'a ServerInfo,
+    clock_skew_checked: Cell<bool>,
+    server_api_version: RefCell<Option<ApiVersion>>,
 }
 
 impl<'a> Api<'a> {
@@ -208,7 +631,12 @@ impl<'a> Api<'a> {
             .timeout(Duration::from_secs(5))
             .redirects(0)
             .build();
-        Self { agent, server }
+        Self {
+            agent,
+            server,
+            clock_skew_checked: Cell::new(false),
+            server_api_version: RefCell::new(None),
+        }
     }
 
     pub fn http<T: DeserializeOwned>(&self, verb: &str, endpoint: &str) -> Result<T, ureq::Error> {
@@ -224,12 +652,52 @@ impl<'a> Api<'a> {
         self.request(verb, endpoint, Some(form))
     }
 
+    /// Like [`Api::http`], but deserializes the response while it's being read rather than
+    /// buffering the whole body first, which keeps memory use down (and avoids ureq's
+    /// response size limit) for responses that grow with the network, like its state.
+    pub fn http_streaming<T: DeserializeOwned>(
+        &self,
+        verb: &str,
+        endpoint: &str,
+    ) -> Result<T, ureq::Error> {
+        let response = self.send::<()>(verb, endpoint, None)?;
+        let reader = io::BufReader::new(response.into_reader());
+        Ok(serde_json::from_reader(reader).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("failed to deserialize JSON response from the server: {}", e),
+            )
+        })?)
+    }
+
     fn request<S: Serialize, T: DeserializeOwned>(
         &self,
         verb: &str,
         endpoint: &str,
         form: Option<S>,
     ) -> Result<T, ureq::Error> {
+        let mut response = self.send(verb, endpoint, form)?.into_string()?;
+        // A little trick for serde to parse an empty response as `()`.
+        if response.is_empty() {
+            response = "null".into();
+        }
+        Ok(serde_json::from_str(&response).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "failed to deserialize JSON response from the server: {}, response={}",
+                    e, &response
+                ),
+            )
+        })?)
+    }
+
+    fn send<S: Serialize>(
+        &self,
+        verb: &str,
+        endpoint: &str,
+        form: Option<S>,
+    ) -> Result<Response, ureq::Error> {
         let request = self
             .agent
             .request(
@@ -238,6 +706,7 @@ impl<'a> Api<'a> {
             )
             .set(INNERNET_PUBKEY_HEADER, &self.server.public_key);
 
+        let sent = SystemTime::now();
         let response = if let Some(form) = form {
             request.send_json(serde_json::to_value(form).map_err(|e| {
                 io::Error::new(
@@ -248,20 +717,489 @@ impl<'a> Api<'a> {
         } else {
             request.call()?
         };
+        self.check_clock_skew(&response, sent);
+        Ok(response)
+    }
 
-        let mut response = response.into_string()?;
-        // A little trick for serde to parse an empty response as `()`.
-        if response.is_empty() {
-            response = "null".into();
+    /// The API versions the server supports, asked for once per `Api`.
+    pub fn server_api_version(&self) -> Result<ApiVersion, ureq::Error> {
+        if let Some(version) = &*self.server_api_version.borrow() {
+            return Ok(version.clone());
         }
-        Ok(serde_json::from_str(&response).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "failed to deserialize JSON response from the server: {}, response={}",
-                    e, &response
-                ),
+        let version = match self.http("GET", "/user/version") {
+            Ok(version) => version,
+            // Servers that predate version negotiation speak version 1.
+            Err(ureq::Error::Status(404, _)) => ApiVersion {
+                min: 1,
+                max: 1,
+                server_version: "(predating version negotiation)".into(),
+            },
+            Err(e) => return Err(e),
+        };
+        *self.server_api_version.borrow_mut() = Some(version.clone());
+        Ok(version)
+    }
+
+    /// Whether the server has the endpoints added in API `version`.
+    pub fn supports_api_version(&self, version: u32) -> Result<bool, ureq::Error> {
+        Ok(self.server_api_version()?.max >= version)
+    }
+
+    /// Fail with a message to upgrade the server if it doesn't have the endpoints of API
+    /// `version` that `action` needs, rather than with a 404 from one of them.
+    pub fn require_api_version(&self, version: u32, action: &str) -> Result<(), anyhow::Error> {
+        let server = self.server_api_version()?;
+        if server.max >= version {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "innernet-server {} (API versions {}-{}) doesn't support {}, which needs API version {}. Upgrade the server.",
+                server.server_version,
+                server.min,
+                server.max,
+                action,
+                version
+            ))
+        }
+    }
+
+    /// Make sure the server speaks an API version this client supports, failing with a clear
+    /// message instead of on an unexpected response later.
+    pub fn check_api_version(&self) -> Result<(), anyhow::Error> {
+        let version = self.server_api_version()?;
+
+        if version.is_compatible_with(API_VERSION_MIN, API_VERSION_MAX) {
+            log::debug!(
+                "server {} supports API versions {}-{}.",
+                version.server_version,
+                version.min,
+                version.max
+            );
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "innernet-server {} (API versions {}-{}) is incompatible with this client (API versions {}-{}). Upgrade the {}.",
+                version.server_version,
+                version.min,
+                version.max,
+                API_VERSION_MIN,
+                API_VERSION_MAX,
+                if version.max < API_VERSION_MIN { "server" } else { "client" }
+            ))
+        }
+    }
+
+    /// Compare the server's reported time with ours (once per `Api`), warning if they've drifted.
+    fn check_clock_skew(&self, response: &Response, sent: SystemTime) {
+        if self.clock_skew_checked.replace(true) {
+            return;
+        }
+        let server_time = match response
+            .header(INNERNET_SERVER_TIME_HEADER)
+            .and_then(|time| time.parse().ok())
+        {
+            Some(secs) => SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            None => {
+                log::debug!("server didn't report its time, skipping clock skew check.");
+                return;
+            },
+        };
+
+        // Assume the server's clock was read halfway through the round trip.
+        let round_trip = sent.elapsed().unwrap_or_default();
+        let local_time = sent + round_trip / 2;
+        let (skew, direction) = match server_time.duration_since(local_time) {
+            Ok(ahead) => (ahead, "ahead of"),
+            Err(e) => (e.duration(), "behind"),
+        };
+        log::debug!("server clock is {}s {} ours.", skew.as_secs(), direction);
+
+        if skew > CLOCK_SKEW_WARNING_THRESHOLD {
+            log::warn!(
+                "the server's clock is {} {} this machine's clock. Invite expirations and \
+                 handshake times will be inaccurate; make sure both machines sync their time (ex. NTP).",
+                human_duration(skew).trim_end_matches(" ago"),
+                direction
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_os_error_code() {
+        #[derive(Debug)]
+        struct Wrapped(io::Error);
+        impl std::fmt::Display for Wrapped {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "wrapped: {}", self.0)
+            }
+        }
+        impl std::error::Error for Wrapped {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                Some(&self.0)
+            }
+        }
+
+        assert_eq!(os_error_code(&io::Error::from_raw_os_error(1)), Some(1));
+        let wrapped = io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            Wrapped(io::Error::from_raw_os_error(1)),
+        );
+        assert_eq!(os_error_code(&wrapped), Some(1));
+        assert_eq!(os_error_code(&io::Error::other("plain")), None);
+    }
+
+    #[test]
+    fn test_interface_statuses() {
+        let names = |names: &[&str]| -> Vec<InterfaceName> {
+            names.iter().map(|name| name.parse().unwrap()).collect()
+        };
+        let present = names(&["wg0", "evilcorp", "tonari"]);
+        let installed = names(&["tonari", "home"]);
+        let statuses: Vec<_> = interface_statuses(&present, &installed)
+            .into_iter()
+            .map(|(name, status)| (name.to_string(), status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("evilcorp".to_string(), InterfaceStatus::Unmanaged),
+                ("home".to_string(), InterfaceStatus::Down),
+                ("tonari".to_string(), InterfaceStatus::Up),
+                ("wg0".to_string(), InterfaceStatus::Unmanaged),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_with_retries() {
+        let status = |code| ureq::Error::Status(code, Response::new(code, "", "").unwrap());
+
+        let mut attempts = 0;
+        let result = with_retries(3, Duration::ZERO, || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(status(502))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        // Definitive rejections aren't retried.
+        let mut attempts = 0;
+        let result: Result<(), _> = with_retries(3, Duration::ZERO, || {
+            attempts += 1;
+            Err(status(401))
+        });
+        assert!(matches!(result, Err(ureq::Error::Status(401, _))));
+        assert_eq!(attempts, 1);
+
+        // Nor is anything beyond the retry limit.
+        let mut attempts = 0;
+        let result: Result<(), _> = with_retries(2, Duration::ZERO, || {
+            attempts += 1;
+            Err(status(500))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn test_os_release_name() {
+        let contents = "NAME=\"Ubuntu\"\nPRETTY_NAME=\"Ubuntu 22.04.3 LTS\"\nID=ubuntu\n";
+        assert_eq!(
+            os_release_name(contents).as_deref(),
+            Some("Ubuntu 22.04.3 LTS")
+        );
+        assert_eq!(os_release_name("NAME=Arch\nPRETTY_NAME=\n"), None);
+        assert_eq!(os_release_name(""), None);
+    }
+
+    #[test]
+    fn test_parse_proc_net_udp() {
+        let v4_word = |ip: [u8; 4]| format!("{:08X}", u32::from_ne_bytes(ip));
+        let udp = format!(
+            "   sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops\n\
+             \x20 1: {}:CA6C 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 0 2 0000000000000000 0\n\
+             \x20 2: {}:0035 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 0 2 0000000000000000 0\n",
+            v4_word([0, 0, 0, 0]),
+            v4_word([127, 0, 0, 53]),
+        );
+        assert_eq!(
+            parse_proc_net_udp(&udp, 51820),
+            vec!["0.0.0.0:51820".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(
+            parse_proc_net_udp(&udp, 53),
+            vec!["127.0.0.53:53".parse::<SocketAddr>().unwrap()]
+        );
+        assert!(parse_proc_net_udp(&udp, 1).is_empty());
+
+        let udp6 = format!(
+            "  sl  local_address                         remote_address                        st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops\n\
+             \x20 1: 00000000000000000000000000000000:CA6C 00000000000000000000000000000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 0 2 0000000000000000 0\n\
+             \x20 2: 000000000000000000000000{}:CA6C 00000000000000000000000000000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 0 2 0000000000000000 0\n",
+            v4_word([0, 0, 0, 1]),
+        );
+        assert_eq!(
+            parse_proc_net_udp(&udp6, 51820),
+            vec![
+                "[::]:51820".parse::<SocketAddr>().unwrap(),
+                "[::1]:51820".parse::<SocketAddr>().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_http_streaming_large_response() {
+        use std::{
+            io::{Read, Write},
+            net::TcpListener,
+        };
+
+        // Bigger than the 10MB that ureq allows when buffering a response into a string.
+        let body = serde_json::to_string(&vec!["x".repeat(1 << 20); 12]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = ServerInfo {
+            public_key: "key".into(),
+            external_endpoint: "127.0.0.1:51820".parse().unwrap(),
+            internal_endpoint: listener.local_addr().unwrap(),
+        };
+        let handle = std::thread::spawn(move || {
+            for body in [&body, &body] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).unwrap();
+                // The buffering client hangs up partway through, so ignore write errors.
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+
+        let api = Api::new(&server);
+        assert!(api.http::<Vec<String>>("GET", "/user/state").is_err());
+        let strings: Vec<String> = api.http_streaming("GET", "/user/state").unwrap();
+        assert_eq!(strings.len(), 12);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_server_api_version() {
+        use std::{
+            io::{Read, Write},
+            net::TcpListener,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = ServerInfo {
+            public_key: "key".into(),
+            external_endpoint: "127.0.0.1:51820".parse().unwrap(),
+            internal_endpoint: listener.local_addr().unwrap(),
+        };
+        // A server predating version negotiation, which is only asked once.
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
             )
-        })?)
+            .unwrap();
+        });
+
+        let api = Api::new(&server);
+        assert!(api.supports_api_version(1).unwrap());
+        assert!(!api.supports_api_version(shared::API_VERSION_2).unwrap());
+        let error = api
+            .require_api_version(shared::API_VERSION_2, "feature flags")
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("Upgrade the server"),
+            "{}",
+            error
+        );
+        api.check_api_version().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_batched() {
+        use std::{
+            io::{Read, Write},
+            net::TcpListener,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = ServerInfo {
+            public_key: "key".into(),
+            external_endpoint: "127.0.0.1:51820".parse().unwrap(),
+            internal_endpoint: listener.local_addr().unwrap(),
+        };
+        // Accept every batch (failing the first attempt) and collect what was sent.
+        let handle = std::thread::spawn(move || {
+            let mut received = vec![];
+            for attempt in 0..5 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = vec![];
+                let mut buf = [0u8; 1024];
+                let body = loop {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some((_, body)) = text.split_once("\r\n\r\n") {
+                        if body.ends_with(']') {
+                            break body.to_string();
+                        }
+                    }
+                };
+                let status = if attempt == 0 {
+                    "503 Service Unavailable"
+                } else {
+                    received.extend(serde_json::from_str::<Vec<u32>>(&body).unwrap());
+                    "204 No Content"
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+            received
+        });
+
+        let items: Vec<u32> = (0..10).collect();
+        send_batched(&server, "PUT", "/user/handshakes", &items, 3, 2).unwrap();
+        let mut received = handle.join().unwrap();
+        received.sort_unstable();
+        assert_eq!(received, items);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let secs = Duration::from_secs;
+        let mut backoff = Backoff::new(secs(60), secs(300), 0.0);
+        assert_eq!(backoff.delay(0.5), secs(60));
+        backoff.failed();
+        assert_eq!(backoff.delay(0.5), secs(120));
+        backoff.failed();
+        assert_eq!(backoff.delay(0.5), secs(240));
+        for _ in 0..100 {
+            backoff.failed();
+        }
+        assert_eq!(backoff.delay(0.5), secs(300));
+        backoff.succeeded();
+        assert_eq!(backoff.delay(0.5), secs(60));
+
+        // Jitter spreads delays out evenly around the backed off delay.
+        let mut backoff = Backoff::new(secs(60), secs(300), 0.5);
+        assert_eq!(backoff.delay(0.0), secs(30));
+        assert_eq!(backoff.delay(0.5), secs(60));
+        backoff.failed();
+        assert_eq!(backoff.delay(1.0), secs(180));
+
+        assert_eq!(parse_jitter("99"), Ok(99));
+        assert!(parse_jitter("100").is_err());
+
+        // Even the most shrunk delay leaves some time between syncs.
+        assert_eq!(
+            Backoff::new(secs(60), secs(300), 1.0).delay(0.0),
+            Backoff::MIN_DELAY
+        );
+        assert_eq!(
+            Backoff::new(secs(0), secs(0), 0.0).delay(0.5),
+            Backoff::MIN_DELAY
+        );
+
+        for _ in 0..100 {
+            let random = random_fraction();
+            assert!((0.0..1.0).contains(&random), "{}", random);
+        }
+    }
+
+    #[test]
+    fn test_sleep_unless_shutdown() {
+        handle_shutdown_signals();
+        assert!(sleep_unless_shutdown(Duration::from_millis(1)));
+        unsafe {
+            libc::raise(libc::SIGTERM);
+        }
+        let start = Instant::now();
+        assert!(!sleep_unless_shutdown(Duration::from_secs(60)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_debouncer() {
+        let window = Duration::from_secs(5);
+        let mut debouncer = Debouncer::new(window);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(debouncer.take_due(start), None);
+
+        // Several triggers in one window are handled by a single run at its end.
+        let mut runs = 0;
+        for secs in 0..10 {
+            if secs < 3 {
+                debouncer.trigger(at(secs));
+            }
+            if let Some(count) = debouncer.take_due(at(secs)) {
+                assert_eq!((secs, count), (5, 3));
+                runs += 1;
+            }
+        }
+        assert_eq!(runs, 1);
+
+        // Later triggers start a new window.
+        debouncer.trigger(at(20));
+        assert_eq!(debouncer.take_due(at(24)), None);
+        assert_eq!(debouncer.take_due(at(25)), Some(1));
+
+        // A zero window never delays.
+        let mut debouncer = Debouncer::new(Duration::ZERO);
+        debouncer.trigger(start);
+        assert_eq!(debouncer.take_due(start), Some(1));
+    }
+
+    #[test]
+    fn test_peer_hostnames() {
+        let interface = "wg0".parse().unwrap();
+        let peers = [
+            ("10.0.0.1".parse().unwrap(), "db1"),
+            ("10.0.1.1".parse().unwrap(), "web"),
+            // The same name again, ex. from another CIDR.
+            ("10.0.2.1".parse().unwrap(), "DB1"),
+        ];
+
+        assert_eq!(
+            peer_hostnames(&interface, None, peers),
+            vec![
+                (peers[0].0, vec!["db1.wg0.wg".to_string()]),
+                (peers[1].0, vec!["web.wg0.wg".to_string()]),
+            ]
+        );
+        assert_eq!(
+            peer_hostnames(&interface, Some("wg0.internal"), peers),
+            vec![
+                (
+                    peers[0].0,
+                    vec!["db1.wg0.wg".to_string(), "db1.wg0.internal".to_string()]
+                ),
+                (
+                    peers[1].0,
+                    vec!["web.wg0.wg".to_string(), "web.wg0.internal".to_string()]
+                ),
+            ]
+        );
     }
 }
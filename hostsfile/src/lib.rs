@@ -97,7 +97,7 @@ impl HostsBuilder {
     /// Adds a mapping of `ip` to `hostname`. If there hostnames associated with the IP already,
     /// the hostname will be appended to the list.
     pub fn add_hostname<S: ToString>(&mut self, ip: IpAddr, hostname: S) {
-        let hostnames_dest = self.hostname_map.entry(ip).or_insert_with(Vec::new);
+        let hostnames_dest = self.hostname_map.entry(ip).or_default();
         hostnames_dest.push(hostname.to_string());
     }
 
@@ -108,7 +108,7 @@ impl HostsBuilder {
         ip: IpAddr,
         hostnames: I,
     ) {
-        let hostnames_dest = self.hostname_map.entry(ip).or_insert_with(Vec::new);
+        let hostnames_dest = self.hostname_map.entry(ip).or_default();
         for hostname in hostnames.into_iter() {
             hostnames_dest.push(hostname.to_string());
         }
@@ -130,17 +130,13 @@ impl HostsBuilder {
                 // the location depends on the environment variable %WinDir%.
                 format!(
                     "{}\\System32\\Drivers\\Etc\\hosts",
-                    std::env::var("WinDir").map_err(|_| io::Error::new(
-                        ErrorKind::Other,
+                    std::env::var("WinDir").map_err(|_| io::Error::other(
                         "WinDir environment variable missing".to_owned()
                     ))?
                 ),
             )
         } else {
-            return Err(io::Error::new(
-                ErrorKind::Other,
-                "unsupported operating system.".to_owned(),
-            ));
+            return Err(io::Error::other("unsupported operating system.".to_owned()));
         };
 
         if !hosts_file.exists() {
@@ -172,7 +168,8 @@ impl HostsBuilder {
     }
 
     /// Inserts a new section to the specified hosts file.  If there is a section with the same tag
-    /// name already, it will be replaced with the new list instead.
+    /// name already, it will be replaced with the new list instead. Sections with other tags are
+    /// left untouched, and a builder without any hostnames removes its section entirely.
     ///
     /// `hosts_path` is the *full* path to write to, including the filename.
     ///
@@ -195,6 +192,7 @@ impl HostsBuilder {
 
         let hosts_file = OpenOptions::new()
             .create(true)
+            .truncate(false)
             .read(true)
             .write(true)
             .open(hosts_path)?;
@@ -204,17 +202,29 @@ impl HostsBuilder {
             .collect::<Vec<_>>();
 
         let begin = lines.iter().position(|line| line.trim() == begin_marker);
-        let end = lines.iter().position(|line| line.trim() == end_marker);
+        // Only look for the end marker after our own section began, so that another section's
+        // (or a stray) marker can't make us drain the wrong lines.
+        let end = lines
+            .iter()
+            .skip(begin.unwrap_or(0))
+            .position(|line| line.trim() == end_marker)
+            .map(|end| end + begin.unwrap_or(0));
 
         let insert = match (begin, end) {
             (Some(begin), Some(end)) => {
                 lines.drain(begin..end + 1);
-                begin
+                // Also remove the blank line that was inserted before the section with it.
+                if self.hostname_map.is_empty() && begin > 0 && lines[begin - 1].is_empty() {
+                    lines.remove(begin - 1);
+                    begin - 1
+                } else {
+                    begin
+                }
             },
             (None, None) => {
                 // Insert a blank line before a new section.
                 if let Some(last_line) = lines.iter().last() {
-                    if !last_line.is_empty() {
+                    if !last_line.is_empty() && !self.hostname_map.is_empty() {
                         lines.push("".to_string());
                     }
                 }
@@ -266,7 +276,7 @@ impl HostsBuilder {
 
     fn write_and_swap(temp_path: &Path, hosts_path: &Path, contents: &[u8]) -> io::Result<()> {
         // Copy the file we plan on modifying so its permissions and metadata are preserved.
-        std::fs::copy(&hosts_path, &temp_path)?;
+        std::fs::copy(hosts_path, temp_path)?;
         Self::write_clobber(temp_path, contents)?;
         std::fs::rename(temp_path, hosts_path)?;
         Ok(())
@@ -322,4 +332,48 @@ mod tests {
         assert!(contents.contains("# DO NOT EDIT foo BEGIN"));
         assert!(contents.contains("1.1.1.1 whatever"));
     }
+
+    #[test]
+    fn test_write_multiple_sections() {
+        let (mut temp_file, temp_path) = tempfile::NamedTempFile::new().unwrap().into_parts();
+        temp_file.write_all(b"127.0.0.1 localhost\n").unwrap();
+        let write = |tag: &str, hosts: &[([u8; 4], &str)]| {
+            let mut builder = HostsBuilder::new(tag);
+            for (ip, hostname) in hosts {
+                builder.add_hostname((*ip).into(), hostname);
+            }
+            builder.write_to(&temp_path).unwrap();
+            std::fs::read_to_string(&temp_path).unwrap()
+        };
+
+        write("innernet wg0", &[([10, 0, 0, 1], "a.wg0.wg")]);
+        let contents = write("innernet wg1", &[([10, 1, 0, 1], "b.wg1.wg")]);
+        assert_eq!(
+            contents,
+            "127.0.0.1 localhost\n\n\
+             # DO NOT EDIT innernet wg0 BEGIN\n10.0.0.1 a.wg0.wg\n# DO NOT EDIT innernet wg0 END\n\n\
+             # DO NOT EDIT innernet wg1 BEGIN\n10.1.0.1 b.wg1.wg\n# DO NOT EDIT innernet wg1 END\n"
+        );
+
+        // Rewriting one network's section keeps the other's, and its position.
+        let contents = write("innernet wg0", &[([10, 0, 0, 2], "c.wg0.wg")]);
+        assert_eq!(
+            contents,
+            "127.0.0.1 localhost\n\n\
+             # DO NOT EDIT innernet wg0 BEGIN\n10.0.0.2 c.wg0.wg\n# DO NOT EDIT innernet wg0 END\n\n\
+             # DO NOT EDIT innernet wg1 BEGIN\n10.1.0.1 b.wg1.wg\n# DO NOT EDIT innernet wg1 END\n"
+        );
+
+        // A section whose tag is a prefix of another's doesn't match it.
+        let contents = write("innernet wg", &[]);
+        assert!(contents.contains("10.0.0.2 c.wg0.wg"));
+
+        // Writing no hostnames removes just that section.
+        let contents = write("innernet wg0", &[]);
+        assert_eq!(
+            contents,
+            "127.0.0.1 localhost\n\n\
+             # DO NOT EDIT innernet wg1 BEGIN\n10.1.0.1 b.wg1.wg\n# DO NOT EDIT innernet wg1 END\n"
+        );
+    }
 }
@@ -15,7 +15,105 @@ mod linux {
     };
     use netlink_packet_route::RtnlMessage;
     use netlink_sys::{constants::NETLINK_GENERIC, protocols::NETLINK_ROUTE, Socket};
-    use std::{fmt::Debug, io};
+    use std::{
+        fmt::{self, Debug},
+        io,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Mutex, PoisonError,
+        },
+        time::{Duration, Instant},
+    };
+
+    /// How long (in milliseconds) a resolved generic netlink family id is reused.
+    static FAMILY_ID_TTL_MS: AtomicU64 = AtomicU64::new(60_000);
+
+    /// Resolved generic netlink family ids, by family name, and when they were resolved.
+    static FAMILY_IDS: Mutex<Vec<(&'static str, u16, Instant)>> = Mutex::new(Vec::new());
+
+    /// Set how long a resolved generic netlink family id is reused before it's looked
+    /// up again. A zero TTL disables caching.
+    pub fn set_family_id_ttl(ttl: Duration) {
+        FAMILY_ID_TTL_MS.store(ttl.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn cached_family_id(name: &str) -> Option<u16> {
+        let ttl = Duration::from_millis(FAMILY_ID_TTL_MS.load(Ordering::Relaxed));
+        let mut ids = FAMILY_IDS.lock().unwrap_or_else(PoisonError::into_inner);
+        ids.retain(|(_, _, resolved)| resolved.elapsed() < ttl);
+        ids.iter()
+            .find(|(family, ..)| *family == name)
+            .map(|(_, id, _)| *id)
+    }
+
+    fn cache_family_id(name: &'static str, id: u16) {
+        let mut ids = FAMILY_IDS.lock().unwrap_or_else(PoisonError::into_inner);
+        ids.retain(|(family, ..)| *family != name);
+        ids.push((name, id, Instant::now()));
+    }
+
+    fn invalidate_family_id(name: &str) {
+        let mut ids = FAMILY_IDS.lock().unwrap_or_else(PoisonError::into_inner);
+        ids.retain(|(family, ..)| *family != name);
+    }
+
+    const EPERM: i32 = 1;
+    const EACCES: i32 = 13;
+
+    /// A netlink request the kernel refused because the process lacks privileges. It's
+    /// returned wrapped in an [`io::Error`] of the same kind as its `source`.
+    #[derive(Debug)]
+    pub struct PermissionError {
+        pub operation: String,
+        pub source: io::Error,
+    }
+
+    impl fmt::Display for PermissionError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "netlink {} failed: {} (this requires root or the CAP_NET_ADMIN capability)",
+                self.operation, self.source
+            )
+        }
+    }
+
+    impl std::error::Error for PermissionError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.source)
+        }
+    }
+
+    /// Replace the kernel's bare "Operation not permitted" with an error naming the failed
+    /// `operation` and the privileges it needs.
+    fn map_permission_error(e: io::Error, operation: &str) -> io::Error {
+        match e.raw_os_error() {
+            Some(EPERM | EACCES) => io::Error::new(
+                e.kind(),
+                PermissionError {
+                    operation: operation.to_string(),
+                    source: e,
+                },
+            ),
+            _ => e,
+        }
+    }
+
+    fn rtnl_operation(message: &RtnlMessage) -> &'static str {
+        match message {
+            RtnlMessage::NewLink(_) => "link creation",
+            RtnlMessage::DelLink(_) => "link deletion",
+            RtnlMessage::GetLink(_) => "link lookup",
+            RtnlMessage::SetLink(_) => "link update",
+            RtnlMessage::NewAddress(_) => "address creation",
+            RtnlMessage::DelAddress(_) => "address deletion",
+            RtnlMessage::GetAddress(_) => "address lookup",
+            RtnlMessage::NewRoute(_) => "route creation",
+            RtnlMessage::DelRoute(_) => "route deletion",
+            RtnlMessage::GetRoute(_) => "route lookup",
+            _ => "route request",
+        }
+    }
 
     macro_rules! get_nla_value {
         ($nlas:expr, $e:ident, $v:ident) => {
@@ -27,6 +125,20 @@ mod linux {
     }
 
     pub fn netlink_request_genl<F>(
+        message: GenlMessage<F>,
+        flags: Option<u16>,
+    ) -> Result<Vec<NetlinkMessage<GenlMessage<F>>>, io::Error>
+    where
+        F: GenlFamily + Clone + Debug + Eq,
+        GenlMessage<F>: Clone + Debug + Eq + NetlinkSerializable + NetlinkDeserializable,
+    {
+        let command = message.payload.command();
+        genl_request(message, flags).map_err(|e| {
+            map_permission_error(e, &format!("{} command {}", F::family_name(), command))
+        })
+    }
+
+    fn genl_request<F>(
         mut message: GenlMessage<F>,
         flags: Option<u16>,
     ) -> Result<Vec<NetlinkMessage<GenlMessage<F>>>, io::Error>
@@ -35,42 +147,86 @@ mod linux {
         GenlMessage<F>: Clone + Debug + Eq + NetlinkSerializable + NetlinkDeserializable,
     {
         if message.family_id() == 0 {
-            let genlmsg: GenlMessage<GenlCtrl> = GenlMessage::from_payload(GenlCtrl {
-                cmd: GenlCtrlCmd::GetFamily,
-                nlas: vec![GenlCtrlAttrs::FamilyName(F::family_name().to_string())],
-            });
-            let responses =
-                netlink_request_genl::<GenlCtrl>(genlmsg, Some(NLM_F_REQUEST | NLM_F_ACK))?;
-
-            match responses.get(0) {
-                Some(NetlinkMessage {
-                    payload:
-                        NetlinkPayload::InnerMessage(GenlMessage {
-                            payload: GenlCtrl { nlas, .. },
-                            ..
-                        }),
-                    ..
-                }) => {
-                    let family_id = get_nla_value!(nlas, GenlCtrlAttrs, FamilyId)
-                        .ok_or_else(|| io::ErrorKind::NotFound)?;
-                    message.set_resolved_family_id(*family_id);
-                },
-                _ => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "Unexpected netlink payload",
-                    ))
-                },
-            };
+            let name = F::family_name();
+            if let Some(family_id) = cached_family_id(name) {
+                let mut cached = message.clone();
+                cached.set_resolved_family_id(family_id);
+                match netlink_request(cached, flags, NETLINK_GENERIC) {
+                    // The module may have been reloaded under a new family id.
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        invalidate_family_id(name);
+                        let family = resolve_genl_family(name)?;
+                        cache_family_id(name, family.id);
+                        // An unchanged id means the family itself failed the request, which it
+                        // may have partly applied, so it mustn't be sent again.
+                        if family.id == family_id {
+                            return Err(e);
+                        }
+                        // Nothing was registered under the old id to act on the request, so
+                        // it's rebuilt for the new one and sent once more.
+                        message.set_resolved_family_id(family.id);
+                        return netlink_request(message, flags, NETLINK_GENERIC);
+                    },
+                    result => return result,
+                }
+            }
+
+            let family = resolve_genl_family(name)?;
+            message.set_resolved_family_id(family.id);
+            cache_family_id(name, family.id);
         }
         netlink_request(message, flags, NETLINK_GENERIC)
     }
 
+    /// A generic netlink family, as registered with the kernel.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct GenlFamilyInfo {
+        pub id: u16,
+        /// The version of the family's interface that the kernel module implements.
+        pub version: u32,
+    }
+
+    /// Look up a generic netlink family by name, bypassing the family id cache.
+    pub fn resolve_genl_family(name: &str) -> Result<GenlFamilyInfo, io::Error> {
+        let genlmsg: GenlMessage<GenlCtrl> = GenlMessage::from_payload(GenlCtrl {
+            cmd: GenlCtrlCmd::GetFamily,
+            nlas: vec![GenlCtrlAttrs::FamilyName(name.to_string())],
+        });
+        let responses = genl_request::<GenlCtrl>(genlmsg, Some(NLM_F_REQUEST | NLM_F_ACK))
+            .map_err(|e| map_permission_error(e, &format!("lookup of the {} family", name)))?;
+
+        match responses.first() {
+            Some(NetlinkMessage {
+                payload:
+                    NetlinkPayload::InnerMessage(GenlMessage {
+                        payload: GenlCtrl { nlas, .. },
+                        ..
+                    }),
+                ..
+            }) => {
+                let id = get_nla_value!(nlas, GenlCtrlAttrs, FamilyId)
+                    .ok_or_else(|| io::ErrorKind::NotFound)?;
+                let version = get_nla_value!(nlas, GenlCtrlAttrs, Version)
+                    .ok_or_else(|| io::ErrorKind::InvalidData)?;
+                Ok(GenlFamilyInfo {
+                    id: *id,
+                    version: *version,
+                })
+            },
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unexpected netlink payload",
+            )),
+        }
+    }
+
     pub fn netlink_request_rtnl(
         message: RtnlMessage,
         flags: Option<u16>,
     ) -> Result<Vec<NetlinkMessage<RtnlMessage>>, io::Error> {
+        let operation = rtnl_operation(&message);
         netlink_request(message, flags, NETLINK_ROUTE)
+            .map_err(|e| map_permission_error(e, operation))
     }
 
     pub fn netlink_request<I>(
@@ -137,10 +293,57 @@ mod linux {
             }
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_family_id_cache() {
+            assert_eq!(cached_family_id("test-family"), None);
+
+            cache_family_id("test-family", 21);
+            assert_eq!(cached_family_id("test-family"), Some(21));
+            cache_family_id("test-family", 22);
+            assert_eq!(cached_family_id("test-family"), Some(22));
+
+            invalidate_family_id("test-family");
+            assert_eq!(cached_family_id("test-family"), None);
+
+            set_family_id_ttl(Duration::ZERO);
+            cache_family_id("test-family", 23);
+            assert_eq!(cached_family_id("test-family"), None);
+            set_family_id_ttl(Duration::from_secs(60));
+        }
+
+        #[test]
+        fn test_permission_error() {
+            for code in [EPERM, EACCES] {
+                let e = map_permission_error(io::Error::from_raw_os_error(code), "link creation");
+                assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+                let message = e.to_string();
+                assert!(message.starts_with("netlink link creation failed: "));
+                assert!(message.contains("CAP_NET_ADMIN"));
+
+                // The original error stays reachable for callers that check the errno.
+                let inner = e
+                    .into_inner()
+                    .unwrap()
+                    .downcast::<PermissionError>()
+                    .unwrap();
+                assert_eq!(inner.source.raw_os_error(), Some(code));
+            }
+
+            // Other errors are passed through untouched.
+            let e = map_permission_error(io::Error::from_raw_os_error(2), "link lookup");
+            assert_eq!(e.raw_os_error(), Some(2));
+        }
+    }
 }
 
 #[cfg(target_os = "linux")]
 pub use linux::{
-    netlink_request, netlink_request_genl, netlink_request_rtnl, MAX_GENL_PAYLOAD_LENGTH,
+    netlink_request, netlink_request_genl, netlink_request_rtnl, resolve_genl_family,
+    set_family_id_ttl, GenlFamilyInfo, PermissionError, MAX_GENL_PAYLOAD_LENGTH,
     MAX_NETLINK_BUFFER_LENGTH,
 };
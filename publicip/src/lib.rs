@@ -1,14 +1,15 @@
 //! Get your public IP address(es) as fast as possible, with no dependencies.
 //!
-//! Currently uses Cloudflare's DNS as it's the simplest, but that could change
-//! in the future.
+//! By default this asks Cloudflare's DNS, falling back to OpenDNS, but any list of
+//! [`Resolver`]s (including a plain HTTP echo service) can be queried instead.
 
 use std::{
     fs::File,
     io::{Cursor, Error, ErrorKind, Read, Write},
-    marker::PhantomData,
-    net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
     str::FromStr,
+    sync::mpsc,
+    thread,
     time::Duration,
 };
 
@@ -20,136 +21,379 @@ macro_rules! ensure {
     };
 }
 
-const TYPE_TXT: u16 = 0x0010; // TXT-type requests (could also be A, AAAA, etc.)
+const TYPE_A: u16 = 0x0001;
+const TYPE_AAAA: u16 = 0x001c;
+const TYPE_TXT: u16 = 0x0010;
+const CLASS_IN: u16 = 0x0001;
 const CLASS_CH: u16 = 0x0003; // Because we are in the chaos realm.
 
 static CLOUDFLARE_QNAME: &[&str] = &["whoami", "cloudflare"];
 const CLOUDFLARE_IPV4: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
 const CLOUDFLARE_IPV6: Ipv6Addr = Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111);
 
+static OPENDNS_QNAME: &[&str] = &["myip", "opendns", "com"];
+const OPENDNS_IPV4: Ipv4Addr = Ipv4Addr::new(208, 67, 222, 222);
+const OPENDNS_IPV6: Ipv6Addr = Ipv6Addr::new(0x2620, 0x119, 0x35, 0, 0, 0, 0, 0x35);
+
+/// How long to wait for each resolver before moving on to the next one.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Preference {
     Ipv4,
     Ipv6,
 }
 
+/// A service that echoes back the address it was contacted from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolver {
+    /// A DNS server answering `whoami.cloudflare` TXT queries in the CHAOS class, like 1.1.1.1.
+    Cloudflare(SocketAddr),
+    /// A DNS server answering `myip.opendns.com` A/AAAA queries, like resolver1.opendns.com.
+    OpenDns(SocketAddr),
+    /// An HTTP server whose response body is the address, ex. `http://ifconfig.me/ip`.
+    Http {
+        host: String,
+        port: u16,
+        path: String,
+    },
+}
+
+impl Resolver {
+    /// Cloudflare and then OpenDNS, over both IPv4 and IPv6.
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            Self::Cloudflare(SocketAddr::new(CLOUDFLARE_IPV4.into(), 53)),
+            Self::Cloudflare(SocketAddr::new(CLOUDFLARE_IPV6.into(), 53)),
+            Self::OpenDns(SocketAddr::new(OPENDNS_IPV4.into(), 53)),
+            Self::OpenDns(SocketAddr::new(OPENDNS_IPV6.into(), 53)),
+        ]
+    }
+
+    /// Ask the resolver for this host's address over the given protocol, waiting at most
+    /// `timeout` for each network operation.
+    pub fn query(&self, protocol: Preference, timeout: Duration) -> Result<IpAddr, Error> {
+        let answer = match self {
+            Self::Cloudflare(server) => {
+                ensure!(
+                    matches(protocol, server),
+                    "resolver is over the other protocol"
+                );
+                dns_query(*server, CLOUDFLARE_QNAME, TYPE_TXT, CLASS_CH, timeout)?
+            },
+            Self::OpenDns(server) => {
+                ensure!(
+                    matches(protocol, server),
+                    "resolver is over the other protocol"
+                );
+                let qtype = match protocol {
+                    Preference::Ipv4 => TYPE_A,
+                    Preference::Ipv6 => TYPE_AAAA,
+                };
+                dns_query(*server, OPENDNS_QNAME, qtype, CLASS_IN, timeout)?
+            },
+            Self::Http { host, port, path } => http_query(host, *port, path, protocol, timeout)?,
+        };
+        let expected = match protocol {
+            Preference::Ipv4 => answer.is_ipv4(),
+            Preference::Ipv6 => answer.is_ipv6(),
+        };
+        ensure!(expected, "answer is from the other protocol");
+        Ok(answer)
+    }
+}
+
+impl FromStr for Resolver {
+    type Err = Error;
+
+    /// Parse an `http://host[:port][/path]` URL. HTTPS isn't supported, since that'd need a
+    /// TLS implementation.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid resolver URL {}", s),
+            )
+        };
+        let rest = s.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|_| invalid())?)
+            },
+            _ => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        ensure!(!host.is_empty(), "resolver URL has no host");
+        Ok(Self::Http {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+fn matches(protocol: Preference, addr: &SocketAddr) -> bool {
+    match protocol {
+        Preference::Ipv4 => addr.is_ipv4(),
+        Preference::Ipv6 => addr.is_ipv6(),
+    }
+}
+
+/// The first answer over the given protocol, trying the resolvers in order.
+fn first_answer(resolvers: &[Resolver], protocol: Preference, timeout: Duration) -> Option<IpAddr> {
+    resolvers
+        .iter()
+        .find_map(|resolver| resolver.query(protocol, timeout).ok())
+}
+
+/// The public addresses of this host, as echoed back by the resolver over IPv4 and IPv6.
+/// Either is `None` if no resolver answered over that protocol.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PublicAddrs {
+    pub v4: Option<Ipv4Addr>,
+    pub v6: Option<Ipv6Addr>,
+}
+
+impl PublicAddrs {
+    /// Query both addresses at once from the [default resolvers](Resolver::defaults).
+    pub fn get() -> Self {
+        Self::get_from(&Resolver::defaults(), DEFAULT_TIMEOUT)
+    }
+
+    /// Query both addresses at once, trying the resolvers in order for each protocol and
+    /// giving each of them `timeout` to answer.
+    pub fn get_from(resolvers: &[Resolver], timeout: Duration) -> Self {
+        let (v4, v6) = thread::scope(|s| {
+            let v6 = s.spawn(|| first_answer(resolvers, Preference::Ipv6, timeout));
+            let v4 = first_answer(resolvers, Preference::Ipv4, timeout);
+            (v4, v6.join().ok().flatten())
+        });
+        Self {
+            v4: match v4 {
+                Some(IpAddr::V4(v4)) => Some(v4),
+                _ => None,
+            },
+            v6: match v6 {
+                Some(IpAddr::V6(v6)) => Some(v6),
+                _ => None,
+            },
+        }
+    }
+
+    /// The preferred address, falling back to the other one.
+    pub fn preferred(&self, preference: Preference) -> Option<IpAddr> {
+        let (v4, v6) = (self.v4.map(IpAddr::from), self.v6.map(IpAddr::from));
+        match preference {
+            Preference::Ipv4 => v4.or(v6),
+            Preference::Ipv6 => v6.or(v4),
+        }
+    }
+}
+
 pub fn get_both() -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
-    let ipv4 = Request::start(CLOUDFLARE_IPV4).ok();
-    let ipv6 = Request::start(CLOUDFLARE_IPV6).ok();
-    (
-        ipv4.and_then(|req| req.read_response().ok()),
-        ipv6.and_then(|req| req.read_response().ok()),
-    )
+    let PublicAddrs { v4, v6 } = PublicAddrs::get();
+    (v4, v6)
 }
 
 pub fn get_any(preference: Preference) -> Option<IpAddr> {
-    let (v4, v6) = get_both();
-    let (v4, v6) = (v4.map(IpAddr::from), v6.map(IpAddr::from));
-    match preference {
-        Preference::Ipv4 => v4.or(v6),
-        Preference::Ipv6 => v6.or(v4),
-    }
-}
-
-struct Request<T> {
-    socket: UdpSocket,
-    id: [u8; 2],
-    buf: [u8; 1500],
-    _ip_type: PhantomData<T>,
-}
-
-impl<T: Into<IpAddr> + FromStr<Err = AddrParseError>> Request<T> {
-    fn start(resolver: T) -> Result<Self, Error> {
-        let socket = UdpSocket::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0))?;
-        socket.set_read_timeout(Some(Duration::from_millis(500)))?;
-        let endpoint = SocketAddr::new(resolver.into(), 53);
-
-        let id = get_id()?;
-        let mut buf = [0u8; 1500];
-        let mut cursor = Cursor::new(&mut buf[..]);
-        cursor.write_all(&id)?;
-        cursor.write_all(&0x0100u16.to_be_bytes())?; // Request type (query, in this case)
-        cursor.write_all(&0x0001u16.to_be_bytes())?; // Number of queries
-        cursor.write_all(&0x0000u16.to_be_bytes())?; // Number of responses
-        cursor.write_all(&0x0000u16.to_be_bytes())?; // Number of name server records
-        cursor.write_all(&0x0000u16.to_be_bytes())?; // Number of additional records
-        for atom in CLOUDFLARE_QNAME {
-            // Write the length of this atom followed by the string itself
-            cursor.write_all(&[atom.len() as u8])?;
-            cursor.write_all(atom.as_bytes())?;
-        }
-        // Finish the qname with a terminating byte (0-length atom).
-        cursor.write_all(&[0x00])?;
-        cursor.write_all(&TYPE_TXT.to_be_bytes())?;
-        cursor.write_all(&CLASS_CH.to_be_bytes())?;
-
-        let len = cursor.position() as usize;
-        socket.connect(endpoint)?;
-        socket.send(&buf[..len])?;
-
-        Ok(Self {
-            socket,
-            id,
-            buf,
-            _ip_type: PhantomData,
-        })
+    PublicAddrs::get().preferred(preference)
+}
+
+fn dns_query(
+    server: SocketAddr,
+    qname: &[&str],
+    qtype: u16,
+    qclass: u16,
+    timeout: Duration,
+) -> Result<IpAddr, Error> {
+    let local: IpAddr = match server {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0))?;
+    socket.set_read_timeout(Some(timeout))?;
+
+    let id = get_id()?;
+    let mut buf = [0u8; 1500];
+    let mut cursor = Cursor::new(&mut buf[..]);
+    cursor.write_all(&id)?;
+    cursor.write_all(&0x0100u16.to_be_bytes())?; // Request type (query, in this case)
+    cursor.write_all(&0x0001u16.to_be_bytes())?; // Number of queries
+    cursor.write_all(&0x0000u16.to_be_bytes())?; // Number of responses
+    cursor.write_all(&0x0000u16.to_be_bytes())?; // Number of name server records
+    cursor.write_all(&0x0000u16.to_be_bytes())?; // Number of additional records
+    for atom in qname {
+        // Write the length of this atom followed by the string itself
+        cursor.write_all(&[atom.len() as u8])?;
+        cursor.write_all(atom.as_bytes())?;
     }
+    // Finish the qname with a terminating byte (0-length atom).
+    cursor.write_all(&[0x00])?;
+    cursor.write_all(&qtype.to_be_bytes())?;
+    cursor.write_all(&qclass.to_be_bytes())?;
+
+    let len = cursor.position() as usize;
+    socket.connect(server)?;
+    socket.send(&buf[..len])?;
+
+    let len = socket.recv(&mut buf)?;
+    ensure!(buf[..2] == id, "question/answer IDs don't match");
+    read_response(&buf[..len], qtype, qclass)
+}
+
+fn read_response(response: &[u8], qtype: u16, qclass: u16) -> Result<IpAddr, Error> {
+    let mut buf = Cursor::new(response);
+    let _id = buf.read_u16()?;
+
+    let flags = buf.read_u16()?;
+    ensure!(flags & 0x8000 != 0, "not a response");
+    ensure!(flags & 0x000f == 0, "non-zero DNS error code");
 
-    fn read_response(mut self) -> Result<T, Error> {
-        let len = self.socket.recv(&mut self.buf)?;
-        ensure!(self.buf[..2] == self.id, "question/answer IDs don't match");
-        let response = &self.buf[..len];
-        let mut buf = Cursor::new(response);
-        let _id = buf.read_u16()?;
-
-        let flags = buf.read_u16()?;
-        ensure!(flags & 0x8000 != 0, "not a response");
-        ensure!(flags & 0x000f == 0, "non-zero DNS error code");
-
-        let qd = buf.read_u16()?;
-        ensure!(qd <= 1, "unexpected number of questions");
-        ensure!(buf.read_u16()? == 1, "unexpected number of answers");
-        ensure!(buf.read_u16()? == 0, "unexpected NS value");
-        ensure!(buf.read_u16()? == 0, "unexpected AR value");
-
-        // Skip past the query section, don't care.
-        if qd != 0 {
-            loop {
-                let len = buf.read_u8()?;
-                if len == 0 {
-                    break;
-                }
-                buf.set_position(buf.position() + len as u64);
+    let qd = buf.read_u16()?;
+    ensure!(qd <= 1, "unexpected number of questions");
+    ensure!(buf.read_u16()? == 1, "unexpected number of answers");
+    ensure!(buf.read_u16()? == 0, "unexpected NS value");
+    ensure!(buf.read_u16()? == 0, "unexpected AR value");
+
+    // Skip past the query section, don't care.
+    if qd != 0 {
+        loop {
+            let len = buf.read_u8()?;
+            if len == 0 {
+                break;
             }
-            // Skip type and class information as well.
-            buf.set_position(buf.position() + 4);
+            buf.set_position(buf.position() + len as u64);
         }
+        // Skip type and class information as well.
+        buf.set_position(buf.position() + 4);
+    }
 
-        let qname_len = buf.read_u16()?;
-        // Ignore if it's a pointer, ignore if it's a normal QNAME...
-        if qname_len & 0xc000 != 0xc000 {
-            buf.set_position(buf.position() + qname_len as u64);
-        }
-        ensure!(buf.read_u16()? == TYPE_TXT, "answer is not TXT type");
-        ensure!(buf.read_u16()? == CLASS_CH, "answer is not CH class");
-        buf.set_position(buf.position() + 4); // Ignore TTL
+    let qname_len = buf.read_u16()?;
+    // Ignore if it's a pointer, ignore if it's a normal QNAME...
+    if qname_len & 0xc000 != 0xc000 {
+        buf.set_position(buf.position() + qname_len as u64);
+    }
+    ensure!(
+        buf.read_u16()? == qtype,
+        "answer is not of the queried type"
+    );
+    ensure!(
+        buf.read_u16()? == qclass,
+        "answer is not of the queried class"
+    );
+    buf.set_position(buf.position() + 4); // Ignore TTL
 
-        let data_len = buf.read_u16()? as usize;
-        let txt_len = buf.read_u8()? as usize;
-        ensure!(txt_len == data_len - 1, "unexpected txt and data lengths.");
+    let data_len = buf.read_u16()? as usize;
+    match qtype {
+        TYPE_A => {
+            ensure!(data_len == 4, "unexpected A record length");
+            let mut octets = [0u8; 4];
+            buf.read_exact(&mut octets)?;
+            Ok(Ipv4Addr::from(octets).into())
+        },
+        TYPE_AAAA => {
+            ensure!(data_len == 16, "unexpected AAAA record length");
+            let mut octets = [0u8; 16];
+            buf.read_exact(&mut octets)?;
+            Ok(Ipv6Addr::from(octets).into())
+        },
+        _ => {
+            let txt_len = buf.read_u8()? as usize;
+            ensure!(txt_len + 1 == data_len, "unexpected txt and data lengths.");
 
-        let start = buf.position() as usize;
-        let end = start + txt_len;
-        ensure!(response.len() >= end, "unexpected txt answer lengths");
+            let start = buf.position() as usize;
+            let end = start + txt_len;
+            ensure!(response.len() >= end, "unexpected txt answer lengths");
 
-        let txt = std::str::from_utf8(&response[start..end]).ok();
-        let answer = txt
-            .and_then(|txt| txt.parse::<T>().ok())
-            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "TXT not IP address".to_string()))?;
+            std::str::from_utf8(&response[start..end])
+                .ok()
+                .and_then(|txt| txt.parse().ok())
+                .ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, "TXT not IP address".to_string())
+                })
+        },
+    }
+}
 
-        Ok(answer)
+fn http_query(
+    host: &str,
+    port: u16,
+    path: &str,
+    protocol: Preference,
+    timeout: Duration,
+) -> Result<IpAddr, Error> {
+    let addr = resolve(host, port, protocol, timeout)?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: text/plain\r\nConnection: close\r\n\r\n",
+        path,
+        host_header(host, port)
+    );
+    stream.write_all(request.as_bytes())?;
+
+    // Echo services' responses are tiny, don't let a misbehaving one send us more.
+    let mut response = String::new();
+    stream.take(4096).read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            "truncated HTTP response".to_string(),
+        )
+    })?;
+    ensure!(
+        head.split(' ').nth(1) == Some("200"),
+        "HTTP request was unsuccessful"
+    );
+    body.trim()
+        .parse()
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "body not IP address".to_string()))
+}
+
+/// The Host header for `host`, which needs brackets around IPv6 literals and the port
+/// unless it's HTTP's default one (RFC 7230 section 5.4).
+fn host_header(host: &str, port: u16) -> String {
+    let host = match host.parse::<Ipv6Addr>() {
+        Ok(_) => format!("[{}]", host),
+        Err(_) => host.to_string(),
+    };
+    match port {
+        80 => host,
+        port => format!("{}:{}", host, port),
     }
 }
 
+/// Look up `host`'s address over the given protocol, giving up after `timeout`. The system
+/// resolver can't be cancelled, so a lookup that takes too long is left to finish on its own.
+fn resolve(
+    host: &str,
+    port: u16,
+    protocol: Preference,
+    timeout: Duration,
+) -> Result<SocketAddr, Error> {
+    let (tx, rx) = mpsc::channel();
+    let target = (host.to_string(), port);
+    thread::spawn(move || {
+        let addrs = target
+            .to_socket_addrs()
+            .map(|mut addrs| addrs.find(|addr| matches(protocol, addr)));
+        let _ = tx.send(addrs);
+    });
+    rx.recv_timeout(timeout)
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::TimedOut,
+                format!("looking up {} timed out", host),
+            )
+        })??
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no address for {}", host)))
+}
+
 /// DNS wants a random-ish ID to be generated per request.
 fn get_id() -> Result<[u8; 2], Error> {
     let mut id = [0u8; 2];
@@ -178,10 +422,58 @@ impl ReadExt for Cursor<&[u8]> {
 
 #[cfg(test)]
 mod tests {
-    use std::time::Instant;
+    use std::{net::TcpListener, time::Instant};
 
     use crate::*;
 
+    /// A DNS server on localhost answering a single query with a CHAOS TXT record of `answer`.
+    fn mock_cloudflare(answer: &'static str) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1500];
+            let (len, peer) = socket.recv_from(&mut buf).unwrap();
+            let mut response = buf[..len].to_vec();
+            response[2..4].copy_from_slice(&0x8180u16.to_be_bytes()); // Response, no error.
+            response[6..8].copy_from_slice(&1u16.to_be_bytes()); // One answer.
+            response.extend_from_slice(&0xc00cu16.to_be_bytes()); // Pointer to the qname.
+            response.extend_from_slice(&TYPE_TXT.to_be_bytes());
+            response.extend_from_slice(&CLASS_CH.to_be_bytes());
+            response.extend_from_slice(&0u32.to_be_bytes()); // TTL
+            response.extend_from_slice(&(answer.len() as u16 + 1).to_be_bytes());
+            response.push(answer.len() as u8);
+            response.extend_from_slice(answer.as_bytes());
+            socket.send_to(&response, peer).unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn test_host_header() {
+        assert_eq!(host_header("ifconfig.me", 80), "ifconfig.me");
+        assert_eq!(host_header("ifconfig.me", 8080), "ifconfig.me:8080");
+        assert_eq!(host_header("1.2.3.4", 80), "1.2.3.4");
+        assert_eq!(host_header("2001:db8::1", 80), "[2001:db8::1]");
+        assert_eq!(host_header("2001:db8::1", 8080), "[2001:db8::1]:8080");
+    }
+
+    /// An HTTP server on localhost answering a single request with `response`.
+    fn mock_http(response: &'static str) -> Resolver {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut byte = [0u8];
+            while !request.ends_with(b"\r\n\r\n") {
+                stream.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        format!("http://127.0.0.1:{}/ip", port).parse().unwrap()
+    }
+
     #[test]
     #[ignore]
     fn it_works() -> Result<(), Error> {
@@ -193,4 +485,85 @@ mod tests {
         assert!(v6.is_some());
         Ok(())
     }
+
+    #[test]
+    fn test_preferred() {
+        let v4 = Ipv4Addr::new(192, 0, 2, 1);
+        let v6 = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        let both = PublicAddrs {
+            v4: Some(v4),
+            v6: Some(v6),
+        };
+        assert_eq!(both.preferred(Preference::Ipv4), Some(v4.into()));
+        assert_eq!(both.preferred(Preference::Ipv6), Some(v6.into()));
+
+        let v4_only = PublicAddrs {
+            v4: Some(v4),
+            v6: None,
+        };
+        assert_eq!(v4_only.preferred(Preference::Ipv6), Some(v4.into()));
+        assert_eq!(PublicAddrs::default().preferred(Preference::Ipv4), None);
+    }
+
+    #[test]
+    fn test_resolver_fallback() {
+        // Bound, but never answers.
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let resolvers = [
+            Resolver::Cloudflare(silent.local_addr().unwrap()),
+            mock_http("HTTP/1.1 500 Internal Server Error\r\n\r\n192.0.2.99"),
+            Resolver::Cloudflare(mock_cloudflare("192.0.2.1")),
+        ];
+        let addrs = PublicAddrs::get_from(&resolvers, Duration::from_millis(200));
+        assert_eq!(
+            addrs,
+            PublicAddrs {
+                v4: Some(Ipv4Addr::new(192, 0, 2, 1)),
+                v6: None,
+            }
+        );
+
+        let resolvers = [
+            Resolver::Cloudflare(mock_cloudflare("not an address")),
+            mock_http("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n192.0.2.2\n"),
+        ];
+        assert_eq!(
+            first_answer(&resolvers, Preference::Ipv4, Duration::from_millis(200)),
+            Some(Ipv4Addr::new(192, 0, 2, 2).into())
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let timeout = Duration::from_millis(500);
+        assert_eq!(
+            resolve("127.0.0.1", 80, Preference::Ipv4, timeout).unwrap(),
+            "127.0.0.1:80".parse().unwrap()
+        );
+        let error = resolve("127.0.0.1", 80, Preference::Ipv6, timeout).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_parse_resolver() {
+        let http = |host: &str, port, path: &str| Resolver::Http {
+            host: host.into(),
+            port,
+            path: path.into(),
+        };
+        assert_eq!(
+            "http://ifconfig.me/ip".parse::<Resolver>().unwrap(),
+            http("ifconfig.me", 80, "/ip")
+        );
+        assert_eq!(
+            "http://10.0.0.1:8080".parse::<Resolver>().unwrap(),
+            http("10.0.0.1", 8080, "/")
+        );
+        assert_eq!(
+            "http://[fd00::1]:8080/echo".parse::<Resolver>().unwrap(),
+            http("fd00::1", 8080, "/echo")
+        );
+        assert!("https://ifconfig.me".parse::<Resolver>().is_err());
+        assert!("http://host:port".parse::<Resolver>().is_err());
+    }
 }
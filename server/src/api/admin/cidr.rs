@@ -1,12 +1,23 @@
-use std::collections::VecDeque;
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    db::DatabaseCidr,
+    db::{DatabaseCidr, DatabasePeer},
+    reserved_ips,
     util::{form_body, json_response, status_response},
-    ServerError, Session,
+    Reservation, ServerError, Session,
 };
 use hyper::{Body, Method, Request, Response, StatusCode};
-use shared::CidrContents;
+use shared::{free_ips, CidrContents, NextIpsRequest};
+
+/// The longest a next-ips reservation may be held for.
+const MAX_RESERVATION: Duration = Duration::from_secs(10 * 60);
+
+/// The most addresses a single next-ips request can ask for.
+const MAX_NEXT_IPS: usize = 256;
 
 pub async fn routes(
     req: Request<Body>,
@@ -15,14 +26,28 @@ pub async fn routes(
 ) -> Result<Response<Body>, ServerError> {
     match (req.method(), components.pop_front().as_deref()) {
         (&Method::GET, None) => handlers::list(session).await,
+        (&Method::GET, Some("utilization")) => handlers::utilization(session).await,
         (&Method::POST, None) => {
             let form = form_body(req).await?;
             handlers::create(form, session).await
         },
+        (&Method::PUT, Some(id)) => {
+            let id: i64 = id.parse().map_err(|_| ServerError::NotFound)?;
+            let form = form_body(req).await?;
+            handlers::update(id, form, session).await
+        },
         (&Method::DELETE, Some(id)) => {
             let id: i64 = id.parse().map_err(|_| ServerError::NotFound)?;
             handlers::delete(id, session).await
         },
+        (&Method::POST, Some(id)) => match components.pop_front().as_deref() {
+            Some("next-ips") => {
+                let id: i64 = id.parse().map_err(|_| ServerError::NotFound)?;
+                let form = form_body(req).await?;
+                handlers::next_ips(id, form, session).await
+            },
+            _ => Err(ServerError::NotFound),
+        },
         _ => Err(ServerError::NotFound),
     }
 }
@@ -50,12 +75,87 @@ mod handlers {
         json_response(&cidrs)
     }
 
+    pub async fn utilization(session: Session) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        let reserved = reserved_ips(&session.context.reservations);
+        json_response(DatabaseCidr::utilization(&conn, &reserved)?)
+    }
+
+    /// Rename a CIDR, leaving its range and parent as they are.
+    pub async fn update(
+        id: i64,
+        contents: CidrContents,
+        session: Session,
+    ) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        let cidr = DatabaseCidr::update(&conn, id, contents)?;
+
+        json_response(cidr)
+    }
+
     pub async fn delete(id: i64, session: Session) -> Result<Response<Body>, ServerError> {
         let conn = session.context.db.lock();
         DatabaseCidr::delete(&conn, id)?;
 
         status_response(StatusCode::NO_CONTENT)
     }
+
+    /// Return the next `count` addresses the allocator would hand out in a leaf CIDR,
+    /// optionally reserving them against later next-ips requests.
+    pub async fn next_ips(
+        id: i64,
+        form: NextIpsRequest,
+        session: Session,
+    ) -> Result<Response<Body>, ServerError> {
+        let reservation = form.reserve_secs.map(Duration::from_secs);
+        if reservation.is_some_and(|ttl| ttl > MAX_RESERVATION) || form.count > MAX_NEXT_IPS {
+            return Err(ServerError::InvalidQuery);
+        }
+
+        let conn = session.context.db.lock();
+        let cidr = DatabaseCidr::get(&conn, id)?;
+        if DatabaseCidr::list(&conn)?
+            .iter()
+            .any(|child| child.parent == Some(cidr.id))
+        {
+            // Peers can only be added to leaf CIDRs.
+            return Err(ServerError::InvalidQuery);
+        }
+        let peers = DatabasePeer::list(&conn)?;
+
+        let now = Instant::now();
+        let mut reservations = session.context.reservations.write();
+        reservations.retain(|_, reservation| reservation.expires > now);
+
+        let ips: Vec<IpAddr> = free_ips(&cidr.cidr, |ip| {
+            peers.iter().any(|peer| peer.covers(ip)) || reservations.contains_key(ip)
+        })
+        .take(form.count)
+        .collect();
+        if ips.len() < form.count {
+            log::warn!(
+                "CIDR {} can't supply {} addresses ({} free).",
+                cidr.name,
+                form.count,
+                ips.len()
+            );
+            return Err(ServerError::InvalidQuery);
+        }
+
+        if let Some(ttl) = reservation {
+            for ip in &ips {
+                reservations.insert(
+                    *ip,
+                    Reservation {
+                        expires: now + ttl,
+                        reserved_by: session.peer.id,
+                    },
+                );
+            }
+        }
+
+        json_response(&ips)
+    }
 }
 
 #[cfg(test)]
@@ -64,7 +164,9 @@ mod tests {
     use crate::{test, DatabasePeer};
     use anyhow::Result;
     use bytes::Buf;
-    use shared::{Cidr, Error};
+    use ipnet::IpNet;
+    use shared::{Cidr, CidrUtilization, Error, PeerContents};
+    use std::collections::HashSet;
 
     #[tokio::test]
     async fn test_cidr_add() -> Result<(), Error> {
@@ -124,6 +226,104 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_cidr_rename() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let path = format!("/v1/admin/cidrs/{}", test::DEVELOPER_CIDR_ID);
+        let developer = DatabaseCidr::get(&server.db().lock(), test::DEVELOPER_CIDR_ID)?;
+
+        let renamed = CidrContents {
+            name: "engineering".to_string(),
+            ..developer.contents.clone()
+        };
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "PUT", &path, &renamed)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let whole_body = hyper::body::aggregate(res).await?;
+        let cidr_res: Cidr = serde_json::from_reader(whole_body.reader())?;
+        assert_eq!(cidr_res.contents, renamed);
+        assert_eq!(
+            DatabaseCidr::get(&server.db().lock(), test::DEVELOPER_CIDR_ID)?.contents,
+            renamed
+        );
+
+        for bad in [
+            // Taken by a sibling.
+            CidrContents {
+                name: "user".to_string(),
+                ..renamed.clone()
+            },
+            // Not a valid hostname.
+            CidrContents {
+                name: "not valid!".to_string(),
+                ..renamed.clone()
+            },
+            // Renames can't move the CIDR.
+            CidrContents {
+                cidr: test::EXPERIMENTAL_CIDR.parse()?,
+                ..renamed.clone()
+            },
+            CidrContents {
+                parent: Some(test::INFRA_CIDR_ID),
+                ..renamed.clone()
+            },
+        ] {
+            let res = server
+                .form_request(test::ADMIN_PEER_IP, "PUT", &path, &bad)
+                .await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{:?}", bad);
+        }
+        assert_eq!(
+            DatabaseCidr::get(&server.db().lock(), test::DEVELOPER_CIDR_ID)?.contents,
+            renamed
+        );
+
+        let res = server
+            .form_request(test::USER1_PEER_IP, "PUT", &path, &renamed)
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "PUT", "/v1/admin/cidrs/100", &renamed)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cidr_overlap_reason() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let developer: IpNet = test::DEVELOPER_CIDR.parse()?;
+
+        for (cidr, reason) in [
+            (developer, "is already the range of CIDR \"developer\""),
+            (
+                developer
+                    .subnets(developer.prefix_len() + 1)?
+                    .next()
+                    .unwrap(),
+                "is inside CIDR \"developer\"",
+            ),
+            (developer.supernet().unwrap(), "overlaps CIDR \"developer\""),
+        ] {
+            let contents = CidrContents {
+                name: "experimental".to_string(),
+                cidr,
+                parent: Some(test::ROOT_CIDR_ID),
+            };
+            let res = server
+                .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/cidrs", &contents)
+                .await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            let body = hyper::body::to_bytes(res).await?;
+            let body = String::from_utf8_lossy(&body);
+            assert!(body.contains(reason), "{}: {}", cidr, body);
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_cidr_create_auth() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -275,4 +475,184 @@ mod tests {
 
         Ok(())
     }
+
+    async fn next_ips(
+        server: &test::Server,
+        cidr_id: i64,
+        form: &NextIpsRequest,
+    ) -> Result<(StatusCode, Vec<IpAddr>), Error> {
+        let res = server
+            .form_request(
+                test::ADMIN_PEER_IP,
+                "POST",
+                &format!("/v1/admin/cidrs/{}/next-ips", cidr_id),
+                form,
+            )
+            .await;
+        let status = res.status();
+        if !status.is_success() {
+            return Ok((status, vec![]));
+        }
+        let whole_body = hyper::body::aggregate(res).await?;
+        Ok((status, serde_json::from_reader(whole_body.reader())?))
+    }
+
+    #[tokio::test]
+    async fn test_next_ips() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let form = NextIpsRequest {
+            count: 3,
+            reserve_secs: None,
+        };
+
+        let (status, ips) = next_ips(&server, test::DEVELOPER_CIDR_ID, &form).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ips.len(), 3);
+        let developer_cidr: ipnet::IpNet = test::DEVELOPER_CIDR.parse()?;
+        let taken: Vec<IpAddr> = vec![
+            test::DEVELOPER1_PEER_IP.parse()?,
+            test::DEVELOPER2_PEER_IP.parse()?,
+        ];
+        for ip in &ips {
+            assert!(developer_cidr.contains(ip));
+            assert!(!taken.contains(ip));
+        }
+        assert_eq!(ips.iter().collect::<HashSet<_>>().len(), ips.len());
+
+        // Without a reservation the same addresses come back again.
+        let (_, again) = next_ips(&server, test::DEVELOPER_CIDR_ID, &form).await?;
+        assert_eq!(ips, again);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_next_ips_skips_extra_allowed_ips() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let form = NextIpsRequest {
+            count: 1,
+            reserve_secs: None,
+        };
+        let (_, ips) = next_ips(&server, test::DEVELOPER_CIDR_ID, &form).await?;
+
+        // Route the address that would be handed out to a gateway in another CIDR.
+        let gateway_ip = test::USER_CIDR.parse::<IpNet>()?.hosts().nth(100).unwrap();
+        let gateway = PeerContents {
+            extra_allowed_ips: vec![IpNet::from(ips[0])],
+            ..test::user_peer_contents("gateway", &gateway_ip.to_string())?
+        };
+        DatabasePeer::create(&server.db().lock(), gateway)?;
+
+        let (status, next) = next_ips(&server, test::DEVELOPER_CIDR_ID, &form).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_ne!(next, ips);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_next_ips_reserved() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let form = NextIpsRequest {
+            count: 2,
+            reserve_secs: Some(60),
+        };
+
+        let (_, first) = next_ips(&server, test::DEVELOPER_CIDR_ID, &form).await?;
+        let (_, second) = next_ips(&server, test::DEVELOPER_CIDR_ID, &form).await?;
+        assert_eq!(first.len(), 2);
+        assert_eq!(second.len(), 2);
+        assert!(first.iter().all(|ip| !second.contains(ip)));
+
+        let (status, _) = next_ips(
+            &server,
+            test::DEVELOPER_CIDR_ID,
+            &NextIpsRequest {
+                count: 1,
+                reserve_secs: Some(MAX_RESERVATION.as_secs() + 1),
+            },
+        )
+        .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = next_ips(
+            &server,
+            test::DEVELOPER_CIDR_ID,
+            &NextIpsRequest {
+                count: MAX_NEXT_IPS + 1,
+                reserve_secs: None,
+            },
+        )
+        .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cidr_utilization() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let res = server
+            .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/cidrs/utilization")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let whole_body = hyper::body::aggregate(res).await?;
+        let usage: Vec<CidrUtilization> = serde_json::from_reader(whole_body.reader())?;
+        let usage_of = |id| usage.iter().find(|u| u.cidr_id == id).unwrap();
+
+        // The infra CIDR is a single address, taken by the server.
+        assert_eq!(usage_of(test::INFRA_CIDR_ID).used, 1);
+        assert_eq!(usage_of(test::INFRA_CIDR_ID).total, 1);
+        // Parents count the peers of their children.
+        assert_eq!(usage_of(test::USER_CIDR_ID).used, 2);
+        assert!(usage_of(test::ROOT_CIDR_ID).used >= 2 + usage_of(test::ADMIN_CIDR_ID).used);
+
+        // Reserved addresses count as used.
+        let form = NextIpsRequest {
+            count: 2,
+            reserve_secs: Some(60),
+        };
+        next_ips(&server, test::USER_CIDR_ID, &form).await?;
+        let res = server
+            .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/cidrs/utilization")
+            .await;
+        let whole_body = hyper::body::aggregate(res).await?;
+        let reserved: Vec<CidrUtilization> = serde_json::from_reader(whole_body.reader())?;
+        let reserved_usage = reserved.iter().find(|u| u.cidr_id == test::USER_CIDR_ID);
+        assert_eq!(reserved_usage.unwrap().used, 4);
+
+        let res = server
+            .request(test::USER1_PEER_IP, "GET", "/v1/admin/cidrs/utilization")
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_next_ips_exhausted() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let form = NextIpsRequest {
+            count: 1,
+            reserve_secs: None,
+        };
+
+        // The infra CIDR only holds the server itself.
+        let (status, _) = next_ips(&server, test::INFRA_CIDR_ID, &form).await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Peers can't be placed directly in the root CIDR.
+        let (status, _) = next_ips(&server, test::ROOT_CIDR_ID, &form).await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let res = server
+            .form_request(
+                test::USER1_PEER_IP,
+                "POST",
+                &format!("/v1/admin/cidrs/{}/next-ips", test::DEVELOPER_CIDR_ID),
+                &form,
+            )
+            .await;
+        assert!(!res.status().is_success());
+
+        Ok(())
+    }
 }
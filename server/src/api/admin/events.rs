@@ -0,0 +1,141 @@
+//! A pull-based, resumable feed of network state changes for external consumers
+//! (ex. syncing an inventory), as an alternative to being pushed notifications.
+
+use std::collections::VecDeque;
+
+use crate::{db::DatabaseEvent, util::json_response, ServerError, Session};
+use hyper::{Body, Method, Request, Response};
+
+pub async fn routes(
+    req: Request<Body>,
+    mut components: VecDeque<String>,
+    session: Session,
+) -> Result<Response<Body>, ServerError> {
+    match (req.method(), components.pop_front().as_deref()) {
+        (&Method::GET, None) => {
+            let since = since_cursor(req.uri().query())?;
+            handlers::list(since, session).await
+        },
+        _ => Err(ServerError::NotFound),
+    }
+}
+
+/// Parse the `since=<cursor>` query parameter, defaulting to the start of the feed.
+fn since_cursor(query: Option<&str>) -> Result<i64, ServerError> {
+    let since = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .find(|(key, _)| key == "since")
+        .map(|(_, value)| value.parse())
+        .transpose()
+        .map_err(|_| ServerError::InvalidQuery)?;
+    Ok(since.unwrap_or(0))
+}
+
+mod handlers {
+    use super::*;
+
+    /// Events after the `since` cursor, oldest first. Responses are capped in size, so
+    /// consumers should keep requesting with the last returned id until nothing comes back.
+    ///
+    /// Old events are pruned (see `serve --event-retention`). If some after a nonzero cursor
+    /// already were, the consumer missed changes and gets 410 Gone, so it knows to resync
+    /// from scratch.
+    pub async fn list(since: i64, session: Session) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        if since > 0 && DatabaseEvent::oldest_id(&conn)?.is_some_and(|oldest| since + 1 < oldest) {
+            return Err(ServerError::Gone);
+        }
+        json_response(DatabaseEvent::list_since(&conn, since)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use bytes::Buf;
+    use hyper::StatusCode;
+    use shared::{CidrContents, Error, Event, EventKind};
+
+    async fn events(server: &test::Server, since: i64) -> Result<Vec<Event>, Error> {
+        let res = server
+            .request(
+                test::ADMIN_PEER_IP,
+                "GET",
+                &format!("/v1/admin/events?since={}", since),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let whole_body = hyper::body::aggregate(res).await?;
+        Ok(serde_json::from_reader(whole_body.reader())?)
+    }
+
+    #[tokio::test]
+    async fn test_events_feed() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        // The test network's setup is already in the feed.
+        let setup = events(&server, 0).await?;
+        assert!(!setup.is_empty());
+        assert!(setup.windows(2).all(|pair| pair[0].id < pair[1].id));
+        let cursor = setup.last().unwrap().id;
+
+        let contents = CidrContents {
+            name: "experimental".to_string(),
+            cidr: test::EXPERIMENTAL_CIDR.parse()?,
+            parent: Some(test::ROOT_CIDR_ID),
+        };
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/cidrs", &contents)
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let new = events(&server, cursor).await?;
+        assert_eq!(new.len(), 1);
+        assert!(
+            matches!(&new[0].kind, EventKind::CidrCreated { cidr } if cidr.contents == contents)
+        );
+        assert!(events(&server, new[0].id).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_events_pruned_cursor() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let latest = DatabaseEvent::latest_id(&server.db().lock())?;
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        DatabaseEvent::prune(&server.db().lock(), later)?;
+
+        // Starting from scratch, or from a cursor that's still covered, works.
+        assert_eq!(events(&server, 0).await?.len(), 1);
+        assert_eq!(events(&server, latest - 1).await?.len(), 1);
+        let res = server
+            .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/events?since=1")
+            .await;
+        assert_eq!(res.status(), StatusCode::GONE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_events_admin_only() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let res = server
+            .request(test::USER1_PEER_IP, "GET", "/v1/admin/events")
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = server
+            .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/events?since=abc")
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[test]
+    fn test_since_cursor() {
+        assert_eq!(since_cursor(None).unwrap(), 0);
+        assert_eq!(since_cursor(Some("since=42")).unwrap(), 42);
+        assert_eq!(since_cursor(Some("foo=bar&since=7")).unwrap(), 7);
+        assert!(since_cursor(Some("since=")).is_err());
+    }
+}
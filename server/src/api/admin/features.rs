@@ -0,0 +1,104 @@
+use std::collections::VecDeque;
+
+use crate::{
+    db::DatabaseFeatureFlags,
+    util::{form_body, json_response},
+    ServerError, Session,
+};
+use hyper::{Body, Method, Request, Response};
+use shared::FeatureFlagContents;
+
+pub async fn routes(
+    req: Request<Body>,
+    mut components: VecDeque<String>,
+    session: Session,
+) -> Result<Response<Body>, ServerError> {
+    match (req.method(), components.pop_front().as_deref()) {
+        (&Method::GET, None) => handlers::get(session).await,
+        (&Method::PUT, None) => {
+            let form = form_body(req).await?;
+            handlers::set(form, session).await
+        },
+        _ => Err(ServerError::NotFound),
+    }
+}
+
+mod handlers {
+    use super::*;
+
+    pub async fn get(session: Session) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        json_response(DatabaseFeatureFlags::get(&conn)?)
+    }
+
+    pub async fn set(
+        form: FeatureFlagContents,
+        session: Session,
+    ) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        let flags = DatabaseFeatureFlags::set(&conn, &form.name, form.enabled)?;
+        log::info!(
+            "feature flag {} turned {} by {}.",
+            form.name,
+            if form.enabled { "on" } else { "off" },
+            &*session.peer
+        );
+        json_response(flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use bytes::Buf;
+    use hyper::StatusCode;
+    use shared::{Error, FeatureFlags};
+
+    async fn set_flag(server: &test::Server, peer_ip: &str, name: &str) -> StatusCode {
+        server
+            .form_request(
+                peer_ip,
+                "PUT",
+                "/v1/admin/features",
+                &FeatureFlagContents {
+                    name: name.into(),
+                    enabled: false,
+                },
+            )
+            .await
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_feature_flags_served_to_users() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        assert_eq!(
+            set_flag(&server, test::ADMIN_PEER_IP, "nat-traversal").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            set_flag(&server, test::ADMIN_PEER_IP, "relays").await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            set_flag(&server, test::USER1_PEER_IP, "report-metadata").await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let res = server
+            .request(test::USER1_PEER_IP, "GET", "/v1/user/features")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let whole_body = hyper::body::aggregate(res).await?;
+        let flags: FeatureFlags = serde_json::from_reader(whole_body.reader())?;
+        assert_eq!(
+            flags,
+            FeatureFlags {
+                nat_traversal: false,
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+}
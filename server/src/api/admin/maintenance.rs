@@ -0,0 +1,146 @@
+use std::{collections::VecDeque, sync::atomic::Ordering};
+
+use crate::{
+    util::{form_body, json_response},
+    ServerError, Session,
+};
+use hyper::{Body, Method, Request, Response};
+use shared::MaintenanceContents;
+
+pub async fn routes(
+    req: Request<Body>,
+    mut components: VecDeque<String>,
+    session: Session,
+) -> Result<Response<Body>, ServerError> {
+    match (req.method(), components.pop_front().as_deref()) {
+        (&Method::GET, None) => handlers::get(session).await,
+        (&Method::PUT, None) => {
+            let form = form_body(req).await?;
+            handlers::set(form, session).await
+        },
+        _ => Err(ServerError::NotFound),
+    }
+}
+
+mod handlers {
+    use super::*;
+
+    pub async fn get(session: Session) -> Result<Response<Body>, ServerError> {
+        json_response(MaintenanceContents {
+            read_only: session.context.read_only.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Turn read-only maintenance mode on or off. This is the only mutating request
+    /// that's still served while read-only.
+    pub async fn set(
+        form: MaintenanceContents,
+        session: Session,
+    ) -> Result<Response<Body>, ServerError> {
+        let was_read_only = session
+            .context
+            .read_only
+            .swap(form.read_only, Ordering::Relaxed);
+        if was_read_only != form.read_only {
+            log::warn!(
+                "read-only maintenance mode turned {} by {}.",
+                if form.read_only { "on" } else { "off" },
+                &*session.peer
+            );
+        }
+        json_response(form)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use bytes::Buf;
+    use hyper::StatusCode;
+    use shared::{CidrContents, Error};
+
+    async fn set_read_only(server: &test::Server, peer_ip: &str, read_only: bool) -> StatusCode {
+        server
+            .form_request(
+                peer_ip,
+                "PUT",
+                "/v1/admin/maintenance",
+                &MaintenanceContents { read_only },
+            )
+            .await
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_read_only_rejects_mutations() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        assert_eq!(
+            set_read_only(&server, test::ADMIN_PEER_IP, true).await,
+            StatusCode::OK
+        );
+
+        let res = server
+            .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/maintenance")
+            .await;
+        let whole_body = hyper::body::aggregate(res).await?;
+        let status: MaintenanceContents = serde_json::from_reader(whole_body.reader())?;
+        assert!(status.read_only);
+
+        // Reads are still served...
+        let res = server
+            .request(test::USER1_PEER_IP, "GET", "/v1/user/state")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = server
+            .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/peers")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // ...but changes aren't.
+        let contents = CidrContents {
+            name: "experimental".to_string(),
+            cidr: test::EXPERIMENTAL_CIDR.parse()?,
+            parent: Some(test::ROOT_CIDR_ID),
+        };
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/cidrs", &contents)
+            .await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let res = server
+            .form_request(
+                test::USER1_PEER_IP,
+                "PUT",
+                "/v1/user/candidates",
+                &Vec::<shared::Endpoint>::new(),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        assert_eq!(
+            set_read_only(&server, test::ADMIN_PEER_IP, false).await,
+            StatusCode::OK
+        );
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/cidrs", &contents)
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_only_admin_only() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        assert_eq!(
+            set_read_only(&server, test::USER1_PEER_IP, true).await,
+            StatusCode::UNAUTHORIZED
+        );
+        set_read_only(&server, test::ADMIN_PEER_IP, true).await;
+        assert_eq!(
+            set_read_only(&server, test::USER1_PEER_IP, false).await,
+            StatusCode::UNAUTHORIZED
+        );
+        Ok(())
+    }
+}
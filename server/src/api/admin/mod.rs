@@ -6,7 +6,11 @@ use crate::{ServerError, Session};
 
 pub mod association;
 pub mod cidr;
+pub mod events;
+pub mod features;
+pub mod maintenance;
 pub mod peer;
+pub mod spec;
 
 pub async fn routes(
     req: Request<Body>,
@@ -20,7 +24,11 @@ pub async fn routes(
     match components.pop_front().as_deref() {
         Some("associations") => association::routes(req, components, session).await,
         Some("cidrs") => cidr::routes(req, components, session).await,
+        Some("events") => events::routes(req, components, session).await,
+        Some("features") => features::routes(req, components, session).await,
+        Some("maintenance") => maintenance::routes(req, components, session).await,
         Some("peers") => peer::routes(req, components, session).await,
+        Some("spec") => spec::routes(req, components, session).await,
         _ => Err(ServerError::NotFound),
     }
 }
@@ -1,14 +1,15 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, time::Instant};
 
 use crate::{
     api::inject_endpoints,
-    db::DatabasePeer,
+    db::{DatabaseCidr, DatabaseMtuHint, DatabasePeer, DatabasePeerMetadata},
+    reserved_ips,
     util::{form_body, json_response, json_status_response, status_response},
     ServerError, Session,
 };
 use hyper::{Body, Method, Request, Response, StatusCode};
-use shared::PeerContents;
-use wireguard_control::{DeviceUpdate, PeerConfigBuilder};
+use shared::{CidrTree, MtuHintContents, PeerContents};
+use wireguard_control::{DeviceUpdate, Key, PeerConfigBuilder};
 
 pub async fn routes(
     req: Request<Body>,
@@ -16,7 +17,17 @@ pub async fn routes(
     session: Session,
 ) -> Result<Response<Body>, ServerError> {
     match (req.method(), components.pop_front().as_deref()) {
-        (&Method::GET, None) => handlers::list(session).await,
+        (&Method::GET, None) => {
+            let query = ListQuery::parse(req.uri().query())?;
+            handlers::list(query, session).await
+        },
+        (&Method::GET, Some("metadata")) => handlers::list_metadata(session).await,
+        (&Method::GET, Some("mtu-hints")) => handlers::list_mtu_hints(session).await,
+        (&Method::PUT, Some(id)) if components.front().map(String::as_str) == Some("mtu-hint") => {
+            let id: i64 = id.parse().map_err(|_| ServerError::NotFound)?;
+            let form = form_body(req).await?;
+            handlers::set_mtu_hint(id, form, session).await
+        },
         (&Method::POST, None) => {
             let form = form_body(req).await?;
             handlers::create(form, session).await
@@ -34,6 +45,34 @@ pub async fn routes(
     }
 }
 
+/// The query parameters of the peer listing.
+#[derive(Debug, Default)]
+pub struct ListQuery {
+    /// `cidr_id=<id>`: only list peers whose IP is within that CIDR or one of its descendants.
+    cidr_id: Option<i64>,
+    /// `include_deleted=true`: also list deleted peers.
+    include_deleted: bool,
+}
+
+impl ListQuery {
+    fn parse(query: Option<&str>) -> Result<Self, ServerError> {
+        let mut parsed = Self::default();
+        for (key, value) in url::form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            match &*key {
+                "cidr_id" => {
+                    parsed.cidr_id = Some(value.parse().map_err(|_| ServerError::InvalidQuery)?);
+                },
+                "include_deleted" => {
+                    parsed.include_deleted =
+                        value.parse().map_err(|_| ServerError::InvalidQuery)?;
+                },
+                _ => {},
+            }
+        }
+        Ok(parsed)
+    }
+}
+
 mod handlers {
 
     use super::*;
@@ -44,9 +83,42 @@ mod handlers {
     ) -> Result<Response<Body>, ServerError> {
         let conn = session.context.db.lock();
 
+        // Hold the reservations across the insert so that a concurrent next-ips request
+        // can't hand out the address in between.
+        let now = Instant::now();
+        let mut reservations = session.context.reservations.write();
+        reservations.retain(|_, reservation| reservation.expires > now);
+        if let Some(reservation) = reservations.get(&form.ip) {
+            if reservation.reserved_by != session.peer.id {
+                return Err(ServerError::Conflict(format!(
+                    "{} is reserved by another admin",
+                    form.ip
+                )));
+            }
+        }
+
         let peer = DatabasePeer::create(&conn, form)?;
+        reservations.remove(&peer.ip);
+        drop(reservations);
         log::info!("adding peer {}", &*peer);
 
+        if let Some(threshold) = session.context.cidr_usage_warning {
+            let reserved = reserved_ips(&session.context.reservations);
+            let usage = DatabaseCidr::utilization(&conn, &reserved)?
+                .into_iter()
+                .find(|usage| usage.cidr_id == peer.cidr_id);
+            if let Some(usage) = usage.filter(|usage| usage.just_crossed(threshold)) {
+                let cidr = DatabaseCidr::get(&conn, peer.cidr_id)?;
+                log::warn!(
+                    "CIDR {} is {:.0}% full ({} of {} addresses used).",
+                    cidr,
+                    usage.percent(),
+                    usage.used,
+                    usage.total
+                );
+            }
+        }
+
         if cfg!(not(test)) {
             // Update the current WireGuard interface with the new peers.
             DeviceUpdate::new()
@@ -66,25 +138,87 @@ mod handlers {
     ) -> Result<Response<Body>, ServerError> {
         let conn = session.context.db.lock();
         let mut peer = DatabasePeer::get(&conn, id)?;
+        let was_deleted = peer.deleted_at.is_some();
         peer.update(&conn, form)?;
 
+        if was_deleted && peer.deleted_at.is_none() {
+            log::info!("restored deleted peer {}", &*peer);
+            if cfg!(not(test)) {
+                DeviceUpdate::new()
+                    .add_peer(PeerConfigBuilder::from(&*peer))
+                    .apply(&session.context.interface, session.context.backend)
+                    .map_err(|_| ServerError::WireGuard)?;
+            }
+        }
+
         status_response(StatusCode::NO_CONTENT)
     }
 
-    /// List all peers, including disabled ones. This is an admin-only endpoint.
-    pub async fn list(session: Session) -> Result<Response<Body>, ServerError> {
+    /// List all peers, including disabled ones (and deleted ones, if asked for). This is an
+    /// admin-only endpoint.
+    pub async fn list(query: ListQuery, session: Session) -> Result<Response<Body>, ServerError> {
         let conn = session.context.db.lock();
         let mut peers = DatabasePeer::list(&conn)?
             .into_iter()
             .map(|peer| peer.inner)
+            .filter(|peer| query.include_deleted || peer.deleted_at.is_none())
             .collect::<Vec<_>>();
+        if let Some(cidr_id) = query.cidr_id {
+            let cidrs = DatabaseCidr::list(&conn)?;
+            let root = cidrs
+                .iter()
+                .find(|cidr| cidr.id == cidr_id)
+                .ok_or(ServerError::NotFound)?;
+            let subtree = CidrTree::with_root(&cidrs, root).subtree();
+            peers.retain(|peer| subtree.iter().any(|cidr| cidr.cidr.contains(&peer.ip)));
+        }
         inject_endpoints(&session, &mut peers);
         json_response(&peers)
     }
 
+    /// The metadata last reported by each peer that has reported any.
+    pub async fn list_metadata(session: Session) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        json_response(DatabasePeerMetadata::list(&conn)?)
+    }
+
+    /// Every peer's MTU hint, for peers that have one.
+    pub async fn list_mtu_hints(session: Session) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        json_response(DatabaseMtuHint::list(&conn)?)
+    }
+
+    pub async fn set_mtu_hint(
+        id: i64,
+        form: MtuHintContents,
+        session: Session,
+    ) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        let peer = DatabasePeer::get(&conn, id)?;
+        DatabaseMtuHint::set(&conn, peer.id, form.mtu)?;
+        match form.mtu {
+            Some(mtu) => log::info!("set MTU hint of peer {} to {}", &*peer, mtu),
+            None => log::info!("cleared MTU hint of peer {}", &*peer),
+        }
+
+        status_response(StatusCode::NO_CONTENT)
+    }
+
     pub async fn delete(id: i64, session: Session) -> Result<Response<Body>, ServerError> {
         let conn = session.context.db.lock();
-        DatabasePeer::disable(&conn, id)?;
+        DatabasePeer::delete(&conn, id)?;
+        let peer = DatabasePeer::get(&conn, id)?;
+        log::info!("deleted peer {}", &*peer);
+
+        if cfg!(not(test)) {
+            // Deleted peers aren't added back to the interface on startup, so drop it now too.
+            if let Ok(public_key) = Key::from_base64(&peer.public_key) {
+                DeviceUpdate::new()
+                    .remove_peer_by_key(&public_key)
+                    .apply(&session.context.interface, session.context.backend)
+                    .map_err(|_| ServerError::WireGuard)?;
+            }
+        }
 
         status_response(StatusCode::NO_CONTENT)
     }
@@ -93,9 +227,10 @@ mod handlers {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test;
+    use crate::{test, Reservation};
     use bytes::Buf;
-    use shared::{Error, Peer};
+    use shared::{CidrContents, Error, Peer};
+    use std::time::Duration;
 
     #[tokio::test]
     async fn test_add_peer() -> Result<(), Error> {
@@ -127,6 +262,45 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_add_peer_honors_reservations() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let (reserved, released) = if cfg!(feature = "v6-test") {
+            ("fd00:1337::2:0:0:3", "fd00:1337::2:0:0:4")
+        } else {
+            ("10.80.64.4", "10.80.64.5")
+        };
+        let reservation = |reserved_by| Reservation {
+            expires: Instant::now() + Duration::from_secs(60),
+            reserved_by,
+        };
+        {
+            let context = server.context();
+            let mut reservations = context.reservations.write();
+            reservations.insert(reserved.parse()?, reservation(test::DEVELOPER1_PEER_ID));
+            reservations.insert(released.parse()?, reservation(test::ADMIN_PEER_ID));
+        }
+
+        // Another admin's reservation can't be taken.
+        let peer = test::developer_peer_contents("developer3", reserved)?;
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/peers", &peer)
+            .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        // Using your own reservation releases it.
+        let peer = test::developer_peer_contents("developer3", released)?;
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/peers", &peer)
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let reservations = server.context().reservations;
+        assert!(!reservations.read().contains_key(&released.parse()?));
+        assert!(reservations.read().contains_key(&reserved.parse()?));
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_add_peer_with_invalid_name() -> Result<(), Error> {
         assert!(test::developer_peer_contents("devel oper", "10.80.64.4").is_err());
@@ -162,13 +336,15 @@ mod tests {
         let old_peers = DatabasePeer::list(&server.db().lock())?;
 
         // Try to add a peer with an IP that is already taken.
-        let peer = test::developer_peer_contents("developer3", "10.80.64.3")?;
+        let peer = test::developer_peer_contents("developer3", test::DEVELOPER2_PEER_IP)?;
 
         let res = server
             .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/peers", &peer)
             .await;
 
-        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let body = hyper::body::to_bytes(res).await?;
+        assert!(String::from_utf8_lossy(&body).contains("\"developer2\""));
 
         // The number of peer entries in the database should not change.
         let new_peers = DatabasePeer::list(&server.db().lock())?;
@@ -177,6 +353,54 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_add_peer_with_extra_allowed_ips() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let (ip, outside) = if cfg!(feature = "v6-test") {
+            ("fd00:1337::2:0:0:3", "fd00:beef::/64")
+        } else {
+            ("10.80.64.4", "192.168.0.0/24")
+        };
+        let peer = |extra: &str| -> Result<PeerContents, Error> {
+            Ok(PeerContents {
+                extra_allowed_ips: vec![extra.parse()?],
+                ..test::developer_peer_contents("gateway", ip)?
+            })
+        };
+
+        // Another peer's IP, and ranges outside of the network, can't be routed to the gateway.
+        for extra in [test::USER_CIDR, outside] {
+            let res = server
+                .form_request(
+                    test::ADMIN_PEER_IP,
+                    "POST",
+                    "/v1/admin/peers",
+                    &peer(extra)?,
+                )
+                .await;
+            assert_eq!(res.status(), StatusCode::CONFLICT, "{}", extra);
+        }
+
+        let res = server
+            .form_request(
+                test::ADMIN_PEER_IP,
+                "POST",
+                "/v1/admin/peers",
+                &peer(test::EXPERIMENTAL_CIDR)?,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let gateway: Peer = serde_json::from_reader(hyper::body::aggregate(res).await?.reader())?;
+        let stored = DatabasePeer::get(&server.db().lock(), gateway.id)?;
+        assert_eq!(
+            stored.extra_allowed_ips,
+            vec![test::EXPERIMENTAL_CIDR.parse()?]
+        );
+        assert_eq!(stored.allowed_ips().len(), 2);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_add_peer_with_outside_cidr_range_ip() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -303,6 +527,85 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_list_peers_in_cidr() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let peer_names = |res: Response<Body>| async move {
+            assert_eq!(res.status(), StatusCode::OK);
+            let whole_body = hyper::body::aggregate(res).await?;
+            let peers: Vec<Peer> = serde_json::from_reader(whole_body.reader())?;
+            Ok::<_, Error>(
+                peers
+                    .into_iter()
+                    .map(|p| p.contents.name.to_string())
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        let res = server
+            .request(
+                test::ADMIN_PEER_IP,
+                "GET",
+                &format!("/v1/admin/peers?cidr_id={}", test::DEVELOPER_CIDR_ID),
+            )
+            .await;
+        assert_eq!(peer_names(res).await?, ["developer1", "developer2"]);
+
+        // Peers in child CIDRs are included.
+        let (experimental_cidr, experimental_subcidr) = {
+            let db = server.db();
+            let conn = db.lock();
+            let experimental = DatabaseCidr::create(
+                &conn,
+                CidrContents {
+                    name: "experimental".to_string(),
+                    cidr: test::EXPERIMENTAL_CIDR.parse()?,
+                    parent: Some(test::ROOT_CIDR_ID),
+                },
+            )?;
+            let subcidr = DatabaseCidr::create(
+                &conn,
+                CidrContents {
+                    name: "experimental-sub".to_string(),
+                    cidr: test::EXPERIMENTAL_SUBCIDR.parse()?,
+                    parent: Some(experimental.id),
+                },
+            )?;
+            DatabasePeer::create(
+                &conn,
+                test::peer_contents(
+                    "experiment-peer",
+                    test::EXPERIMENT_SUBCIDR_PEER_IP,
+                    subcidr.id,
+                    false,
+                )?,
+            )?;
+            (experimental.id, subcidr.id)
+        };
+        for cidr_id in [experimental_cidr, experimental_subcidr] {
+            let res = server
+                .request(
+                    test::ADMIN_PEER_IP,
+                    "GET",
+                    &format!("/v1/admin/peers?cidr_id={}", cidr_id),
+                )
+                .await;
+            assert_eq!(peer_names(res).await?, ["experiment-peer"]);
+        }
+
+        let res = server
+            .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/peers?cidr_id=1000")
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = server
+            .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/peers?cidr_id=dev")
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_list_all_peers_from_non_admin() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -378,4 +681,75 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_delete_leaves_tombstone() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let list = |query: &'static str| {
+            let server = &server;
+            async move {
+                let res = server
+                    .request(
+                        test::ADMIN_PEER_IP,
+                        "GET",
+                        &format!("/v1/admin/peers{}", query),
+                    )
+                    .await;
+                assert_eq!(res.status(), StatusCode::OK);
+                let whole_body = hyper::body::aggregate(res).await?;
+                Ok::<Vec<Peer>, Error>(serde_json::from_reader(whole_body.reader())?)
+            }
+        };
+        let delete_path = format!("/v1/admin/peers/{}", test::USER1_PEER_ID);
+
+        let res = server
+            .request(test::ADMIN_PEER_IP, "DELETE", &delete_path)
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        // The peer is hidden from the listing, but kept.
+        assert!(!list("").await?.iter().any(|p| p.id == test::USER1_PEER_ID));
+        let deleted = list("?include_deleted=true")
+            .await?
+            .into_iter()
+            .find(|p| p.id == test::USER1_PEER_ID)
+            .unwrap();
+        assert!(deleted.is_disabled);
+        assert!(deleted.deleted_at.is_some());
+
+        // It can't be deleted twice.
+        let res = server
+            .request(test::ADMIN_PEER_IP, "DELETE", &delete_path)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        // Enabling it again restores it.
+        let res = server
+            .form_request(
+                test::ADMIN_PEER_IP,
+                "PUT",
+                &delete_path,
+                &PeerContents {
+                    is_disabled: false,
+                    ..deleted.contents
+                },
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let restored = DatabasePeer::get(&server.db().lock(), test::USER1_PEER_ID)?;
+        assert!(!restored.is_disabled);
+        assert_eq!(restored.deleted_at, None);
+        assert!(list("").await?.iter().any(|p| p.id == test::USER1_PEER_ID));
+
+        let res = server
+            .request(
+                test::ADMIN_PEER_IP,
+                "GET",
+                "/v1/admin/peers?include_deleted=yes",
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,373 @@
+//! Reconciling the network with a declarative [`NetworkSpec`], in a single transaction.
+
+use std::{collections::VecDeque, net::IpAddr};
+
+use crate::{
+    db::{DatabaseAssociation, DatabaseCidr, DatabasePeer},
+    util::{form_body_with_limit, json_response},
+    ServerError, Session,
+};
+use hyper::{Body, Method, Request, Response};
+use rusqlite::Connection;
+use shared::{
+    spec::{NetworkSpec, SpecChange},
+    AssociationContents, CidrContents, PeerContents,
+};
+
+/// Specs describe every peer, so they can be much larger than other requests.
+const MAX_SPEC_LEN: usize = 1024 * 1024;
+
+pub async fn routes(
+    req: Request<Body>,
+    mut components: VecDeque<String>,
+    session: Session,
+) -> Result<Response<Body>, ServerError> {
+    match (req.method(), components.pop_front().as_deref()) {
+        (&Method::POST, None) => {
+            let form = form_body_with_limit(req, MAX_SPEC_LEN).await?;
+            handlers::apply(form, false, session).await
+        },
+        (&Method::POST, Some("plan")) => {
+            let form = form_body_with_limit(req, MAX_SPEC_LEN).await?;
+            handlers::apply(form, true, session).await
+        },
+        _ => Err(ServerError::NotFound),
+    }
+}
+
+fn current_spec(conn: &Connection, network: &str) -> Result<NetworkSpec, ServerError> {
+    let cidrs = DatabaseCidr::list(conn)?;
+    let peers = DatabasePeer::list(conn)?
+        .into_iter()
+        .map(|peer| peer.inner)
+        .filter(|peer| peer.deleted_at.is_none())
+        .collect::<Vec<_>>();
+    let associations = DatabaseAssociation::list(conn)?;
+    NetworkSpec::from_state(network.to_string(), &cidrs, &peers, &associations).map_err(|e| {
+        log::warn!("can't describe the network as a spec: {}", e);
+        ServerError::InvalidQuery
+    })
+}
+
+fn cidr_id(conn: &Connection, name: &str) -> Result<i64, ServerError> {
+    DatabaseCidr::list(conn)?
+        .into_iter()
+        .find(|cidr| cidr.name == name)
+        .map(|cidr| cidr.id)
+        .ok_or(ServerError::NotFound)
+}
+
+/// Deleted peers are kept around as tombstones, but they're no longer part of the network, so
+/// a spec (ex. one exported before the deletion) must not be able to touch, or revive, them.
+fn live_peer_from_ip(conn: &Connection, ip: IpAddr) -> Result<DatabasePeer, ServerError> {
+    let peer = DatabasePeer::get_from_ip(conn, ip)?;
+    if peer.deleted_at.is_some() {
+        return Err(ServerError::NotFound);
+    }
+    Ok(peer)
+}
+
+/// Refuse to disable or demote the server's own peer or the admin applying the spec, which
+/// would lock everyone out of managing the network.
+fn check_lockout(
+    session: &Session,
+    peer: &DatabasePeer,
+    is_admin: bool,
+    is_disabled: bool,
+) -> Result<(), ServerError> {
+    // The server's peer is the first one `init` creates.
+    let who = if peer.id == 1 {
+        "the server's own peer"
+    } else if peer.id == session.peer.id {
+        "the peer applying the spec"
+    } else {
+        return Ok(());
+    };
+    if is_disabled && !peer.is_disabled {
+        return Err(ServerError::Rejected(format!(
+            "refusing to disable {} ({})",
+            who, peer.name
+        )));
+    }
+    if !is_admin && peer.is_admin {
+        return Err(ServerError::Rejected(format!(
+            "refusing to remove admin from {} ({})",
+            who, peer.name
+        )));
+    }
+    Ok(())
+}
+
+fn apply_change(
+    conn: &Connection,
+    session: &Session,
+    change: &SpecChange,
+) -> Result<(), ServerError> {
+    match change {
+        SpecChange::DeleteAssociation { cidr1, cidr2 } => {
+            let (id1, id2) = (cidr_id(conn, cidr1)?, cidr_id(conn, cidr2)?);
+            let association = DatabaseAssociation::list(conn)?
+                .into_iter()
+                .find(|a| {
+                    (a.cidr_id_1, a.cidr_id_2) == (id1, id2)
+                        || (a.cidr_id_1, a.cidr_id_2) == (id2, id1)
+                })
+                .ok_or(ServerError::NotFound)?;
+            DatabaseAssociation::delete(conn, association.id)
+        },
+        SpecChange::DeleteCidr { name, .. } => DatabaseCidr::delete(conn, cidr_id(conn, name)?),
+        SpecChange::CreateCidr { name, cidr, parent } => {
+            let parent = parent
+                .as_deref()
+                .map(|parent| cidr_id(conn, parent))
+                .transpose()?;
+            DatabaseCidr::create(
+                conn,
+                CidrContents {
+                    name: name.clone(),
+                    cidr: *cidr,
+                    parent,
+                },
+            )
+            .map(|_| ())
+        },
+        SpecChange::CreateAssociation { cidr1, cidr2 } => DatabaseAssociation::create(
+            conn,
+            AssociationContents {
+                cidr_id_1: cidr_id(conn, cidr1)?,
+                cidr_id_2: cidr_id(conn, cidr2)?,
+            },
+        )
+        .map(|_| ()),
+        SpecChange::UpdatePeer {
+            name,
+            ip,
+            is_admin,
+            is_disabled,
+        } => {
+            let mut peer = live_peer_from_ip(conn, *ip)?;
+            check_lockout(session, &peer, *is_admin, *is_disabled)?;
+            let contents = PeerContents {
+                name: name.clone(),
+                is_admin: *is_admin,
+                is_disabled: *is_disabled,
+                ..peer.contents.clone()
+            };
+            peer.update(conn, contents)
+        },
+        SpecChange::DisablePeer { ip, .. } => {
+            let peer = live_peer_from_ip(conn, *ip)?;
+            check_lockout(session, &peer, peer.is_admin, true)?;
+            DatabasePeer::disable(conn, peer.id)
+        },
+    }
+}
+
+mod handlers {
+    use super::*;
+
+    /// Apply the changes needed to match `spec` and return them. With `dry_run`, the
+    /// changes are still made (so they're fully validated) but then rolled back.
+    pub async fn apply(
+        spec: NetworkSpec,
+        dry_run: bool,
+        session: Session,
+    ) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        let tx = conn.unchecked_transaction()?;
+
+        let current = current_spec(&tx, &spec.network)?;
+        let changes = spec.changes_from(&current).map_err(|e| {
+            log::warn!("rejected network spec: {}", e);
+            ServerError::InvalidQuery
+        })?;
+        for change in &changes {
+            apply_change(&tx, &session, change).map_err(|e| {
+                log::warn!("network spec change \"{}\" failed: {}", change, e);
+                e
+            })?;
+        }
+
+        if dry_run {
+            tx.rollback()?;
+        } else {
+            tx.commit()?;
+            for change in &changes {
+                log::info!("applied network spec change: {}", change);
+            }
+        }
+        json_response(&changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use bytes::Buf;
+    use hyper::StatusCode;
+    use shared::{spec::CidrSpec, Error};
+
+    fn spec(server: &test::Server) -> Result<NetworkSpec, Error> {
+        Ok(current_spec(&server.db().lock(), "test")?)
+    }
+
+    async fn changes(res: Response<Body>) -> Result<Vec<SpecChange>, Error> {
+        let whole_body = hyper::body::aggregate(res).await?;
+        Ok(serde_json::from_reader(whole_body.reader())?)
+    }
+
+    #[tokio::test]
+    async fn test_plan_and_apply_spec() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let current = spec(&server)?;
+        let root = current
+            .cidrs
+            .iter()
+            .find(|cidr| cidr.parent.is_none())
+            .unwrap()
+            .name
+            .clone();
+
+        let mut desired = current.clone();
+        desired.cidrs.push(CidrSpec {
+            name: "lab".into(),
+            cidr: if cfg!(feature = "v6-test") {
+                "fd00:1337::5:0:0:0/80"
+            } else {
+                "10.80.2.0/24"
+            }
+            .parse()?,
+            parent: Some(root),
+        });
+        let user2_ip: IpAddr = test::USER2_PEER_IP.parse()?;
+        desired.peers.retain(|peer| peer.ip != user2_ip);
+
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/spec/plan", &desired)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let planned = changes(res).await?;
+        assert_eq!(planned.len(), 2);
+        assert_eq!(spec(&server)?, current, "a plan shouldn't change anything");
+
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/spec", &desired)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(changes(res).await?, planned);
+
+        let applied = spec(&server)?;
+        assert!(applied.cidrs.iter().any(|cidr| cidr.name == "lab"));
+        assert!(applied
+            .peers
+            .iter()
+            .any(|peer| peer.ip == user2_ip && peer.is_disabled));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spec_cant_lock_out_the_network() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let current = spec(&server)?;
+        let admin_ip: IpAddr = test::ADMIN_PEER_IP.parse()?;
+        let server_ip = DatabasePeer::get(&server.db().lock(), 1)?.ip;
+
+        let lockouts: [fn(&mut NetworkSpec, IpAddr, IpAddr); 4] = [
+            |spec, server_ip, _| spec.peers.retain(|peer| peer.ip != server_ip),
+            |spec, _, admin_ip| spec.peers.retain(|peer| peer.ip != admin_ip),
+            |spec, _, admin_ip| {
+                for peer in &mut spec.peers {
+                    if peer.ip == admin_ip {
+                        peer.is_admin = false;
+                    }
+                }
+            },
+            |spec, server_ip, _| {
+                for peer in &mut spec.peers {
+                    if peer.ip == server_ip {
+                        peer.is_disabled = true;
+                    }
+                }
+            },
+        ];
+        for lockout in lockouts {
+            let mut desired = current.clone();
+            lockout(&mut desired, server_ip, admin_ip);
+            let res = server
+                .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/spec", &desired)
+                .await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(spec(&server)?, current);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_spec_rolls_back() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let current = spec(&server)?;
+
+        // Deleting a CIDR that still has (now disabled) peers fails partway through.
+        let developer = DatabaseCidr::get(&server.db().lock(), test::DEVELOPER_CIDR_ID)?
+            .name
+            .clone();
+        let mut desired = current.clone();
+        desired.cidrs.retain(|cidr| cidr.name != developer);
+        desired
+            .associations
+            .retain(|a| a.cidr1 != developer && a.cidr2 != developer);
+        desired.peers.retain(|peer| peer.cidr != developer);
+
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/spec", &desired)
+            .await;
+        assert!(!res.status().is_success());
+        assert_eq!(spec(&server)?, current);
+
+        // Specs that can't be planned at all are rejected up-front.
+        let mut desired = current;
+        desired.peers[0].cidr = developer;
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/spec/plan", &desired)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spec_doesnt_revive_deleted_peers() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let exported = spec(&server)?;
+        let user2_ip: IpAddr = test::USER2_PEER_IP.parse()?;
+        DatabasePeer::delete(&server.db().lock(), test::USER2_PEER_ID)?;
+
+        let current = spec(&server)?;
+        assert!(current.peers.iter().all(|peer| peer.ip != user2_ip));
+
+        // The spec exported before the deletion still lists the peer as enabled.
+        let res = server
+            .form_request(test::ADMIN_PEER_IP, "POST", "/v1/admin/spec", &exported)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(spec(&server)?, current);
+
+        // A change touching a tombstone directly is refused as well.
+        let session = Session {
+            context: server.context(),
+            peer: DatabasePeer::get(&server.db().lock(), test::ADMIN_PEER_ID)?,
+        };
+        let change = SpecChange::UpdatePeer {
+            name: "user2".parse().unwrap(),
+            ip: user2_ip,
+            is_admin: false,
+            is_disabled: false,
+        };
+        let res = apply_change(&server.db().lock(), &session, &change);
+        assert!(matches!(res, Err(ServerError::NotFound)));
+
+        let peer = DatabasePeer::get(&server.db().lock(), test::USER2_PEER_ID)?;
+        assert!(peer.deleted_at.is_some() && peer.is_disabled);
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
-use shared::Peer;
+use shared::{Peer, PeerContents};
+use std::{net::SocketAddr, time::SystemTime};
 
 use crate::Session;
 
@@ -8,11 +9,108 @@ pub mod user;
 /// Inject the collected endpoints from the WG interface into a list of peers.
 /// This is essentially what adds NAT holepunching functionality.
 pub fn inject_endpoints(session: &Session, peers: &mut Vec<Peer>) {
-    for mut peer in peers {
+    let now = SystemTime::now();
+    let ttl = session.context.observed_endpoint_ttl;
+    for peer in peers {
         if peer.contents.endpoint.is_none() {
-            if let Some(endpoint) = session.context.endpoints.read().get(&peer.public_key) {
-                peer.contents.endpoint = Some(endpoint.to_owned().into());
+            if let Some(observed) = session.context.endpoints.read().get(&peer.public_key) {
+                if observed.is_fresh(ttl, now) {
+                    peer.contents.endpoint = Some(observed.addr.into());
+                }
             }
         }
     }
 }
+
+/// With `serve --nat-keepalive`, give peers that the server has observed behind NAT a
+/// persistent keepalive if they don't already have one. Other peers keep their own.
+pub fn suggest_keepalives(session: &Session, peers: &mut Vec<Peer>) {
+    if let Some(interval) = session.context.nat_keepalive {
+        let endpoints = session.context.endpoints.read();
+        for peer in peers {
+            if peer.contents.persistent_keepalive_interval.is_some() {
+                continue;
+            }
+            let behind_nat = endpoints
+                .get(&peer.public_key)
+                .is_none_or(|observed| is_behind_nat(&peer.contents, &observed.addr));
+            if behind_nat {
+                peer.contents.persistent_keepalive_interval = Some(interval);
+            }
+        }
+    }
+}
+
+/// A peer is behind NAT if the server sees its traffic coming from a port that it didn't
+/// declare in its endpoint or candidates. Peers that declared nothing are assumed to be.
+fn is_behind_nat(peer: &PeerContents, observed: &SocketAddr) -> bool {
+    !peer
+        .endpoint
+        .iter()
+        .chain(&peer.candidates)
+        .any(|declared| declared.port() == observed.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::DatabasePeer, test, ObservedEndpoint};
+
+    #[test]
+    fn test_is_behind_nat() {
+        let mut peer = test::user_peer_contents("peer", test::USER1_PEER_IP).unwrap();
+        let observed: SocketAddr = "1.2.3.4:51820".parse().unwrap();
+        assert!(is_behind_nat(&peer, &observed));
+
+        peer.candidates = vec!["192.168.1.5:51820".parse().unwrap()];
+        assert!(!is_behind_nat(&peer, &observed));
+        assert!(is_behind_nat(&peer, &"1.2.3.4:40123".parse().unwrap()));
+
+        peer.candidates.clear();
+        peer.endpoint = Some("1.2.3.4:40123".parse().unwrap());
+        assert!(!is_behind_nat(&peer, &"1.2.3.4:40123".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_suggest_keepalives() -> anyhow::Result<()> {
+        let server = test::Server::new()?;
+        let mut context = server.context();
+        context.nat_keepalive = Some(25);
+        let session = Session {
+            peer: DatabasePeer::get(&server.db().lock(), test::ADMIN_PEER_ID)?,
+            context,
+        };
+        let observed = |addr: &str| ObservedEndpoint {
+            addr: addr.parse().unwrap(),
+            seen_at: SystemTime::now(),
+        };
+
+        let peer = |name: &str, ip: &str, keepalive: Option<u16>| {
+            let mut contents = test::user_peer_contents(name, ip).unwrap();
+            contents.endpoint = Some("1.2.3.4:51820".parse().unwrap());
+            contents.persistent_keepalive_interval = keepalive;
+            Peer { id: 0, contents }
+        };
+        let mut peers = vec![
+            peer("natted", test::USER1_PEER_IP, None),
+            peer("natted-custom", test::USER2_PEER_IP, Some(10)),
+            peer("direct", test::DEVELOPER1_PEER_IP, Some(15)),
+            peer("direct-none", test::DEVELOPER2_PEER_IP, None),
+        ];
+        {
+            let mut endpoints = session.context.endpoints.write();
+            endpoints.insert(peers[0].public_key.clone(), observed("1.2.3.4:40123"));
+            endpoints.insert(peers[1].public_key.clone(), observed("1.2.3.4:40124"));
+            endpoints.insert(peers[2].public_key.clone(), observed("1.2.3.4:51820"));
+            endpoints.insert(peers[3].public_key.clone(), observed("1.2.3.4:51820"));
+        }
+
+        suggest_keepalives(&session, &mut peers);
+        let keepalives: Vec<_> = peers
+            .iter()
+            .map(|peer| peer.contents.persistent_keepalive_interval)
+            .collect();
+        assert_eq!(keepalives, vec![Some(25), Some(10), Some(15), None]);
+        Ok(())
+    }
+}
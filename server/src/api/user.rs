@@ -1,13 +1,22 @@
-use std::collections::VecDeque;
+use std::{
+    collections::{HashSet, VecDeque},
+    time::SystemTime,
+};
 
 use crate::{
-    api::inject_endpoints,
-    db::{DatabaseCidr, DatabasePeer},
+    api::{inject_endpoints, suggest_keepalives},
+    db::{
+        DatabaseCidr, DatabaseFeatureFlags, DatabaseHandshakeReport, DatabaseMtuHint, DatabasePeer,
+        DatabasePeerMetadata,
+    },
     util::{form_body, json_response, status_response},
-    Context, ServerError, Session,
+    Context, ServerError, Session, VERSION,
 };
 use hyper::{Body, Method, Request, Response, StatusCode};
-use shared::{EndpointContents, PeerContents, RedeemContents, State, REDEEM_TRANSITION_WAIT};
+use shared::{
+    ApiVersion, EndpointContents, PeerContents, RedeemContents, State, API_VERSION_MAX,
+    API_VERSION_MIN, REDEEM_TRANSITION_WAIT,
+};
 use wireguard_control::{DeviceUpdate, PeerConfigBuilder};
 
 pub async fn routes(
@@ -16,12 +25,26 @@ pub async fn routes(
     session: Session,
 ) -> Result<Response<Body>, ServerError> {
     match (req.method(), components.pop_front().as_deref()) {
+        // Any peer with a session may ask, including not-yet-redeemed invitees.
+        (&Method::GET, Some("version")) => handlers::version().await,
         (&Method::GET, Some("state")) => {
             if !session.user_capable() {
                 return Err(ServerError::Unauthorized);
             }
             handlers::state(session).await
         },
+        (&Method::GET, Some("features")) => {
+            if !session.user_capable() {
+                return Err(ServerError::Unauthorized);
+            }
+            handlers::features(session).await
+        },
+        (&Method::GET, Some("mtu-hints")) => {
+            if !session.user_capable() {
+                return Err(ServerError::Unauthorized);
+            }
+            handlers::mtu_hints(session).await
+        },
         (&Method::POST, Some("redeem")) => {
             if !session.redeemable() {
                 return Err(ServerError::Unauthorized);
@@ -43,12 +66,26 @@ pub async fn routes(
             let form = form_body(req).await?;
             handlers::candidates(form, session).await
         },
+        (&Method::PUT, Some("metadata")) => {
+            if !session.user_capable() {
+                return Err(ServerError::Unauthorized);
+            }
+            let form = form_body(req).await?;
+            handlers::metadata(form, session).await
+        },
+        (&Method::PUT, Some("handshakes")) => {
+            if !session.user_capable() {
+                return Err(ServerError::Unauthorized);
+            }
+            let form = form_body(req).await?;
+            handlers::handshakes(form, session).await
+        },
         _ => Err(ServerError::NotFound),
     }
 }
 
 mod handlers {
-    use shared::Endpoint;
+    use shared::{Endpoint, HandshakeReport, PeerMetadata, MAX_HANDSHAKE_REPORT_BATCH};
 
     use super::*;
 
@@ -68,9 +105,20 @@ mod handlers {
             .map(|p| p.inner)
             .collect();
         inject_endpoints(&session, &mut peers);
+        suggest_keepalives(&session, &mut peers);
         json_response(State { peers, cidrs })
     }
 
+    /// Report the range of API versions this server supports, so clients can refuse to
+    /// enroll against an incompatible server up front.
+    pub async fn version() -> Result<Response<Body>, ServerError> {
+        json_response(ApiVersion {
+            min: API_VERSION_MIN,
+            max: API_VERSION_MAX,
+            server_version: VERSION.to_string(),
+        })
+    }
+
     /// Redeems an invitation. An invitation includes a WireGuard keypair generated by either the server
     /// or a peer with admin rights.
     ///
@@ -129,12 +177,21 @@ mod handlers {
     /// Report any other endpoint candidates that can be tried by peers to connect.
     /// Currently limited to 10 candidates max.
     pub async fn candidates(
-        contents: Vec<Endpoint>,
+        mut contents: Vec<Endpoint>,
         session: Session,
     ) -> Result<Response<Body>, ServerError> {
         if contents.len() > 10 {
             return status_response(StatusCode::PAYLOAD_TOO_LARGE);
         }
+        let ports = &session.context.allowed_endpoint_ports;
+        for candidate in ports.retain_allowed(&mut contents) {
+            log::warn!(
+                "dropping candidate {} reported by peer {}: port isn't allowed ({}).",
+                candidate,
+                &*session.peer,
+                ports
+            );
+        }
         let conn = session.context.db.lock();
         let mut selected_peer = DatabasePeer::get(&conn, session.peer.id)?;
         selected_peer.update(
@@ -144,21 +201,95 @@ mod handlers {
                 ..selected_peer.contents.clone()
             },
         )?;
+        DatabasePeer::touch_candidates(&conn, session.peer.id, SystemTime::now())?;
 
         status_response(StatusCode::NO_CONTENT)
     }
 
+    /// Report optional information about the client, for the admins' inventory.
+    pub async fn metadata(
+        contents: PeerMetadata,
+        session: Session,
+    ) -> Result<Response<Body>, ServerError> {
+        if !contents.is_valid() {
+            return status_response(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        let conn = session.context.db.lock();
+        DatabasePeerMetadata::set(&conn, session.peer.id, &contents)?;
+
+        status_response(StatusCode::NO_CONTENT)
+    }
+
+    /// Report the latest handshakes this peer has had with other peers. Reports about peers
+    /// it isn't allowed to connect to are ignored.
+    pub async fn handshakes(
+        contents: Vec<HandshakeReport>,
+        session: Session,
+    ) -> Result<Response<Body>, ServerError> {
+        if contents.len() > MAX_HANDSHAKE_REPORT_BATCH {
+            return status_response(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        let conn = session.context.db.lock();
+        let selected_peer = DatabasePeer::get(&conn, session.peer.id)?;
+        let allowed: HashSet<i64> = selected_peer
+            .get_all_allowed_peers(&conn)?
+            .iter()
+            .map(|peer| peer.id)
+            .collect();
+        let reports: Vec<_> = contents
+            .into_iter()
+            .filter(|report| allowed.contains(&report.peer_id) && report.peer_id != session.peer.id)
+            .collect();
+        DatabaseHandshakeReport::upsert(&conn, session.peer.id, &reports)?;
+
+        status_response(StatusCode::NO_CONTENT)
+    }
+
+    /// The network's feature flags, for clients to adjust their behavior by.
+    pub async fn features(session: Session) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        json_response(DatabaseFeatureFlags::get(&conn)?)
+    }
+
+    /// The MTU hints of the current peer and every peer it can see.
+    pub async fn mtu_hints(session: Session) -> Result<Response<Body>, ServerError> {
+        let conn = session.context.db.lock();
+        let selected_peer = DatabasePeer::get(&conn, session.peer.id)?;
+        let visible: HashSet<i64> = selected_peer
+            .get_all_allowed_peers(&conn)?
+            .iter()
+            .map(|peer| peer.id)
+            .chain([session.peer.id])
+            .collect();
+        let hints: Vec<_> = DatabaseMtuHint::list(&conn)?
+            .into_iter()
+            .filter(|hint| visible.contains(&hint.peer_id))
+            .collect();
+
+        json_response(hints)
+    }
+
     /// Force a specific endpoint to be reported by the server.
     pub async fn endpoint(
         contents: EndpointContents,
         session: Session,
     ) -> Result<Response<Body>, ServerError> {
+        let endpoint: Option<Endpoint> = contents.into();
+        let ports = &session.context.allowed_endpoint_ports;
+        if let Some(endpoint) = &endpoint {
+            if !ports.allows(endpoint.port()) {
+                return Err(ServerError::Rejected(format!(
+                    "endpoint {} isn't allowed, its port must be in {}",
+                    endpoint, ports
+                )));
+            }
+        }
         let conn = session.context.db.lock();
         let mut selected_peer = DatabasePeer::get(&conn, session.peer.id)?;
         selected_peer.update(
             &conn,
             PeerContents {
-                endpoint: contents.into(),
+                endpoint,
                 ..selected_peer.contents.clone()
             },
         )?;
@@ -174,7 +305,10 @@ mod tests {
     use super::*;
     use crate::{db::DatabaseAssociation, test};
     use bytes::Buf;
-    use shared::{AssociationContents, CidrContents, Endpoint, EndpointContents, Error};
+    use shared::{
+        AssociationContents, CidrContents, Endpoint, EndpointContents, EndpointPortPolicy, Error,
+        HandshakeReport, MtuHint, PeerMetadata, ReportedMetadata, MAX_HANDSHAKE_REPORT_BATCH,
+    };
 
     #[tokio::test]
     async fn test_get_state_from_developer1() -> Result<(), Error> {
@@ -198,6 +332,22 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_version() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let res = server
+            .request(test::USER1_PEER_IP, "GET", "/v1/user/version")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let whole_body = hyper::body::aggregate(res).await?;
+        let version: ApiVersion = serde_json::from_reader(whole_body.reader())?;
+        assert!(version.is_compatible_with(API_VERSION_MIN, API_VERSION_MAX));
+        assert_eq!(version.server_version, VERSION);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_override_endpoint() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -244,6 +394,36 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_override_endpoint_port_policy() -> Result<(), Error> {
+        let mut server = test::Server::new()?;
+        server.set_allowed_endpoint_ports(EndpointPortPolicy(vec!["51820-51830".parse().unwrap()]));
+        let set_endpoint = |endpoint: &str| EndpointContents::Set(endpoint.parse().unwrap());
+
+        let res = server
+            .form_request(
+                test::DEVELOPER1_PEER_IP,
+                "PUT",
+                "/v1/user/endpoint",
+                &set_endpoint("1.1.1.1:22"),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let peer = DatabasePeer::get(&server.db().lock(), test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(peer.endpoint, None);
+
+        let res = server
+            .form_request(
+                test::DEVELOPER1_PEER_IP,
+                "PUT",
+                "/v1/user/endpoint",
+                &set_endpoint("1.1.1.1:51825"),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_list_peers_from_unknown_ip() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -433,6 +613,67 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_redeem_failure_keeps_invite() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let experimental_cidr = DatabaseCidr::create(
+            &server.db().lock(),
+            CidrContents {
+                name: "experimental".to_string(),
+                cidr: test::EXPERIMENTAL_CIDR.parse()?,
+                parent: Some(test::ROOT_CIDR_ID),
+            },
+        )?;
+
+        let mut peer_contents = test::peer_contents(
+            "experiment-peer",
+            test::EXPERIMENT_SUBCIDR_PEER_IP,
+            experimental_cidr.id,
+            false,
+        )?;
+        peer_contents.is_redeemed = false;
+        peer_contents.invite_expires = Some(SystemTime::now() + Duration::from_secs(100));
+        let experiment_peer = DatabasePeer::create(&server.db().lock(), peer_contents)?;
+
+        // Break the tail end of redemption so that it fails after marking the peer redeemed.
+        server
+            .db()
+            .lock()
+            .execute("ALTER TABLE events RENAME TO broken_events", [])?;
+        let body = RedeemContents {
+            public_key: "YBVIgpfLbi/knrMCTEb0L6eVy0daiZnJJQkxBK9s+2I=".into(),
+        };
+        let res = server
+            .form_request(
+                test::EXPERIMENT_SUBCIDR_PEER_IP,
+                "POST",
+                "/v1/user/redeem",
+                &body,
+            )
+            .await;
+        assert!(res.status().is_server_error());
+        let peer = DatabasePeer::get(&server.db().lock(), experiment_peer.id)?;
+        assert!(!peer.is_redeemed);
+        assert_eq!(peer.public_key, experiment_peer.public_key);
+
+        // Once the server recovers, the same invitation can still be redeemed.
+        server
+            .db()
+            .lock()
+            .execute("ALTER TABLE broken_events RENAME TO events", [])?;
+        let res = server
+            .form_request(
+                test::EXPERIMENT_SUBCIDR_PEER_IP,
+                "POST",
+                "/v1/user/redeem",
+                &body,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_candidates() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -464,4 +705,165 @@ mod tests {
         assert_eq!(peer.candidates, candidates);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_candidates_expire() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let candidates = vec!["1.1.1.1:51820".parse::<Endpoint>().unwrap()];
+        let res = server
+            .form_request(
+                test::DEVELOPER1_PEER_IP,
+                "PUT",
+                "/v1/user/candidates",
+                &candidates,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        // Candidates an admin set weren't learned, and stay.
+        let db = server.db();
+        let conn = db.lock();
+        let mut static_peer = DatabasePeer::get(&conn, test::DEVELOPER2_PEER_ID)?;
+        let contents = PeerContents {
+            candidates: candidates.clone(),
+            ..static_peer.contents.clone()
+        };
+        static_peer.update(&conn, contents)?;
+
+        let ttl = Duration::from_secs(60);
+        let now = SystemTime::now();
+        assert_eq!(DatabasePeer::expire_candidates(&conn, ttl, now)?, 0);
+        let later = now + ttl + Duration::from_secs(1);
+        assert_eq!(DatabasePeer::expire_candidates(&conn, ttl, later)?, 1);
+
+        let peer = DatabasePeer::get(&conn, test::DEVELOPER1_PEER_ID)?;
+        assert_eq!(peer.candidates, vec![]);
+        let static_peer = DatabasePeer::get(&conn, test::DEVELOPER2_PEER_ID)?;
+        assert_eq!(static_peer.candidates, candidates);
+        // Nothing is left to expire until the peer reports again.
+        assert_eq!(DatabasePeer::expire_candidates(&conn, ttl, later)?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_metadata() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let metadata = PeerMetadata {
+            os: Some("linux".into()),
+            version: Some(VERSION.into()),
+            hostname: Some("devbox".into()),
+            backend: Some("kernel".into()),
+        };
+        let res = server
+            .form_request(
+                test::DEVELOPER1_PEER_IP,
+                "PUT",
+                "/v1/user/metadata",
+                &metadata,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let oversized = PeerMetadata {
+            hostname: Some("x".repeat(PeerMetadata::MAX_FIELD_LEN + 1)),
+            ..Default::default()
+        };
+        let res = server
+            .form_request(
+                test::DEVELOPER1_PEER_IP,
+                "PUT",
+                "/v1/user/metadata",
+                &oversized,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Only admins get to see the inventory.
+        let res = server
+            .request(test::DEVELOPER1_PEER_IP, "GET", "/v1/admin/peers/metadata")
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = server
+            .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/peers/metadata")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let whole_body = hyper::body::aggregate(res).await?;
+        let listed: Vec<ReportedMetadata> = serde_json::from_reader(whole_body.reader())?;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].peer_id, test::DEVELOPER1_PEER_ID);
+        assert_eq!(listed[0].metadata, metadata);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handshake_reports() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let report = |peer_id| HandshakeReport {
+            peer_id,
+            last_handshake: 1_600_000_000,
+        };
+
+        // developer1 can't see user1, so only the report about developer2 is kept.
+        let batch = vec![
+            report(test::DEVELOPER2_PEER_ID),
+            report(test::USER1_PEER_ID),
+        ];
+        for _ in 0..2 {
+            let res = server
+                .form_request(
+                    test::DEVELOPER1_PEER_IP,
+                    "PUT",
+                    "/v1/user/handshakes",
+                    &batch,
+                )
+                .await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        }
+        let latest = DatabaseHandshakeReport::latest(&server.db().lock())?;
+        assert_eq!(
+            latest.keys().collect::<Vec<_>>(),
+            vec![&test::DEVELOPER2_PEER_ID]
+        );
+
+        let oversized = vec![report(test::DEVELOPER2_PEER_ID); MAX_HANDSHAKE_REPORT_BATCH + 1];
+        let res = server
+            .form_request(
+                test::DEVELOPER1_PEER_IP,
+                "PUT",
+                "/v1/user/handshakes",
+                &oversized,
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mtu_hints_visibility() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        {
+            let db = server.db();
+            let conn = db.lock();
+            DatabaseMtuHint::set(&conn, test::DEVELOPER2_PEER_ID, Some(1400))?;
+            DatabaseMtuHint::set(&conn, test::USER1_PEER_ID, Some(1300))?;
+        }
+
+        // developer1 can't see user1, so only developer2's hint is returned.
+        let res = server
+            .request(test::DEVELOPER1_PEER_IP, "GET", "/v1/user/mtu-hints")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let whole_body = hyper::body::aggregate(res).await?;
+        let hints: Vec<MtuHint> = serde_json::from_reader(whole_body.reader())?;
+        assert_eq!(
+            hints,
+            vec![MtuHint {
+                peer_id: test::DEVELOPER2_PEER_ID,
+                mtu: 1400
+            }]
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,284 @@
+//! Serving the API over keep-alive connections, closing ones that sit idle and limiting how
+//! many are open at once so a crowd of clients can't exhaust the server's file descriptors.
+
+use clap::Args;
+use hyper::{server::conn::Http, service::service_fn, Body, Request, Response};
+use parking_lot::Mutex;
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+};
+
+#[derive(Debug, Clone, Args)]
+pub struct ConnectionOpts {
+    /// Close API connections that haven't sent or received anything in this many seconds.
+    /// Clients reuse their connection for requests made within that time
+    #[clap(long, value_name = "SECS", default_value = "60")]
+    pub connection_idle_timeout: u64,
+
+    /// The most API connections to have open at once. Further clients wait until one closes
+    #[clap(long, value_name = "COUNT", default_value = "1024")]
+    pub max_connections: usize,
+}
+
+impl ConnectionOpts {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.connection_idle_timeout)
+    }
+}
+
+/// A connection that remembers when it last transferred anything.
+struct TrackedStream {
+    inner: TcpStream,
+    last_active: Arc<Mutex<Instant>>,
+}
+
+impl TrackedStream {
+    fn touch<T>(&self, poll: &Poll<io::Result<T>>) {
+        if let Poll::Ready(Ok(_)) = poll {
+            *self.last_active.lock() = Instant::now();
+        }
+    }
+}
+
+impl AsyncRead for TrackedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.touch(&poll);
+        poll
+    }
+}
+
+impl AsyncWrite for TrackedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.touch(&poll);
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// How long to wait before accepting again when the server's out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Whether an accept error means the listener itself is broken, rather than one connection
+/// failing or the server being temporarily out of resources.
+fn is_fatal_accept_error(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EBADF | libc::EINVAL | libc::ENOTSOCK | libc::EOPNOTSUPP | libc::EFAULT)
+    )
+}
+
+fn is_out_of_resources(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    )
+}
+
+/// Serve every connection accepted on `listener` with the service `handler` makes for the
+/// connection's remote address. Only a broken listener stops the server; other accept
+/// errors are logged and accepting carries on.
+pub async fn serve<H, F>(listener: TcpListener, opts: ConnectionOpts, handler: H) -> io::Result<()>
+where
+    H: Fn(Request<Body>, SocketAddr) -> F + Clone + Send + 'static,
+    F: Future<Output = Result<Response<Body>, hyper::http::Error>> + Send + 'static,
+{
+    let idle_timeout = opts.idle_timeout();
+    let limit = Arc::new(Semaphore::new(opts.max_connections.max(1)));
+    loop {
+        let permit = limit
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("connection limit is never closed");
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) if is_fatal_accept_error(&e) => return Err(e),
+            Err(e) => {
+                log::warn!("failed to accept a connection: {}", e);
+                if is_out_of_resources(&e) {
+                    // Give open connections a chance to close before trying again.
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                }
+                continue;
+            },
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let last_active = Arc::new(Mutex::new(Instant::now()));
+            let stream = TrackedStream {
+                inner: stream,
+                last_active: last_active.clone(),
+            };
+            // Requests whose responses are still being worked out, which leaves the
+            // connection quiet without it being idle.
+            let in_flight = Arc::new(AtomicUsize::new(0));
+            let service = {
+                let in_flight = in_flight.clone();
+                service_fn(move |req| {
+                    in_flight.fetch_add(1, Ordering::SeqCst);
+                    let response = handler(req, remote_addr);
+                    let in_flight = in_flight.clone();
+                    async move {
+                        let response = response.await;
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                        response
+                    }
+                })
+            };
+            let connection = Http::new()
+                .http1_only(true)
+                .http1_keep_alive(true)
+                .serve_connection(stream, service);
+            tokio::pin!(connection);
+
+            let mut idle_check =
+                tokio::time::interval((idle_timeout / 4).max(Duration::from_millis(10)));
+            loop {
+                tokio::select! {
+                    result = connection.as_mut() => {
+                        if let Err(e) = result {
+                            log::debug!("connection from {} failed: {}", remote_addr, e);
+                        }
+                        break;
+                    },
+                    _ = idle_check.tick() => {
+                        let is_idle = last_active.lock().elapsed() >= idle_timeout
+                            && in_flight.load(Ordering::SeqCst) == 0;
+                        if is_idle {
+                            log::debug!("closing idle connection from {}.", remote_addr);
+                            break;
+                        }
+                    },
+                }
+            }
+            drop(permit);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use anyhow::Result;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    /// Read one response off `reader`, returning its status code.
+    async fn read_response<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Result<u16> {
+        let mut status = String::new();
+        reader.read_line(&mut status).await?;
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await?;
+            let line = line.trim_end().to_ascii_lowercase();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(length) = line.strip_prefix("content-length:") {
+                content_length = length.trim().parse()?;
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).await?;
+        Ok(status.split(' ').nth(1).unwrap_or_default().parse()?)
+    }
+
+    #[test]
+    fn test_accept_errors() {
+        let error = io::Error::from_raw_os_error;
+        for transient in [libc::ECONNABORTED, libc::EMFILE, libc::ENFILE, libc::EPROTO] {
+            assert!(!is_fatal_accept_error(&error(transient)), "{}", transient);
+        }
+        assert!(is_out_of_resources(&error(libc::EMFILE)));
+        assert!(!is_out_of_resources(&error(libc::ECONNABORTED)));
+        assert!(is_fatal_accept_error(&error(libc::EBADF)));
+        assert!(!is_fatal_accept_error(&io::Error::other("no errno")));
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive() -> Result<()> {
+        let server = test::Server::new()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let context = server.context();
+        let opts = ConnectionOpts {
+            connection_idle_timeout: 60,
+            max_connections: 1,
+        };
+        // Every connection comes from localhost here, so pretend it's the admin peer's.
+        let admin_addr = SocketAddr::new(test::ADMIN_PEER_IP.parse()?, 54321);
+        tokio::spawn(serve(listener, opts, move |req, _| {
+            crate::hyper_service(req, context.clone(), admin_addr)
+        }));
+
+        let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+        for path in ["/v1/admin/peers", "/v1/user/state"] {
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\n{}: {}\r\n\r\n",
+                path,
+                addr,
+                shared::INNERNET_PUBKEY_HEADER,
+                server.public_key().to_base64()
+            );
+            stream.get_mut().write_all(request.as_bytes()).await?;
+            assert_eq!(read_response(&mut stream).await?, 200, "{}", path);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let opts = ConnectionOpts {
+            connection_idle_timeout: 1,
+            max_connections: 1,
+        };
+        tokio::spawn(serve(listener, opts, |_, _| async {
+            Response::builder().body(Body::empty())
+        }));
+
+        // The server hangs up on the idle connection, which frees up the only slot.
+        let mut idle = TcpStream::connect(addr).await?;
+        assert_eq!(idle.read(&mut [0; 1]).await?, 0);
+        let mut stream = BufReader::new(TcpStream::connect(addr).await?);
+        stream
+            .get_mut()
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        assert_eq!(read_response(&mut stream).await?, 200);
+        Ok(())
+    }
+}
@@ -2,9 +2,10 @@
 //!
 //! A peer belongs to one parent CIDR, and can by default see all peers within that parent.
 
+use super::{atomically, DatabaseEvent};
 use crate::ServerError;
 use rusqlite::{params, Connection};
-use shared::{Association, AssociationContents};
+use shared::{Association, AssociationContents, EventKind};
 use std::ops::{Deref, DerefMut};
 
 pub static CREATE_TABLE_SQL: &str = "CREATE TABLE associations (
@@ -81,18 +82,34 @@ impl DatabaseAssociation {
             return Err(ServerError::InvalidQuery);
         }
 
-        conn.execute(
-            "INSERT INTO associations (cidr_id_1, cidr_id_2)
-              VALUES (?1, ?2)",
-            params![cidr_id_1, cidr_id_2],
-        )?;
-        let id = conn.last_insert_rowid();
-        Ok(Association { id, contents })
+        atomically(conn, |conn| {
+            conn.execute(
+                "INSERT INTO associations (cidr_id_1, cidr_id_2)
+                  VALUES (?1, ?2)",
+                params![cidr_id_1, cidr_id_2],
+            )?;
+            let id = conn.last_insert_rowid();
+            let association = Association {
+                id,
+                contents: contents.clone(),
+            };
+            DatabaseEvent::record(
+                conn,
+                EventKind::AssociationCreated {
+                    association: association.clone(),
+                },
+            )?;
+            Ok(association)
+        })
     }
 
     pub fn delete(conn: &Connection, id: i64) -> Result<(), ServerError> {
-        conn.execute("DELETE FROM associations WHERE id = ?1", params![id])?;
-        Ok(())
+        atomically(conn, |conn| {
+            if conn.execute("DELETE FROM associations WHERE id = ?1", params![id])? > 0 {
+                DatabaseEvent::record(conn, EventKind::AssociationDeleted { association_id: id })?;
+            }
+            Ok(())
+        })
     }
 
     pub fn list(conn: &Connection) -> Result<Vec<Association>, ServerError> {
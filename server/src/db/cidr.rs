@@ -1,8 +1,9 @@
+use super::{atomically, DatabaseEvent, DatabasePeer};
 use crate::ServerError;
 use ipnet::IpNet;
 use rusqlite::{params, Connection};
-use shared::{Cidr, CidrContents};
-use std::ops::Deref;
+use shared::{Cidr, CidrContents, CidrUtilization, EventKind, Hostname};
+use std::{net::IpAddr, ops::Deref};
 
 pub static CREATE_TABLE_SQL: &str = "CREATE TABLE cidrs (
       id               INTEGER PRIMARY KEY,
@@ -51,9 +52,18 @@ impl DatabaseCidr {
             return Err(ServerError::InvalidQuery);
         }
 
-        if let Some(parent_id) = parent {
-            let cidrs = Self::list(conn)?;
+        let cidrs = Self::list(conn)?;
+        if let Some(duplicate) = cidrs
+            .iter()
+            .find(|current| current.cidr.trunc() == cidr.trunc())
+        {
+            return Err(ServerError::Rejected(format!(
+                "{} is already the range of CIDR \"{}\".",
+                cidr, duplicate.name
+            )));
+        }
 
+        if let Some(parent_id) = parent {
             let closest_parent = cidrs
                 .iter()
                 .filter(|current| current.cidr.contains(cidr))
@@ -61,8 +71,10 @@ impl DatabaseCidr {
 
             if let Some(closest_parent) = closest_parent {
                 if closest_parent.id != *parent_id {
-                    log::warn!("tried to add a CIDR at the incorrect place in the tree (should be added to {}).", closest_parent.name);
-                    return Err(ServerError::InvalidQuery);
+                    return Err(ServerError::Rejected(format!(
+                        "{} is inside CIDR \"{}\" ({}), it must be added there instead.",
+                        cidr, closest_parent.name, closest_parent.cidr
+                    )));
                 }
             } else {
                 log::warn!("tried to add a CIDR outside of the root network range.");
@@ -76,39 +88,87 @@ impl DatabaseCidr {
             }
         }
 
-        let overlapping_sibling = Self::list(conn)?
+        let overlapping_sibling = cidrs
             .iter()
             .filter(|current| current.parent == *parent)
-            .map(|sibling| sibling.cidr)
-            .any(|sibling| {
+            .find(|sibling| {
+                let sibling = sibling.cidr;
                 cidr.contains(&sibling.network())
                     || cidr.contains(&sibling.broadcast())
                     || sibling.contains(&cidr.network())
                     || sibling.contains(&cidr.broadcast())
             });
 
-        if overlapping_sibling {
-            log::warn!("tried to add a CIDR that overlaps with a sibling.");
+        if let Some(sibling) = overlapping_sibling {
+            return Err(ServerError::Rejected(format!(
+                "{} overlaps CIDR \"{}\" ({}).",
+                cidr, sibling.name, sibling.cidr
+            )));
+        }
+
+        atomically(conn, |conn| {
+            conn.execute(
+                "INSERT INTO cidrs (name, ip, prefix, parent)
+                  VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    name,
+                    cidr.addr().to_string(),
+                    cidr.prefix_len() as i32,
+                    parent
+                ],
+            )?;
+            let id = conn.last_insert_rowid();
+            let cidr = Cidr {
+                id,
+                contents: contents.clone(),
+            };
+            DatabaseEvent::record(conn, EventKind::CidrCreated { cidr: cidr.clone() })?;
+            Ok(cidr)
+        })
+    }
+
+    /// Rename a CIDR. Its range and parent can't be changed, since its peers and children were
+    /// validated against them.
+    pub fn update(conn: &Connection, id: i64, contents: CidrContents) -> Result<Cidr, ServerError> {
+        let old = Self::get(conn, id)?;
+        if contents.cidr != old.cidr || contents.parent != old.parent {
+            log::warn!("tried to change the range or parent of CIDR {}.", old.name);
+            return Err(ServerError::InvalidQuery);
+        }
+        if !Hostname::is_valid(&contents.name) {
+            log::warn!("CIDR name is invalid, must conform to hostname(7) requirements.");
+            return Err(ServerError::InvalidQuery);
+        }
+        if contents.name == old.name {
+            return Ok(old);
+        }
+        // Names are unique across the whole network, not just among siblings.
+        if Self::list(conn)?
+            .iter()
+            .any(|cidr| cidr.id != id && cidr.name == contents.name)
+        {
+            log::warn!("tried to rename CIDR {} to a name that's taken.", old.name);
             return Err(ServerError::InvalidQuery);
         }
 
-        conn.execute(
-            "INSERT INTO cidrs (name, ip, prefix, parent)
-              VALUES (?1, ?2, ?3, ?4)",
-            params![
-                name,
-                cidr.addr().to_string(),
-                cidr.prefix_len() as i32,
-                parent
-            ],
-        )?;
-        let id = conn.last_insert_rowid();
-        Ok(Cidr { id, contents })
+        atomically(conn, |conn| {
+            conn.execute(
+                "UPDATE cidrs SET name = ?2 WHERE id = ?1",
+                params![id, contents.name],
+            )?;
+            let cidr = Cidr { id, contents };
+            DatabaseEvent::record(conn, EventKind::CidrUpdated { cidr: cidr.clone() })?;
+            Ok(cidr)
+        })
     }
 
     pub fn delete(conn: &Connection, id: i64) -> Result<(), ServerError> {
-        conn.execute("DELETE FROM cidrs WHERE id = ?1", params![id])?;
-        Ok(())
+        atomically(conn, |conn| {
+            if conn.execute("DELETE FROM cidrs WHERE id = ?1", params![id])? > 0 {
+                DatabaseEvent::record(conn, EventKind::CidrDeleted { cidr_id: id })?;
+            }
+            Ok(())
+        })
     }
 
     fn from_row(row: &rusqlite::Row) -> Result<Cidr, rusqlite::Error> {
@@ -141,4 +201,20 @@ impl DatabaseCidr {
 
         Ok(cidr_iter.collect::<Result<Vec<_>, rusqlite::Error>>()?)
     }
+
+    /// How much of each CIDR is used, counting addresses that are `reserved` for peers that
+    /// haven't been added yet.
+    pub fn utilization(
+        conn: &Connection,
+        reserved: &[IpAddr],
+    ) -> Result<Vec<CidrUtilization>, ServerError> {
+        let peer_ips: Vec<_> = DatabasePeer::list(conn)?
+            .into_iter()
+            .map(|peer| peer.ip)
+            .collect();
+        Ok(Self::list(conn)?
+            .iter()
+            .map(|cidr| CidrUtilization::new(cidr, peer_ips.iter().chain(reserved)))
+            .collect())
+    }
 }
@@ -0,0 +1,179 @@
+//! An append-only log of changes to the network state, for external consumers to tail.
+
+use crate::ServerError;
+use rusqlite::{params, types::Type, Connection};
+use shared::{Event, EventKind};
+use std::time::SystemTime;
+
+pub static CREATE_TABLE_SQL: &str = "CREATE TABLE events (
+      id         INTEGER PRIMARY KEY AUTOINCREMENT,  /* Never reused, so consumers can use the last seen id as a cursor. */
+      timestamp  INTEGER NOT NULL,                   /* The UNIX time that the change was made.                        */
+      data       TEXT NOT NULL                       /* The change itself, as a JSON-encoded EventKind.                */
+    )";
+
+/// The most events returned by a single [`DatabaseEvent::list_since`] call.
+pub const PAGE_SIZE: usize = 1000;
+
+pub struct DatabaseEvent;
+
+impl DatabaseEvent {
+    pub fn record(conn: &Connection, kind: EventKind) -> Result<(), ServerError> {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Something is horribly wrong with system time.")
+            .as_secs();
+        conn.execute(
+            "INSERT INTO events (timestamp, data) VALUES (?1, ?2)",
+            params![timestamp, serde_json::to_string(&kind)?],
+        )?;
+        Ok(())
+    }
+
+    /// Events with an id greater than `since`, oldest first, at most [`PAGE_SIZE`] of them.
+    pub fn list_since(conn: &Connection, since: i64) -> Result<Vec<Event>, ServerError> {
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, timestamp, data FROM events WHERE id > ?1 ORDER BY id LIMIT ?2",
+        )?;
+        let events = stmt
+            .query_map(params![since, PAGE_SIZE], |row| {
+                let kind = serde_json::from_str(&row.get::<_, String>(2)?).map_err(|_| {
+                    rusqlite::Error::InvalidColumnType(2, "data (json)".into(), Type::Text)
+                })?;
+                Ok(Event {
+                    id: row.get(0)?,
+                    timestamp: row.get(1)?,
+                    kind,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(events)
+    }
+
+    /// Delete events recorded before `before`, returning how many were deleted. The latest
+    /// event is always kept, so the feed never looks empty to a consumer that's behind.
+    pub fn prune(conn: &Connection, before: SystemTime) -> Result<usize, ServerError> {
+        let cutoff = before
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Something is horribly wrong with system time.")
+            .as_secs();
+        let deleted = conn.execute(
+            "DELETE FROM events WHERE timestamp < ?1 AND id < (SELECT MAX(id) FROM events)",
+            params![cutoff],
+        )?;
+        Ok(deleted)
+    }
+
+    /// The id of the oldest event still kept, or `None` if there are none.
+    pub fn oldest_id(conn: &Connection) -> Result<Option<i64>, ServerError> {
+        let id = conn.query_row("SELECT MIN(id) FROM events", params![], |r| r.get(0))?;
+        Ok(id)
+    }
+
+    /// The id of the latest event, or 0 if there are none.
+    pub fn latest_id(conn: &Connection) -> Result<i64, ServerError> {
+        let id = conn.query_row("SELECT COALESCE(MAX(id), 0) FROM events", params![], |r| {
+            r.get(0)
+        })?;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::DatabaseCidr, test};
+    use shared::{CidrContents, Error};
+
+    #[test]
+    fn test_event_schema() {
+        let event = Event {
+            id: 7,
+            timestamp: 1_600_000_000,
+            kind: EventKind::CidrDeleted { cidr_id: 3 },
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"id": 7, "timestamp": 1_600_000_000, "type": "cidr_deleted", "cidr_id": 3})
+        );
+        assert_eq!(serde_json::from_value::<Event>(json).unwrap(), event);
+    }
+
+    #[tokio::test]
+    async fn test_cidr_events() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let db = server.db();
+        let conn = db.lock();
+        let cursor = DatabaseEvent::latest_id(&conn)?;
+
+        let cidr = DatabaseCidr::create(
+            &conn,
+            CidrContents {
+                name: "experimental".to_string(),
+                cidr: test::EXPERIMENTAL_CIDR.parse()?,
+                parent: Some(test::ROOT_CIDR_ID),
+            },
+        )?;
+        DatabaseCidr::delete(&conn, cidr.id)?;
+
+        let events: Vec<_> = DatabaseEvent::list_since(&conn, cursor)?
+            .into_iter()
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                EventKind::CidrCreated { cidr: cidr.clone() },
+                EventKind::CidrDeleted { cidr_id: cidr.id },
+            ]
+        );
+
+        let latest = DatabaseEvent::latest_id(&conn)?;
+        assert!(DatabaseEvent::list_since(&conn, latest)?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prune() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let db = server.db();
+        let conn = db.lock();
+        let latest = DatabaseEvent::latest_id(&conn)?;
+        assert!(latest > 1);
+
+        assert_eq!(DatabaseEvent::prune(&conn, SystemTime::UNIX_EPOCH)?, 0);
+        assert_eq!(DatabaseEvent::oldest_id(&conn)?, Some(1));
+
+        // Everything is older than the cutoff, but the latest event stays.
+        let later = SystemTime::now() + std::time::Duration::from_secs(60);
+        assert_eq!(DatabaseEvent::prune(&conn, later)?, latest as usize - 1);
+        assert_eq!(DatabaseEvent::oldest_id(&conn)?, Some(latest));
+        assert_eq!(DatabaseEvent::latest_id(&conn)?, latest);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_events_share_the_change_transaction() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let db = server.db();
+        let conn = db.lock();
+        let cursor = DatabaseEvent::latest_id(&conn)?;
+
+        // Without the events table, recording fails, and so must the change.
+        conn.execute("ALTER TABLE events RENAME TO events_old", params![])?;
+        let contents = CidrContents {
+            name: "experimental".to_string(),
+            cidr: test::EXPERIMENTAL_CIDR.parse()?,
+            parent: Some(test::ROOT_CIDR_ID),
+        };
+        assert!(DatabaseCidr::create(&conn, contents.clone()).is_err());
+        conn.execute("ALTER TABLE events_old RENAME TO events", params![])?;
+        assert!(DatabaseCidr::list(&conn)?
+            .iter()
+            .all(|cidr| cidr.name != contents.name));
+
+        DatabaseCidr::create(&conn, contents)?;
+        assert_eq!(DatabaseEvent::list_since(&conn, cursor)?.len(), 1);
+        Ok(())
+    }
+}
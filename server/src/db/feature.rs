@@ -0,0 +1,39 @@
+//! Network-wide feature flags, stored as overrides of [`FeatureFlags`]'s defaults.
+
+use crate::ServerError;
+use rusqlite::{params, Connection};
+use shared::FeatureFlags;
+
+pub static CREATE_TABLE_SQL: &str = "CREATE TABLE feature_flags (
+      name     TEXT PRIMARY KEY,  /* The flag's kebab-case name.           */
+      enabled  INTEGER NOT NULL   /* Whether the flag is turned on or off. */
+    )";
+
+pub struct DatabaseFeatureFlags;
+
+impl DatabaseFeatureFlags {
+    pub fn get(conn: &Connection) -> Result<FeatureFlags, ServerError> {
+        let mut stmt = conn.prepare_cached("SELECT name, enabled FROM feature_flags")?;
+        let overrides = stmt
+            .query_map(params![], |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(FeatureFlags::with_overrides(
+            overrides
+                .iter()
+                .map(|(name, enabled)| (name.as_str(), *enabled)),
+        ))
+    }
+
+    /// Turn a flag on or off, returning the resulting flags. Unknown flags are rejected.
+    pub fn set(conn: &Connection, name: &str, enabled: bool) -> Result<FeatureFlags, ServerError> {
+        let mut flags = Self::get(conn)?;
+        flags
+            .set(name, enabled)
+            .map_err(|_| ServerError::InvalidQuery)?;
+        conn.execute(
+            "INSERT OR REPLACE INTO feature_flags (name, enabled) VALUES (?1, ?2)",
+            params![name, enabled],
+        )?;
+        Ok(flags)
+    }
+}
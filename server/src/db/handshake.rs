@@ -0,0 +1,137 @@
+//! The latest handshakes peers have reported seeing with other peers, for judging liveness
+//! beyond what the server sees itself.
+
+use crate::ServerError;
+use rusqlite::{params, Connection};
+use shared::HandshakeReport;
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+pub static CREATE_TABLE_SQL: &str = "CREATE TABLE handshake_reports (
+      peer_id         INTEGER PRIMARY KEY,  /* The peer that was handshaked with.                 */
+      last_handshake  INTEGER NOT NULL,     /* The UNIX time of the latest reported handshake.    */
+      reporter_id     INTEGER NOT NULL,     /* The peer that reported it.                         */
+      FOREIGN KEY (peer_id)
+         REFERENCES peers (id)
+            ON UPDATE RESTRICT
+            ON DELETE CASCADE,
+      FOREIGN KEY (reporter_id)
+         REFERENCES peers (id)
+            ON UPDATE RESTRICT
+            ON DELETE CASCADE
+    )";
+
+pub struct DatabaseHandshakeReport;
+
+impl DatabaseHandshakeReport {
+    /// Record a batch of reports, keeping only the latest handshake per peer, so the same
+    /// batch can be safely sent more than once. Handshakes reported in the future (ex. from
+    /// a reporter with a fast clock) are recorded as happening now, so they can't hide the
+    /// peer going stale later.
+    pub fn upsert(
+        conn: &Connection,
+        reporter_id: i64,
+        reports: &[HandshakeReport],
+    ) -> Result<(), ServerError> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let tx = conn.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO handshake_reports (peer_id, last_handshake, reporter_id)
+                  VALUES (?1, ?2, ?3)
+                  ON CONFLICT (peer_id) DO UPDATE SET
+                    last_handshake = excluded.last_handshake,
+                    reporter_id = excluded.reporter_id
+                  WHERE excluded.last_handshake > handshake_reports.last_handshake",
+            )?;
+            for report in reports {
+                let last_handshake = report.last_handshake.min(now);
+                stmt.execute(params![report.peer_id, last_handshake, reporter_id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The latest reported handshake of every peer that's had one reported.
+    pub fn latest(conn: &Connection) -> Result<HashMap<i64, SystemTime>, ServerError> {
+        let mut stmt =
+            conn.prepare_cached("SELECT peer_id, last_handshake FROM handshake_reports")?;
+        let latest = stmt
+            .query_map(params![], |row| {
+                let last_handshake: u64 = row.get(1)?;
+                Ok((
+                    row.get(0)?,
+                    SystemTime::UNIX_EPOCH + Duration::from_secs(last_handshake),
+                ))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(latest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use shared::Error;
+
+    #[test]
+    fn test_upsert_keeps_latest() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let db = server.db();
+        let conn = db.lock();
+        let report = |peer_id, last_handshake| HandshakeReport {
+            peer_id,
+            last_handshake,
+        };
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+
+        let batch = [
+            report(test::USER1_PEER_ID, 1000),
+            report(test::USER2_PEER_ID, 1000),
+        ];
+        DatabaseHandshakeReport::upsert(&conn, test::ADMIN_PEER_ID, &batch)?;
+        // Resending a batch, or an older one, changes nothing.
+        DatabaseHandshakeReport::upsert(&conn, test::ADMIN_PEER_ID, &batch)?;
+        DatabaseHandshakeReport::upsert(
+            &conn,
+            test::DEVELOPER1_PEER_ID,
+            &[report(test::USER1_PEER_ID, 900)],
+        )?;
+        DatabaseHandshakeReport::upsert(
+            &conn,
+            test::DEVELOPER1_PEER_ID,
+            &[report(test::USER2_PEER_ID, 1100)],
+        )?;
+
+        let latest = DatabaseHandshakeReport::latest(&conn)?;
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest[&test::USER1_PEER_ID], at(1000));
+        assert_eq!(latest[&test::USER2_PEER_ID], at(1100));
+        Ok(())
+    }
+
+    #[test]
+    fn test_upsert_clamps_future_handshakes() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let db = server.db();
+        let conn = db.lock();
+        let in_a_day = SystemTime::now() + Duration::from_secs(24 * 60 * 60);
+        let report = HandshakeReport {
+            peer_id: test::USER1_PEER_ID,
+            last_handshake: in_a_day.duration_since(SystemTime::UNIX_EPOCH)?.as_secs(),
+        };
+        DatabaseHandshakeReport::upsert(&conn, test::ADMIN_PEER_ID, &[report])?;
+
+        let latest = DatabaseHandshakeReport::latest(&conn)?[&test::USER1_PEER_ID];
+        assert!(latest <= SystemTime::now());
+        assert!(latest.elapsed()? < Duration::from_secs(60));
+        Ok(())
+    }
+}
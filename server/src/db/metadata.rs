@@ -0,0 +1,107 @@
+//! Self-reported information about each peer's client, ex. its OS and innernet version.
+//!
+//! This is purely informational and never affects how peers are configured.
+
+use crate::ServerError;
+use rusqlite::{params, Connection};
+use shared::{PeerMetadata, ReportedMetadata};
+use std::time::SystemTime;
+
+pub static CREATE_TABLE_SQL: &str = "CREATE TABLE peer_metadata (
+      peer_id      INTEGER PRIMARY KEY,  /* One row per peer, replaced on every report. */
+      reported_at  INTEGER NOT NULL,     /* The UNIX time of the last report.           */
+      os           TEXT,
+      version      TEXT,
+      hostname     TEXT,
+      backend      TEXT,
+      FOREIGN KEY (peer_id)
+         REFERENCES peers (id)
+            ON UPDATE RESTRICT
+            ON DELETE CASCADE
+    )";
+
+pub struct DatabasePeerMetadata;
+
+impl DatabasePeerMetadata {
+    pub fn set(
+        conn: &Connection,
+        peer_id: i64,
+        metadata: &PeerMetadata,
+    ) -> Result<(), ServerError> {
+        if !metadata.is_valid() {
+            return Err(ServerError::InvalidQuery);
+        }
+        let reported_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Something is horribly wrong with system time.")
+            .as_secs();
+        let PeerMetadata {
+            os,
+            version,
+            hostname,
+            backend,
+        } = metadata;
+        conn.execute(
+            "INSERT OR REPLACE INTO peer_metadata (peer_id, reported_at, os, version, hostname, backend)
+              VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![peer_id, reported_at, os, version, hostname, backend],
+        )?;
+        Ok(())
+    }
+
+    pub fn list(conn: &Connection) -> Result<Vec<ReportedMetadata>, ServerError> {
+        let mut stmt = conn.prepare_cached(
+            "SELECT peer_id, reported_at, os, version, hostname, backend FROM peer_metadata ORDER BY peer_id",
+        )?;
+        let metadata = stmt
+            .query_map(params![], |row| {
+                Ok(ReportedMetadata {
+                    peer_id: row.get(0)?,
+                    reported_at: row.get(1)?,
+                    metadata: PeerMetadata {
+                        os: row.get(2)?,
+                        version: row.get(3)?,
+                        hostname: row.get(4)?,
+                        backend: row.get(5)?,
+                    },
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use shared::Error;
+
+    #[test]
+    fn test_metadata_replaced_and_bounded() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let db = server.db();
+        let conn = db.lock();
+
+        let mut metadata = PeerMetadata {
+            os: Some("linux".into()),
+            version: Some("1.5.0".into()),
+            ..Default::default()
+        };
+        DatabasePeerMetadata::set(&conn, test::ADMIN_PEER_ID, &metadata)?;
+        metadata.version = Some("1.6.0".into());
+        DatabasePeerMetadata::set(&conn, test::ADMIN_PEER_ID, &metadata)?;
+
+        let listed = DatabasePeerMetadata::list(&conn)?;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].peer_id, test::ADMIN_PEER_ID);
+        assert_eq!(listed[0].metadata, metadata);
+
+        metadata.hostname = Some("x".repeat(PeerMetadata::MAX_FIELD_LEN + 1));
+        assert!(matches!(
+            DatabasePeerMetadata::set(&conn, test::ADMIN_PEER_ID, &metadata),
+            Err(ServerError::InvalidQuery)
+        ));
+        Ok(())
+    }
+}
@@ -1,16 +1,50 @@
 pub mod association;
 pub mod cidr;
+pub mod event;
+pub mod feature;
+pub mod handshake;
+pub mod metadata;
+pub mod mtu;
 pub mod peer;
 
+use crate::ServerError;
 pub use association::DatabaseAssociation;
 pub use cidr::DatabaseCidr;
+pub use event::DatabaseEvent;
+pub use feature::DatabaseFeatureFlags;
+pub use handshake::DatabaseHandshakeReport;
+pub use metadata::DatabasePeerMetadata;
+pub use mtu::DatabaseMtuHint;
 pub use peer::DatabasePeer;
-use rusqlite::params;
+use rusqlite::{params, Connection};
 
 const INVITE_EXPIRATION_VERSION: usize = 1;
 const ENDPOINT_CANDIDATES_VERSION: usize = 2;
+const EVENTS_VERSION: usize = 3;
+const PEER_METADATA_VERSION: usize = 4;
+const HANDSHAKE_REPORTS_VERSION: usize = 5;
+const MTU_HINTS_VERSION: usize = 6;
+const FEATURE_FLAGS_VERSION: usize = 7;
+const PEER_TOMBSTONES_VERSION: usize = 8;
+const EXTRA_ALLOWED_IPS_VERSION: usize = 9;
+const CANDIDATES_LEARNED_AT_VERSION: usize = 10;
 
-pub const CURRENT_VERSION: usize = ENDPOINT_CANDIDATES_VERSION;
+pub const CURRENT_VERSION: usize = CANDIDATES_LEARNED_AT_VERSION;
+
+/// Run `f` in a transaction, so that a change and the event recording it are committed
+/// together. If `conn` is already in a transaction, `f` becomes part of that one instead.
+pub fn atomically<T>(
+    conn: &Connection,
+    f: impl FnOnce(&Connection) -> Result<T, ServerError>,
+) -> Result<T, ServerError> {
+    if !conn.is_autocommit() {
+        return f(conn);
+    }
+    let tx = conn.unchecked_transaction()?;
+    let result = f(&tx)?;
+    tx.commit()?;
+    Ok(result)
+}
 
 pub fn auto_migrate(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
     let old_version: usize = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
@@ -27,8 +61,46 @@ pub fn auto_migrate(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error>
         conn.execute("ALTER TABLE peers ADD COLUMN candidates TEXT", params![])?;
     }
 
+    if old_version < EVENTS_VERSION {
+        conn.execute(event::CREATE_TABLE_SQL, params![])?;
+    }
+
+    if old_version < PEER_METADATA_VERSION {
+        conn.execute(metadata::CREATE_TABLE_SQL, params![])?;
+    }
+
+    if old_version < HANDSHAKE_REPORTS_VERSION {
+        conn.execute(handshake::CREATE_TABLE_SQL, params![])?;
+    }
+
+    if old_version < MTU_HINTS_VERSION {
+        conn.execute(mtu::CREATE_TABLE_SQL, params![])?;
+    }
+
+    if old_version < FEATURE_FLAGS_VERSION {
+        conn.execute(feature::CREATE_TABLE_SQL, params![])?;
+    }
+
+    if old_version < PEER_TOMBSTONES_VERSION {
+        conn.execute("ALTER TABLE peers ADD COLUMN deleted_at INTEGER", params![])?;
+    }
+
+    if old_version < EXTRA_ALLOWED_IPS_VERSION {
+        conn.execute(
+            "ALTER TABLE peers ADD COLUMN extra_allowed_ips TEXT",
+            params![],
+        )?;
+    }
+
+    if old_version < CANDIDATES_LEARNED_AT_VERSION {
+        conn.execute(
+            "ALTER TABLE peers ADD COLUMN candidates_learned_at INTEGER",
+            params![],
+        )?;
+    }
+
     if old_version != CURRENT_VERSION {
-        conn.pragma_update(None, "user_version", &CURRENT_VERSION)?;
+        conn.pragma_update(None, "user_version", CURRENT_VERSION)?;
         log::info!(
             "migrated db version from {} to {}",
             old_version,
@@ -0,0 +1,79 @@
+//! Admin-set hints of the path MTU to each peer, which clients use to pick an interface MTU
+//! that's safe for all of their peers.
+
+use crate::ServerError;
+use rusqlite::{params, Connection};
+use shared::MtuHint;
+
+pub static CREATE_TABLE_SQL: &str = "CREATE TABLE mtu_hints (
+      peer_id  INTEGER PRIMARY KEY,  /* One hint per peer.                             */
+      mtu      INTEGER NOT NULL,     /* The largest packet that fits the path to it.   */
+      FOREIGN KEY (peer_id)
+         REFERENCES peers (id)
+            ON UPDATE RESTRICT
+            ON DELETE CASCADE
+    )";
+
+pub struct DatabaseMtuHint;
+
+impl DatabaseMtuHint {
+    /// Set a peer's hint, or clear it with `None`.
+    pub fn set(conn: &Connection, peer_id: i64, mtu: Option<u32>) -> Result<(), ServerError> {
+        match mtu {
+            Some(mtu) if !MtuHint::is_valid_mtu(mtu) => return Err(ServerError::InvalidQuery),
+            Some(mtu) => conn.execute(
+                "INSERT OR REPLACE INTO mtu_hints (peer_id, mtu) VALUES (?1, ?2)",
+                params![peer_id, mtu],
+            )?,
+            None => conn.execute("DELETE FROM mtu_hints WHERE peer_id = ?1", params![peer_id])?,
+        };
+        Ok(())
+    }
+
+    pub fn list(conn: &Connection) -> Result<Vec<MtuHint>, ServerError> {
+        let mut stmt =
+            conn.prepare_cached("SELECT peer_id, mtu FROM mtu_hints ORDER BY peer_id")?;
+        let hints = stmt
+            .query_map(params![], |row| {
+                Ok(MtuHint {
+                    peer_id: row.get(0)?,
+                    mtu: row.get(1)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(hints)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test;
+    use shared::Error;
+
+    #[test]
+    fn test_set_and_clear_mtu_hint() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let db = server.db();
+        let conn = db.lock();
+
+        DatabaseMtuHint::set(&conn, test::USER1_PEER_ID, Some(1400))?;
+        DatabaseMtuHint::set(&conn, test::USER1_PEER_ID, Some(1380))?;
+        assert_eq!(
+            DatabaseMtuHint::list(&conn)?,
+            vec![MtuHint {
+                peer_id: test::USER1_PEER_ID,
+                mtu: 1380
+            }]
+        );
+
+        assert!(matches!(
+            DatabaseMtuHint::set(&conn, test::USER1_PEER_ID, Some(100)),
+            Err(ServerError::InvalidQuery)
+        ));
+
+        DatabaseMtuHint::set(&conn, test::USER1_PEER_ID, None)?;
+        assert!(DatabaseMtuHint::list(&conn)?.is_empty());
+        Ok(())
+    }
+}
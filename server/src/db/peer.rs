@@ -1,9 +1,9 @@
-use super::DatabaseCidr;
+use super::{atomically, DatabaseCidr, DatabaseEvent};
 use crate::ServerError;
 use lazy_static::lazy_static;
 use regex::Regex;
-use rusqlite::{params, types::Type, Connection};
-use shared::{IpNetExt, Peer, PeerContents, PERSISTENT_KEEPALIVE_INTERVAL_SECS};
+use rusqlite::{params, types::Type, Connection, OptionalExtension};
+use shared::{EventKind, IpNetExt, Peer, PeerContents, PERSISTENT_KEEPALIVE_INTERVAL_SECS};
 use std::{
     net::IpAddr,
     ops::{Deref, DerefMut},
@@ -22,6 +22,9 @@ pub static CREATE_TABLE_SQL: &str = "CREATE TABLE peers (
       is_redeemed     INTEGER DEFAULT 0 NOT NULL,   /* Has the peer redeemed their invite yet?                          */
       invite_expires  INTEGER,                      /* The UNIX time that an invited peer can no longer redeem.         */
       candidates      TEXT,                         /* A list of additional endpoints that peers can use to connect.    */
+      deleted_at      INTEGER,                      /* The UNIX time that the (now disabled) peer was deleted.          */
+      extra_allowed_ips TEXT,                       /* A list of ranges the peer is a gateway to, routed to it too.     */
+      candidates_learned_at INTEGER,                /* The UNIX time the peer last reported its candidates, if it did.  */
       FOREIGN KEY (cidr_id)
          REFERENCES cidrs (id)
             ON UPDATE RESTRICT
@@ -40,6 +43,8 @@ pub static COLUMNS: &[&str] = &[
     "is_redeemed",
     "invite_expires",
     "candidates",
+    "deleted_at",
+    "extra_allowed_ips",
 ];
 
 lazy_static! {
@@ -74,7 +79,10 @@ impl DerefMut for DatabasePeer {
 }
 
 impl DatabasePeer {
-    pub fn create(conn: &Connection, contents: PeerContents) -> Result<Self, ServerError> {
+    pub fn create(conn: &Connection, mut contents: PeerContents) -> Result<Self, ServerError> {
+        for extra in &mut contents.extra_allowed_ips {
+            *extra = extra.trunc();
+        }
         let PeerContents {
             name,
             ip,
@@ -86,6 +94,7 @@ impl DatabasePeer {
             is_redeemed,
             invite_expires,
             candidates,
+            extra_allowed_ips,
             ..
         } = &contents;
         log::info!("creating peer {:?}", contents);
@@ -109,33 +118,72 @@ impl DatabasePeer {
             return Err(ServerError::InvalidQuery);
         }
 
+        // Deleted peers keep their IPs too, so they can be restored.
+        let holder: Option<String> = conn
+            .query_row(
+                "SELECT name FROM peers WHERE ip = ?1",
+                params![ip.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(holder) = holder {
+            return Err(ServerError::Conflict(format!(
+                "{} is already taken by peer \"{}\".",
+                ip, holder
+            )));
+        }
+
+        let network = DatabaseCidr::list(conn)?
+            .into_iter()
+            .find(|cidr| cidr.parent.is_none())
+            .ok_or(ServerError::NotFound)?;
+        let peers: Vec<Peer> = Self::list(conn)?
+            .into_iter()
+            .map(|peer| peer.inner)
+            .collect();
+        contents
+            .check_allowed_ips(&network.cidr, &peers)
+            .map_err(ServerError::Conflict)?;
+
         let invite_expires = invite_expires
             .map(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
             .flatten()
             .map(|t| t.as_secs());
 
         let candidates = serde_json::to_string(candidates)?;
+        let extra_allowed_ips = serde_json::to_string(extra_allowed_ips)?;
 
-        conn.execute(
-            &format!(
-                "INSERT INTO peers ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
-                COLUMNS[1..].join(", ")
-            ),
-            params![
-                &**name,
-                ip.to_string(),
-                cidr_id,
-                &public_key,
-                endpoint.as_ref().map(|endpoint| endpoint.to_string()),
-                is_admin,
-                is_disabled,
-                is_redeemed,
-                invite_expires,
-                candidates,
-            ],
-        )?;
-        let id = conn.last_insert_rowid();
-        Ok(Peer { id, contents }.into())
+        atomically(conn, |conn| {
+            conn.execute(
+                &format!(
+                    "INSERT INTO peers ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, NULL, ?11)",
+                    COLUMNS[1..].join(", ")
+                ),
+                params![
+                    &**name,
+                    ip.to_string(),
+                    cidr_id,
+                    &public_key,
+                    endpoint.as_ref().map(|endpoint| endpoint.to_string()),
+                    is_admin,
+                    is_disabled,
+                    is_redeemed,
+                    invite_expires,
+                    candidates,
+                    extra_allowed_ips,
+                ],
+            )?;
+            let id = conn.last_insert_rowid();
+            let peer = Peer {
+                id,
+                contents: PeerContents {
+                    deleted_at: None,
+                    ..contents.clone()
+                },
+            };
+            DatabaseEvent::record(conn, EventKind::PeerCreated { peer: peer.clone() })?;
+            Ok(peer.into())
+        })
     }
 
     fn is_valid_name(name: &str) -> bool {
@@ -160,43 +208,88 @@ impl DatabasePeer {
             is_admin: contents.is_admin,
             is_disabled: contents.is_disabled,
             candidates: contents.candidates,
+            // Enabling a deleted peer restores it.
+            deleted_at: self.deleted_at.filter(|_| contents.is_disabled),
             ..self.contents.clone()
         };
 
         let new_candidates = serde_json::to_string(&new_contents.candidates)?;
-        conn.execute(
-            "UPDATE peers SET
-                name = ?2,
-                endpoint = ?3,
-                is_admin = ?4,
-                is_disabled = ?5,
-                candidates = ?6
-            WHERE id = ?1",
-            params![
-                self.id,
-                &*new_contents.name,
-                new_contents
-                    .endpoint
-                    .as_ref()
-                    .map(|endpoint| endpoint.to_string()),
-                new_contents.is_admin,
-                new_contents.is_disabled,
-                new_candidates,
-            ],
-        )?;
-
-        self.contents = new_contents;
+        let updated = Peer {
+            id: self.id,
+            contents: new_contents,
+        };
+        atomically(conn, |conn| {
+            conn.execute(
+                "UPDATE peers SET
+                    name = ?2,
+                    endpoint = ?3,
+                    is_admin = ?4,
+                    is_disabled = ?5,
+                    candidates = ?6,
+                    deleted_at = CASE WHEN ?5 THEN deleted_at END
+                WHERE id = ?1",
+                params![
+                    self.id,
+                    &*updated.name,
+                    updated
+                        .endpoint
+                        .as_ref()
+                        .map(|endpoint| endpoint.to_string()),
+                    updated.is_admin,
+                    updated.is_disabled,
+                    new_candidates,
+                ],
+            )?;
+
+            // Clients re-report their endpoint candidates on every fetch, so only log real
+            // changes.
+            if updated.contents != self.contents {
+                DatabaseEvent::record(
+                    conn,
+                    EventKind::PeerUpdated {
+                        peer: updated.clone(),
+                    },
+                )?;
+            }
+            Ok(())
+        })?;
+        self.inner = updated;
         Ok(())
     }
 
     pub fn disable(conn: &Connection, id: i64) -> Result<(), ServerError> {
-        match conn.execute(
-            "UPDATE peers SET is_disabled = 1 WHERE id = ?1",
-            params![id],
-        )? {
-            0 => Err(ServerError::NotFound),
-            _ => Ok(()),
-        }
+        atomically(conn, |conn| {
+            match conn.execute(
+                "UPDATE peers SET is_disabled = 1 WHERE id = ?1",
+                params![id],
+            )? {
+                0 => Err(ServerError::NotFound),
+                _ => {
+                    let peer = Self::get(conn, id)?.inner;
+                    DatabaseEvent::record(conn, EventKind::PeerUpdated { peer })
+                },
+            }
+        })
+    }
+
+    /// Disable the peer and mark it deleted. It's kept in the database, so its name and IP stay
+    /// taken, but it's hidden from peer listings unless deleted peers are asked for.
+    pub fn delete(conn: &Connection, id: i64) -> Result<(), ServerError> {
+        let unix_now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Something is horribly wrong with system time.");
+        atomically(conn, |conn| {
+            match conn.execute(
+                "UPDATE peers SET is_disabled = 1, deleted_at = ?2 WHERE id = ?1 AND deleted_at IS NULL",
+                params![id, unix_now.as_secs()],
+            )? {
+                0 => Err(ServerError::NotFound),
+                _ => {
+                    let peer = Self::get(conn, id)?.inner;
+                    DatabaseEvent::record(conn, EventKind::PeerUpdated { peer })
+                },
+            }
+        })
     }
 
     pub fn redeem(&mut self, conn: &Connection, pubkey: &str) -> Result<(), ServerError> {
@@ -208,14 +301,26 @@ impl DatabasePeer {
             return Err(ServerError::Unauthorized);
         }
 
-        match conn.execute(
+        // The invitation is only consumed if the whole redemption succeeds, so that clients can
+        // safely retry after a server error.
+        let tx = conn.unchecked_transaction()?;
+        match tx.execute(
             "UPDATE peers SET is_redeemed = 1, public_key = ?1 WHERE id = ?2 AND is_redeemed = 0",
             params![pubkey, self.id],
         )? {
             0 => Err(ServerError::NotFound),
             _ => {
-                self.contents.public_key = pubkey.into();
-                self.contents.is_redeemed = true;
+                let mut redeemed = self.inner.clone();
+                redeemed.contents.public_key = pubkey.into();
+                redeemed.contents.is_redeemed = true;
+                DatabaseEvent::record(
+                    &tx,
+                    EventKind::PeerUpdated {
+                        peer: redeemed.clone(),
+                    },
+                )?;
+                tx.commit()?;
+                self.inner = redeemed;
                 Ok(())
             },
         }
@@ -250,6 +355,20 @@ impl DatabasePeer {
         } else {
             vec![]
         };
+        let deleted_at = row
+            .get::<_, Option<u64>>(11)?
+            .map(|unixtime| SystemTime::UNIX_EPOCH + Duration::from_secs(unixtime));
+        let extra_allowed_ips = if let Some(ips) = row.get::<_, Option<String>>(12)? {
+            serde_json::from_str(&ips).map_err(|_| {
+                rusqlite::Error::InvalidColumnType(
+                    12,
+                    "extra_allowed_ips (json)".into(),
+                    Type::Text,
+                )
+            })?
+        } else {
+            vec![]
+        };
 
         let persistent_keepalive_interval = Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS);
 
@@ -267,6 +386,8 @@ impl DatabasePeer {
                 is_redeemed,
                 invite_expires,
                 candidates,
+                extra_allowed_ips,
+                deleted_at,
             },
         }
         .into())
@@ -323,7 +444,7 @@ impl DatabasePeer {
                 SELECT DISTINCT {}
                 FROM peers
                 JOIN associated_subcidrs ON peers.cidr_id=associated_subcidrs.cidr_id
-                WHERE peers.is_disabled = 0 AND peers.is_redeemed = 1;",
+                WHERE peers.is_disabled = 0 AND peers.is_redeemed = 1 AND peers.deleted_at IS NULL;",
                 COLUMNS.iter().map(|col| format!("peers.{}", col)).collect::<Vec<_>>().join(", ")
             ),
         )?;
@@ -333,6 +454,7 @@ impl DatabasePeer {
         Ok(peers)
     }
 
+    /// Every peer in the database, including deleted ones.
     pub fn list(conn: &Connection) -> Result<Vec<Self>, ServerError> {
         let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM peers", COLUMNS.join(", ")))?;
         let peer_iter = stmt.query_map(params![], Self::from_row)?;
@@ -340,16 +462,72 @@ impl DatabasePeer {
         Ok(peer_iter.collect::<Result<_, _>>()?)
     }
 
+    /// Record that the peer just reported its own candidates. Unlike candidates set by an
+    /// admin, these were learned from the peer's current network and expire with
+    /// [`Self::expire_candidates`].
+    pub fn touch_candidates(
+        conn: &Connection,
+        id: i64,
+        now: SystemTime,
+    ) -> Result<(), ServerError> {
+        let unix_now = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Something is horribly wrong with system time.");
+        conn.execute(
+            "UPDATE peers SET candidates_learned_at = ?2 WHERE id = ?1",
+            params![id, unix_now.as_secs()],
+        )?;
+        Ok(())
+    }
+
+    /// Clear the reported candidates of peers that haven't re-reported them within `ttl`,
+    /// returning how many peers were affected.
+    pub fn expire_candidates(
+        conn: &Connection,
+        ttl: Duration,
+        now: SystemTime,
+    ) -> Result<usize, ServerError> {
+        let cutoff = (now - ttl)
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Something is horribly wrong with system time.");
+        let expired: Vec<i64> = conn
+            .prepare_cached("SELECT id FROM peers WHERE candidates_learned_at < ?1")?
+            .query_map(params![cutoff.as_secs()], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        for &peer_id in &expired {
+            atomically(conn, |conn| {
+                let mut peer = Self::get(conn, peer_id)?;
+                let contents = PeerContents {
+                    candidates: vec![],
+                    ..peer.contents.clone()
+                };
+                peer.update(conn, contents)?;
+                conn.execute(
+                    "UPDATE peers SET candidates_learned_at = NULL WHERE id = ?1",
+                    params![peer_id],
+                )?;
+                Ok(())
+            })?;
+        }
+
+        Ok(expired.len())
+    }
+
     pub fn delete_expired_invites(conn: &Connection) -> Result<usize, ServerError> {
         let unix_now = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .expect("Something is horribly wrong with system time.");
-        let deleted = conn.execute(
-            "DELETE FROM peers
-            WHERE is_redeemed = 0 AND invite_expires < ?1",
-            params![unix_now.as_secs()],
-        )?;
+        let expired: Vec<i64> = conn
+            .prepare_cached("SELECT id FROM peers WHERE is_redeemed = 0 AND invite_expires < ?1")?
+            .query_map(params![unix_now.as_secs()], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        for &peer_id in &expired {
+            atomically(conn, |conn| {
+                conn.execute("DELETE FROM peers WHERE id = ?1", params![peer_id])?;
+                DatabaseEvent::record(conn, EventKind::PeerDeleted { peer_id })
+            })?;
+        }
 
-        Ok(deleted)
+        Ok(expired.len())
     }
 }
@@ -1,6 +1,6 @@
-use std::convert::TryFrom;
+use std::{convert::TryFrom, time::Duration};
 
-use hyper::{http, Body, Response, StatusCode};
+use hyper::{header, http, Body, Response, StatusCode};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -14,9 +14,24 @@ pub enum ServerError {
     #[error("invalid query")]
     InvalidQuery,
 
+    /// An invalid query, with the reason sent back to the client.
+    #[error("{0}")]
+    Rejected(String),
+
+    /// The request conflicts with the current state, ex. a new peer's IP is already taken.
+    #[error("{0}")]
+    Conflict(String),
+
     #[error("endpoint gone")]
     Gone,
 
+    #[error("server is in read-only maintenance mode")]
+    ReadOnly,
+
+    /// The peer is out of requests, and may make another after this long.
+    #[error("too many requests")]
+    TooManyRequests(Duration),
+
     #[error("internal database error")]
     Database(#[from] rusqlite::Error),
 
@@ -36,14 +51,17 @@ pub enum ServerError {
     Hyper(#[from] hyper::Error),
 }
 
-impl<'a> From<&'a ServerError> for StatusCode {
+impl From<&ServerError> for StatusCode {
     fn from(error: &ServerError) -> StatusCode {
         use ServerError::*;
         match error {
             Unauthorized => StatusCode::UNAUTHORIZED,
             NotFound => StatusCode::NOT_FOUND,
+            Conflict(_) => StatusCode::CONFLICT,
             Gone => StatusCode::GONE,
-            InvalidQuery | Json(_) => StatusCode::BAD_REQUEST,
+            ReadOnly => StatusCode::SERVICE_UNAVAILABLE,
+            TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            InvalidQuery | Rejected(_) | Json(_) => StatusCode::BAD_REQUEST,
             // Special-case the constraint violation situation.
             Database(rusqlite::Error::SqliteFailure(libsqlite3_sys::Error { code, .. }, ..))
                 if *code == libsqlite3_sys::ErrorCode::ConstraintViolation =>
@@ -62,8 +80,19 @@ impl TryFrom<ServerError> for Response<Body> {
     type Error = http::Error;
 
     fn try_from(e: ServerError) -> Result<Self, Self::Error> {
-        Response::builder()
-            .status(StatusCode::from(&e))
-            .body(Body::empty())
+        let body = match &e {
+            ServerError::Rejected(reason) | ServerError::Conflict(reason) => {
+                log::warn!("rejected request: {}", reason);
+                Body::from(reason.clone())
+            },
+            _ => Body::empty(),
+        };
+        let mut response = Response::builder().status(StatusCode::from(&e));
+        if let ServerError::TooManyRequests(wait) = &e {
+            // Retry-After is in whole seconds, so round up to not have clients retry early.
+            let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            response = response.header(header::RETRY_AFTER, seconds.max(1));
+        }
+        response.body(body)
     }
 }
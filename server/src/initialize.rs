@@ -5,10 +5,10 @@ use db::DatabaseCidr;
 use dialoguer::{theme::ColorfulTheme, Input};
 use indoc::printdoc;
 use ipnet::IpNet;
-use publicip::Preference;
 use rusqlite::{params, Connection};
 use shared::{
-    prompts, CidrContents, Endpoint, IpNetExt, PeerContents, PERSISTENT_KEEPALIVE_INTERVAL_SECS,
+    prompts, CidrContents, Endpoint, IpNetExt, PeerContents, PublicIpOpts,
+    PERSISTENT_KEEPALIVE_INTERVAL_SECS,
 };
 use wireguard_control::KeyPair;
 
@@ -16,11 +16,16 @@ fn create_database<P: AsRef<Path>>(
     database_path: P,
 ) -> Result<Connection, Box<dyn std::error::Error>> {
     let conn = Connection::open(&database_path)?;
-    conn.pragma_update(None, "foreign_keys", &1)?;
+    conn.pragma_update(None, "foreign_keys", 1)?;
     conn.execute(db::peer::CREATE_TABLE_SQL, params![])?;
     conn.execute(db::association::CREATE_TABLE_SQL, params![])?;
     conn.execute(db::cidr::CREATE_TABLE_SQL, params![])?;
-    conn.pragma_update(None, "user_version", &db::CURRENT_VERSION)?;
+    conn.execute(db::event::CREATE_TABLE_SQL, params![])?;
+    conn.execute(db::metadata::CREATE_TABLE_SQL, params![])?;
+    conn.execute(db::handshake::CREATE_TABLE_SQL, params![])?;
+    conn.execute(db::mtu::CREATE_TABLE_SQL, params![])?;
+    conn.execute(db::feature::CREATE_TABLE_SQL, params![])?;
+    conn.pragma_update(None, "user_version", db::CURRENT_VERSION)?;
     log::debug!("set database version to db::CURRENT_VERSION");
 
     Ok(conn)
@@ -47,6 +52,9 @@ pub struct InitializeOpts {
     /// Port to listen on (for the WireGuard interface)
     #[clap(long)]
     pub listen_port: Option<u16>,
+
+    #[clap(flatten)]
+    pub public_ip: PublicIpOpts,
 }
 
 struct DbInitData {
@@ -95,6 +103,8 @@ fn populate_database(conn: &Connection, db_init_data: DbInitData) -> Result<(),
             persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
             invite_expires: None,
             candidates: vec![],
+            extra_allowed_ips: vec![],
+            deleted_at: None,
         },
     )
     .map_err(|_| anyhow!("failed to create innernet peer."))?;
@@ -158,11 +168,13 @@ pub fn init_wizard(conf: &ServerConfig, opts: InitializeOpts) -> Result<(), Erro
     let endpoint: Endpoint = if let Some(endpoint) = opts.external_endpoint {
         endpoint
     } else if opts.auto_external_endpoint {
-        let ip = publicip::get_any(Preference::Ipv4)
+        let ip = opts
+            .public_ip
+            .get_any()
             .ok_or_else(|| anyhow!("couldn't get external IP"))?;
         SocketAddr::new(ip, listen_port).into()
     } else {
-        prompts::ask_endpoint(listen_port)?
+        prompts::ask_endpoint(listen_port, &opts.public_ip)?
     };
 
     let our_ip = root_cidr
@@ -177,6 +189,8 @@ pub fn init_wizard(conf: &ServerConfig, opts: InitializeOpts) -> Result<(), Erro
         listen_port,
         address: our_ip,
         network_cidr_prefix: root_cidr.prefix_len(),
+        allowed_endpoint_ports: Default::default(),
+        rate_limits: Default::default(),
     };
     config.write_to_path(&config_path)?;
 
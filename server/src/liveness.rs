@@ -0,0 +1,298 @@
+//! Tracking which peers are online from how recently they've handshaked with the server (or
+//! with peers that report their handshakes), with optional webhook notifications when that
+//! changes.
+
+use crate::{
+    db::{DatabaseHandshakeReport, DatabasePeer},
+    util::{minutes, parse_minutes},
+    Db,
+};
+use clap::Args;
+use hyper::{header, Body, Client, Method, Request, Uri};
+use serde::Serialize;
+use shared::NetworkOpts;
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+use wireguard_control::{Device, InterfaceName};
+
+/// How often peers' handshake times are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a webhook endpoint gets to accept a notification.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Args)]
+pub struct LivenessOpts {
+    /// Consider a peer offline once neither the server nor any peer reporting handshakes has
+    /// seen a handshake from it in this many minutes, and log when peers go offline or come
+    /// back
+    #[clap(long, value_name = "MINS", parse(try_from_str = parse_minutes))]
+    pub offline_after: Option<u64>,
+
+    /// Only consider an offline peer back online once it has handshaked within this many
+    /// minutes, to avoid flapping. Defaults to half of --offline-after
+    #[clap(
+        long,
+        value_name = "MINS",
+        requires = "offline-after",
+        parse(try_from_str = parse_minutes)
+    )]
+    pub online_within: Option<u64>,
+
+    /// An http:// URL to POST a JSON notification to whenever a peer goes offline or comes
+    /// back online
+    #[clap(long, value_name = "URL", requires = "offline-after")]
+    pub liveness_webhook: Option<Uri>,
+}
+
+impl LivenessOpts {
+    pub fn policy(&self) -> Option<LivenessPolicy> {
+        let offline_after = minutes(self.offline_after?);
+        let online_within = self
+            .online_within
+            .map(minutes)
+            .unwrap_or(offline_after / 2)
+            .min(offline_after);
+        Some(LivenessPolicy {
+            offline_after,
+            online_within,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Liveness {
+    Online,
+    Offline,
+}
+
+/// When peers switch between online and offline. The gap between the two thresholds keeps
+/// a peer that handshakes right around the offline threshold from flapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LivenessPolicy {
+    pub offline_after: Duration,
+    pub online_within: Duration,
+}
+
+impl LivenessPolicy {
+    /// A peer's liveness given its `current` one (if it's been evaluated before) and when it
+    /// last handshaked.
+    pub fn evaluate(
+        &self,
+        current: Option<Liveness>,
+        last_handshake: Option<SystemTime>,
+        now: SystemTime,
+    ) -> Liveness {
+        let within = |threshold: Duration| {
+            last_handshake.is_some_and(|handshake| {
+                now.duration_since(handshake)
+                    .map_or(true, |age| age <= threshold)
+            })
+        };
+        let threshold = match current {
+            Some(Liveness::Offline) => self.online_within,
+            Some(Liveness::Online) | None => self.offline_after,
+        };
+        if within(threshold) {
+            Liveness::Online
+        } else {
+            Liveness::Offline
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Notification {
+    peer_id: i64,
+    name: String,
+    status: Liveness,
+    /// In seconds since the UNIX epoch, or `null` if the peer never handshaked.
+    last_handshake: Option<u64>,
+}
+
+pub fn spawn_liveness_monitor(
+    interface: InterfaceName,
+    network: NetworkOpts,
+    db: Db,
+    policy: LivenessPolicy,
+    webhook: Option<Uri>,
+) {
+    tokio::task::spawn(async move {
+        let client = Client::new();
+        let mut states: HashMap<i64, Liveness> = HashMap::new();
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let handshakes: HashMap<String, Option<SystemTime>> =
+                match Device::get(&interface, network.backend) {
+                    Ok(device) => device
+                        .peers
+                        .into_iter()
+                        .map(|peer| {
+                            (
+                                peer.config.public_key.to_base64(),
+                                peer.stats.last_handshake_time,
+                            )
+                        })
+                        .collect(),
+                    Err(e) => {
+                        log::error!("liveness check failed to read the interface: {}", e);
+                        continue;
+                    },
+                };
+            let (peers, reported) = {
+                let conn = db.lock();
+                match (
+                    DatabasePeer::list(&conn),
+                    DatabaseHandshakeReport::latest(&conn),
+                ) {
+                    (Ok(peers), Ok(reported)) => (peers, reported),
+                    (Err(e), _) | (_, Err(e)) => {
+                        log::error!("liveness check failed to read the database: {}", e);
+                        continue;
+                    },
+                }
+            };
+
+            let now = SystemTime::now();
+            for peer in peers.iter().filter(|p| !p.is_disabled && p.is_redeemed) {
+                let last_handshake = handshakes
+                    .get(&peer.public_key)
+                    .copied()
+                    .flatten()
+                    .max(reported.get(&peer.id).copied());
+                let previous = states.get(&peer.id).copied();
+                let current = policy.evaluate(previous, last_handshake, now);
+                states.insert(peer.id, current);
+                if previous.is_none_or(|previous| previous == current) {
+                    continue;
+                }
+
+                match current {
+                    Liveness::Offline => log::warn!("peer {} went offline.", &**peer),
+                    Liveness::Online => log::info!("peer {} is back online.", &**peer),
+                }
+                if let Some(webhook) = &webhook {
+                    let notification = Notification {
+                        peer_id: peer.id,
+                        name: peer.name.to_string(),
+                        status: current,
+                        last_handshake: last_handshake
+                            .and_then(|handshake| {
+                                handshake.duration_since(SystemTime::UNIX_EPOCH).ok()
+                            })
+                            .map(|since_epoch| since_epoch.as_secs()),
+                    };
+                    // Delivered in the background so a stalled endpoint can't hold up the checks.
+                    let (client, webhook) = (client.clone(), webhook.clone());
+                    tokio::task::spawn(async move {
+                        let delivery = notify(&client, &webhook, &notification);
+                        match tokio::time::timeout(WEBHOOK_TIMEOUT, delivery).await {
+                            Ok(Ok(())) => {},
+                            Ok(Err(e)) => log::error!("failed to send liveness webhook: {}", e),
+                            Err(_) => log::error!(
+                                "liveness webhook timed out after {}s.",
+                                WEBHOOK_TIMEOUT.as_secs()
+                            ),
+                        }
+                    });
+                }
+            }
+            states.retain(|id, _| peers.iter().any(|peer| peer.id == *id));
+        }
+    });
+}
+
+async fn notify(
+    client: &Client<hyper::client::HttpConnector>,
+    webhook: &Uri,
+    notification: &Notification,
+) -> Result<(), anyhow::Error> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(webhook)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(notification)?))?;
+    let response = client.request(request).await?;
+    if !response.status().is_success() {
+        anyhow::bail!("webhook responded with {}", response.status());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_liveness_hysteresis() {
+        let policy = LivenessPolicy {
+            offline_after: Duration::from_secs(600),
+            online_within: Duration::from_secs(300),
+        };
+        let now = SystemTime::now();
+        let ago = |secs| Some(now - Duration::from_secs(secs));
+
+        assert_eq!(policy.evaluate(None, ago(60), now), Liveness::Online);
+        assert_eq!(policy.evaluate(None, None, now), Liveness::Offline);
+
+        let online = Some(Liveness::Online);
+        assert_eq!(policy.evaluate(online, ago(500), now), Liveness::Online);
+        assert_eq!(policy.evaluate(online, ago(700), now), Liveness::Offline);
+
+        // Coming back online takes a more recent handshake than staying online does.
+        let offline = Some(Liveness::Offline);
+        assert_eq!(policy.evaluate(offline, ago(500), now), Liveness::Offline);
+        assert_eq!(policy.evaluate(offline, ago(200), now), Liveness::Online);
+        assert_eq!(policy.evaluate(offline, None, now), Liveness::Offline);
+    }
+
+    #[test]
+    fn test_liveness_opts_policy() {
+        let opts = |offline_after, online_within| LivenessOpts {
+            offline_after,
+            online_within,
+            liveness_webhook: None,
+        };
+        assert_eq!(opts(None, None).policy(), None);
+        assert_eq!(
+            opts(Some(10), None).policy(),
+            Some(LivenessPolicy {
+                offline_after: Duration::from_secs(600),
+                online_within: Duration::from_secs(300),
+            })
+        );
+        // The online threshold can't be looser than the offline one.
+        assert_eq!(
+            opts(Some(10), Some(20)).policy().unwrap().online_within,
+            Duration::from_secs(600)
+        );
+    }
+
+    #[test]
+    fn test_liveness_opts_reject_overflowing_minutes() {
+        use clap::Parser;
+
+        #[derive(Parser)]
+        struct Cli {
+            #[clap(flatten)]
+            liveness: LivenessOpts,
+        }
+
+        let cli = Cli::try_parse_from(["test", "--offline-after", "10"]).unwrap();
+        assert_eq!(cli.liveness.offline_after, Some(10));
+        let too_long = (u64::MAX / 60 + 1).to_string();
+        assert!(Cli::try_parse_from(["test", "--offline-after", &too_long]).is_err());
+        assert!(Cli::try_parse_from([
+            "test",
+            "--offline-after",
+            "10",
+            "--online-within",
+            &too_long
+        ])
+        .is_err());
+    }
+}
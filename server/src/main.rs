@@ -1,28 +1,33 @@
 use anyhow::{anyhow, bail};
-use clap::{AppSettings, IntoApp, Parser, Subcommand};
+use clap::{AppSettings, Args, IntoApp, Parser, Subcommand};
 use colored::*;
 use dialoguer::Confirm;
-use hyper::{http, server::conn::AddrStream, Body, Request, Response};
+use hyper::{http, Body, Method, Request, Response};
 use indoc::printdoc;
 use ipnet::IpNet;
 use parking_lot::{Mutex, RwLock};
 use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
 use shared::{
-    get_local_addrs, AddCidrOpts, AddPeerOpts, DeleteCidrOpts, Endpoint, IoErrorContext,
-    NetworkOpts, PeerContents, RenamePeerOpts, INNERNET_PUBKEY_HEADER,
+    get_local_addrs, AddCidrOpts, AddPeerOpts, DeleteCidrOpts, DuplicateIp, Endpoint,
+    EndpointPortPolicy, IoErrorContext, IpNetExt, NetworkOpts, Peer, PeerContents, PreRegisterOpts,
+    RenameCidrOpts, RenamePeerOpts, INNERNET_PUBKEY_HEADER, INNERNET_SERVER_TIME_HEADER,
+    PERSISTENT_KEEPALIVE_INTERVAL_SECS,
 };
 use std::{
     collections::{HashMap, VecDeque},
     convert::TryInto,
     env,
-    fs::File,
+    fs::{File, OpenOptions},
     io::prelude::*,
     net::{IpAddr, SocketAddr, TcpListener},
     ops::Deref,
     path::{Path, PathBuf},
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 use subtle::ConstantTimeEq;
 use wireguard_control::{Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder};
@@ -34,11 +39,19 @@ pub mod error;
 mod test;
 pub mod util;
 
+mod connections;
 mod initialize;
+mod liveness;
+mod metrics;
+mod rate_limit;
 
-use db::{DatabaseCidr, DatabasePeer};
+use connections::ConnectionOpts;
+use db::{DatabaseCidr, DatabaseEvent, DatabasePeer};
 pub use error::ServerError;
 use initialize::InitializeOpts;
+use liveness::{spawn_liveness_monitor, LivenessOpts};
+use metrics::{spawn_metrics_server, MetricsOpts};
+use rate_limit::{RateLimiter, RateLimits, Scope};
 use shared::{prompts, wg, CidrTree, Error, Interface};
 pub use shared::{Association, AssociationContents};
 
@@ -79,6 +92,9 @@ enum Command {
 
         #[clap(flatten)]
         network: NetworkOpts,
+
+        #[clap(flatten)]
+        opts: ServeOpts,
     },
 
     /// Add a peer to an existing network.
@@ -89,6 +105,15 @@ enum Command {
         args: AddPeerOpts,
     },
 
+    /// Register a peer by a public key it generated offline, skipping the invitation
+    /// roundtrip. Writes a bootstrap file (without secrets) for the peer to install.
+    PreRegister {
+        interface: Interface,
+
+        #[clap(flatten)]
+        args: PreRegisterOpts,
+    },
+
     /// Rename an existing peer.
     RenamePeer {
         interface: Interface,
@@ -105,6 +130,14 @@ enum Command {
         args: AddCidrOpts,
     },
 
+    /// Rename a CIDR.
+    RenameCidr {
+        interface: Interface,
+
+        #[clap(flatten)]
+        args: RenameCidrOpts,
+    },
+
     /// Delete a CIDR.
     DeleteCidr {
         interface: Interface,
@@ -120,13 +153,126 @@ enum Command {
     },
 }
 
+#[derive(Debug, Clone, Args)]
+struct ServeOpts {
+    /// Start in read-only maintenance mode, rejecting mutating requests until
+    /// an admin turns it off
+    #[clap(long)]
+    read_only: bool,
+
+    /// Give peers that the server has observed behind NAT a persistent keepalive of this
+    /// many seconds when they don't already have one. Other peers keep their own settings
+    #[clap(long, value_name = "SECS")]
+    nat_keepalive: Option<u16>,
+
+    /// Log a warning when adding a peer fills its CIDR to this percentage or more
+    #[clap(long, value_name = "PERCENT")]
+    cidr_usage_warning: Option<u8>,
+
+    /// Stop handing out a peer's observed endpoint once it hasn't handshaked from it in this
+    /// many seconds, and drop the candidates a peer reported once it hasn't re-reported
+    /// them in as long, so roaming peers aren't tried at stale addresses. Overridden
+    /// endpoints and candidates set by an admin never expire. By default, nothing does
+    #[clap(long, value_name = "SECS")]
+    observed_endpoint_ttl: Option<u64>,
+
+    /// Delete entries from the /v1/admin/events change feed once they're this many days
+    /// old. 0 keeps them forever
+    #[clap(
+        long,
+        value_name = "DAYS",
+        default_value = "90",
+        parse(try_from_str = util::parse_days)
+    )]
+    event_retention: u64,
+
+    #[clap(flatten)]
+    liveness: LivenessOpts,
+
+    #[clap(flatten)]
+    metrics: MetricsOpts,
+
+    #[clap(flatten)]
+    connections: ConnectionOpts,
+}
+
 pub type Db = Arc<Mutex<Connection>>;
-pub type Endpoints = Arc<RwLock<HashMap<String, SocketAddr>>>;
+pub type Endpoints = Arc<RwLock<HashMap<String, ObservedEndpoint>>>;
+/// Addresses handed out by the next-ips endpoint.
+pub type Reservations = Arc<RwLock<HashMap<IpAddr, Reservation>>>;
+
+/// An address held by a next-ips request, which only the admin that made it can add a peer
+/// with until it expires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reservation {
+    pub expires: Instant,
+    /// The ID of the admin peer that made the request.
+    pub reserved_by: i64,
+}
+
+/// The addresses currently held by unexpired reservations.
+pub fn reserved_ips(reservations: &Reservations) -> Vec<IpAddr> {
+    let now = Instant::now();
+    reservations
+        .read()
+        .iter()
+        .filter(|(_, reservation)| reservation.expires > now)
+        .map(|(ip, _)| *ip)
+        .collect()
+}
+
+/// The address the server last saw a peer's traffic come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObservedEndpoint {
+    pub addr: SocketAddr,
+    /// When the peer was last seen at `addr`.
+    pub seen_at: SystemTime,
+}
+
+impl ObservedEndpoint {
+    /// Update a peer's observed endpoint from its latest handshake, if it had one. Otherwise
+    /// it's been seen at `addr` since it first showed up there.
+    pub fn refresh(
+        previous: Option<&Self>,
+        addr: SocketAddr,
+        last_handshake: Option<SystemTime>,
+        now: SystemTime,
+    ) -> Self {
+        let seen_at = match (last_handshake, previous) {
+            (Some(handshake), _) => handshake,
+            (None, Some(previous)) if previous.addr == addr => previous.seen_at,
+            (None, _) => now,
+        };
+        Self { addr, seen_at }
+    }
+
+    pub fn is_fresh(&self, ttl: Option<Duration>, now: SystemTime) -> bool {
+        match ttl {
+            Some(ttl) => now
+                .duration_since(self.seen_at)
+                .map_or(true, |age| age <= ttl),
+            None => true,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Context {
     pub db: Db,
-    pub endpoints: Arc<RwLock<HashMap<String, SocketAddr>>>,
+    pub endpoints: Endpoints,
+    /// How long observed endpoints stay valid for, see [`ObservedEndpoint::is_fresh`].
+    pub observed_endpoint_ttl: Option<Duration>,
+    pub reservations: Reservations,
+    /// When set, mutating requests are rejected (see [`is_mutating`]).
+    pub read_only: Arc<AtomicBool>,
+    /// See [`api::suggest_keepalives`].
+    pub nat_keepalive: Option<u16>,
+    /// The CIDR usage percentage to warn about when crossed by a new peer.
+    pub cidr_usage_warning: Option<u8>,
+    /// See [`ConfigFile::allowed_endpoint_ports`].
+    pub allowed_endpoint_ports: EndpointPortPolicy,
+    /// Limits each peer's requests, see [`ConfigFile::rate_limits`].
+    pub rate_limiter: RateLimiter,
     pub interface: InterfaceName,
     pub backend: Backend,
     pub public_key: Key,
@@ -165,6 +311,17 @@ pub struct ConfigFile {
 
     /// The CIDR prefix of the WireGuard network
     pub network_cidr_prefix: u8,
+
+    /// The ports peers may report NAT traversal candidates or override their endpoint on, ex.
+    /// `["51820", "51000-51999"]`. Candidates on other ports are dropped, and endpoint
+    /// overrides on them are rejected. By default, any port is allowed.
+    #[serde(default, skip_serializing_if = "EndpointPortPolicy::is_unrestricted")]
+    pub allowed_endpoint_ports: EndpointPortPolicy,
+
+    /// How many requests each peer may make, with separate limits for the user and admin
+    /// endpoints. Peers over a limit are answered with 429 Too Many Requests.
+    #[serde(default, skip_serializing_if = "RateLimits::is_unlimited")]
+    pub rate_limits: RateLimits,
 }
 
 impl ConfigFile {
@@ -187,7 +344,7 @@ impl ConfigFile {
                 path.display()
             );
         }
-        Ok(toml::from_slice(&std::fs::read(&path).with_path(path)?)?)
+        Ok(toml::from_slice(&std::fs::read(path).with_path(path)?)?)
     }
 }
 
@@ -226,6 +383,34 @@ impl ServerConfig {
             .join(interface.to_string())
             .with_extension("conf")
     }
+
+    /// Find another network in the config directory that's configured with `listen_port`.
+    /// The kernel would refuse to bring up a second interface on the same port.
+    fn listen_port_owner(
+        &self,
+        interface: &InterfaceName,
+        listen_port: u16,
+    ) -> Result<Option<String>, Error> {
+        for entry in std::fs::read_dir(self.config_dir()).with_path(self.config_dir())? {
+            let path = entry?.path();
+            let name = match (path.extension(), path.file_stem()) {
+                (Some(extension), Some(stem)) if extension == "conf" => {
+                    stem.to_string_lossy().to_string()
+                },
+                _ => continue,
+            };
+            if name == interface.to_string() {
+                continue;
+            }
+            let contents = std::fs::read(&path).with_path(&path)?;
+            match toml::from_slice::<ConfigFile>(&contents) {
+                Ok(config) if config.listen_port == listen_port => return Ok(Some(name)),
+                Ok(_) => {},
+                Err(e) => log::debug!("skipping {}: {}", path.display(), e),
+            }
+        }
+        Ok(None)
+    }
 }
 
 #[tokio::main]
@@ -255,10 +440,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Command::Serve {
             interface,
             network: routing,
-        } => serve(*interface, &conf, routing).await?,
+            opts,
+        } => serve(*interface, &conf, routing, opts).await?,
         Command::AddPeer { interface, args } => add_peer(&interface, &conf, args, opts.network)?,
+        Command::PreRegister { interface, args } => {
+            pre_register(&interface, &conf, args, opts.network)?
+        },
         Command::RenamePeer { interface, args } => rename_peer(&interface, &conf, args)?,
         Command::AddCidr { interface, args } => add_cidr(&interface, &conf, args)?,
+        Command::RenameCidr { interface, args } => rename_cidr(&interface, &conf, args)?,
         Command::DeleteCidr { interface, args } => delete_cidr(&interface, &conf, args)?,
         Command::Completions { shell } => {
             let mut app = Opts::command();
@@ -285,7 +475,7 @@ fn open_database_connection(
 
     let conn = Connection::open(&database_path)?;
     // Foreign key constraints aren't on in SQLite by default. Enable.
-    conn.pragma_update(None, "foreign_keys", &1)?;
+    conn.pragma_update(None, "foreign_keys", 1)?;
     db::auto_migrate(&conn)?;
     Ok(conn)
 }
@@ -323,7 +513,7 @@ fn add_peer(
             (&mut target_file, &target_path),
             interface,
             &peer,
-            &*server_peer,
+            &server_peer,
             &cidr_tree,
             keypair,
             &SocketAddr::new(config.address, config.listen_port),
@@ -335,6 +525,98 @@ fn add_peer(
     Ok(())
 }
 
+fn pre_register(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    opts: PreRegisterOpts,
+    network: NetworkOpts,
+) -> Result<(), Error> {
+    let public_key = Key::from_base64(opts.public_key.trim())
+        .map_err(|_| anyhow!("'{}' isn't a valid WireGuard public key.", opts.public_key))?;
+    let config = ConfigFile::from_file(conf.config_path(interface))?;
+    let conn = open_database_connection(interface, conf)?;
+    let peers = DatabasePeer::list(&conn)?
+        .into_iter()
+        .map(|dp| dp.inner)
+        .collect::<Vec<_>>();
+    let cidrs = DatabaseCidr::list(&conn)?;
+    let cidr_tree = CidrTree::new(&cidrs[..]);
+
+    if peers
+        .iter()
+        .any(|peer| peer.public_key == public_key.to_base64())
+    {
+        bail!("a peer with that public key already exists.");
+    }
+    let leaves = cidr_tree.leaves();
+    let cidr = leaves
+        .iter()
+        .find(|cidr| cidr.name == opts.cidr)
+        .ok_or_else(|| anyhow!("No eligible CIDR with that name exists."))?;
+    let is_taken = |ip: &IpAddr| peers.iter().any(|peer| peer.covers(ip));
+    let ip = match opts.ip {
+        Some(ip) if !cidr.is_assignable(&ip) => bail!("{} isn't assignable in {}.", ip, cidr.cidr),
+        Some(ip) if is_taken(&ip) => bail!("{} is already in use.", ip),
+        Some(ip) => ip,
+        None => shared::free_ips(cidr, is_taken)
+            .next()
+            .ok_or_else(|| anyhow!("No IPs in {} are available.", cidr.name))?,
+    };
+
+    let target_path = opts
+        .save_config
+        .unwrap_or_else(|| format!("{}.toml", opts.name));
+    if Path::new(&target_path).exists() {
+        bail!("{} already exists.", target_path);
+    }
+
+    // The key is final, so the peer is created as already redeemed. The bootstrap file is
+    // only written once the peer exists, so a failed insert doesn't leave one behind.
+    let peer = DatabasePeer::create(
+        &conn,
+        PeerContents {
+            name: opts.name,
+            ip,
+            cidr_id: cidr.id,
+            public_key: public_key.to_base64(),
+            endpoint: None,
+            persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
+            is_admin: opts.admin,
+            is_disabled: false,
+            is_redeemed: true,
+            invite_expires: None,
+            candidates: vec![],
+            extra_allowed_ips: vec![],
+            deleted_at: None,
+        },
+    )?;
+    if Device::get(interface, network.backend).is_ok() {
+        DeviceUpdate::new()
+            .add_peer(PeerConfigBuilder::from(&*peer))
+            .apply(interface, network.backend)
+            .map_err(|_| ServerError::WireGuard)?;
+
+        println!("adding to WireGuard interface: {}", &*peer);
+    }
+
+    let server_peer = DatabasePeer::get(&conn, 1)?;
+    let mut target_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&target_path)
+        .with_path(&target_path)?;
+    prompts::write_peer_bootstrap(
+        (&mut target_file, &target_path),
+        interface,
+        &peer,
+        &server_peer,
+        &cidr_tree,
+        &SocketAddr::new(config.address, config.listen_port),
+    )?;
+
+    Ok(())
+}
+
 fn rename_peer(
     interface: &InterfaceName,
     conf: &ServerConfig,
@@ -351,7 +633,7 @@ fn rename_peer(
             .into_iter()
             .find(|p| p.name == old_name)
             .ok_or_else(|| anyhow!("Peer not found."))?;
-        let _peer = db_peer.update(&conn, peer_request)?;
+        db_peer.update(&conn, peer_request)?;
     } else {
         println!("exited without creating peer.");
     }
@@ -386,6 +668,23 @@ fn add_cidr(
     Ok(())
 }
 
+fn rename_cidr(
+    interface: &InterfaceName,
+    conf: &ServerConfig,
+    args: RenameCidrOpts,
+) -> Result<(), Error> {
+    let conn = open_database_connection(interface, conf)?;
+    let cidrs = DatabaseCidr::list(&conn)?;
+    if let Some((id, cidr_request)) = prompts::rename_cidr(&cidrs, &args)? {
+        let cidr = DatabaseCidr::update(&conn, id, cidr_request)?;
+        println!("CIDR renamed to {}.", cidr.name.bold());
+    } else {
+        println!("exited without renaming CIDR.");
+    }
+
+    Ok(())
+}
+
 fn delete_cidr(
     interface: &InterfaceName,
     conf: &ServerConfig,
@@ -402,7 +701,7 @@ fn delete_cidr(
     let cidr_id = prompts::delete_cidr(&cidrs, &peers, &args)?;
 
     println!("Deleting CIDR...");
-    let _ = DatabaseCidr::delete(&conn, cidr_id)?;
+    DatabaseCidr::delete(&conn, cidr_id)?;
 
     println!("CIDR deleted.");
 
@@ -415,7 +714,7 @@ fn uninstall(
     network: NetworkOpts,
 ) -> Result<(), Error> {
     if Confirm::with_theme(&*prompts::THEME)
-        .with_prompt(&format!(
+        .with_prompt(format!(
             "Permanently delete network \"{}\"?",
             interface.as_str_lossy().yellow()
         ))
@@ -452,11 +751,18 @@ fn spawn_endpoint_refresher(interface: InterfaceName, network: NetworkOpts) -> E
             loop {
                 interval.tick().await;
                 if let Ok(info) = Device::get(&interface, network.backend) {
+                    let now = SystemTime::now();
+                    let mut endpoints = endpoints.write();
                     for peer in info.peers {
-                        if let Some(endpoint) = peer.config.endpoint {
-                            endpoints
-                                .write()
-                                .insert(peer.config.public_key.to_base64(), endpoint);
+                        if let Some(addr) = peer.config.endpoint {
+                            let public_key = peer.config.public_key.to_base64();
+                            let observed = ObservedEndpoint::refresh(
+                                endpoints.get(&public_key),
+                                addr,
+                                peer.stats.last_handshake_time,
+                                now,
+                            );
+                            endpoints.insert(public_key, observed);
                         }
                     }
                 }
@@ -466,6 +772,36 @@ fn spawn_endpoint_refresher(interface: InterfaceName, network: NetworkOpts) -> E
     endpoints
 }
 
+fn spawn_stale_candidate_sweeper(db: Db, ttl: Duration) {
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            match DatabasePeer::expire_candidates(&db.lock(), ttl, SystemTime::now()) {
+                Ok(expired) if expired > 0 => {
+                    log::info!("Dropped the stale candidates of {} peers.", expired)
+                },
+                Err(e) => log::error!("Failed to drop stale candidates: {}", e),
+                _ => {},
+            }
+        }
+    });
+}
+
+fn spawn_event_pruner(db: Db, retention: Duration) {
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            match DatabaseEvent::prune(&db.lock(), SystemTime::now() - retention) {
+                Ok(deleted) if deleted > 0 => log::info!("Pruned {} old events.", deleted),
+                Err(e) => log::error!("Failed to prune old events: {}", e),
+                _ => {},
+            }
+        }
+    });
+}
+
 fn spawn_expired_invite_sweeper(db: Db) {
     tokio::task::spawn(async move {
         let mut interval = tokio::time::interval(Duration::from_secs(10));
@@ -486,15 +822,43 @@ async fn serve(
     interface: InterfaceName,
     conf: &ServerConfig,
     network: NetworkOpts,
+    opts: ServeOpts,
 ) -> Result<(), Error> {
+    let ServeOpts {
+        read_only,
+        nat_keepalive,
+        cidr_usage_warning,
+        observed_endpoint_ttl,
+        event_retention,
+        liveness,
+        metrics,
+        connections,
+    } = opts;
     let config = ConfigFile::from_file(conf.config_path(&interface))?;
+    if let Some(other) = conf.listen_port_owner(&interface, config.listen_port)? {
+        bail!(
+            "network {} is also configured to listen on port {}, give each network its own listen_port in {}.",
+            other,
+            config.listen_port,
+            conf.config_dir().display()
+        );
+    }
     log::debug!("opening database connection...");
     let conn = open_database_connection(&interface, conf)?;
 
     let mut peers = DatabasePeer::list(&conn)?;
     log::debug!("peers listed...");
+    let all_peers: Vec<Peer> = peers.iter().map(|peer| peer.inner.clone()).collect();
+    for duplicate in DuplicateIp::find(&all_peers) {
+        log::warn!(
+            "IP {} is assigned to multiple peers ({}); routing to them will be unreliable.",
+            duplicate.ip,
+            duplicate.peers.join(", ")
+        );
+    }
     let peer_configs = peers
         .iter()
+        .filter(|peer| peer.deleted_at.is_none())
         .map(|peer| peer.deref().into())
         .collect::<Vec<PeerConfigBuilder>>();
 
@@ -506,13 +870,18 @@ async fn serve(
         Some(config.listen_port),
         None,
         network,
+        network.mtu_or(None),
     )?;
 
+    // The interface may have outlived the last run, so keep the stats of peers that haven't
+    // changed since, and drop ones the database no longer has.
+    let device = Device::get(&interface, network.backend)?;
     DeviceUpdate::new()
+        .replace_peers_preserving(&device)
         .add_peers(&peer_configs)
         .apply(&interface, network.backend)?;
 
-    log::info!("{} peers added to wireguard interface.", peers.len());
+    log::info!("{} peers added to wireguard interface.", peer_configs.len());
 
     let candidates: Vec<Endpoint> = get_local_addrs()?
         .map(|addr| SocketAddr::from((addr, config.listen_port)).into())
@@ -539,33 +908,62 @@ async fn serve(
     let db = Arc::new(Mutex::new(conn));
     let endpoints = spawn_endpoint_refresher(interface, network);
     spawn_expired_invite_sweeper(db.clone());
+    if let Some(ttl) = observed_endpoint_ttl {
+        spawn_stale_candidate_sweeper(db.clone(), Duration::from_secs(ttl));
+    }
+    if event_retention > 0 {
+        spawn_event_pruner(db.clone(), util::days(event_retention));
+    }
+    if let Some(policy) = liveness.policy() {
+        spawn_liveness_monitor(
+            interface,
+            network,
+            db.clone(),
+            policy,
+            liveness.liveness_webhook,
+        );
+    }
+    if let Some(addr) = metrics.metrics_listen {
+        spawn_metrics_server(
+            addr,
+            interface,
+            network,
+            db.clone(),
+            util::minutes(metrics.metrics_stale_after),
+        );
+    }
 
     let context = Context {
         db,
         endpoints,
+        observed_endpoint_ttl: observed_endpoint_ttl.map(Duration::from_secs),
+        reservations: Default::default(),
+        read_only: Arc::new(AtomicBool::new(read_only)),
+        nat_keepalive,
+        cidr_usage_warning,
+        allowed_endpoint_ports: config.allowed_endpoint_ports.clone(),
+        rate_limiter: RateLimiter::new(config.rate_limits.clone()),
         interface,
         public_key,
         backend: network.backend,
     };
+    if read_only {
+        log::warn!("starting in read-only maintenance mode.");
+    }
 
     log::info!("innernet-server {} starting.", VERSION);
 
     let listener = get_listener((config.address, config.listen_port).into(), &interface)?;
 
-    let make_svc = hyper::service::make_service_fn(move |socket: &AddrStream| {
-        let remote_addr = socket.remote_addr();
-        let context = context.clone();
-        async move {
-            Ok::<_, http::Error>(hyper::service::service_fn(move |req: Request<Body>| {
-                log::debug!("{} - {} {}", &remote_addr, req.method(), req.uri());
-                hyper_service(req, context.clone(), remote_addr)
-            }))
-        }
-    });
-
-    let server = hyper::Server::from_tcp(listener)?.serve(make_svc);
-
-    server.await?;
+    connections::serve(
+        tokio::net::TcpListener::from_std(listener)?,
+        connections,
+        move |req, remote_addr| {
+            log::debug!("{} - {} {}", &remote_addr, req.method(), req.uri());
+            hyper_service(req, context.clone(), remote_addr)
+        },
+    )
+    .await?;
 
     Ok(())
 }
@@ -578,7 +976,7 @@ async fn serve(
 /// See https://github.com/tonarino/innernet/issues/26 for more details.
 #[cfg(target_os = "linux")]
 fn get_listener(addr: SocketAddr, interface: &InterfaceName) -> Result<TcpListener, Error> {
-    let listener = TcpListener::bind(&addr)?;
+    let listener = TcpListener::bind(addr)?;
     listener.set_nonblocking(true)?;
     let sock = socket2::Socket::from(listener);
     sock.bind_device(Some(interface.as_str_lossy().as_bytes()))?;
@@ -612,9 +1010,17 @@ pub(crate) async fn hyper_service(
         .map(String::from)
         .collect();
 
-    routes(req, context, remote_addr, components)
+    let mut response = routes(req, context, remote_addr, components)
         .await
-        .or_else(TryInto::try_into)
+        .or_else(TryInto::try_into)?;
+
+    // Let clients detect clock skew, which breaks invite expiry and handshake recency.
+    if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        response
+            .headers_mut()
+            .insert(INNERNET_SERVER_TIME_HEADER, now.as_secs().into());
+    }
+    Ok(response)
 }
 
 async fn routes(
@@ -628,7 +1034,21 @@ async fn routes(
         Err(ServerError::NotFound)
     } else {
         let session = get_session(&req, context, remote_addr.ip())?;
+        if session.context.read_only.load(Ordering::Relaxed)
+            && is_mutating(req.method(), &components)
+        {
+            return Err(ServerError::ReadOnly);
+        }
         let component = components.pop_front();
+        let scope = match component.as_deref() {
+            Some("admin") => Scope::Admin,
+            _ => Scope::User,
+        };
+        session
+            .context
+            .rate_limiter
+            .check(scope, &session.peer.public_key, Instant::now())
+            .map_err(ServerError::TooManyRequests)?;
         match component.as_deref() {
             Some("user") => api::user::routes(req, components, session).await,
             Some("admin") => api::admin::routes(req, components, session).await,
@@ -637,6 +1057,22 @@ async fn routes(
     }
 }
 
+/// Whether a request under `/v1/` changes server state, and so is rejected in
+/// read-only mode.
+///
+/// Only `GET` requests (fetching the network state, listing peers, CIDRs and
+/// associations) are read-only. Everything else is mutating: redeeming invites,
+/// reporting endpoints and NAT candidates, and the admin create, update and delete
+/// requests, including next-ips reservations. The exception is
+/// `PUT /v1/admin/maintenance`, so the mode can be turned back off.
+fn is_mutating(method: &Method, components: &VecDeque<String>) -> bool {
+    let is_maintenance = components
+        .iter()
+        .map(String::as_str)
+        .eq(["admin", "maintenance"]);
+    method != Method::GET && !is_maintenance
+}
+
 fn get_session(
     req: &Request<Body>,
     context: Context,
@@ -674,6 +1110,70 @@ mod tests {
     use hyper::StatusCode;
     use std::path::Path;
 
+    #[test]
+    fn test_event_retention_overflow() {
+        let serve = |retention: &str| {
+            Opts::try_parse_from([
+                "innernet-server",
+                "serve",
+                "evilcorp",
+                "--event-retention",
+                retention,
+            ])
+        };
+        assert!(serve("36500").is_ok());
+        assert!(serve(&(u64::MAX / (24 * 60 * 60) + 1).to_string()).is_err());
+    }
+
+    #[test]
+    fn test_observed_endpoint_ttl() {
+        let now = SystemTime::now();
+        let addr: SocketAddr = "1.2.3.4:51820".parse().unwrap();
+        let roamed: SocketAddr = "5.6.7.8:51820".parse().unwrap();
+        let handshake = now - Duration::from_secs(200);
+
+        let observed = ObservedEndpoint::refresh(None, addr, Some(handshake), now);
+        assert_eq!(observed.seen_at, handshake);
+        assert!(observed.is_fresh(None, now));
+        assert!(observed.is_fresh(Some(Duration::from_secs(300)), now));
+        assert!(!observed.is_fresh(Some(Duration::from_secs(100)), now));
+
+        // Without a handshake, the endpoint keeps the age it had, unless it changed.
+        let later = now + Duration::from_secs(60);
+        let kept = ObservedEndpoint::refresh(Some(&observed), addr, None, later);
+        assert_eq!(kept.seen_at, handshake);
+        let moved = ObservedEndpoint::refresh(Some(&observed), roamed, None, later);
+        assert_eq!(moved.seen_at, later);
+    }
+
+    #[test]
+    fn test_listen_port_owner() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let conf = ServerConfig::new(dir.path().to_path_buf(), dir.path().to_path_buf());
+        let config = |listen_port| ConfigFile {
+            private_key: Key::generate_private().to_base64(),
+            listen_port,
+            address: "10.0.0.1".parse().unwrap(),
+            network_cidr_prefix: 16,
+            allowed_endpoint_ports: Default::default(),
+            rate_limits: Default::default(),
+        };
+        let (evilcorp, othernet) = ("evilcorp".parse()?, "othernet".parse()?);
+        config(51820).write_to_path(conf.config_path(&evilcorp))?;
+        std::fs::write(dir.path().join("notes.conf"), "not a network")?;
+
+        // A network's own config doesn't count.
+        assert_eq!(conf.listen_port_owner(&evilcorp, 51820)?, None);
+        assert_eq!(
+            conf.listen_port_owner(&othernet, 51820)?,
+            Some("evilcorp".into())
+        );
+        config(51821).write_to_path(conf.config_path(&othernet))?;
+        assert_eq!(conf.listen_port_owner(&othernet, 51821)?, None);
+
+        Ok(())
+    }
+
     #[test]
     fn test_init_wizard() -> Result<(), Error> {
         // This runs init_wizard().
@@ -684,6 +1184,46 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_pre_register() -> Result<(), Error> {
+        let server = test::Server::new()?;
+        let dir = tempfile::tempdir()?;
+        let network = NetworkOpts {
+            no_routing: true,
+            backend: Default::default(),
+            mtu: None,
+        };
+        let opts = |name: &str| -> Result<PreRegisterOpts, Error> {
+            Ok(PreRegisterOpts {
+                public_key: Key::generate_private().get_public().to_base64(),
+                name: name.parse().map_err(|e: &str| anyhow!(e))?,
+                cidr: "developer".into(),
+                ip: None,
+                admin: false,
+                save_config: Some(dir.path().join(name).to_string_lossy().into()),
+            })
+        };
+        let peer_count = || DatabasePeer::list(&server.db().lock()).map(|peers| peers.len());
+        let old_count = peer_count()?;
+
+        // The name is taken, so the insert fails and no bootstrap file is left behind.
+        let taken = opts("developer1")?;
+        assert!(pre_register(server.interface(), server.conf(), taken, network).is_err());
+        assert!(!dir.path().join("developer1").exists());
+        assert_eq!(peer_count()?, old_count);
+
+        pre_register(
+            server.interface(),
+            server.conf(),
+            opts("developer3")?,
+            network,
+        )?;
+        assert!(dir.path().join("developer3").exists());
+        assert_eq!(peer_count()?, old_count + 1);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_with_session_disguised_with_headers() -> Result<(), Error> {
         let server = test::Server::new()?;
@@ -716,6 +1256,66 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_server_time_header() -> Result<(), Error> {
+        let server = test::Server::new()?;
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+        for path in ["/v1/user/state", "/v1/nonexistent"] {
+            let res = server.request(test::USER1_PEER_IP, "GET", path).await;
+            let server_time: u64 = res
+                .headers()
+                .get(INNERNET_SERVER_TIME_HEADER)
+                .expect("server time header is always set")
+                .to_str()?
+                .parse()?;
+            assert!(server_time.abs_diff(now) <= 1);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits() -> Result<(), Error> {
+        let mut server = test::Server::new()?;
+        server.set_rate_limits(RateLimits {
+            user: Some(rate_limit::RateLimit {
+                burst: 1,
+                per_minute: 1,
+            }),
+            admin: None,
+        });
+
+        let res = server
+            .request(test::USER1_PEER_IP, "GET", "/v1/user/state")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = server
+            .request(test::USER1_PEER_IP, "GET", "/v1/user/state")
+            .await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = res.headers()[hyper::header::RETRY_AFTER]
+            .to_str()?
+            .parse()?;
+        assert!((59..=60).contains(&retry_after), "{}", retry_after);
+
+        // Each peer has its own allowance, and the admin endpoints aren't limited.
+        let res = server
+            .request(test::USER2_PEER_IP, "GET", "/v1/user/state")
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        for _ in 0..3 {
+            let res = server
+                .request(test::ADMIN_PEER_IP, "GET", "/v1/admin/peers")
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_incorrect_public_key() -> Result<(), Error> {
         let server = test::Server::new()?;
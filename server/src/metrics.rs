@@ -0,0 +1,254 @@
+//! Prometheus metrics of the server's WireGuard peers, served on a separate (by default
+//! disabled) listener so they aren't exposed to the whole network.
+
+use crate::{db::DatabasePeer, util::parse_minutes, Db};
+use clap::Args;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use shared::NetworkOpts;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Write,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+use wireguard_control::{Device, InterfaceName, PeerInfo};
+
+#[derive(Debug, Clone, Args)]
+pub struct MetricsOpts {
+    /// Serve Prometheus metrics of the interface's peers at http://<ADDR>/metrics, ex.
+    /// 127.0.0.1:9586. Disabled by default
+    #[clap(long, value_name = "ADDR")]
+    pub metrics_listen: Option<SocketAddr>,
+
+    /// Count peers that haven't handshaked with the server in this many minutes as stale
+    #[clap(
+        long,
+        value_name = "MINS",
+        default_value = "5",
+        parse(try_from_str = parse_minutes)
+    )]
+    pub metrics_stale_after: u64,
+}
+
+/// Quote a Prometheus label value.
+fn label(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+/// Render the metrics of an interface's peers in the Prometheus text format. `names` maps
+/// public keys to peer names.
+pub fn render(
+    interface: &InterfaceName,
+    peers: &[PeerInfo],
+    names: &HashMap<String, String>,
+    stale_after: Duration,
+    now: SystemTime,
+) -> String {
+    let interface = label(&interface.to_string());
+    let peers: Vec<_> = peers
+        .iter()
+        .map(|peer| {
+            let public_key = peer.config.public_key.to_base64();
+            let name = names.get(&public_key).map(String::as_str).unwrap_or("");
+            let labels = format!(
+                "interface={},name={},public_key={}",
+                interface,
+                label(name),
+                label(&public_key)
+            );
+            let handshake_age = peer
+                .stats
+                .last_handshake_time
+                .map(|time| now.duration_since(time).unwrap_or_default());
+            (labels, peer, handshake_age)
+        })
+        .collect();
+
+    let mut out = String::new();
+    let mut gauge = |name: &str, help: &str, samples: &mut dyn Iterator<Item = (&str, u64)>| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    };
+    gauge(
+        "innernet_peer_rx_bytes",
+        "Bytes received from the peer since the interface came up.",
+        &mut peers
+            .iter()
+            .map(|(labels, peer, _)| (labels.as_str(), peer.stats.rx_bytes)),
+    );
+    gauge(
+        "innernet_peer_tx_bytes",
+        "Bytes sent to the peer since the interface came up.",
+        &mut peers
+            .iter()
+            .map(|(labels, peer, _)| (labels.as_str(), peer.stats.tx_bytes)),
+    );
+    gauge(
+        "innernet_peer_last_handshake_age_seconds",
+        "Seconds since the peer's latest handshake. Missing for peers that never handshaked.",
+        &mut peers
+            .iter()
+            .filter_map(|(labels, _, age)| age.map(|age| (labels.as_str(), age.as_secs()))),
+    );
+    let stale = peers
+        .iter()
+        .filter(|(_, _, age)| age.is_none_or(|age| age > stale_after))
+        .count();
+    let interface_labels = format!("interface={}", interface);
+    gauge(
+        "innernet_stale_peers",
+        &format!(
+            "Peers without a handshake in the last {} minutes.",
+            stale_after.as_secs() / 60
+        ),
+        &mut std::iter::once((interface_labels.as_str(), stale as u64)),
+    );
+    out
+}
+
+fn metrics(
+    interface: &InterfaceName,
+    network: NetworkOpts,
+    db: &Db,
+    stale_after: Duration,
+) -> Result<String, anyhow::Error> {
+    let device = Device::get(interface, network.backend)?;
+    let names = DatabasePeer::list(&db.lock())?
+        .into_iter()
+        .map(|peer| (peer.public_key.clone(), peer.name.to_string()))
+        .collect();
+    Ok(render(
+        interface,
+        &device.peers,
+        &names,
+        stale_after,
+        SystemTime::now(),
+    ))
+}
+
+pub fn spawn_metrics_server(
+    addr: SocketAddr,
+    interface: InterfaceName,
+    network: NetworkOpts,
+    db: Db,
+    stale_after: Duration,
+) {
+    let make_svc = hyper::service::make_service_fn(move |_| {
+        let db = db.clone();
+        async move {
+            Ok::<_, Infallible>(hyper::service::service_fn(move |req: Request<Body>| {
+                let db = db.clone();
+                async move {
+                    match (req.method(), req.uri().path()) {
+                        (&Method::GET, "/metrics") => {
+                            // Reading the interface and locking the database both block.
+                            let collected = tokio::task::spawn_blocking(move || {
+                                metrics(&interface, network, &db, stale_after)
+                            })
+                            .await
+                            .map_err(anyhow::Error::from)
+                            .and_then(|collected| collected);
+                            match collected {
+                                Ok(metrics) => Response::builder()
+                                    .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                                    .body(Body::from(metrics)),
+                                Err(e) => {
+                                    log::error!("failed to collect metrics: {}", e);
+                                    Response::builder()
+                                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                                        .body(Body::empty())
+                                },
+                            }
+                        },
+                        _ => Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty()),
+                    }
+                }
+            }))
+        }
+    });
+
+    tokio::task::spawn(async move {
+        let server = match hyper::Server::try_bind(&addr) {
+            Ok(server) => server,
+            Err(e) => {
+                log::error!("failed to serve metrics on {}: {}", addr, e);
+                return;
+            },
+        };
+        log::info!("serving metrics on http://{}/metrics.", addr);
+        if let Err(e) = server.serve(make_svc).await {
+            log::error!("metrics server failed: {}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wireguard_control::{KeyPair, PeerConfigBuilder, PeerStats};
+
+    #[test]
+    fn test_render_metrics() {
+        let now = SystemTime::now();
+        let keys: Vec<_> = (0..3).map(|_| KeyPair::generate().public).collect();
+        let peer = |i: usize, last_handshake_time: Option<SystemTime>| PeerInfo {
+            config: PeerConfigBuilder::new(&keys[i]).into_peer_config(),
+            stats: PeerStats {
+                last_handshake_time,
+                rx_bytes: 100 * i as u64,
+                tx_bytes: 10 * i as u64,
+            },
+        };
+        let peers = [
+            peer(0, Some(now - Duration::from_secs(30))),
+            peer(1, Some(now - Duration::from_secs(3600))),
+            peer(2, None),
+        ];
+        let names = [(keys[0].to_base64(), "alice".to_string())].into();
+
+        let metrics = render(
+            &"innernet".parse().unwrap(),
+            &peers,
+            &names,
+            Duration::from_secs(300),
+            now,
+        );
+        let labels = |i: usize, name: &str| {
+            format!(
+                "{{interface=\"innernet\",name=\"{}\",public_key=\"{}\"}}",
+                name,
+                keys[i].to_base64()
+            )
+        };
+        for line in [
+            format!("innernet_peer_rx_bytes{} 0", labels(0, "alice")),
+            format!("innernet_peer_tx_bytes{} 20", labels(2, "")),
+            format!(
+                "innernet_peer_last_handshake_age_seconds{} 30",
+                labels(0, "alice")
+            ),
+            format!(
+                "innernet_peer_last_handshake_age_seconds{} 3600",
+                labels(1, "")
+            ),
+            "# HELP innernet_stale_peers Peers without a handshake in the last 5 minutes.".into(),
+            "innernet_stale_peers{interface=\"innernet\"} 2".into(),
+        ] {
+            assert!(metrics.lines().any(|l| l == line), "{} missing", line);
+        }
+        // Peers that never handshaked don't get a handshake age.
+        assert!(!metrics.contains(&format!("seconds{}", labels(2, ""))));
+
+        assert_eq!(label("a\"b\\c\nd"), r#""a\"b\\c\nd""#);
+    }
+}
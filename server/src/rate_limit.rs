@@ -0,0 +1,141 @@
+//! Per-peer token buckets, so one misbehaving client can't hammer the API and degrade the
+//! server for everyone else.
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// A token bucket's size and refill rate.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// How many requests a peer can make at once.
+    pub burst: u32,
+
+    /// How many requests a peer's allowance refills by each minute.
+    pub per_minute: u32,
+}
+
+/// The `[rate_limits]` section of a network's config file, ex.
+///
+/// ```toml
+/// [rate_limits.user]
+/// burst = 30
+/// per_minute = 60
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RateLimits {
+    /// The limit on each peer's `/v1/user` requests. Unlimited by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<RateLimit>,
+
+    /// The separate limit on each admin's `/v1/admin` requests. Unlimited by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin: Option<RateLimit>,
+}
+
+impl RateLimits {
+    pub fn is_unlimited(&self) -> bool {
+        self.user.is_none() && self.admin.is_none()
+    }
+}
+
+/// Which of the [`RateLimits`] a request counts against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    User,
+    Admin,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: Arc<Mutex<HashMap<(Scope, String), Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Count a request from the peer with `public_key` against its `scope` limit, returning
+    /// how long until it may make another if it's out of requests.
+    pub fn check(&self, scope: Scope, public_key: &str, now: Instant) -> Result<(), Duration> {
+        let limit = match scope {
+            Scope::User => self.limits.user,
+            Scope::Admin => self.limits.admin,
+        };
+        let limit = match limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let burst = f64::from(limit.burst);
+        let per_second = f64::from(limit.per_minute) / 60.0;
+
+        let mut buckets = self.buckets.lock();
+        let bucket = buckets
+            .entry((scope, public_key.to_string()))
+            .or_insert(Bucket {
+                tokens: burst,
+                refilled_at: now,
+            });
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * per_second).min(burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if per_second > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        } else {
+            Err(Duration::from_secs(60))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(RateLimits {
+            user: Some(RateLimit {
+                burst: 2,
+                per_minute: 6,
+            }),
+            admin: None,
+        });
+        let wait = |result: Result<(), Duration>| result.unwrap_err().as_secs_f64().round();
+        let now = Instant::now();
+        assert_eq!(limiter.check(Scope::User, "a", now), Ok(()));
+        assert_eq!(limiter.check(Scope::User, "a", now), Ok(()));
+        // Out of requests, with one coming back every 10 seconds.
+        assert_eq!(wait(limiter.check(Scope::User, "a", now)), 10.0);
+        let later = now + Duration::from_secs(4);
+        assert_eq!(wait(limiter.check(Scope::User, "a", later)), 6.0);
+        // Other peers and the admin endpoints have their own allowances.
+        assert_eq!(limiter.check(Scope::User, "b", later), Ok(()));
+        for _ in 0..100 {
+            assert_eq!(limiter.check(Scope::Admin, "a", later), Ok(()));
+        }
+
+        // Waiting out the window brings back the requests, up to the burst.
+        let recovered = now + Duration::from_secs(600);
+        assert_eq!(limiter.check(Scope::User, "a", recovered), Ok(()));
+        assert_eq!(limiter.check(Scope::User, "a", recovered), Ok(()));
+        assert!(limiter.check(Scope::User, "a", recovered).is_err());
+    }
+}
@@ -2,15 +2,21 @@
 use crate::{
     db::{DatabaseCidr, DatabasePeer},
     initialize::{init_wizard, InitializeOpts},
-    Context, Db, Endpoints, ServerConfig,
+    rate_limit::{RateLimiter, RateLimits},
+    Context, Db, Endpoints, Reservations, ServerConfig,
 };
 use anyhow::anyhow;
 use hyper::{header::HeaderValue, http, Body, Request, Response};
 use parking_lot::{Mutex, RwLock};
 use rusqlite::Connection;
 use serde::Serialize;
-use shared::{Cidr, CidrContents, Error, PeerContents};
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc};
+use shared::{Cidr, CidrContents, EndpointPortPolicy, Error, PeerContents};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc},
+};
 use tempfile::TempDir;
 use wireguard_control::{Backend, InterfaceName, Key, KeyPair};
 
@@ -71,6 +77,10 @@ pub const USER2_PEER_ID: i64 = 6;
 pub struct Server {
     pub db: Db,
     endpoints: Endpoints,
+    reservations: Reservations,
+    read_only: Arc<AtomicBool>,
+    rate_limiter: RateLimiter,
+    allowed_endpoint_ports: EndpointPortPolicy,
     interface: InterfaceName,
     conf: ServerConfig,
     public_key: Key,
@@ -98,13 +108,14 @@ impl Server {
             external_endpoint: Some("155.155.155.155:54321".parse().unwrap()),
             listen_port: Some(54321),
             auto_external_endpoint: false,
+            public_ip: Default::default(),
         };
         init_wizard(&conf, opts).map_err(|_| anyhow!("init_wizard failed"))?;
 
         let interface = interface.parse().unwrap();
         // Add developer CIDR and user CIDR and some peers for testing.
-        let db = Connection::open(&conf.database_path(&interface))?;
-        db.pragma_update(None, "foreign_keys", &1)?;
+        let db = Connection::open(conf.database_path(&interface))?;
+        db.pragma_update(None, "foreign_keys", 1)?;
         assert_eq!(ADMIN_CIDR_ID, create_cidr(&db, "admin", ADMIN_CIDR)?.id);
         assert_eq!(
             ADMIN_PEER_ID,
@@ -147,6 +158,10 @@ impl Server {
             conf,
             db,
             endpoints,
+            reservations: Default::default(),
+            read_only: Default::default(),
+            rate_limiter: Default::default(),
+            allowed_endpoint_ports: Default::default(),
             interface,
             public_key,
             _test_dir: test_dir,
@@ -162,6 +177,13 @@ impl Server {
             db: self.db.clone(),
             interface: self.interface,
             endpoints: self.endpoints.clone(),
+            observed_endpoint_ttl: None,
+            reservations: self.reservations.clone(),
+            read_only: self.read_only.clone(),
+            nat_keepalive: None,
+            cidr_usage_warning: None,
+            allowed_endpoint_ports: self.allowed_endpoint_ports.clone(),
+            rate_limiter: self.rate_limiter.clone(),
             public_key: self.public_key.clone(),
             #[cfg(target_os = "linux")]
             backend: Backend::Kernel,
@@ -170,6 +192,26 @@ impl Server {
         }
     }
 
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.rate_limiter = RateLimiter::new(limits);
+    }
+
+    pub fn set_allowed_endpoint_ports(&mut self, policy: EndpointPortPolicy) {
+        self.allowed_endpoint_ports = policy;
+    }
+
+    pub fn public_key(&self) -> &Key {
+        &self.public_key
+    }
+
+    pub fn conf(&self) -> &ServerConfig {
+        &self.conf
+    }
+
+    pub fn interface(&self) -> &InterfaceName {
+        &self.interface
+    }
+
     pub fn wg_conf_path(&self) -> PathBuf {
         self.conf.config_path(&self.interface)
     }
@@ -249,17 +291,13 @@ pub fn peer_contents(
     let public_key = KeyPair::generate().public;
 
     Ok(PeerContents {
-        name: name.parse().map_err(|e: &str| anyhow!(e))?,
-        ip: ip_str.parse()?,
-        cidr_id,
-        public_key: public_key.to_base64(),
         is_admin,
-        endpoint: None,
-        persistent_keepalive_interval: None,
-        is_disabled: false,
-        is_redeemed: true,
-        invite_expires: None,
-        candidates: vec![],
+        ..PeerContents::new(
+            name.parse().map_err(|e: &str| anyhow!(e))?,
+            ip_str.parse()?,
+            cidr_id,
+            public_key.to_base64(),
+        )
     })
 }
 
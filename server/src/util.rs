@@ -1,10 +1,22 @@
 use bytes::Buf;
 use hyper::{header, Body, Request, Response, StatusCode};
 use serde::{de::DeserializeOwned, Serialize};
+use std::time::Duration;
 
 use crate::ServerError;
 
+/// The largest request body accepted by [`form_body`].
+const MAX_FORM_BODY_LEN: usize = 16 * 1024;
+
 pub async fn form_body<F: DeserializeOwned>(req: Request<Body>) -> Result<F, ServerError> {
+    form_body_with_limit(req, MAX_FORM_BODY_LEN).await
+}
+
+/// Like [`form_body`], for the rare requests that are expected to be larger.
+pub async fn form_body_with_limit<F: DeserializeOwned>(
+    req: Request<Body>,
+    max_len: usize,
+) -> Result<F, ServerError> {
     let content_len: usize = req
         .headers()
         .get(header::CONTENT_LENGTH)
@@ -12,7 +24,7 @@ pub async fn form_body<F: DeserializeOwned>(req: Request<Body>) -> Result<F, Ser
         .and_then(|header| header.parse().ok())
         .ok_or(ServerError::InvalidQuery)?;
 
-    if content_len > 16 * 1024 {
+    if content_len > max_len {
         return Err(ServerError::InvalidQuery);
     }
 
@@ -43,3 +55,33 @@ pub fn json_status_response<F: Serialize>(
 pub fn status_response(status: StatusCode) -> Result<Response<Body>, ServerError> {
     Ok(Response::builder().status(status).body(Body::empty())?)
 }
+
+/// Parse a command line option counting minutes, rejecting counts too large to be a
+/// [`Duration`] once converted to seconds.
+pub fn parse_minutes(count: &str) -> Result<u64, String> {
+    parse_time_units(count, 60, "minutes")
+}
+
+/// Parse a command line option counting days, rejecting counts too large to be a
+/// [`Duration`] once converted to seconds.
+pub fn parse_days(count: &str) -> Result<u64, String> {
+    parse_time_units(count, 24 * 60 * 60, "days")
+}
+
+fn parse_time_units(count: &str, unit_secs: u64, unit: &str) -> Result<u64, String> {
+    let count: u64 = count.parse().map_err(|e| format!("{}", e))?;
+    match count.checked_mul(unit_secs) {
+        Some(_) => Ok(count),
+        None => Err(format!("{} {} is too long", count, unit)),
+    }
+}
+
+/// `count` minutes, which [`parse_minutes`] already made sure fits.
+pub fn minutes(count: u64) -> Duration {
+    Duration::from_secs(count.saturating_mul(60))
+}
+
+/// `count` days, which [`parse_days`] already made sure fits.
+pub fn days(count: u64) -> Duration {
+    Duration::from_secs(count.saturating_mul(24 * 60 * 60))
+}
@@ -1,7 +1,10 @@
-use crate::{chmod, ensure_dirs_exist, Endpoint, Error, IoErrorContext, WrappedIoError};
+use crate::{
+    chmod, ensure_dirs_exist, parse_hosts_domain, Endpoint, EndpointPortPolicy, Error,
+    InterfaceNameTemplate, IoErrorContext, WrappedIoError,
+};
 use indoc::writedoc;
 use ipnet::IpNet;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::{
     fs::{File, OpenOptions},
     io::{self, Write},
@@ -35,6 +38,46 @@ pub struct InterfaceInfo {
 
     /// The local listen port. A random port will be used if `None`.
     pub listen_port: Option<u16>,
+
+    /// Without a `listen_port`, pick a fresh random port whenever the interface is brought
+    /// up instead of reusing the last one, at the cost of NAT mappings not surviving restarts.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub random_listen_port: bool,
+
+    /// The ports peers may advertise as NAT traversal candidates. Candidates on other ports
+    /// are never tried. By default, any port is allowed.
+    #[serde(default, skip_serializing_if = "EndpointPortPolicy::is_unrestricted")]
+    pub allowed_endpoint_ports: EndpointPortPolicy,
+
+    /// An extra domain to write peers to the hosts file under, ex. with `wg0.internal` a peer
+    /// named `db1` is also reachable as `db1.wg0.internal`, not only as `db1.<interface>.wg`.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_hosts_domain"
+    )]
+    pub hosts_domain: Option<String>,
+
+    /// The interface's MTU, unless `--mtu` overrides it. Clients still lower it to fit
+    /// their peers' MTU hints.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u32>,
+
+    /// The template the interface name is derived from on install, unless `--name-template`
+    /// overrides it. Kept across fetches so reinstalls derive the same name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name_template: Option<InterfaceNameTemplate>,
+}
+
+/// The domain ends up verbatim in the hosts file, so anything but hostnames (ex. whitespace,
+/// `#`, newlines) is rejected rather than corrupting it.
+fn deserialize_hosts_domain<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    let domain = String::deserialize(deserializer)?;
+    parse_hosts_domain(&domain)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
@@ -142,4 +185,181 @@ impl InterfaceInfo {
             .get_public()
             .to_base64())
     }
+
+    /// The configured [`hosts_domain`](Self::hosts_domain), without surrounding dots. An empty
+    /// domain is the same as none.
+    pub fn hosts_domain(&self) -> Option<&str> {
+        self.hosts_domain
+            .as_deref()
+            .map(|domain| domain.trim_matches('.'))
+            .filter(|domain| !domain.is_empty())
+    }
+
+    /// The port to bring the interface up with, given the one it last had (if known).
+    pub fn startup_listen_port(&self, last_listen_port: Option<u16>) -> Option<u16> {
+        match self.listen_port {
+            None if !self.random_listen_port => last_listen_port,
+            listen_port => listen_port,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interface_info(listen_port: Option<u16>, random_listen_port: bool) -> InterfaceInfo {
+        InterfaceInfo {
+            network_name: "test".into(),
+            address: "10.0.0.2/24".parse().unwrap(),
+            private_key: String::new(),
+            listen_port,
+            random_listen_port,
+            allowed_endpoint_ports: Default::default(),
+            hosts_domain: None,
+            mtu: None,
+            name_template: None,
+        }
+    }
+
+    #[test]
+    fn test_startup_listen_port() {
+        // A restart reuses the last port unless one is configured or randomness is asked for.
+        assert_eq!(
+            interface_info(None, false).startup_listen_port(Some(41000)),
+            Some(41000)
+        );
+        assert_eq!(interface_info(None, false).startup_listen_port(None), None);
+        assert_eq!(
+            interface_info(Some(51820), false).startup_listen_port(Some(41000)),
+            Some(51820)
+        );
+        assert_eq!(
+            interface_info(None, true).startup_listen_port(Some(41000)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_random_listen_port_config() {
+        let info: InterfaceInfo = toml::from_str(
+            r#"
+                network-name = "test"
+                address = "10.0.0.2/24"
+                private-key = ""
+            "#,
+        )
+        .unwrap();
+        assert!(!info.random_listen_port);
+        assert!(!toml::to_string(&info)
+            .unwrap()
+            .contains("random-listen-port"));
+
+        let info = interface_info(None, true);
+        let written = toml::to_string(&info).unwrap();
+        assert!(
+            toml::from_str::<InterfaceInfo>(&written)
+                .unwrap()
+                .random_listen_port
+        );
+    }
+
+    #[test]
+    fn test_allowed_endpoint_ports_config() {
+        let info: InterfaceInfo = toml::from_str(
+            r#"
+                network-name = "test"
+                address = "10.0.0.2/24"
+                private-key = ""
+                allowed-endpoint-ports = ["51820", "51000-51999"]
+            "#,
+        )
+        .unwrap();
+        assert!(info.allowed_endpoint_ports.allows(51500));
+        assert!(!info.allowed_endpoint_ports.allows(4444));
+        assert!(!toml::to_string(&interface_info(None, false))
+            .unwrap()
+            .contains("allowed-endpoint-ports"));
+    }
+
+    #[test]
+    fn test_mtu_config() {
+        let mut info = interface_info(None, false);
+        assert!(!toml::to_string(&info).unwrap().contains("mtu"));
+        info.mtu = Some(1380);
+        let written = toml::to_string(&info).unwrap();
+        assert!(written.contains("mtu = 1380"), "{}", written);
+        assert_eq!(
+            toml::from_str::<InterfaceInfo>(&written).unwrap().mtu,
+            Some(1380)
+        );
+    }
+
+    #[test]
+    fn test_hosts_domain_config() {
+        let mut info = interface_info(None, false);
+        assert_eq!(info.hosts_domain(), None);
+        assert!(!toml::to_string(&info).unwrap().contains("hosts-domain"));
+
+        for (configured, domain) in [
+            ("wg0.internal", Some("wg0.internal")),
+            (".wg0.internal.", Some("wg0.internal")),
+            ("", None),
+            (".", None),
+        ] {
+            info.hosts_domain = Some(configured.into());
+            assert_eq!(info.hosts_domain(), domain, "{:?}", configured);
+        }
+
+        let info: InterfaceInfo = toml::from_str(
+            r#"
+                network-name = "test"
+                address = "10.0.0.2/24"
+                private-key = ""
+                hosts-domain = "wg0.internal"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(info.hosts_domain(), Some("wg0.internal"));
+
+        for invalid in [
+            "wg0 internal",
+            "wg0.internal # x",
+            "wg0\n10.0.0.1 evil",
+            "wg0..internal",
+        ] {
+            let config = format!(
+                "network-name = \"test\"\naddress = \"10.0.0.2/24\"\nprivate-key = \"\"\nhosts-domain = {:?}\n",
+                invalid
+            );
+            assert!(
+                toml::from_str::<InterfaceInfo>(&config).is_err(),
+                "{:?}",
+                invalid
+            );
+            assert!(crate::parse_hosts_domain(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_name_template_config() {
+        let mut info = interface_info(None, false);
+        assert!(!toml::to_string(&info).unwrap().contains("name-template"));
+        info.name_template = Some("in-{network}".parse().unwrap());
+        let written = toml::to_string(&info).unwrap();
+        assert!(
+            written.contains("name-template = \"in-{network}\""),
+            "{}",
+            written
+        );
+        assert_eq!(
+            toml::from_str::<InterfaceInfo>(&written)
+                .unwrap()
+                .name_template,
+            info.name_template
+        );
+
+        let config = "network-name = \"test\"\naddress = \"10.0.0.2/24\"\nprivate-key = \"\"\nname-template = \"no-placeholder\"\n";
+        assert!(toml::from_str::<InterfaceInfo>(config).is_err());
+    }
 }
@@ -13,6 +13,8 @@ pub mod interface_config;
 #[cfg(target_os = "linux")]
 mod netlink;
 pub mod prompts;
+pub mod reachability;
+pub mod spec;
 pub mod types;
 pub mod wg;
 
@@ -20,7 +22,24 @@ pub use types::*;
 
 pub const REDEEM_TRANSITION_WAIT: Duration = Duration::from_secs(5);
 pub const PERSISTENT_KEEPALIVE_INTERVAL_SECS: u16 = 25;
+/// The interface MTU used when none is specified, which is also the smallest that IPv6
+/// allows.
+pub const DEFAULT_MTU: u32 = 1280;
 pub const INNERNET_PUBKEY_HEADER: &str = "X-Innernet-Server-Key";
+/// The oldest and newest versions of the HTTP API this build can speak. Servers that
+/// predate version negotiation are treated as speaking version 1.
+pub const API_VERSION_MIN: u32 = 1;
+pub const API_VERSION_MAX: u32 = 2;
+/// The API version that added everything beyond the original state, redeem, endpoint and
+/// candidates endpoints: `/user/version`, feature flags, MTU hints, metadata and handshake
+/// reports, CIDR renames, utilization and IP reservations, and the admin spec, maintenance
+/// and features endpoints. Clients check for it before calling any of those.
+pub const API_VERSION_2: u32 = 2;
+/// Response header carrying the server's current time as seconds since the UNIX epoch.
+pub const INNERNET_SERVER_TIME_HEADER: &str = "X-Innernet-Server-Time";
+/// The most handshake reports the server accepts in a single request, which keeps
+/// batches within its request body size limit.
+pub const MAX_HANDSHAKE_REPORT_BATCH: usize = 250;
 
 pub fn ensure_dirs_exist(dirs: &[&Path]) -> Result<(), WrappedIoError> {
     for dir in dirs {
@@ -118,8 +137,22 @@ pub fn get_local_addrs() -> Result<impl Iterator<Item = std::net::IpAddr>, io::E
         .take(10))
 }
 
+/// The public IP version to advertise as an endpoint: IPv6 if this host has a global IPv6
+/// address (so it's likely reachable without NAT), otherwise IPv4.
+pub fn public_ip_preference() -> publicip::Preference {
+    let has_global_ipv6 = get_local_addrs().is_ok_and(|mut addrs| addrs.any(|ip| ip.is_ipv6()));
+    if has_global_ipv6 {
+        publicip::Preference::Ipv6
+    } else {
+        publicip::Preference::Ipv4
+    }
+}
+
 pub trait IpNetExt {
     fn is_assignable(&self, ip: &IpAddr) -> bool;
+
+    /// The number of addresses for which [`is_assignable`](IpNetExt::is_assignable) is true.
+    fn assignable_count(&self) -> u128;
 }
 
 impl IpNetExt for IpNet {
@@ -132,4 +165,24 @@ impl IpNetExt for IpNet {
                 IpNet::V6(_) => self.prefix_len() >= 127 || ip != &self.network(),
             }
     }
+
+    fn assignable_count(&self) -> u128 {
+        let host_bits = u32::from(self.max_prefix_len() - self.prefix_len());
+        let size = 1u128.checked_shl(host_bits).unwrap_or(u128::MAX);
+        match self {
+            IpNet::V4(_) if self.prefix_len() < 31 => size - 2,
+            IpNet::V6(_) if self.prefix_len() < 127 => size - 1,
+            _ => size,
+        }
+    }
+}
+
+/// Iterate over the addresses in `cidr` that can be given to a new peer, from lowest to
+/// highest, skipping any address for which `is_taken` returns true.
+pub fn free_ips<'a>(
+    cidr: &'a IpNet,
+    is_taken: impl Fn(&IpAddr) -> bool + 'a,
+) -> impl DoubleEndedIterator<Item = IpAddr> + 'a {
+    cidr.hosts()
+        .filter(move |ip| cidr.is_assignable(ip) && !is_taken(ip))
 }
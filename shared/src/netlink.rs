@@ -4,8 +4,8 @@ use netlink_packet_route::{
     address,
     constants::*,
     link::{self, nlas::State},
-    route, AddressHeader, AddressMessage, LinkHeader, LinkMessage, RouteHeader, RouteMessage,
-    RtnlMessage, RTN_UNICAST, RT_SCOPE_LINK, RT_TABLE_MAIN,
+    route, AddressHeader, AddressMessage, LinkMessage, RouteHeader, RouteMessage, RtnlMessage,
+    RTN_UNICAST, RT_SCOPE_LINK, RT_TABLE_MAIN,
 };
 use netlink_request::netlink_request_rtnl;
 use std::{io, net::IpAddr};
@@ -21,21 +21,6 @@ fn if_nametoindex(interface: &InterfaceName) -> Result<u32, io::Error> {
     }
 }
 
-pub fn set_up(interface: &InterfaceName, mtu: u32) -> Result<(), io::Error> {
-    let index = if_nametoindex(interface)?;
-    let message = LinkMessage {
-        header: LinkHeader {
-            index,
-            flags: IFF_UP,
-            ..Default::default()
-        },
-        nlas: vec![link::nlas::Nla::Mtu(mtu)],
-    };
-    netlink_request_rtnl(RtnlMessage::SetLink(message), None)?;
-    log::debug!("set interface {} up with mtu {}", interface, mtu);
-    Ok(())
-}
-
 pub fn set_addr(interface: &InterfaceName, addr: IpNet) -> Result<(), io::Error> {
     let index = if_nametoindex(interface)?;
     let (family, nlas) = match addr {
@@ -1,22 +1,22 @@
 use crate::{
     interface_config::{InterfaceConfig, InterfaceInfo, ServerInfo},
     AddCidrOpts, AddDeleteAssociationOpts, AddPeerOpts, Association, Cidr, CidrContents, CidrTree,
-    DeleteCidrOpts, Endpoint, Error, Hostname, IpNetExt, ListenPortOpts, OverrideEndpointOpts,
-    Peer, PeerContents, RenamePeerOpts, PERSISTENT_KEEPALIVE_INTERVAL_SECS,
+    DeleteCidrOpts, Endpoint, Error, Hostname, IpAssignment, ListenPortOpts, OverrideEndpointOpts,
+    Peer, PeerContents, PublicIpOpts, RenameCidrOpts, RenamePeerOpts,
+    PERSISTENT_KEEPALIVE_INTERVAL_SECS,
 };
 use anyhow::anyhow;
 use colored::*;
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
 use ipnet::IpNet;
 use lazy_static::lazy_static;
-use publicip::Preference;
 use std::{
     fmt::{Debug, Display},
     fs::{File, OpenOptions},
     io,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     str::FromStr,
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 use wireguard_control::{InterfaceName, KeyPair};
 
@@ -140,6 +140,41 @@ pub fn delete_cidr(cidrs: &[Cidr], peers: &[Peer], request: &DeleteCidrOpts) ->
     }
 }
 
+/// Bring up a prompt to rename a CIDR. Returns the CIDR's ID and renamed contents.
+pub fn rename_cidr(
+    cidrs: &[Cidr],
+    args: &RenameCidrOpts,
+) -> Result<Option<(i64, CidrContents)>, Error> {
+    let cidr = if let Some(ref name) = args.name {
+        find_cidr(cidrs, name)?
+    } else {
+        choose_cidr(cidrs, "CIDR to rename")?
+    };
+    let new_name: Hostname = if let Some(ref name) = args.new_name {
+        name.clone()
+    } else {
+        input("New Name", Prefill::None)?
+    };
+
+    Ok(
+        if args.yes
+            || confirm(&format!(
+                "Rename CIDR {} to {}?",
+                cidr.name.yellow(),
+                new_name.yellow()
+            ))?
+        {
+            let contents = CidrContents {
+                name: new_name.to_string(),
+                ..cidr.contents.clone()
+            };
+            Some((cidr.id, contents))
+        } else {
+            None
+        },
+    )
+}
+
 pub fn choose_cidr<'a>(cidrs: &'a [Cidr], text: &'static str) -> Result<&'a Cidr, Error> {
     let eligible_cidrs: Vec<_> = cidrs
         .iter()
@@ -266,23 +301,17 @@ pub fn add_peer(
         choose_cidr(&leaves[..], "Eligible CIDRs for peer")?
     };
 
-    let mut available_ip = None;
-    let candidate_ips = cidr.hosts().filter(|ip| cidr.is_assignable(ip));
-    for ip in candidate_ips {
-        if !peers.iter().any(|peer| peer.ip == ip) {
-            available_ip = Some(ip);
-            break;
-        }
-    }
-
-    let available_ip = available_ip.expect("No IPs in this CIDR are avavilable");
-
+    let is_taken = |ip: &IpAddr| peers.iter().any(|peer| peer.covers(ip));
     let ip = if let Some(ip) = args.ip {
-        ip
-    } else if args.auto_ip {
-        available_ip
+        IpAssignment::Specific(ip).assign(&cidr.cidr, is_taken)?
     } else {
-        input("IP", Prefill::Default(available_ip))?
+        let available_ip = IpAssignment::from(args.ip_order).assign(&cidr.cidr, is_taken)?;
+        if args.auto_ip {
+            available_ip
+        } else {
+            let ip = input("IP", Prefill::Default(available_ip))?;
+            IpAssignment::Specific(ip).assign(&cidr.cidr, is_taken)?
+        }
     };
 
     let name = if let Some(ref name) = args.name {
@@ -315,20 +344,9 @@ pub fn add_peer(
         )?
     };
 
-    let default_keypair = KeyPair::generate();
-    let peer_request = PeerContents {
-        name,
-        ip,
-        cidr_id: cidr.id,
-        public_key: default_keypair.public.to_base64(),
-        endpoint: None,
-        is_admin,
-        is_disabled: false,
-        is_redeemed: false,
-        persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
-        invite_expires: Some(SystemTime::now() + invite_expires.into()),
-        candidates: vec![],
-    };
+    let (mut peer_request, default_keypair) =
+        invited_peer(name, ip, cidr.id, is_admin, invite_expires.into());
+    peer_request.extra_allowed_ips = args.extra_allowed_ips.iter().map(IpNet::trunc).collect();
 
     Ok(
         if args.yes || confirm(&format!("Create peer {}?", peer_request.name.yellow()))? {
@@ -344,6 +362,33 @@ pub fn add_peer(
     )
 }
 
+/// The contents of a new peer to invite, and the keypair its invitation is written with.
+pub fn invited_peer(
+    name: Hostname,
+    ip: IpAddr,
+    cidr_id: i64,
+    is_admin: bool,
+    invite_expires: Duration,
+) -> (PeerContents, KeyPair) {
+    let keypair = KeyPair::generate();
+    let contents = PeerContents {
+        name,
+        ip,
+        cidr_id,
+        public_key: keypair.public.to_base64(),
+        endpoint: None,
+        is_admin,
+        is_disabled: false,
+        is_redeemed: false,
+        persistent_keepalive_interval: Some(PERSISTENT_KEEPALIVE_INTERVAL_SECS),
+        invite_expires: Some(SystemTime::now() + invite_expires),
+        candidates: vec![],
+        extra_allowed_ips: vec![],
+        deleted_at: None,
+    };
+    (contents, keypair)
+}
+
 /// Bring up a prompt to create a new peer. Returns the peer request.
 pub fn rename_peer(
     peers: &[Peer],
@@ -435,22 +480,14 @@ pub fn write_peer_invitation(
     keypair: KeyPair,
     server_api_addr: &SocketAddr,
 ) -> Result<(), Error> {
-    let peer_invitation = InterfaceConfig {
-        interface: InterfaceInfo {
-            network_name: network_name.to_string(),
-            private_key: keypair.private.to_base64(),
-            address: IpNet::new(peer.ip, root_cidr.prefix_len())?,
-            listen_port: None,
-        },
-        server: ServerInfo {
-            external_endpoint: server_peer
-                .endpoint
-                .clone()
-                .expect("The innernet server should have a WireGuard endpoint"),
-            internal_endpoint: *server_api_addr,
-            public_key: server_peer.public_key.clone(),
-        },
-    };
+    let peer_invitation = peer_config(
+        network_name,
+        peer,
+        server_peer,
+        root_cidr,
+        keypair.private.to_base64(),
+        server_api_addr,
+    )?;
 
     peer_invitation.write_to(target_file.0, true, None)?;
 
@@ -466,11 +503,77 @@ pub fn write_peer_invitation(
     Ok(())
 }
 
+/// Write a bootstrap file for a peer that was pre-registered with a public key
+/// generated offline. It's an invitation without the private key, which the peer fills
+/// in from its own key file when installing.
+pub fn write_peer_bootstrap(
+    target_file: (&mut File, &str),
+    network_name: &InterfaceName,
+    peer: &Peer,
+    server_peer: &Peer,
+    root_cidr: &Cidr,
+    server_api_addr: &SocketAddr,
+) -> Result<(), Error> {
+    let bootstrap = peer_config(
+        network_name,
+        peer,
+        server_peer,
+        root_cidr,
+        String::new(),
+        server_api_addr,
+    )?;
+
+    bootstrap.write_to(target_file.0, true, None)?;
+
+    println!(
+        "\nPeer \"{}\" pre-registered\n\
+         Bootstrap file written to {}\n\
+         It contains no secrets. Install it on the peer with \
+         'innernet install --private-key-file <key file> {}'.",
+        peer.name.bold(),
+        target_file.1.bold(),
+        target_file.1
+    );
+
+    Ok(())
+}
+
+fn peer_config(
+    network_name: &InterfaceName,
+    peer: &Peer,
+    server_peer: &Peer,
+    root_cidr: &Cidr,
+    private_key: String,
+    server_api_addr: &SocketAddr,
+) -> Result<InterfaceConfig, Error> {
+    Ok(InterfaceConfig {
+        interface: InterfaceInfo {
+            network_name: network_name.to_string(),
+            private_key,
+            address: IpNet::new(peer.ip, root_cidr.prefix_len())?,
+            listen_port: None,
+            random_listen_port: false,
+            allowed_endpoint_ports: Default::default(),
+            hosts_domain: None,
+            mtu: None,
+            name_template: None,
+        },
+        server: ServerInfo {
+            external_endpoint: server_peer
+                .endpoint
+                .clone()
+                .expect("The innernet server should have a WireGuard endpoint"),
+            internal_endpoint: *server_api_addr,
+            public_key: server_peer.public_key.clone(),
+        },
+    })
+}
+
 pub fn set_listen_port(
     interface: &InterfaceInfo,
     args: ListenPortOpts,
 ) -> Result<Option<Option<u16>>, Error> {
-    let listen_port = if let Some(listen_port) = args.listen_port {
+    let listen_port = if let Some(listen_port) = args.port.or(args.listen_port) {
         Some(listen_port)
     } else if !args.unset {
         Some(input(
@@ -503,15 +606,20 @@ pub fn set_listen_port(
     }
 }
 
-pub fn ask_endpoint(listen_port: u16) -> Result<Endpoint, Error> {
+pub fn ask_endpoint(listen_port: u16, public_ip: &PublicIpOpts) -> Result<Endpoint, Error> {
     println!("getting external IP address.");
 
+    let prompt = if public_ip.is_default() {
+        "Auto-fill public IP address (via a DNS query to 1.1.1.1)?"
+    } else {
+        "Auto-fill public IP address (via --public-ip-resolver)?"
+    };
     let external_ip = if Confirm::with_theme(&*THEME)
         .wait_for_newline(true)
-        .with_prompt("Auto-fill public IP address (via a DNS query to 1.1.1.1)?")
+        .with_prompt(prompt)
         .interact()?
     {
-        publicip::get_any(Preference::Ipv4)
+        public_ip.get_any()
     } else {
         None
     };
@@ -531,7 +639,7 @@ pub fn override_endpoint(
 ) -> Result<Option<Endpoint>, Error> {
     let endpoint = match &args.endpoint {
         Some(endpoint) => endpoint.clone(),
-        None => ask_endpoint(listen_port)?,
+        None => ask_endpoint(listen_port, &args.public_ip)?,
     };
     if args.yes || confirm(&format!("Set external endpoint to {}?", endpoint))? {
         Ok(Some(endpoint))
@@ -0,0 +1,318 @@
+//! Offline evaluation of which peers can reach each other, mirroring the server's
+//! `DatabasePeer::get_all_allowed_peers` query, so association changes can be
+//! previewed before they're made.
+
+use crate::{CandidateKind, Cidr, Peer};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    net::IpAddr,
+};
+
+/// The special "infra" CIDR that every peer is implicitly associated with.
+const INFRA_CIDR_ID: i64 = 2;
+
+/// The CIDRs whose peers are visible to peers of `cidr_id`: its own subtree, the infra
+/// CIDR's, and the subtrees of CIDRs associated with it or any of its ancestors.
+pub fn visible_cidrs(cidr_id: i64, cidrs: &[Cidr], associations: &[(i64, i64)]) -> HashSet<i64> {
+    let mut ancestors = HashSet::new();
+    let mut current = Some(cidr_id);
+    while let Some(id) = current {
+        if !ancestors.insert(id) {
+            break;
+        }
+        current = cidrs
+            .iter()
+            .find(|cidr| cidr.id == id)
+            .and_then(|cidr| cidr.parent);
+    }
+
+    let mut visible: HashSet<i64> = associations
+        .iter()
+        .filter_map(|&(a, b)| {
+            if ancestors.contains(&a) {
+                Some(b)
+            } else if ancestors.contains(&b) {
+                Some(a)
+            } else {
+                None
+            }
+        })
+        .chain([cidr_id, INFRA_CIDR_ID])
+        .collect();
+
+    // Walk down to every descendant of the CIDRs found so far.
+    loop {
+        let children: Vec<i64> = cidrs
+            .iter()
+            .filter(|cidr| matches!(cidr.parent, Some(parent) if visible.contains(&parent)))
+            .map(|cidr| cidr.id)
+            .filter(|id| !visible.contains(id))
+            .collect();
+        if children.is_empty() {
+            break visible;
+        }
+        visible.extend(children);
+    }
+}
+
+/// Pairs of active peer ids (lower id first) that can see each other, which WireGuard
+/// needs to connect them. The server peers with everyone regardless, so its pairs aren't
+/// meaningful here.
+pub fn reachable_pairs(
+    peers: &[Peer],
+    cidrs: &[Cidr],
+    associations: &[(i64, i64)],
+) -> BTreeSet<(i64, i64)> {
+    let active: Vec<&Peer> = peers
+        .iter()
+        .filter(|peer| !peer.is_disabled && peer.is_redeemed)
+        .collect();
+    // Visibility only depends on the CIDR, so work it out once per CIDR rather than per peer.
+    let mut visible: HashMap<i64, HashSet<i64>> = HashMap::new();
+    for peer in &active {
+        visible
+            .entry(peer.cidr_id)
+            .or_insert_with(|| visible_cidrs(peer.cidr_id, cidrs, associations));
+    }
+
+    let mut pairs = BTreeSet::new();
+    for (i, a) in active.iter().enumerate() {
+        let seen_by_a = &visible[&a.cidr_id];
+        for b in &active[i + 1..] {
+            if seen_by_a.contains(&b.cidr_id) && visible[&b.cidr_id].contains(&a.cidr_id) {
+                pairs.insert((a.id.min(b.id), a.id.max(b.id)));
+            }
+        }
+    }
+    pairs
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PeerPair {
+    pub peer1: String,
+    pub peer2: String,
+}
+
+/// The connectivity change caused by going from one set of associations to another.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ReachabilityDelta {
+    pub gained: Vec<PeerPair>,
+    pub lost: Vec<PeerPair>,
+}
+
+impl ReachabilityDelta {
+    pub fn between(
+        peers: &[Peer],
+        cidrs: &[Cidr],
+        before: &[(i64, i64)],
+        after: &[(i64, i64)],
+    ) -> Self {
+        let before = reachable_pairs(peers, cidrs, before);
+        let after = reachable_pairs(peers, cidrs, after);
+        let named = |pairs: BTreeSet<&(i64, i64)>| {
+            pairs
+                .into_iter()
+                .filter_map(|(a, b)| {
+                    let name = |id: &i64| {
+                        peers
+                            .iter()
+                            .find(|p| p.id == *id)
+                            .map(|p| p.name.to_string())
+                    };
+                    Some(PeerPair {
+                        peer1: name(a)?,
+                        peer2: name(b)?,
+                    })
+                })
+                .collect()
+        };
+        Self {
+            gained: named(after.difference(&before).collect()),
+            lost: named(before.difference(&after).collect()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.gained.is_empty() && self.lost.is_empty()
+    }
+}
+
+/// Active peers whose server-observed endpoints share an IP, ex. because they're behind the
+/// same NAT.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SharedEndpointGroup {
+    pub ip: IpAddr,
+    pub peers: Vec<String>,
+    /// Pairs in the group that are allowed to reach each other but don't both have LAN
+    /// candidates to connect over directly, so depend on the NAT hairpinning (or a relay).
+    pub needs_hairpin: Vec<PeerPair>,
+}
+
+/// Group peers by the IP of their observed endpoint, leaving out IPs with only one peer.
+pub fn shared_endpoint_groups(
+    peers: &[Peer],
+    cidrs: &[Cidr],
+    associations: &[(i64, i64)],
+) -> Vec<SharedEndpointGroup> {
+    let mut by_ip: BTreeMap<IpAddr, Vec<&Peer>> = BTreeMap::new();
+    for peer in peers
+        .iter()
+        .filter(|peer| !peer.is_disabled && peer.is_redeemed)
+    {
+        if let Some(ip) = peer.endpoint.as_ref().and_then(|endpoint| endpoint.ip()) {
+            by_ip.entry(ip).or_default().push(peer);
+        }
+    }
+
+    let reachable = reachable_pairs(peers, cidrs, associations);
+    let has_lan_candidate = |peer: &Peer| {
+        peer.candidates
+            .iter()
+            .any(|candidate| CandidateKind::of(candidate) == CandidateKind::Lan)
+    };
+
+    by_ip
+        .into_iter()
+        .filter(|(_, group)| group.len() > 1)
+        .map(|(ip, group)| {
+            let mut needs_hairpin = vec![];
+            for (i, a) in group.iter().enumerate() {
+                for b in &group[i + 1..] {
+                    let (a, b) = if a.id < b.id { (a, b) } else { (b, a) };
+                    if reachable.contains(&(a.id, b.id))
+                        && !(has_lan_candidate(a) && has_lan_candidate(b))
+                    {
+                        needs_hairpin.push(PeerPair {
+                            peer1: a.name.to_string(),
+                            peer2: b.name.to_string(),
+                        });
+                    }
+                }
+            }
+            SharedEndpointGroup {
+                ip,
+                peers: group.iter().map(|peer| peer.name.to_string()).collect(),
+                needs_hairpin,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CidrContents, PeerContents};
+
+    fn cidr(id: i64, name: &str, cidr: &str, parent: Option<i64>) -> Cidr {
+        Cidr {
+            id,
+            contents: CidrContents {
+                name: name.to_string(),
+                cidr: cidr.parse().unwrap(),
+                parent,
+            },
+        }
+    }
+
+    fn peer(id: i64, name: &str, cidr_id: i64) -> Peer {
+        Peer {
+            id,
+            contents: PeerContents::new(
+                name.parse().unwrap(),
+                format!("10.0.{}.1", id).parse().unwrap(),
+                cidr_id,
+                format!("key{}", id),
+            ),
+        }
+    }
+
+    #[test]
+    fn test_shared_endpoint_groups() {
+        let cidrs = vec![
+            cidr(1, "root", "10.0.0.0/8", None),
+            cidr(2, "infra", "10.0.0.0/24", Some(1)),
+            cidr(3, "devs", "10.1.0.0/16", Some(1)),
+            cidr(4, "lab", "10.2.0.0/16", Some(1)),
+        ];
+        let behind = |mut peer: Peer, endpoint: &str, lan: Option<&str>| {
+            peer.endpoint = Some(endpoint.parse().unwrap());
+            peer.candidates = lan.into_iter().map(|c| c.parse().unwrap()).collect();
+            peer
+        };
+        let peers = vec![
+            behind(peer(2, "alice", 3), "203.0.113.7:51820", None),
+            behind(
+                peer(3, "bob", 3),
+                "203.0.113.7:40000",
+                Some("192.168.1.3:51820"),
+            ),
+            behind(
+                peer(4, "carol", 3),
+                "203.0.113.7:40001",
+                Some("192.168.1.4:51820"),
+            ),
+            behind(peer(5, "scope", 4), "203.0.113.7:40002", None),
+            behind(peer(6, "dave", 3), "198.51.100.1:51820", None),
+        ];
+
+        let groups = shared_endpoint_groups(&peers, &cidrs, &[]);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].ip, "203.0.113.7".parse::<IpAddr>().unwrap());
+        assert_eq!(groups[0].peers, vec!["alice", "bob", "carol", "scope"]);
+        // bob and carol can use their LAN candidates, and scope isn't allowed to reach anyone.
+        let pair = |a: &str, b: &str| PeerPair {
+            peer1: a.to_string(),
+            peer2: b.to_string(),
+        };
+        assert_eq!(
+            groups[0].needs_hairpin,
+            vec![pair("alice", "bob"), pair("alice", "carol")]
+        );
+    }
+
+    #[test]
+    fn test_reachability_delta() {
+        let cidrs = vec![
+            cidr(1, "root", "10.0.0.0/8", None),
+            cidr(2, "infra", "10.0.0.0/24", Some(1)),
+            cidr(3, "office", "10.1.0.0/16", Some(1)),
+            cidr(4, "devs", "10.1.1.0/24", Some(3)),
+            cidr(5, "lab", "10.2.0.0/16", Some(1)),
+        ];
+        let peers = vec![
+            peer(1, "server", 2),
+            peer(2, "alice", 4),
+            peer(3, "bob", 4),
+            peer(4, "scope", 5),
+        ];
+        let pair = |a: &str, b: &str| PeerPair {
+            peer1: a.to_string(),
+            peer2: b.to_string(),
+        };
+
+        // Without associations, peers only reach their own CIDR. Infra peers are visible
+        // to everyone, but (unlike the server itself) only see other infra peers.
+        assert_eq!(
+            reachable_pairs(&peers, &cidrs, &[]),
+            [(2, 3)].into_iter().collect()
+        );
+
+        // Associating the lab with a parent of devs connects the lab to devs.
+        let delta = ReachabilityDelta::between(&peers, &cidrs, &[], &[(3, 5)]);
+        assert_eq!(
+            delta.gained,
+            vec![pair("alice", "scope"), pair("bob", "scope")]
+        );
+        assert!(delta.lost.is_empty());
+
+        let delta = ReachabilityDelta::between(&peers, &cidrs, &[(5, 3)], &[]);
+        assert!(delta.gained.is_empty());
+        assert_eq!(
+            delta.lost,
+            vec![pair("alice", "scope"), pair("bob", "scope")]
+        );
+
+        assert!(ReachabilityDelta::between(&peers, &cidrs, &[(3, 5)], &[(5, 3)]).is_empty());
+    }
+}
@@ -0,0 +1,565 @@
+//! A declarative description of a network's CIDRs, associations and peers, so it can be
+//! kept in version control and the server reconciled against it.
+//!
+//! CIDRs are identified by name and peers by IP. Peers can't be created from a spec (they
+//! need invitations), only updated or disabled.
+
+use crate::{Association, Cidr, Error, Hostname, Peer};
+use anyhow::{anyhow, bail};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display, Formatter},
+    net::IpAddr,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NetworkSpec {
+    /// The name of the interface the spec applies to.
+    pub network: String,
+    #[serde(default)]
+    pub cidrs: Vec<CidrSpec>,
+    #[serde(default)]
+    pub associations: Vec<AssociationSpec>,
+    #[serde(default)]
+    pub peers: Vec<PeerSpec>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CidrSpec {
+    pub name: String,
+    pub cidr: IpNet,
+    /// The parent CIDR's name, or `None` for the root CIDR.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AssociationSpec {
+    pub cidr1: String,
+    pub cidr2: String,
+}
+
+impl AssociationSpec {
+    /// The association's CIDR names in a fixed order, since associations are undirected.
+    fn key(&self) -> (String, String) {
+        if self.cidr1 <= self.cidr2 {
+            (self.cidr1.clone(), self.cidr2.clone())
+        } else {
+            (self.cidr2.clone(), self.cidr1.clone())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PeerSpec {
+    pub name: Hostname,
+    pub ip: IpAddr,
+    /// The name of the CIDR the peer belongs to.
+    pub cidr: String,
+    #[serde(default)]
+    pub is_admin: bool,
+    #[serde(default)]
+    pub is_disabled: bool,
+}
+
+/// One step of reconciling a network with a [`NetworkSpec`], in the order they're applied.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum SpecChange {
+    DeleteAssociation {
+        cidr1: String,
+        cidr2: String,
+    },
+    DeleteCidr {
+        name: String,
+        cidr: IpNet,
+    },
+    CreateCidr {
+        name: String,
+        cidr: IpNet,
+        parent: Option<String>,
+    },
+    CreateAssociation {
+        cidr1: String,
+        cidr2: String,
+    },
+    UpdatePeer {
+        name: Hostname,
+        ip: IpAddr,
+        is_admin: bool,
+        is_disabled: bool,
+    },
+    DisablePeer {
+        name: Hostname,
+        ip: IpAddr,
+    },
+}
+
+impl Display for SpecChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DeleteAssociation { cidr1, cidr2 } => {
+                write!(f, "- association {} <=> {}", cidr1, cidr2)
+            },
+            Self::DeleteCidr { name, cidr } => write!(f, "- CIDR {} ({})", name, cidr),
+            Self::CreateCidr { name, cidr, parent } => {
+                write!(f, "+ CIDR {} ({})", name, cidr)?;
+                match parent {
+                    Some(parent) => write!(f, " under {}", parent),
+                    None => Ok(()),
+                }
+            },
+            Self::CreateAssociation { cidr1, cidr2 } => {
+                write!(f, "+ association {} <=> {}", cidr1, cidr2)
+            },
+            Self::UpdatePeer {
+                name,
+                ip,
+                is_admin,
+                is_disabled,
+            } => write!(
+                f,
+                "~ peer {} ({}): admin: {}, disabled: {}",
+                name, ip, is_admin, is_disabled
+            ),
+            Self::DisablePeer { name, ip } => write!(f, "~ peer {} ({}): disabled", name, ip),
+        }
+    }
+}
+
+/// How deep each named CIDR is in its tree, with the root at 0.
+fn depths(cidrs: &[CidrSpec]) -> BTreeMap<&str, usize> {
+    let parents: BTreeMap<&str, Option<&str>> = cidrs
+        .iter()
+        .map(|cidr| (cidr.name.as_str(), cidr.parent.as_deref()))
+        .collect();
+    parents
+        .keys()
+        .map(|&name| {
+            let mut depth = 0;
+            let mut current = parents.get(name).copied().flatten();
+            while let Some(parent) = current {
+                depth += 1;
+                if depth > parents.len() {
+                    break;
+                }
+                current = parents.get(parent).copied().flatten();
+            }
+            (name, depth)
+        })
+        .collect()
+}
+
+impl NetworkSpec {
+    /// Describe a network's current state as a spec.
+    pub fn from_state(
+        network: String,
+        cidrs: &[Cidr],
+        peers: &[Peer],
+        associations: &[Association],
+    ) -> Result<Self, Error> {
+        let mut names = BTreeMap::new();
+        for cidr in cidrs {
+            if names.insert(cidr.id, cidr.name.clone()).is_some() {
+                bail!("duplicate CIDR id {}", cidr.id);
+            }
+        }
+        if names.values().collect::<BTreeSet<_>>().len() != names.len() {
+            bail!("CIDR names must be unique to describe the network as a spec");
+        }
+        let name = |id: i64| {
+            names
+                .get(&id)
+                .cloned()
+                .ok_or_else(|| anyhow!("unknown CIDR id {}", id))
+        };
+
+        let mut peers = peers
+            .iter()
+            .map(|peer| {
+                Ok(PeerSpec {
+                    name: peer.name.clone(),
+                    ip: peer.ip,
+                    cidr: name(peer.cidr_id)?,
+                    is_admin: peer.is_admin,
+                    is_disabled: peer.is_disabled,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        peers.sort_by_key(|peer| peer.ip);
+
+        Ok(Self {
+            network,
+            cidrs: cidrs
+                .iter()
+                .map(|cidr| {
+                    Ok(CidrSpec {
+                        name: cidr.name.clone(),
+                        cidr: cidr.cidr,
+                        parent: cidr.parent.map(name).transpose()?,
+                    })
+                })
+                .collect::<Result<_, Error>>()?,
+            associations: associations
+                .iter()
+                .map(|association| {
+                    Ok(AssociationSpec {
+                        cidr1: name(association.cidr_id_1)?,
+                        cidr2: name(association.cidr_id_2)?,
+                    })
+                })
+                .collect::<Result<_, Error>>()?,
+            peers,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let names: BTreeSet<&str> = self.cidrs.iter().map(|cidr| cidr.name.as_str()).collect();
+        if names.len() != self.cidrs.len() {
+            bail!("CIDR names must be unique");
+        }
+        let known = |name: &str| -> Result<(), Error> {
+            if names.contains(name) {
+                Ok(())
+            } else {
+                Err(anyhow!("unknown CIDR {}", name))
+            }
+        };
+        if self
+            .cidrs
+            .iter()
+            .filter(|cidr| cidr.parent.is_none())
+            .count()
+            != 1
+        {
+            bail!("there must be exactly one root CIDR (without a parent)");
+        }
+        for cidr in &self.cidrs {
+            if let Some(parent) = &cidr.parent {
+                known(parent)?;
+            }
+        }
+        if depths(&self.cidrs)
+            .values()
+            .any(|&depth| depth > self.cidrs.len())
+        {
+            bail!("CIDR parents can't form a cycle");
+        }
+        for association in &self.associations {
+            known(&association.cidr1)?;
+            known(&association.cidr2)?;
+        }
+        let mut ips = BTreeSet::new();
+        for peer in &self.peers {
+            known(&peer.cidr)?;
+            if !ips.insert(peer.ip) {
+                bail!("IP {} is assigned to more than one peer", peer.ip);
+            }
+        }
+        Ok(())
+    }
+
+    /// The changes that turn the `current` network into this one.
+    ///
+    /// A CIDR whose range or parent changes is deleted and recreated (along with its
+    /// descendants and associations), which fails if peers belong to it.
+    pub fn changes_from(&self, current: &NetworkSpec) -> Result<Vec<SpecChange>, Error> {
+        self.validate()?;
+
+        let current_cidrs: BTreeMap<&str, &CidrSpec> = current
+            .cidrs
+            .iter()
+            .map(|cidr| (cidr.name.as_str(), cidr))
+            .collect();
+        let mut replaced: BTreeSet<&str> = self
+            .cidrs
+            .iter()
+            .filter(|cidr| {
+                current_cidrs
+                    .get(cidr.name.as_str())
+                    .is_some_and(|current| current != cidr)
+            })
+            .map(|cidr| cidr.name.as_str())
+            .collect();
+        // Recreating a CIDR gives it a new id, so its children have to be recreated under it.
+        loop {
+            let children: Vec<&str> = self
+                .cidrs
+                .iter()
+                .filter(|cidr| current_cidrs.contains_key(cidr.name.as_str()))
+                .filter(|cidr| {
+                    cidr.parent
+                        .as_deref()
+                        .is_some_and(|parent| replaced.contains(parent))
+                })
+                .map(|cidr| cidr.name.as_str())
+                .filter(|name| !replaced.contains(name))
+                .collect();
+            if children.is_empty() {
+                break;
+            }
+            replaced.extend(children);
+        }
+
+        let desired_names: BTreeSet<&str> =
+            self.cidrs.iter().map(|cidr| cidr.name.as_str()).collect();
+        let is_deleted = |name: &str| !desired_names.contains(name) || replaced.contains(name);
+
+        let current_associations: BTreeSet<_> = current
+            .associations
+            .iter()
+            .map(AssociationSpec::key)
+            .collect();
+        let desired_associations: BTreeSet<_> =
+            self.associations.iter().map(AssociationSpec::key).collect();
+        let touches_replaced = |(a, b): &&(String, String)| {
+            replaced.contains(a.as_str()) || replaced.contains(b.as_str())
+        };
+
+        let mut changes = vec![];
+        for (cidr1, cidr2) in current_associations
+            .iter()
+            .filter(|key| !desired_associations.contains(key) || touches_replaced(key))
+        {
+            changes.push(SpecChange::DeleteAssociation {
+                cidr1: cidr1.clone(),
+                cidr2: cidr2.clone(),
+            });
+        }
+
+        let current_depths = depths(&current.cidrs);
+        let mut deleted: Vec<&CidrSpec> = current
+            .cidrs
+            .iter()
+            .filter(|cidr| is_deleted(&cidr.name))
+            .collect();
+        deleted.sort_by_key(|cidr| std::cmp::Reverse(current_depths[cidr.name.as_str()]));
+        for cidr in deleted {
+            changes.push(SpecChange::DeleteCidr {
+                name: cidr.name.clone(),
+                cidr: cidr.cidr,
+            });
+        }
+
+        let desired_depths = depths(&self.cidrs);
+        let mut created: Vec<&CidrSpec> = self
+            .cidrs
+            .iter()
+            .filter(|cidr| {
+                !current_cidrs.contains_key(cidr.name.as_str())
+                    || replaced.contains(cidr.name.as_str())
+            })
+            .collect();
+        created.sort_by_key(|cidr| desired_depths[cidr.name.as_str()]);
+        for cidr in created {
+            changes.push(SpecChange::CreateCidr {
+                name: cidr.name.clone(),
+                cidr: cidr.cidr,
+                parent: cidr.parent.clone(),
+            });
+        }
+
+        for (cidr1, cidr2) in desired_associations
+            .iter()
+            .filter(|key| !current_associations.contains(key) || touches_replaced(key))
+        {
+            changes.push(SpecChange::CreateAssociation {
+                cidr1: cidr1.clone(),
+                cidr2: cidr2.clone(),
+            });
+        }
+
+        for peer in &self.peers {
+            let existing = current
+                .peers
+                .iter()
+                .find(|existing| existing.ip == peer.ip)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "peer {} ({}) doesn't exist; invite it with add-peer first",
+                        peer.name,
+                        peer.ip
+                    )
+                })?;
+            if existing.cidr != peer.cidr {
+                bail!(
+                    "peer {} ({}) can't be moved from CIDR {} to {}",
+                    peer.name,
+                    peer.ip,
+                    existing.cidr,
+                    peer.cidr
+                );
+            }
+            if existing != peer {
+                changes.push(SpecChange::UpdatePeer {
+                    name: peer.name.clone(),
+                    ip: peer.ip,
+                    is_admin: peer.is_admin,
+                    is_disabled: peer.is_disabled,
+                });
+            }
+        }
+        for existing in &current.peers {
+            if !existing.is_disabled && self.peers.iter().all(|peer| peer.ip != existing.ip) {
+                changes.push(SpecChange::DisablePeer {
+                    name: existing.name.clone(),
+                    ip: existing.ip,
+                });
+            }
+        }
+
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(name: &str, cidr: &str, parent: Option<&str>) -> CidrSpec {
+        CidrSpec {
+            name: name.into(),
+            cidr: cidr.parse().unwrap(),
+            parent: parent.map(Into::into),
+        }
+    }
+
+    fn association(cidr1: &str, cidr2: &str) -> AssociationSpec {
+        AssociationSpec {
+            cidr1: cidr1.into(),
+            cidr2: cidr2.into(),
+        }
+    }
+
+    fn peer(name: &str, ip: &str, cidr: &str) -> PeerSpec {
+        PeerSpec {
+            name: name.parse().unwrap(),
+            ip: ip.parse().unwrap(),
+            cidr: cidr.into(),
+            is_admin: false,
+            is_disabled: false,
+        }
+    }
+
+    fn current() -> NetworkSpec {
+        NetworkSpec {
+            network: "test".into(),
+            cidrs: vec![
+                cidr("root", "10.0.0.0/16", None),
+                cidr("infra", "10.0.0.0/24", Some("root")),
+                cidr("office", "10.0.1.0/24", Some("root")),
+                cidr("desks", "10.0.1.0/25", Some("office")),
+            ],
+            associations: vec![association("infra", "desks")],
+            peers: vec![
+                peer("server", "10.0.0.1", "infra"),
+                peer("laptop", "10.0.1.2", "desks"),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_unchanged_spec() {
+        let current = current();
+        assert_eq!(current.changes_from(&current).unwrap(), vec![]);
+
+        // Associations are undirected.
+        let swapped = NetworkSpec {
+            associations: vec![association("desks", "infra")],
+            ..current.clone()
+        };
+        assert_eq!(swapped.changes_from(&current).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_spec_changes_are_ordered() {
+        let current = current();
+        let mut desired = current.clone();
+        desired.cidrs.push(cidr("lab", "10.0.2.0/24", Some("root")));
+        desired
+            .cidrs
+            .push(cidr("bench", "10.0.2.0/25", Some("lab")));
+        desired.associations = vec![association("bench", "infra")];
+        // Moving office to a new range recreates its child too.
+        desired.cidrs[2].cidr = "10.0.3.0/24".parse().unwrap();
+        desired.cidrs[3].cidr = "10.0.3.0/25".parse().unwrap();
+        desired.peers.truncate(1);
+
+        let changes = desired.changes_from(&current).unwrap();
+        let described: Vec<String> = changes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            described,
+            vec![
+                "- association desks <=> infra",
+                "- CIDR desks (10.0.1.0/25)",
+                "- CIDR office (10.0.1.0/24)",
+                "+ CIDR office (10.0.3.0/24) under root",
+                "+ CIDR lab (10.0.2.0/24) under root",
+                "+ CIDR desks (10.0.3.0/25) under office",
+                "+ CIDR bench (10.0.2.0/25) under lab",
+                "+ association bench <=> infra",
+                "~ peer laptop (10.0.1.2): disabled",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_peer_updates() {
+        let current = current();
+        let mut desired = current.clone();
+        desired.peers[1].name = "workstation".parse().unwrap();
+        desired.peers[1].is_admin = true;
+        assert_eq!(
+            desired.changes_from(&current).unwrap(),
+            vec![SpecChange::UpdatePeer {
+                name: "workstation".parse().unwrap(),
+                ip: "10.0.1.2".parse().unwrap(),
+                is_admin: true,
+                is_disabled: false,
+            }]
+        );
+
+        desired.peers[1].cidr = "infra".into();
+        assert!(desired.changes_from(&current).is_err());
+
+        let mut desired = current.clone();
+        desired.peers.push(peer("phone", "10.0.1.3", "desks"));
+        assert!(desired.changes_from(&current).is_err());
+    }
+
+    #[test]
+    fn test_invalid_specs() {
+        let current = current();
+        let invalid = |change: fn(&mut NetworkSpec)| {
+            let mut desired = current.clone();
+            change(&mut desired);
+            desired.changes_from(&current).is_err()
+        };
+        assert!(invalid(|spec| spec.cidrs.push(cidr(
+            "infra",
+            "10.0.9.0/24",
+            Some("root")
+        ))));
+        assert!(invalid(|spec| spec.cidrs[1].parent = Some("nowhere".into())));
+        assert!(invalid(|spec| spec
+            .associations
+            .push(association("infra", "nowhere"))));
+        assert!(invalid(|spec| spec
+            .peers
+            .push(peer("clone", "10.0.0.1", "infra"))));
+        assert!(invalid(|spec| spec.cidrs[0].parent = Some("desks".into())));
+    }
+
+    #[test]
+    fn test_spec_toml_round_trip() {
+        let spec = current();
+        let written = toml::to_string(&spec).unwrap();
+        assert_eq!(toml::from_str::<NetworkSpec>(&written).unwrap(), spec);
+    }
+}
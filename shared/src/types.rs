@@ -5,11 +5,12 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     fmt::{self, Display, Formatter},
     io,
     net::{IpAddr, SocketAddr, ToSocketAddrs},
     ops::{Deref, DerefMut},
-    path::Path,
+    path::{Path, PathBuf},
     str::FromStr,
     time::{Duration, SystemTime},
     vec,
@@ -20,7 +21,7 @@ use wireguard_control::{
     PeerInfo,
 };
 
-use crate::wg::PeerInfoExt;
+use crate::{wg::PeerInfoExt, IpNetExt, DEFAULT_MTU};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Interface {
@@ -134,6 +135,19 @@ impl Display for Endpoint {
 }
 
 impl Endpoint {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The endpoint's IP address, if it isn't a domain name.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self.host {
+            Host::Ipv4(ip) => Some(ip.into()),
+            Host::Ipv6(ip) => Some(ip.into()),
+            Host::Domain(_) => None,
+        }
+    }
+
     pub fn resolve(&self) -> Result<SocketAddr, io::Error> {
         let mut addrs = self.to_string().to_socket_addrs()?;
         addrs.next().ok_or_else(|| {
@@ -170,13 +184,13 @@ impl From<Option<Endpoint>> for EndpointContents {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct AssociationContents {
     pub cidr_id_1: i64,
     pub cidr_id_2: i64,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct Association {
     pub id: i64,
 
@@ -259,7 +273,7 @@ impl<'a> CidrTree<'a> {
         }
     }
 
-    pub fn children(&self) -> impl Iterator<Item = CidrTree> {
+    pub fn children(&self) -> impl Iterator<Item = CidrTree<'a>> + '_ {
         self.cidrs
             .iter()
             .filter(move |c| c.parent == Some(self.contents.id))
@@ -269,6 +283,13 @@ impl<'a> CidrTree<'a> {
             })
     }
 
+    /// This CIDR followed by all of its descendants.
+    pub fn subtree(&self) -> Vec<&'a Cidr> {
+        std::iter::once(self.contents)
+            .chain(self.children().flat_map(|child| child.subtree()))
+            .collect()
+    }
+
     pub fn leaves(&self) -> Vec<Cidr> {
         if !self.cidrs.iter().any(|cidr| cidr.parent == Some(self.id)) {
             vec![self.contents.clone()]
@@ -283,6 +304,294 @@ pub struct RedeemContents {
     pub public_key: String,
 }
 
+/// The range of API versions a server supports, as reported by `/v1/user/version`.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ApiVersion {
+    pub min: u32,
+    pub max: u32,
+    /// The server's package version, for error messages.
+    pub server_version: String,
+}
+
+impl ApiVersion {
+    /// Whether a client supporting API versions `min..=max` can talk to this server.
+    pub fn is_compatible_with(&self, min: u32, max: u32) -> bool {
+        self.min <= max && min <= self.max
+    }
+}
+
+/// The server's maintenance state, as reported and set by `/v1/admin/maintenance`.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub struct MaintenanceContents {
+    /// Whether the server is rejecting mutating requests.
+    pub read_only: bool,
+}
+
+/// Network-wide toggles for client behavior, set by admins on the server and read by clients
+/// on every fetch. Flags that a client doesn't know about (ex. from a newer server) are
+/// ignored, and flags the server doesn't send keep their defaults. A client's own opt-outs,
+/// like `--no-nat-traversal`, still take precedence.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct FeatureFlags {
+    /// Whether clients attempt NAT traversal with their peers' candidates.
+    pub nat_traversal: bool,
+    /// Whether clients report their peers' handshakes, as if run with `--report-handshakes`.
+    pub report_handshakes: bool,
+    /// Whether clients report their metadata (OS, version, etc.) for the admins' inventory.
+    pub report_metadata: bool,
+}
+
+impl Default for FeatureFlags {
+    fn default() -> Self {
+        Self {
+            nat_traversal: true,
+            report_handshakes: false,
+            report_metadata: true,
+        }
+    }
+}
+
+impl FeatureFlags {
+    pub const NAMES: [&'static str; 3] = ["nat-traversal", "report-handshakes", "report-metadata"];
+
+    /// A flag's state by its name, or `None` for unknown names.
+    pub fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "nat-traversal" => Some(self.nat_traversal),
+            "report-handshakes" => Some(self.report_handshakes),
+            "report-metadata" => Some(self.report_metadata),
+            _ => None,
+        }
+    }
+
+    /// Set a flag by its name, failing for unknown names.
+    pub fn set(&mut self, name: &str, enabled: bool) -> Result<(), &'static str> {
+        match name {
+            "nat-traversal" => self.nat_traversal = enabled,
+            "report-handshakes" => self.report_handshakes = enabled,
+            "report-metadata" => self.report_metadata = enabled,
+            _ => return Err("unknown feature flag"),
+        }
+        Ok(())
+    }
+
+    /// The defaults with the given flags changed, skipping any names that aren't known.
+    pub fn with_overrides<'a>(overrides: impl IntoIterator<Item = (&'a str, bool)>) -> Self {
+        let mut flags = Self::default();
+        for (name, enabled) in overrides {
+            if flags.set(name, enabled).is_err() {
+                log::debug!("ignoring unknown feature flag {}.", name);
+            }
+        }
+        flags
+    }
+}
+
+/// Request to turn a network's feature flag on or off, see [`FeatureFlags`].
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct FeatureFlagContents {
+    pub name: String,
+    pub enabled: bool,
+}
+
+/// An entry in the server's append-only change feed, served by `/v1/admin/events`.
+///
+/// Ids are strictly increasing, so the last id seen works as a cursor for resuming.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Event {
+    pub id: i64,
+    /// When the change happened, in seconds since the UNIX epoch.
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// A change to the network state. Serialized with a `"type"` tag, ex.
+/// `{"type": "peer_created", "peer": {...}}`.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    PeerCreated {
+        peer: Peer,
+    },
+    /// Any change to an existing peer: renames, enabling/disabling, redemption, endpoints.
+    PeerUpdated {
+        peer: Peer,
+    },
+    PeerDeleted {
+        peer_id: i64,
+    },
+    CidrCreated {
+        cidr: Cidr,
+    },
+    /// A renamed CIDR, the only change CIDRs allow.
+    CidrUpdated {
+        cidr: Cidr,
+    },
+    CidrDeleted {
+        cidr_id: i64,
+    },
+    AssociationCreated {
+        association: Association,
+    },
+    AssociationDeleted {
+        association_id: i64,
+    },
+}
+
+/// How much of a CIDR's address space is taken, as reported by `/v1/admin/cidrs/utilization`.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub struct CidrUtilization {
+    pub cidr_id: i64,
+    /// Peers with an address in the CIDR, including those in its child CIDRs, plus addresses
+    /// reserved for peers that haven't been added yet.
+    pub used: u64,
+    /// Addresses that can be given to peers, so excluding network and broadcast addresses.
+    /// Saturates for very large (IPv6) CIDRs.
+    pub total: u64,
+}
+
+impl CidrUtilization {
+    pub fn new<'a>(cidr: &Cidr, peer_ips: impl IntoIterator<Item = &'a IpAddr>) -> Self {
+        let used = peer_ips
+            .into_iter()
+            .filter(|ip| cidr.cidr.contains(*ip))
+            .count();
+        Self {
+            cidr_id: cidr.id,
+            used: used as u64,
+            total: u64::try_from(cidr.cidr.assignable_count()).unwrap_or(u64::MAX),
+        }
+    }
+
+    pub fn percent(&self) -> f64 {
+        match self.total {
+            0 => 100.0,
+            total => self.used as f64 * 100.0 / total as f64,
+        }
+    }
+
+    /// Whether the most recently added peer pushed usage to `threshold` percent or above.
+    pub fn just_crossed(&self, threshold: u8) -> bool {
+        let before = Self {
+            used: self.used.saturating_sub(1),
+            ..*self
+        };
+        self.percent() >= f64::from(threshold) && before.percent() < f64::from(threshold)
+    }
+}
+
+/// Information a client optionally reports about itself on fetch via `/v1/user/metadata`,
+/// so admins can inventory what's running in the network.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct PeerMetadata {
+    pub os: Option<String>,
+    /// The innernet client version.
+    pub version: Option<String>,
+    pub hostname: Option<String>,
+    /// The WireGuard backend in use, ex. "kernel" or "userspace".
+    pub backend: Option<String>,
+}
+
+impl PeerMetadata {
+    /// The longest any single field is allowed to be, in bytes.
+    pub const MAX_FIELD_LEN: usize = 64;
+
+    pub fn is_valid(&self) -> bool {
+        [&self.os, &self.version, &self.hostname, &self.backend]
+            .iter()
+            .filter_map(|field| field.as_deref())
+            .all(|field| field.len() <= Self::MAX_FIELD_LEN)
+    }
+}
+
+/// A peer's last reported metadata, as listed by `/v1/admin/peers/metadata`.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ReportedMetadata {
+    pub peer_id: i64,
+    /// When the metadata was last reported, in seconds since the UNIX epoch.
+    pub reported_at: u64,
+    #[serde(flatten)]
+    pub metadata: PeerMetadata,
+}
+
+/// The latest handshake a peer has seen with another peer, reported in batches to
+/// `/v1/user/handshakes` so the server knows about traffic that doesn't pass through it.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub struct HandshakeReport {
+    pub peer_id: i64,
+    /// In seconds since the UNIX epoch.
+    pub last_handshake: u64,
+}
+
+/// An admin-set hint of the largest packet that fits the path to a peer, served to peers
+/// that can see it by `/v1/user/mtu-hints`.
+///
+/// WireGuard's MTU is per-interface rather than per-peer, so clients can't apply hints to
+/// individual peers. Instead, they lower their interface's MTU to fit the smallest hint among
+/// their peers once WireGuard's own overhead is added, which is safe for all of them at the
+/// cost of smaller packets to the rest.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub struct MtuHint {
+    pub peer_id: i64,
+    pub mtu: u32,
+}
+
+impl MtuHint {
+    pub const MAX: u32 = 65535;
+    /// The smallest MTU every IPv4 path has to support.
+    pub const MIN: u32 = 576;
+
+    pub fn is_valid_mtu(mtu: u32) -> bool {
+        (Self::MIN..=Self::MAX).contains(&mtu)
+    }
+
+    /// What WireGuard adds to each packet: the outer IP and UDP headers, plus 32 bytes of
+    /// its own header and authentication tag. Endpoints that aren't an IPv4 address (or
+    /// aren't known) get IPv6's larger overhead, to be safe.
+    pub fn wireguard_overhead(endpoint_ip: Option<IpAddr>) -> u32 {
+        match endpoint_ip {
+            Some(IpAddr::V4(_)) => 60,
+            _ => 80,
+        }
+    }
+
+    /// The interface MTU that's safe for every peer with a hint: the smallest hint less
+    /// WireGuard's overhead for the peer's endpoint (looked up with `endpoint_ip`), or
+    /// `interface_mtu` if that's smaller.
+    pub fn effective_mtu(
+        interface_mtu: u32,
+        hints: &[MtuHint],
+        endpoint_ip: impl Fn(i64) -> Option<IpAddr>,
+    ) -> u32 {
+        hints
+            .iter()
+            .map(|hint| {
+                hint.mtu
+                    .saturating_sub(Self::wireguard_overhead(endpoint_ip(hint.peer_id)))
+            })
+            .fold(interface_mtu, u32::min)
+    }
+}
+
+/// Request to set (or with `None`, clear) a peer's MTU hint.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct MtuHintContents {
+    pub mtu: Option<u32>,
+}
+
+/// Request for the next free addresses in a CIDR.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct NextIpsRequest {
+    pub count: usize,
+
+    /// Hold the returned addresses for this many seconds so that other
+    /// next-ips requests won't hand them out again.
+    #[serde(default)]
+    pub reserve_secs: Option<u64>,
+}
+
 #[derive(Debug, Clone, PartialEq, Args)]
 pub struct InstallOpts {
     /// Set a specific interface name
@@ -293,9 +602,208 @@ pub struct InstallOpts {
     #[clap(long = "default-name")]
     pub default_name: bool,
 
+    /// Derive the interface name from the network name using a template, ex. 'in-{network}'.
+    /// Names too long for an interface have their network portion shortened and hashed.
+    /// Overrides and replaces 'name-template' in the network's config
+    #[clap(long, conflicts_with = "name")]
+    pub name_template: Option<InterfaceNameTemplate>,
+
     /// Delete the invitation after a successful install
     #[clap(short, long)]
     pub delete_invite: bool,
+
+    /// Install a bootstrap file from 'innernet-server pre-register' using this private key
+    /// (ex. from 'innernet generate-keypair') instead of redeeming an invitation
+    #[clap(long)]
+    pub private_key_file: Option<PathBuf>,
+
+    /// How many times to retry redeeming the invitation after a transient failure, like a
+    /// server error or timeout. Rejected invitations are never retried.
+    #[clap(long, default_value = "3")]
+    pub redeem_retries: u32,
+
+    /// Also write peers to the hosts file under this domain, ex. 'wg0.internal' for
+    /// 'db1.wg0.internal'. Saved as 'hosts-domain' in the network's config
+    #[clap(long, value_name = "DOMAIN", parse(try_from_str = parse_hosts_domain))]
+    pub hosts_domain: Option<String>,
+}
+
+/// A template for deriving interface names from network names, containing a single
+/// `{network}` placeholder (ex. `vpn-{network}`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceNameTemplate {
+    prefix: String,
+    suffix: String,
+}
+
+impl InterfaceNameTemplate {
+    /// Hex digits of the network name's hash kept when it has to be shortened.
+    const HASH_LEN: usize = 4;
+    const PLACEHOLDER: &'static str = "{network}";
+
+    /// Derive the interface name for a network, shortening the network portion (keeping a
+    /// short hash of the full name to stay distinct) if the result would exceed `IFNAMSIZ`.
+    pub fn derive(&self, network: &str) -> Result<Interface, Error> {
+        let max_len = libc::IFNAMSIZ - 1;
+        let available = max_len.saturating_sub(self.prefix.len() + self.suffix.len());
+        let network = if network.len() <= available {
+            network.to_string()
+        } else if available > Self::HASH_LEN {
+            let hash = format!("{:08x}", fnv1a(network.as_bytes()));
+            let keep = available - Self::HASH_LEN;
+            format!(
+                "{}{}",
+                network[..keep].trim_end_matches('-'),
+                &hash[..Self::HASH_LEN]
+            )
+        } else {
+            return Err(anyhow!(
+                "interface name template is too long to fit a network name in {} characters",
+                max_len
+            ));
+        };
+
+        let name = format!("{}{}{}", self.prefix, network, self.suffix);
+        name.parse()
+            .map_err(|e| anyhow!("derived interface name \"{}\" is invalid: {}", name, e))
+    }
+}
+
+/// A small stable hash (32-bit FNV-1a), used to keep shortened names distinct.
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+impl FromStr for InterfaceNameTemplate {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split(Self::PLACEHOLDER).collect::<Vec<_>>().as_slice() {
+            [prefix, suffix] => Ok(Self {
+                prefix: prefix.to_string(),
+                suffix: suffix.to_string(),
+            }),
+            _ => Err("template must contain '{network}' exactly once"),
+        }
+    }
+}
+
+impl Display for InterfaceNameTemplate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}{}", self.prefix, Self::PLACEHOLDER, self.suffix)
+    }
+}
+
+impl Serialize for InterfaceNameTemplate {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for InterfaceNameTemplate {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct InterfaceNameTemplateVisitor;
+        impl<'de> serde::de::Visitor<'de> for InterfaceNameTemplateVisitor {
+            type Value = InterfaceNameTemplate;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("an interface name template like in-{network}")
+            }
+
+            fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+        deserializer.deserialize_str(InterfaceNameTemplateVisitor)
+    }
+}
+
+/// Which end of a CIDR new peers' IPs are auto-assigned from, selectable with `--ip-order`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpOrder {
+    #[default]
+    Lowest,
+    Highest,
+}
+
+impl IpOrder {
+    pub fn variants() -> &'static [&'static str] {
+        &["lowest", "highest"]
+    }
+}
+
+impl FromStr for IpOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lowest" => Ok(Self::Lowest),
+            "highest" => Ok(Self::Highest),
+            _ => Err(format!("valid values: {}.", Self::variants().join(", "))),
+        }
+    }
+}
+
+impl Display for IpOrder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Lowest => write!(f, "lowest"),
+            Self::Highest => write!(f, "highest"),
+        }
+    }
+}
+
+/// How a new peer's IP is picked from its CIDR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpAssignment {
+    LowestAvailable,
+    HighestAvailable,
+    Specific(IpAddr),
+}
+
+impl From<IpOrder> for IpAssignment {
+    fn from(order: IpOrder) -> Self {
+        match order {
+            IpOrder::Lowest => Self::LowestAvailable,
+            IpOrder::Highest => Self::HighestAvailable,
+        }
+    }
+}
+
+impl IpAssignment {
+    /// The IP to give a new peer in `cidr`, where `is_taken` tells which IPs other peers have.
+    /// Fails if the CIDR is full, or a specific IP isn't free to hand out.
+    pub fn assign(
+        &self,
+        cidr: &IpNet,
+        is_taken: impl Fn(&IpAddr) -> bool,
+    ) -> Result<IpAddr, Error> {
+        match *self {
+            Self::LowestAvailable => crate::free_ips(cidr, is_taken).next(),
+            Self::HighestAvailable => crate::free_ips(cidr, is_taken).next_back(),
+            Self::Specific(ip) => {
+                if !cidr.is_assignable(&ip) {
+                    return Err(anyhow!("{} isn't an assignable IP in {}.", ip, cidr));
+                }
+                if is_taken(&ip) {
+                    return Err(anyhow!("{} is already taken by another peer.", ip));
+                }
+                Some(ip)
+            },
+        }
+        .ok_or_else(|| anyhow!("No IPs in {} are available.", cidr))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Args)]
@@ -312,6 +820,10 @@ pub struct AddPeerOpts {
     #[clap(long = "auto-ip")]
     pub auto_ip: bool,
 
+    /// Which available IP to auto-assign (or suggest): the lowest or highest in the CIDR
+    #[clap(long, default_value_t, possible_values = IpOrder::variants(), conflicts_with = "ip")]
+    pub ip_order: IpOrder,
+
     /// Name of CIDR to add new peer under
     #[clap(long)]
     pub cidr: Option<String>,
@@ -331,6 +843,37 @@ pub struct AddPeerOpts {
     /// Invite expiration period (eg. '30d', '7w', '2h', '60m', '1000s')
     #[clap(long)]
     pub invite_expires: Option<Timestring>,
+
+    /// Ranges within the network that the new peer is a gateway to, which other peers will
+    /// route to it. ex. --extra-allowed-ips 10.42.8.0/24,10.42.9.0/24
+    #[clap(long, use_value_delimiter = true)]
+    pub extra_allowed_ips: Vec<IpNet>,
+}
+
+#[derive(Debug, Clone, PartialEq, Args)]
+pub struct PreRegisterOpts {
+    /// The peer's WireGuard public key, generated offline (ex. with 'innernet generate-keypair')
+    pub public_key: String,
+
+    /// Name of new peer
+    #[clap(long)]
+    pub name: Hostname,
+
+    /// Name of CIDR to add new peer under
+    #[clap(long)]
+    pub cidr: String,
+
+    /// Specify desired IP of new peer (defaults to the first available IP within the CIDR)
+    #[clap(long)]
+    pub ip: Option<IpAddr>,
+
+    /// Make new peer an admin
+    #[clap(long)]
+    pub admin: bool,
+
+    /// Save the bootstrap file to the given location (defaults to '<name>.toml')
+    #[clap(long)]
+    pub save_config: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Args)]
@@ -348,6 +891,21 @@ pub struct RenamePeerOpts {
     pub yes: bool,
 }
 
+#[derive(Debug, Clone, PartialEq, Args)]
+pub struct RenameCidrOpts {
+    /// Name of CIDR to rename
+    #[clap(long)]
+    pub name: Option<String>,
+
+    /// The new name of the CIDR
+    #[clap(long)]
+    pub new_name: Option<Hostname>,
+
+    /// Bypass confirmation
+    #[clap(long)]
+    pub yes: bool,
+}
+
 #[derive(Debug, Clone, PartialEq, Args)]
 pub struct AddCidrOpts {
     /// The CIDR name (eg. 'engineers')
@@ -394,11 +952,15 @@ pub struct AddDeleteAssociationOpts {
 #[derive(Debug, Clone, PartialEq, Args)]
 pub struct ListenPortOpts {
     /// The listen port you'd like to set for the interface
+    #[clap(conflicts_with_all = &["listen-port", "unset"])]
+    pub port: Option<u16>,
+
+    /// The listen port you'd like to set for the interface, like the positional argument
     #[clap(short, long)]
     pub listen_port: Option<u16>,
 
     /// Unset the local listen port to use a randomized port
-    #[clap(short, long, conflicts_with = "listen-port")]
+    #[clap(short, long, visible_alias = "random", conflicts_with = "listen-port")]
     pub unset: bool,
 
     /// Bypass confirmation
@@ -419,6 +981,53 @@ pub struct OverrideEndpointOpts {
     /// Bypass confirmation
     #[clap(long)]
     pub yes: bool,
+
+    #[clap(flatten)]
+    pub public_ip: PublicIpOpts,
+}
+
+/// How to look up this host's public IP address, for filling in endpoints.
+#[derive(Debug, Clone, PartialEq, Args)]
+pub struct PublicIpOpts {
+    /// An HTTP service that answers with the address it was contacted from, ex.
+    /// 'http://ifconfig.me/ip', to ask instead of Cloudflare's and OpenDNS's DNS. Can be
+    /// given more than once, to try them in order
+    #[clap(long = "public-ip-resolver", value_name = "URL")]
+    pub public_ip_resolvers: Vec<publicip::Resolver>,
+
+    /// How long to wait for each public IP resolver to answer
+    #[clap(long, value_name = "MILLIS", default_value_t = PublicIpOpts::DEFAULT_TIMEOUT_MILLIS)]
+    pub public_ip_timeout: u64,
+}
+
+impl PublicIpOpts {
+    const DEFAULT_TIMEOUT_MILLIS: u64 = publicip::DEFAULT_TIMEOUT.as_millis() as u64;
+
+    pub fn is_default(&self) -> bool {
+        self.public_ip_resolvers.is_empty()
+    }
+
+    /// This host's public IP address, preferring the version it's likely reachable over
+    /// (see [`crate::public_ip_preference`]).
+    pub fn get_any(&self) -> Option<IpAddr> {
+        let resolvers = if self.is_default() {
+            publicip::Resolver::defaults()
+        } else {
+            self.public_ip_resolvers.clone()
+        };
+        let timeout = Duration::from_millis(self.public_ip_timeout);
+        publicip::PublicAddrs::get_from(&resolvers, timeout)
+            .preferred(crate::public_ip_preference())
+    }
+}
+
+impl Default for PublicIpOpts {
+    fn default() -> Self {
+        Self {
+            public_ip_resolvers: vec![],
+            public_ip_timeout: Self::DEFAULT_TIMEOUT_MILLIS,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Args)]
@@ -437,17 +1046,53 @@ pub struct NatOpts {
     /// Don't report any candidates to coordinating server.
     /// Shorthand for --exclude-nat-candidates '0.0.0.0/0'.
     pub no_nat_candidates: bool,
+
+    #[clap(long, default_value_t)]
+    /// The order in which NAT traversal candidates are attempted, as weights
+    /// per candidate kind (higher is tried first).
+    /// ex. --nat-candidate-weights 'lan=30,public=20,observed=10'
+    pub nat_candidate_weights: CandidateWeights,
+
+    #[clap(long, value_name = "SECS", default_value_t = DEFAULT_NAT_CANDIDATE_TIMEOUT_SECS)]
+    /// How long to wait for a handshake after switching a peer to a NAT traversal
+    /// candidate, before moving on to its next one.
+    pub nat_candidate_timeout: u64,
+
+    #[clap(long)]
+    /// Before keeping a new endpoint the server reported for a peer, check that a
+    /// handshake completes over it, going back to the previous endpoint if none does. Failed
+    /// endpoints aren't tried again for an hour.
+    pub probe_endpoints: bool,
+
+    #[clap(long, value_name = "SECS", default_value_t = DEFAULT_ENDPOINT_PROBE_TIMEOUT_SECS)]
+    /// How long to wait for a handshake over a probed endpoint.
+    pub endpoint_probe_timeout: u64,
 }
 
+pub const DEFAULT_NAT_CANDIDATE_TIMEOUT_SECS: u64 = 5;
+pub const DEFAULT_ENDPOINT_PROBE_TIMEOUT_SECS: u64 = 5;
+
 impl NatOpts {
     pub fn all_disabled() -> Self {
         Self {
             no_nat_traversal: true,
             exclude_nat_candidates: vec![],
             no_nat_candidates: true,
+            nat_candidate_weights: Default::default(),
+            nat_candidate_timeout: DEFAULT_NAT_CANDIDATE_TIMEOUT_SECS,
+            probe_endpoints: false,
+            endpoint_probe_timeout: DEFAULT_ENDPOINT_PROBE_TIMEOUT_SECS,
         }
     }
 
+    pub fn nat_candidate_timeout(&self) -> Duration {
+        Duration::from_secs(self.nat_candidate_timeout)
+    }
+
+    pub fn endpoint_probe_timeout(&self) -> Duration {
+        Duration::from_secs(self.endpoint_probe_timeout)
+    }
+
     /// Check if an IP is allowed to be reported as a candidate.
     pub fn is_excluded(&self, ip: IpAddr) -> bool {
         self.no_nat_candidates
@@ -458,6 +1103,238 @@ impl NatOpts {
     }
 }
 
+/// The kind of network path a NAT traversal candidate represents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CandidateKind {
+    /// A private or link-local address, likely only reachable on a shared LAN.
+    Lan,
+    /// A globally routable address (or hostname) reported by the peer itself.
+    Public,
+    /// The endpoint the coordinating server observed the peer connecting from.
+    Observed,
+}
+
+impl CandidateKind {
+    /// Classify a candidate reported by a peer. Server-observed endpoints
+    /// aren't reported as candidates, so this never returns `Observed`.
+    pub fn of(endpoint: &Endpoint) -> Self {
+        let is_lan = match endpoint.host {
+            Host::Ipv4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+            Host::Ipv6(ip) => {
+                (ip.segments()[0] & 0xffc0) == 0xfe80 // unicast link local
+                    || (ip.segments()[0] & 0xfe00) == 0xfc00 // unicast local
+                    || ip.is_loopback()
+            },
+            Host::Domain(_) => false,
+        };
+
+        if is_lan {
+            Self::Lan
+        } else {
+            Self::Public
+        }
+    }
+}
+
+/// Where the endpoint currently applied to a peer most likely came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointSource {
+    /// The endpoint the server reported for the peer (observed or overridden).
+    Server,
+    /// One of the NAT traversal candidates the peer reported.
+    Candidate(CandidateKind),
+    /// Neither, ex. an endpoint set by hand with `wg set` or a hostname candidate.
+    Unknown,
+}
+
+impl EndpointSource {
+    /// Match the live endpoint of a peer against what the server last told us about it.
+    pub fn of(peer: &Peer, live: SocketAddr) -> Self {
+        let live = Endpoint::from(live);
+        if peer.endpoint.as_ref() == Some(&live) {
+            Self::Server
+        } else if let Some(candidate) = peer.candidates.iter().find(|c| **c == live) {
+            Self::Candidate(CandidateKind::of(candidate))
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+impl Display for EndpointSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Server => write!(f, "server-reported"),
+            Self::Candidate(CandidateKind::Lan) => write!(f, "LAN candidate"),
+            Self::Candidate(_) => write!(f, "public candidate"),
+            Self::Unknown => write!(f, "unknown source"),
+        }
+    }
+}
+
+/// Weights used to order NAT traversal candidates, where a higher weight is
+/// attempted earlier. Candidates of equal weight keep their reported order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CandidateWeights {
+    pub lan: u32,
+    pub public: u32,
+    pub observed: u32,
+}
+
+impl Default for CandidateWeights {
+    fn default() -> Self {
+        Self {
+            lan: 30,
+            public: 20,
+            observed: 10,
+        }
+    }
+}
+
+impl CandidateWeights {
+    pub fn weight(&self, kind: CandidateKind) -> u32 {
+        match kind {
+            CandidateKind::Lan => self.lan,
+            CandidateKind::Public => self.public,
+            CandidateKind::Observed => self.observed,
+        }
+    }
+}
+
+impl FromStr for CandidateWeights {
+    type Err = &'static str;
+
+    /// Parses a comma-separated list of `kind=weight` pairs, ex. `lan=30,public=20`.
+    /// Kinds that aren't specified keep their default weight.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut weights = Self::default();
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (kind, weight) = pair
+                .split_once('=')
+                .ok_or("weights must be in the form of 'kind=weight'")?;
+            let weight = weight.trim().parse().map_err(|_| "couldn't parse weight")?;
+            match kind.trim() {
+                "lan" => weights.lan = weight,
+                "public" => weights.public = weight,
+                "observed" => weights.observed = weight,
+                _ => return Err("unknown candidate kind (expected lan, public, or observed)"),
+            }
+        }
+        Ok(weights)
+    }
+}
+
+impl Display for CandidateWeights {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "lan={},public={},observed={}",
+            self.lan, self.public, self.observed
+        )
+    }
+}
+
+/// An inclusive range of UDP ports, written as a single port (`51820`) or `start-end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let start = start.trim().parse().map_err(|_| "couldn't parse port")?;
+        let end = end.trim().parse().map_err(|_| "couldn't parse port")?;
+        if start > end {
+            return Err("port range must start before it ends");
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct PortRangeVisitor;
+        impl<'de> serde::de::Visitor<'de> for PortRangeVisitor {
+            type Value = PortRange;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a port or a range of ports like 51000-51999")
+            }
+
+            fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+        deserializer.deserialize_str(PortRangeVisitor)
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+/// The ports that peers may advertise as endpoints. An empty policy allows any port.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct EndpointPortPolicy(pub Vec<PortRange>);
+
+impl EndpointPortPolicy {
+    pub fn is_unrestricted(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn allows(&self, port: u16) -> bool {
+        self.is_unrestricted() || self.0.iter().any(|range| range.contains(port))
+    }
+
+    /// Remove the endpoints whose ports aren't allowed, returning them.
+    pub fn retain_allowed(&self, endpoints: &mut Vec<Endpoint>) -> Vec<Endpoint> {
+        let (allowed, rejected) = endpoints
+            .drain(..)
+            .partition(|endpoint| self.allows(endpoint.port()));
+        *endpoints = allowed;
+        rejected
+    }
+}
+
+impl Display for EndpointPortPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let ranges: Vec<_> = self.0.iter().map(PortRange::to_string).collect();
+        f.write_str(&ranges.join(","))
+    }
+}
+
 #[derive(Debug, Clone, Copy, Args)]
 pub struct NetworkOpts {
     #[clap(long)]
@@ -471,10 +1348,20 @@ pub struct NetworkOpts {
     pub backend: Backend,
 
     #[clap(long)]
-    /// Specify the desired MTU for your interface (default: 1280).
+    /// Specify the desired MTU for your interface (default: 1280), overriding the network's
+    /// configured 'mtu'. Saved to the network's config when given to install. Clients lower
+    /// it further if any of their peers has a smaller MTU hint (see set-mtu-hint).
     pub mtu: Option<u32>,
 }
 
+impl NetworkOpts {
+    /// The MTU to give an interface: `--mtu`, or else the network's configured MTU, or
+    /// else the default.
+    pub fn mtu_or(&self, configured: Option<u32>) -> u32 {
+        self.mtu.or(configured).unwrap_or(DEFAULT_MTU)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub struct PeerContents {
     pub name: Hostname,
@@ -489,6 +1376,87 @@ pub struct PeerContents {
     pub invite_expires: Option<SystemTime>,
     #[serde(default)]
     pub candidates: Vec<Endpoint>,
+    /// Ranges beyond its own IP that the peer is a gateway to, which are routed to it too.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_allowed_ips: Vec<IpNet>,
+    /// When the peer was deleted. Deleted peers are kept (and disabled) as a record, and can
+    /// be restored by enabling them again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<SystemTime>,
+}
+
+impl PeerContents {
+    /// An enabled, redeemed peer with nothing set but what identifies it. The rest can be
+    /// filled in with struct update syntax.
+    pub fn new(name: Hostname, ip: IpAddr, cidr_id: i64, public_key: String) -> Self {
+        Self {
+            name,
+            ip,
+            cidr_id,
+            public_key,
+            endpoint: None,
+            persistent_keepalive_interval: None,
+            is_admin: false,
+            is_disabled: false,
+            is_redeemed: true,
+            invite_expires: None,
+            candidates: vec![],
+            extra_allowed_ips: vec![],
+            deleted_at: None,
+        }
+    }
+
+    /// The peer's own IP followed by its extra allowed IPs, as WireGuard peers have them.
+    pub fn allowed_ips(&self) -> Vec<AllowedIp> {
+        let host = IpNet::from(self.ip);
+        std::iter::once(host)
+            .chain(self.extra_allowed_ips.iter().copied())
+            .map(|net| AllowedIp {
+                address: net.addr(),
+                cidr: net.prefix_len(),
+            })
+            .collect()
+    }
+
+    /// Whether `ip` is the peer's own or within its extra allowed IPs, so that traffic for it
+    /// is routed to the peer and it can't be given to another one.
+    pub fn covers(&self, ip: &IpAddr) -> bool {
+        self.ip == *ip
+            || self
+                .extra_allowed_ips
+                .iter()
+                .any(|extra| extra.contains(ip))
+    }
+
+    /// Check that the peer's extra allowed IPs are routable within the `network`, and that
+    /// neither they nor the peer's IP take traffic meant for any of the other `peers`.
+    pub fn check_allowed_ips(&self, network: &IpNet, peers: &[Peer]) -> Result<(), String> {
+        let others = || peers.iter().filter(|peer| peer.ip != self.ip);
+        if let Some(gateway) = others().find(|peer| peer.covers(&self.ip)) {
+            return Err(format!(
+                "{} is within the extra allowed IPs of peer \"{}\".",
+                self.ip, gateway.name
+            ));
+        }
+        for extra in &self.extra_allowed_ips {
+            if !network.contains(extra) {
+                return Err(format!(
+                    "extra allowed IPs {} are outside of the network ({}).",
+                    extra, network
+                ));
+            }
+            for peer in others() {
+                let overlaps = |net: &IpNet| net.contains(extra) || extra.contains(net);
+                if extra.contains(&peer.ip) || peer.extra_allowed_ips.iter().any(overlaps) {
+                    return Err(format!(
+                        "extra allowed IPs {} overlap with peer \"{}\".",
+                        extra, peer.name
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -519,6 +1487,32 @@ impl Display for Peer {
     }
 }
 
+/// An IP address assigned to more than one peer, which silently breaks routing to them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DuplicateIp {
+    pub ip: IpAddr,
+    /// The names of the peers sharing the address, including disabled ones.
+    pub peers: Vec<String>,
+}
+
+impl DuplicateIp {
+    /// Every address shared by more than one of `peers`, in address order.
+    pub fn find(peers: &[Peer]) -> Vec<Self> {
+        let mut by_ip: BTreeMap<IpAddr, Vec<String>> = BTreeMap::new();
+        for peer in peers {
+            by_ip
+                .entry(peer.ip)
+                .or_default()
+                .push(peer.name.to_string());
+        }
+        by_ip
+            .into_iter()
+            .filter(|(_, peers)| peers.len() > 1)
+            .map(|(ip, peers)| Self { ip, peers })
+            .collect()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct ChangeString {
     name: &'static str,
@@ -614,18 +1608,22 @@ impl<'a> PeerDiff<'a> {
 
         // TODO(jake): use contains() when stable: https://github.com/rust-lang/rust/issues/62358
 
-        let new_allowed_ips = &[AllowedIp {
-            address: new.ip,
-            cidr: if new.ip.is_ipv4() { 32 } else { 128 },
-        }];
-        if old.is_none() || matches!(old, Some(old) if old.allowed_ips != new_allowed_ips) {
+        let new_allowed_ips = new.allowed_ips();
+        // The interface doesn't necessarily list allowed IPs in the order they were added.
+        let same_allowed_ips = |old: &PeerConfig| {
+            old.allowed_ips.len() == new_allowed_ips.len()
+                && new_allowed_ips
+                    .iter()
+                    .all(|ip| old.allowed_ips.contains(ip))
+        };
+        if old.is_none() || matches!(old, Some(old) if !same_allowed_ips(old)) {
             builder = builder
                 .replace_allowed_ips()
-                .add_allowed_ips(new_allowed_ips);
+                .add_allowed_ips(&new_allowed_ips);
             changes.push(ChangeString::new(
                 "AllowedIPs",
                 old.map(|o| &o.allowed_ips[..]),
-                Some(&new_allowed_ips[0]),
+                Some(&new_allowed_ips[..]),
             ));
         }
 
@@ -678,7 +1676,7 @@ impl<'a> PeerDiff<'a> {
     }
 }
 
-impl<'a> From<&'a Peer> for PeerConfigBuilder {
+impl From<&Peer> for PeerConfigBuilder {
     fn from(peer: &Peer) -> Self {
         PeerDiff::new(None, Some(peer))
             .expect("No Err on explicitly set peer data")
@@ -724,7 +1722,14 @@ impl Display for Timestring {
 impl FromStr for Timestring {
     type Err = &'static str;
 
+    /// A number followed by a time unit, or a bare number of seconds.
     fn from_str(timestring: &str) -> Result<Self, Self::Err> {
+        if let Ok(seconds) = timestring.parse() {
+            return Ok(Self {
+                timestring: timestring.to_string(),
+                seconds,
+            });
+        }
         if timestring.len() < 2 {
             Err("timestring isn't long enough!")
         } else {
@@ -743,7 +1748,7 @@ impl FromStr for Timestring {
 
             Ok(Self {
                 timestring: timestring.to_string(),
-                seconds: n * multiplier,
+                seconds: n.checked_mul(multiplier).ok_or("timestring is too long")?,
             })
         }
     }
@@ -768,6 +1773,22 @@ impl Hostname {
     pub fn is_valid(name: &str) -> bool {
         name.len() < 64 && HOSTNAME_REGEX.is_match(name)
     }
+
+    /// Whether `domain` is made of valid hostnames separated by dots, ex. `wg0.internal`.
+    /// Surrounding dots are ignored, and a domain that's otherwise empty is the same as none.
+    pub fn is_valid_domain(domain: &str) -> bool {
+        let domain = domain.trim_matches('.');
+        domain.is_empty() || (domain.len() < 254 && domain.split('.').all(Self::is_valid))
+    }
+}
+
+/// Parse a domain that peers' hostnames are written to the hosts file under.
+pub fn parse_hosts_domain(domain: &str) -> Result<String, &'static str> {
+    if Hostname::is_valid_domain(domain) {
+        Ok(domain.to_string())
+    } else {
+        Err("invalid domain (only dot-separated hostnames, which are alphanumeric with dashes)")
+    }
 }
 
 impl FromStr for Hostname {
@@ -842,25 +1863,320 @@ mod tests {
     use std::net::IpAddr;
     use wireguard_control::{Key, PeerConfigBuilder, PeerStats};
 
+    #[test]
+    fn test_interface_name_template() {
+        let template: InterfaceNameTemplate = "in-{network}".parse().unwrap();
+        assert_eq!(template.to_string(), "in-{network}");
+        assert_eq!(template.derive("tonari").unwrap().to_string(), "in-tonari");
+
+        // Long names get shortened to fit IFNAMSIZ, but stay distinct from each other.
+        let a = template.derive("engineering-west").unwrap().to_string();
+        let b = template.derive("engineering-east").unwrap().to_string();
+        assert_eq!(a.len(), 15);
+        assert!(a.starts_with("in-engineer"), "{}", a);
+        assert_ne!(a, b);
+        assert_eq!(a, template.derive("engineering-west").unwrap().to_string());
+
+        let suffixed: InterfaceNameTemplate = "{network}-vpn".parse().unwrap();
+        assert_eq!(suffixed.derive("home").unwrap().to_string(), "home-vpn");
+
+        assert!("no-placeholder".parse::<InterfaceNameTemplate>().is_err());
+        assert!("{network}-{network}"
+            .parse::<InterfaceNameTemplate>()
+            .is_err());
+        assert!("much-too-long-{network}"
+            .parse::<InterfaceNameTemplate>()
+            .unwrap()
+            .derive("net")
+            .is_err());
+        assert!("UPPER-{network}"
+            .parse::<InterfaceNameTemplate>()
+            .unwrap()
+            .derive("net")
+            .is_err());
+    }
+
+    #[test]
+    fn test_candidate_kind() {
+        let kind = |s: &str| CandidateKind::of(&s.parse().unwrap());
+        assert_eq!(kind("192.168.1.10:51820"), CandidateKind::Lan);
+        assert_eq!(kind("10.0.0.1:51820"), CandidateKind::Lan);
+        assert_eq!(kind("[fd00::1]:51820"), CandidateKind::Lan);
+        assert_eq!(kind("[fe80::1]:51820"), CandidateKind::Lan);
+        assert_eq!(kind("1.1.1.1:51820"), CandidateKind::Public);
+        assert_eq!(kind("[2606:4700::1111]:51820"), CandidateKind::Public);
+        assert_eq!(kind("example.com:51820"), CandidateKind::Public);
+    }
+
+    #[test]
+    fn test_cidr_utilization() {
+        let cidr = |cidr: &str| Cidr {
+            id: 1,
+            contents: CidrContents {
+                name: "test".into(),
+                cidr: cidr.parse().unwrap(),
+                parent: None,
+            },
+        };
+        let ips: Vec<IpAddr> = ["10.0.0.1", "10.0.0.2", "10.0.1.1", "fd00::1"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+
+        let usage = CidrUtilization::new(&cidr("10.0.0.0/24"), &ips);
+        assert_eq!((usage.used, usage.total), (2, 254));
+        let usage = CidrUtilization::new(&cidr("10.0.0.1/32"), &ips);
+        assert_eq!((usage.used, usage.total), (1, 1));
+        let usage = CidrUtilization::new(&cidr("10.0.0.0/31"), &ips);
+        assert_eq!((usage.used, usage.total), (1, 2));
+        let usage = CidrUtilization::new(&cidr("fd00::/64"), &ips);
+        assert_eq!((usage.used, usage.total), (1, u64::MAX));
+        let usage = CidrUtilization::new(&cidr("fd00::/120"), &ips);
+        assert_eq!(usage.total, 255);
+
+        let usage = |used| CidrUtilization {
+            cidr_id: 1,
+            used,
+            total: 10,
+        };
+        assert!(!usage(8).just_crossed(90));
+        assert!(usage(9).just_crossed(90));
+        assert!(!usage(10).just_crossed(90));
+    }
+
+    #[test]
+    fn test_api_version_compatibility() {
+        let server = ApiVersion {
+            min: 2,
+            max: 4,
+            server_version: "1.6.0".into(),
+        };
+        assert!(server.is_compatible_with(1, 2));
+        assert!(server.is_compatible_with(3, 3));
+        assert!(server.is_compatible_with(4, 9));
+        assert!(!server.is_compatible_with(1, 1));
+        assert!(!server.is_compatible_with(5, 6));
+    }
+
+    #[test]
+    fn test_network_mtu() {
+        let opts = |mtu| NetworkOpts {
+            no_routing: false,
+            backend: Default::default(),
+            mtu,
+        };
+        assert_eq!(opts(None).mtu_or(None), DEFAULT_MTU);
+        assert_eq!(opts(None).mtu_or(Some(1380)), 1380);
+        assert_eq!(opts(Some(1420)).mtu_or(Some(1380)), 1420);
+    }
+
+    #[test]
+    fn test_effective_mtu() {
+        let hint = |peer_id, mtu| MtuHint { peer_id, mtu };
+        let v4 = |_| Some(IpAddr::from([192, 0, 2, 1]));
+        let v6 = |_| Some("2001:db8::1".parse().unwrap());
+        assert_eq!(MtuHint::effective_mtu(1420, &[], v4), 1420);
+        let hints = [hint(1, 1500), hint(2, 1380), hint(3, 1400)];
+        assert_eq!(MtuHint::effective_mtu(1420, &hints, v4), 1320);
+        assert_eq!(MtuHint::effective_mtu(1420, &hints, v6), 1300);
+        // Peers without a known endpoint get the IPv6 overhead.
+        assert_eq!(MtuHint::effective_mtu(1420, &hints, |_| None), 1300);
+        let mixed = |peer_id| {
+            if peer_id == 2 {
+                v6(peer_id)
+            } else {
+                v4(peer_id)
+            }
+        };
+        assert_eq!(MtuHint::effective_mtu(1420, &hints, mixed), 1300);
+        assert_eq!(
+            MtuHint::effective_mtu(1420, &[hint(1, 1380), hint(2, 1390)], mixed),
+            1310
+        );
+        assert_eq!(MtuHint::effective_mtu(1280, &[hint(1, 1400)], v4), 1280);
+        assert!(!MtuHint::is_valid_mtu(100));
+        assert!(MtuHint::is_valid_mtu(1280));
+    }
+
+    #[test]
+    fn test_duplicate_ips() {
+        let peer = |id, name: &str, ip: &str| Peer {
+            id,
+            contents: PeerContents::new(
+                name.parse().unwrap(),
+                ip.parse().unwrap(),
+                1,
+                format!("key{}", id),
+            ),
+        };
+        let peers = vec![
+            peer(1, "a", "10.0.0.1"),
+            peer(2, "b", "10.0.0.2"),
+            peer(3, "c", "10.0.0.1"),
+            peer(4, "d", "fd00::1"),
+            peer(5, "e", "fd00:0:0::1"),
+        ];
+        assert_eq!(
+            DuplicateIp::find(&peers),
+            vec![
+                DuplicateIp {
+                    ip: "10.0.0.1".parse().unwrap(),
+                    peers: vec!["a".into(), "c".into()],
+                },
+                DuplicateIp {
+                    ip: "fd00::1".parse().unwrap(),
+                    peers: vec!["d".into(), "e".into()],
+                },
+            ]
+        );
+        assert!(DuplicateIp::find(&peers[..2]).is_empty());
+    }
+
+    #[test]
+    fn test_endpoint_source() {
+        let peer = Peer {
+            id: 1,
+            contents: PeerContents {
+                endpoint: Some("1.1.1.1:51820".parse().unwrap()),
+                candidates: vec![
+                    "192.168.1.10:51820".parse().unwrap(),
+                    "8.8.8.8:51820".parse().unwrap(),
+                ],
+                ..PeerContents::new(
+                    "peer1".parse().unwrap(),
+                    "10.0.0.1".parse().unwrap(),
+                    1,
+                    "4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=".to_owned(),
+                )
+            },
+        };
+        let source = |s: &str| EndpointSource::of(&peer, s.parse().unwrap());
+        assert_eq!(source("1.1.1.1:51820"), EndpointSource::Server);
+        assert_eq!(
+            source("192.168.1.10:51820"),
+            EndpointSource::Candidate(CandidateKind::Lan)
+        );
+        assert_eq!(
+            source("8.8.8.8:51820"),
+            EndpointSource::Candidate(CandidateKind::Public)
+        );
+        assert_eq!(source("1.1.1.1:51821"), EndpointSource::Unknown);
+    }
+
+    #[test]
+    fn test_feature_flags() {
+        let flags = FeatureFlags::with_overrides([("report-handshakes", true), ("relays", true)]);
+        assert!(flags.report_handshakes);
+        assert_eq!(
+            FeatureFlags {
+                report_handshakes: false,
+                ..flags
+            },
+            FeatureFlags::default()
+        );
+        assert!(FeatureFlags::default().set("relays", true).is_err());
+
+        // Flags from a newer server are ignored, and missing ones keep their defaults.
+        let flags: FeatureFlags = toml::from_str(
+            r#"
+                nat-traversal = false
+                dns-push = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            flags,
+            FeatureFlags {
+                nat_traversal: false,
+                ..Default::default()
+            }
+        );
+        for name in FeatureFlags::NAMES {
+            let mut flags = FeatureFlags::default();
+            assert!(flags.set(name, false).is_ok());
+            assert_eq!(flags.get(name), Some(false));
+        }
+        assert_eq!(FeatureFlags::default().get("relays"), None);
+    }
+
+    #[test]
+    fn test_timestring() {
+        let secs = |timestring: &str| timestring.parse::<Timestring>().map(Duration::from);
+        assert_eq!(secs("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(secs("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(secs("2w"), Ok(Duration::from_secs(2 * 7 * 24 * 60 * 60)));
+        // Plain numbers are seconds, like options that used to only take seconds.
+        assert_eq!(secs("60"), Ok(Duration::from_secs(60)));
+        assert!(secs("5y").is_err());
+        assert!(secs(&format!("{}w", u64::MAX / 2)).is_err());
+    }
+
+    #[test]
+    fn test_endpoint_port_policy() {
+        assert_eq!(
+            "51820".parse(),
+            Ok(PortRange {
+                start: 51820,
+                end: 51820
+            })
+        );
+        assert_eq!(
+            "51000-51999".parse(),
+            Ok(PortRange {
+                start: 51000,
+                end: 51999
+            })
+        );
+        assert!("51999-51000".parse::<PortRange>().is_err());
+        assert!("51820-".parse::<PortRange>().is_err());
+
+        let mut endpoints: Vec<Endpoint> = ["1.1.1.1:51820", "1.1.1.1:4444", "1.1.1.1:51500"]
+            .iter()
+            .map(|e| e.parse().unwrap())
+            .collect();
+        assert!(EndpointPortPolicy::default().allows(4444));
+
+        let policy = EndpointPortPolicy(vec![
+            "51820".parse().unwrap(),
+            "51000-51999".parse().unwrap(),
+        ]);
+        assert_eq!(policy.to_string(), "51820,51000-51999");
+        let rejected = policy.retain_allowed(&mut endpoints);
+        assert_eq!(rejected, vec!["1.1.1.1:4444".parse().unwrap()]);
+        assert_eq!(endpoints.len(), 2);
+    }
+
+    #[test]
+    fn test_candidate_weights_parse() {
+        let default = CandidateWeights::default();
+        assert_eq!(default.to_string().parse(), Ok(default));
+        assert_eq!(
+            "public=50".parse(),
+            Ok(CandidateWeights {
+                public: 50,
+                ..default
+            })
+        );
+        assert_eq!(
+            " lan = 1 , observed=2".parse(),
+            Ok(CandidateWeights {
+                lan: 1,
+                observed: 2,
+                ..default
+            })
+        );
+        assert!("relay=5".parse::<CandidateWeights>().is_err());
+        assert!("lan".parse::<CandidateWeights>().is_err());
+        assert!("lan=-1".parse::<CandidateWeights>().is_err());
+    }
+
     #[test]
     fn test_peer_no_diff() {
         const PUBKEY: &str = "4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=";
         let ip: IpAddr = "10.0.0.1".parse().unwrap();
         let peer = Peer {
             id: 1,
-            contents: PeerContents {
-                name: "peer1".parse().unwrap(),
-                ip,
-                cidr_id: 1,
-                public_key: PUBKEY.to_owned(),
-                endpoint: None,
-                persistent_keepalive_interval: None,
-                is_admin: false,
-                is_disabled: false,
-                is_redeemed: true,
-                invite_expires: None,
-                candidates: vec![],
-            },
+            contents: PeerContents::new("peer1".parse().unwrap(), ip, 1, PUBKEY.to_owned()),
         };
         let builder =
             PeerConfigBuilder::new(&Key::from_base64(PUBKEY).unwrap()).add_allowed_ip(ip, 32);
@@ -877,6 +2193,68 @@ mod tests {
         assert_eq!(diff, None);
     }
 
+    #[test]
+    fn test_extra_allowed_ips() {
+        const PUBKEY: &str = "4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=";
+        let peer = |id, name: &str, ip: &str, extra: &[&str]| Peer {
+            id,
+            contents: PeerContents {
+                extra_allowed_ips: extra.iter().map(|net| net.parse().unwrap()).collect(),
+                ..PeerContents::new(
+                    name.parse().unwrap(),
+                    ip.parse().unwrap(),
+                    1,
+                    PUBKEY.to_owned(),
+                )
+            },
+        };
+        let gateway = peer(1, "gateway", "10.0.0.1", &["10.0.8.0/24", "10.0.9.0/24"]);
+        let config = PeerConfigBuilder::from(&gateway).into_peer_config();
+        assert_eq!(config.allowed_ips, gateway.allowed_ips());
+        assert_eq!(config.allowed_ips[0].cidr, 32);
+        for (ip, covered) in [
+            ("10.0.0.1", true),
+            ("10.0.9.200", true),
+            ("10.0.10.1", false),
+        ] {
+            assert_eq!(gateway.covers(&ip.parse().unwrap()), covered, "{}", ip);
+        }
+
+        // The interface listing the allowed IPs in another order isn't a change.
+        let mut allowed_ips = gateway.allowed_ips();
+        allowed_ips.reverse();
+        let info = PeerInfo {
+            config: PeerConfigBuilder::new(&Key::from_base64(PUBKEY).unwrap())
+                .add_allowed_ips(&allowed_ips)
+                .into_peer_config(),
+            stats: Default::default(),
+        };
+        assert_eq!(PeerDiff::new(Some(&info), Some(&gateway)).unwrap(), None);
+
+        let network: IpNet = "10.0.0.0/16".parse().unwrap();
+        let others = [
+            peer(2, "other", "10.0.0.2", &[]),
+            peer(3, "other-gateway", "10.0.0.3", &["10.0.10.0/24"]),
+        ];
+        assert!(gateway.check_allowed_ips(&network, &others).is_ok());
+        for extra in [
+            "10.0.0.0/24",
+            "10.0.10.128/25",
+            "10.0.0.0/8",
+            "192.168.0.0/24",
+        ] {
+            let gateway = peer(1, "gateway", "10.0.0.1", &[extra]);
+            assert!(
+                gateway.check_allowed_ips(&network, &others).is_err(),
+                "{}",
+                extra
+            );
+        }
+        // Nor can a new peer take an IP that's routed to a gateway.
+        let new = peer(4, "new", "10.0.10.5", &[]);
+        assert!(new.check_allowed_ips(&network, &others).is_err());
+    }
+
     #[test]
     fn test_peer_diff() {
         const PUBKEY: &str = "4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=";
@@ -884,17 +2262,8 @@ mod tests {
         let peer = Peer {
             id: 1,
             contents: PeerContents {
-                name: "peer1".parse().unwrap(),
-                ip,
-                cidr_id: 1,
-                public_key: PUBKEY.to_owned(),
-                endpoint: None,
                 persistent_keepalive_interval: Some(15),
-                is_admin: false,
-                is_disabled: false,
-                is_redeemed: true,
-                invite_expires: None,
-                candidates: vec![],
+                ..PeerContents::new("peer1".parse().unwrap(), ip, 1, PUBKEY.to_owned())
             },
         };
         let builder =
@@ -909,7 +2278,7 @@ mod tests {
 
         println!("{:?}", peer);
         println!("{:?}", info.config);
-        assert!(matches!(diff, Some(_)));
+        assert!(diff.is_some());
     }
 
     #[test]
@@ -919,17 +2288,8 @@ mod tests {
         let peer = Peer {
             id: 1,
             contents: PeerContents {
-                name: "peer1".parse().unwrap(),
-                ip,
-                cidr_id: 1,
-                public_key: PUBKEY.to_owned(),
                 endpoint: Some("1.1.1.1:1111".parse().unwrap()),
-                persistent_keepalive_interval: None,
-                is_admin: false,
-                is_disabled: false,
-                is_redeemed: true,
-                invite_expires: None,
-                candidates: vec![],
+                ..PeerContents::new("peer1".parse().unwrap(), ip, 1, PUBKEY.to_owned())
             },
         };
         let builder =
@@ -954,4 +2314,39 @@ mod tests {
         info.stats.last_handshake_time = Some(SystemTime::now());
         assert!(matches!(PeerDiff::new(Some(&info), Some(&peer)), Ok(None)));
     }
+
+    #[test]
+    fn test_ip_assignment() {
+        let cidr: IpNet = "10.0.0.0/29".parse().unwrap();
+        let taken = ["10.0.0.1", "10.0.0.6"].map(|ip| ip.parse::<IpAddr>().unwrap());
+        let is_taken = |ip: &IpAddr| taken.contains(ip);
+        let assign = |assignment: IpAssignment| assignment.assign(&cidr, is_taken);
+
+        assert_eq!(
+            assign(IpAssignment::LowestAvailable).unwrap(),
+            "10.0.0.2".parse::<IpAddr>().unwrap()
+        );
+        // The broadcast address (10.0.0.7) is never handed out.
+        assert_eq!(
+            assign(IpAssignment::HighestAvailable).unwrap(),
+            "10.0.0.5".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            assign(IpAssignment::Specific("10.0.0.4".parse().unwrap())).unwrap(),
+            "10.0.0.4".parse::<IpAddr>().unwrap()
+        );
+        for ip in ["10.0.0.1", "10.0.0.0", "10.0.0.7", "10.0.1.1"] {
+            assert!(assign(IpAssignment::Specific(ip.parse().unwrap())).is_err());
+        }
+
+        let full: IpNet = "10.0.0.0/30".parse().unwrap();
+        assert!(IpAssignment::HighestAvailable
+            .assign(&full, |_| true)
+            .is_err());
+        assert_eq!("highest".parse(), Ok(IpOrder::Highest));
+        assert_eq!(
+            IpAssignment::from(IpOrder::default()),
+            IpAssignment::LowestAvailable
+        );
+    }
 }
@@ -1,12 +1,13 @@
 use crate::{Error, IoErrorContext, NetworkOpts, Peer, PeerDiff};
+use anyhow::anyhow;
 use ipnet::IpNet;
 use std::{
-    io,
+    fmt, io,
     net::{IpAddr, SocketAddr},
     time::Duration,
 };
 use wireguard_control::{
-    Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder, PeerInfo,
+    AllowedIp, Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder, PeerInfo,
 };
 
 #[cfg(target_os = "macos")]
@@ -57,9 +58,9 @@ pub fn set_addr(interface: &InterfaceName, addr: IpNet) -> Result<(), io::Error>
 }
 
 #[cfg(target_os = "macos")]
-pub fn set_up(interface: &InterfaceName, mtu: u32) -> Result<(), io::Error> {
+pub fn set_up(interface: &InterfaceName) -> Result<(), io::Error> {
     let real_interface = wireguard_control::backends::userspace::resolve_tun(interface)?;
-    cmd("ifconfig", &[&real_interface, "mtu", &mtu.to_string()])?;
+    cmd("ifconfig", &[&real_interface, "up"])?;
     Ok(())
 }
 
@@ -67,7 +68,7 @@ pub fn set_up(interface: &InterfaceName, mtu: u32) -> Result<(), io::Error> {
 pub use super::netlink::set_addr;
 
 #[cfg(target_os = "linux")]
-pub use super::netlink::set_up;
+pub use wireguard_control::backends::kernel::set_link_up as set_up;
 
 pub fn up(
     interface: &InterfaceName,
@@ -76,8 +77,9 @@ pub fn up(
     listen_port: Option<u16>,
     peer: Option<(&str, IpAddr, SocketAddr)>,
     network: NetworkOpts,
+    mtu: u32,
 ) -> Result<(), io::Error> {
-    let mut device = DeviceUpdate::new();
+    let mut device = DeviceUpdate::new().set_mtu(mtu);
     if let Some((public_key, address, endpoint)) = peer {
         let prefix = if address.is_ipv4() { 32 } else { 128 };
         let peer_config = PeerConfigBuilder::new(
@@ -100,7 +102,7 @@ pub fn up(
         .set_private_key(wireguard_control::Key::from_base64(private_key).unwrap())
         .apply(interface, network.backend)?;
     set_addr(interface, address)?;
-    set_up(interface, network.mtu.unwrap_or(1280))?;
+    set_up(interface)?;
     if !network.no_routing {
         add_route(interface, address)?;
     }
@@ -118,9 +120,23 @@ pub fn set_listen_port(
     } else {
         device = device.randomize_listen_port();
     }
-    device.apply(interface, backend)?;
+    device
+        .apply(interface, backend)
+        .map_err(|e| listen_port_error(interface, listen_port, e))
+}
 
-    Ok(())
+/// Explain a failure to set the listen port, which is most often the port being taken.
+fn listen_port_error(interface: &InterfaceName, listen_port: Option<u16>, e: io::Error) -> Error {
+    match (listen_port, e.raw_os_error()) {
+        (Some(port), Some(libc::EADDRINUSE)) => anyhow!(
+            "UDP port {} is already in use on this machine, so {} can't listen on it ({}). \
+             Pick another port, or use --random.",
+            port,
+            interface,
+            e
+        ),
+        _ => Error::new(e).context(format!("failed to set the listen port of {}", interface)),
+    }
 }
 
 pub fn down(interface: &InterfaceName, backend: Backend) -> Result<(), Error> {
@@ -130,6 +146,33 @@ pub fn down(interface: &InterfaceName, backend: Backend) -> Result<(), Error> {
         .with_str(interface.as_str_lossy())?)
 }
 
+/// Ensure that an existing interface belongs to the network about to use it, so that
+/// bringing up one network never clobbers another network's (or a non-innernet)
+/// WireGuard interface that happens to share the same name.
+pub fn ensure_interface_owned(
+    interface: &InterfaceName,
+    device_public_key: Option<&Key>,
+    private_key: &str,
+) -> Result<(), Error> {
+    let public_key = Key::from_base64(private_key)?.get_public();
+    match device_public_key {
+        Some(existing) if existing != &public_key => Err(anyhow!(
+            "interface {} already exists with a different key (public key {}), likely belonging \
+             to another network. Refusing to modify it.",
+            interface,
+            existing.to_base64()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Compare an interface's live fwmark with the desired one, returning the fwmark to
+/// apply if they differ. An unset desired fwmark leaves whatever is live alone.
+pub fn fwmark_update(live: Option<u32>, desired: Option<u32>) -> Option<u32> {
+    // An unset fwmark is the same as a fwmark of 0.
+    desired.filter(|desired| live.unwrap_or(0) != *desired)
+}
+
 /// Add a route in the OS's routing table to get traffic flowing through this interface.
 /// Returns an error if the process doesn't exit successfully, otherwise returns
 /// true if the route was changed, false if the route already exists.
@@ -174,6 +217,44 @@ pub trait DeviceExt {
 
     // /// Get a peer by their public key, a helper function.
     fn get_peer(&self, public_key: &str) -> Option<&PeerInfo>;
+
+    /// Compare the allowed IPs on the interface against the (order-independent) set
+    /// that a list of server-reported peers calls for.
+    fn allowed_ip_mismatches<'a>(&self, peers: &'a [Peer]) -> Vec<AllowedIpMismatch<'a>>;
+}
+
+/// A peer whose allowed IPs on the interface differ from what it should have.
+#[derive(Debug)]
+pub struct AllowedIpMismatch<'a> {
+    pub peer: &'a Peer,
+    pub intended: Vec<AllowedIp>,
+    /// `None` if the peer is missing from the interface entirely.
+    pub applied: Option<Vec<AllowedIp>>,
+}
+
+impl<'a> fmt::Display for AllowedIpMismatch<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |ips: &[AllowedIp]| {
+            ips.iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        write!(
+            f,
+            "peer {} should have allowed IPs [{}] but ",
+            self.peer.name,
+            list(&self.intended)
+        )?;
+        match &self.applied {
+            Some(applied) => write!(f, "has [{}]", list(applied)),
+            None => write!(f, "is missing from the interface"),
+        }
+    }
+}
+
+fn allowed_ips_match(intended: &[AllowedIp], applied: &[AllowedIp]) -> bool {
+    intended.iter().all(|ip| applied.contains(ip)) && applied.iter().all(|ip| intended.contains(ip))
 }
 
 impl DeviceExt for Device {
@@ -213,7 +294,34 @@ impl DeviceExt for Device {
     fn get_peer(&self, public_key: &str) -> Option<&PeerInfo> {
         Key::from_base64(public_key)
             .ok()
-            .and_then(|key| self.peers.iter().find(|peer| peer.config.public_key == key))
+            .and_then(|key| self.find_peer(&key))
+    }
+
+    fn allowed_ip_mismatches<'a>(&self, peers: &'a [Peer]) -> Vec<AllowedIpMismatch<'a>> {
+        let interface_public_key = self
+            .public_key
+            .as_ref()
+            .map(|k| k.to_base64())
+            .unwrap_or_default();
+
+        peers
+            .iter()
+            .filter(|peer| !peer.is_disabled && peer.public_key != interface_public_key)
+            .filter_map(|peer| {
+                let intended = PeerConfigBuilder::from(peer).into_peer_config().allowed_ips;
+                let applied = self
+                    .get_peer(&peer.public_key)
+                    .map(|info| info.config.allowed_ips.clone());
+                match &applied {
+                    Some(applied) if allowed_ips_match(&intended, applied) => None,
+                    _ => Some(AllowedIpMismatch {
+                        peer,
+                        intended,
+                        applied,
+                    }),
+                }
+            })
+            .collect()
     }
 }
 
@@ -226,12 +334,80 @@ impl PeerInfoExt for PeerInfo {
     fn is_recently_connected(&self) -> bool {
         const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
 
-        let last_handshake = self
-            .stats
-            .last_handshake_time
-            .and_then(|t| t.elapsed().ok())
-            .unwrap_or(Duration::MAX);
+        !self.stats.is_stale(REJECT_AFTER_TIME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_port_error() {
+        let interface: InterfaceName = "innernet".parse().unwrap();
+        let in_use = io::Error::from_raw_os_error(libc::EADDRINUSE);
+        let message = listen_port_error(&interface, Some(51820), in_use).to_string();
+        assert!(message.contains("51820 is already in use"), "{}", message);
+
+        let other = listen_port_error(&interface, None, io::ErrorKind::Other.into());
+        assert_eq!(
+            other.to_string(),
+            "failed to set the listen port of innernet"
+        );
+    }
+
+    #[test]
+    fn test_colliding_interface_names() {
+        let interface: InterfaceName = "innernet".parse().unwrap();
+        let network_a = Key::generate_private();
+        let network_b = Key::generate_private();
+
+        // Network A brought the interface up first.
+        let device_public_key = network_a.get_public();
+
+        assert!(ensure_interface_owned(
+            &interface,
+            Some(&device_public_key),
+            &network_a.to_base64()
+        )
+        .is_ok());
+        assert!(ensure_interface_owned(
+            &interface,
+            Some(&device_public_key),
+            &network_b.to_base64()
+        )
+        .is_err());
+        assert!(ensure_interface_owned(&interface, None, &network_b.to_base64()).is_ok());
+    }
+
+    #[test]
+    fn test_allowed_ips_match() {
+        let ip = |s: &str| s.parse::<AllowedIp>().unwrap();
+        let intended = [ip("10.0.0.1/32"), ip("10.1.0.0/16")];
+
+        assert!(allowed_ips_match(
+            &intended,
+            &[ip("10.1.0.0/16"), ip("10.0.0.1/32")]
+        ));
+        assert!(!allowed_ips_match(&intended, &[ip("10.0.0.1/32")]));
+        assert!(!allowed_ips_match(
+            &intended,
+            &[ip("10.0.0.1/24"), ip("10.1.0.0/16")]
+        ));
+        assert!(!allowed_ips_match(
+            &intended,
+            &[ip("10.0.0.1/32"), ip("10.1.0.0/16"), ip("10.2.0.0/16")]
+        ));
+    }
 
-        last_handshake <= REJECT_AFTER_TIME
+    #[test]
+    fn test_fwmark_update() {
+        assert_eq!(fwmark_update(Some(51820), None), None);
+        assert_eq!(fwmark_update(Some(51820), Some(51820)), None);
+        assert_eq!(fwmark_update(Some(51820), Some(1)), Some(1));
+        assert_eq!(fwmark_update(None, Some(1)), Some(1));
+        assert_eq!(fwmark_update(None, Some(0)), None);
+        assert_eq!(fwmark_update(Some(0), Some(0)), None);
+        assert_eq!(fwmark_update(Some(1), Some(0)), Some(0));
     }
 }
@@ -1,5 +1,6 @@
 use crate::{
-    device::AllowedIp, Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfig,
+    device::{missing_interface_error, normalize_endpoint, AllowedIp},
+    Backend, Device, DeviceUpdate, InterfaceName, InvalidInterfaceName, Key, PeerConfig,
     PeerConfigBuilder, PeerInfo, PeerStats,
 };
 use netlink_packet_core::{
@@ -21,9 +22,19 @@ use netlink_packet_wireguard::{
     nlas::{WgAllowedIp, WgAllowedIpAttrs, WgDeviceAttrs, WgPeer, WgPeerAttrs},
     Wireguard, WireguardCmd,
 };
-use netlink_request::{netlink_request_genl, netlink_request_rtnl, MAX_GENL_PAYLOAD_LENGTH};
+use netlink_request::{
+    netlink_request_genl, netlink_request_rtnl, resolve_genl_family, MAX_GENL_PAYLOAD_LENGTH,
+};
+
+use std::{convert::TryFrom, fmt, io, sync::Once, time::Duration};
 
-use std::{convert::TryFrom, io};
+/// Attempts made at sending each SetDevice message when the kernel reports a transient error.
+const SET_DEVICE_ATTEMPTS: u32 = 3;
+/// The delay before the first retry of a SetDevice message, doubled for each one after.
+const SET_DEVICE_RETRY_BACKOFF: Duration = Duration::from_millis(20);
+/// The version of the kernel's `wireguard` generic netlink family (`WG_GENL_VERSION`) that
+/// this backend was written and tested against.
+pub const TESTED_GENL_VERSION: u32 = 1;
 
 macro_rules! get_nla_value {
     ($nlas:expr, $e:ident, $v:ident) => {
@@ -34,14 +45,54 @@ macro_rules! get_nla_value {
     };
 }
 
-impl<'a> TryFrom<WgAllowedIp> for AllowedIp {
+/// A netlink response that's missing an attribute the kernel always sends, which usually
+/// means the kernel's `wireguard` netlink family doesn't match what this backend expects.
+///
+/// Converts into an [`io::Error`] of kind [`io::ErrorKind::InvalidData`] that wraps it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseDeviceError {
+    /// A device without `WGDEVICE_A_IFNAME`.
+    MissingIfName,
+    /// A peer without `WGPEER_A_PUBLIC_KEY`.
+    MissingPublicKey,
+    /// An allowed IP without `WGALLOWEDIP_A_IPADDR`.
+    MissingIpAddr,
+    /// An allowed IP without `WGALLOWEDIP_A_CIDR_MASK`.
+    MissingCidr,
+}
+
+impl fmt::Display for ParseDeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (item, attribute) = match self {
+            Self::MissingIfName => ("device", "interface name (IfName)"),
+            Self::MissingPublicKey => ("peer", "public key (PublicKey)"),
+            Self::MissingIpAddr => ("allowed IP", "address (IpAddr)"),
+            Self::MissingCidr => ("allowed IP", "prefix length (Cidr)"),
+        };
+        write!(
+            f,
+            "netlink response has a {} without its {} attribute",
+            item, attribute
+        )
+    }
+}
+
+impl std::error::Error for ParseDeviceError {}
+
+impl From<ParseDeviceError> for io::Error {
+    fn from(e: ParseDeviceError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+impl TryFrom<WgAllowedIp> for AllowedIp {
     type Error = io::Error;
 
     fn try_from(attrs: WgAllowedIp) -> Result<Self, Self::Error> {
         let address = *get_nla_value!(attrs, WgAllowedIpAttrs, IpAddr)
-            .ok_or_else(|| io::ErrorKind::NotFound)?;
-        let cidr = *get_nla_value!(attrs, WgAllowedIpAttrs, Cidr)
-            .ok_or_else(|| io::ErrorKind::NotFound)?;
+            .ok_or(ParseDeviceError::MissingIpAddr)?;
+        let cidr =
+            *get_nla_value!(attrs, WgAllowedIpAttrs, Cidr).ok_or(ParseDeviceError::MissingCidr)?;
         Ok(AllowedIp { address, cidr })
     }
 }
@@ -73,8 +124,11 @@ impl PeerConfigBuilder {
         if let Some(i) = self.persistent_keepalive_interval {
             attrs.push(WgPeerAttrs::PersistentKeepalive(i));
         }
-        let allowed_ips: Vec<_> = self.allowed_ips.iter().map(AllowedIp::to_nla).collect();
-        attrs.push(WgPeerAttrs::AllowedIps(allowed_ips));
+        // Peers whose allowed IPs aren't changing don't need them re-sent.
+        if !self.keeps_allowed_ips() {
+            let allowed_ips: Vec<_> = self.allowed_ips.iter().map(AllowedIp::to_nla).collect();
+            attrs.push(WgPeerAttrs::AllowedIps(allowed_ips));
+        }
         if self.remove_me {
             flags |= WGPEER_F_REMOVE_ME;
         }
@@ -88,15 +142,17 @@ impl PeerConfigBuilder {
     }
 }
 
-impl<'a> TryFrom<WgPeer> for PeerInfo {
+impl TryFrom<WgPeer> for PeerInfo {
     type Error = io::Error;
 
     fn try_from(attrs: WgPeer) -> Result<Self, Self::Error> {
         let public_key = get_nla_value!(attrs, WgPeerAttrs, PublicKey)
             .map(|key| Key(*key))
-            .ok_or(io::ErrorKind::NotFound)?;
+            .ok_or(ParseDeviceError::MissingPublicKey)?;
         let preshared_key = get_nla_value!(attrs, WgPeerAttrs, PresharedKey).map(|key| Key(*key));
-        let endpoint = get_nla_value!(attrs, WgPeerAttrs, Endpoint).cloned();
+        let endpoint = get_nla_value!(attrs, WgPeerAttrs, Endpoint)
+            .cloned()
+            .map(normalize_endpoint);
         let persistent_keepalive_interval =
             get_nla_value!(attrs, WgPeerAttrs, PersistentKeepalive).cloned();
         let allowed_ips = get_nla_value!(attrs, WgPeerAttrs, AllowedIps)
@@ -119,7 +175,6 @@ impl<'a> TryFrom<WgPeer> for PeerInfo {
                 endpoint,
                 persistent_keepalive_interval,
                 allowed_ips,
-                __cant_construct_me: (),
             },
             stats: PeerStats {
                 last_handshake_time,
@@ -135,12 +190,15 @@ impl<'a> TryFrom<&'a [WgDeviceAttrs]> for Device {
 
     fn try_from(nlas: &'a [WgDeviceAttrs]) -> Result<Self, Self::Error> {
         let name = get_nla_value!(nlas, WgDeviceAttrs, IfName)
-            .ok_or_else(|| io::ErrorKind::NotFound)?
+            .ok_or(ParseDeviceError::MissingIfName)?
             .parse()?;
         let public_key = get_nla_value!(nlas, WgDeviceAttrs, PublicKey).map(|key| Key(*key));
         let private_key = get_nla_value!(nlas, WgDeviceAttrs, PrivateKey).map(|key| Key(*key));
         let listen_port = get_nla_value!(nlas, WgDeviceAttrs, ListenPort).cloned();
-        let fwmark = get_nla_value!(nlas, WgDeviceAttrs, Fwmark).cloned();
+        // The kernel always sends a fwmark, using 0 for none, unlike the userspace backend.
+        let fwmark = get_nla_value!(nlas, WgDeviceAttrs, Fwmark)
+            .cloned()
+            .filter(|fwmark| *fwmark != 0);
         let peers = nlas
             .iter()
             .filter_map(|nla| match nla {
@@ -159,17 +217,35 @@ impl<'a> TryFrom<&'a [WgDeviceAttrs]> for Device {
             peers,
             linked_name: None,
             backend: Backend::Kernel,
-            __cant_construct_me: (),
         })
     }
 }
 
 pub fn enumerate() -> Result<Vec<InterfaceName>, io::Error> {
-    let link_responses = netlink_request_rtnl(
+    enumerate_iter().collect()
+}
+
+/// Like [`enumerate`], but yields each interface as its link message is looked at, so callers
+/// looking for one interface can stop early without the other links' attributes being parsed.
+/// A failed request is yielded as the only item.
+pub fn enumerate_iter() -> impl Iterator<Item = io::Result<InterfaceName>> {
+    let (link_responses, error) = match netlink_request_rtnl(
         RtnlMessage::GetLink(LinkMessage::default()),
         Some(NLM_F_DUMP | NLM_F_REQUEST),
-    )?;
-    let links = link_responses
+    ) {
+        Ok(link_responses) => (link_responses, None),
+        Err(e) => (vec![], Some(e)),
+    };
+    error
+        .map(Err)
+        .into_iter()
+        .chain(wireguard_link_names(link_responses).map(Ok))
+}
+
+fn wireguard_link_names(
+    link_responses: Vec<NetlinkMessage<RtnlMessage>>,
+) -> impl Iterator<Item = InterfaceName> {
+    link_responses
         .into_iter()
         // Filter out non-link messages
         .filter_map(|response| match response {
@@ -188,13 +264,118 @@ pub fn enumerate() -> Result<Vec<InterfaceName>, io::Error> {
             false
         })
         .filter_map(|link| link.nlas.iter().find_map(|nla| match nla {
-            link::nlas::Nla::IfName(name) => Some(name.clone()),
+            link::nlas::Nla::IfName(name) => name.parse().ok(),
             _ => None,
         }))
-        .filter_map(|name| name.parse().ok())
-        .collect::<Vec<_>>();
+}
+
+/// A link that [`enumerate_with_diagnostics`] couldn't interpret as a WireGuard interface.
+#[derive(Debug, PartialEq)]
+pub struct SkippedLink {
+    /// The kernel's interface index for the link (0 if not a link message).
+    pub index: u32,
+    /// The link's name, if the kernel reported one.
+    pub name: Option<String>,
+    pub reason: SkipReason,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SkipReason {
+    /// The netlink response wasn't a link message.
+    UnexpectedMessage,
+    /// The link has link info, but no link kind within it.
+    MissingKind,
+    /// A WireGuard link without an interface name.
+    MissingName,
+    /// A WireGuard link whose name couldn't be parsed as an [`InterfaceName`].
+    InvalidName(InvalidInterfaceName),
+}
 
-    Ok(links)
+impl fmt::Display for SkippedLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "link {} ({}): ",
+            self.index,
+            self.name.as_deref().unwrap_or("unnamed")
+        )?;
+        match &self.reason {
+            SkipReason::UnexpectedMessage => write!(f, "unexpected netlink message"),
+            SkipReason::MissingKind => write!(f, "link info is missing the link kind"),
+            SkipReason::MissingName => write!(f, "WireGuard link has no name"),
+            SkipReason::InvalidName(e) => write!(f, "invalid interface name: {}", e),
+        }
+    }
+}
+
+/// Like [`enumerate`], but also returns any links that were skipped and why, to help debug
+/// WireGuard interfaces that exist but don't show up. Links of other kinds aren't reported.
+pub fn enumerate_with_diagnostics() -> Result<(Vec<InterfaceName>, Vec<SkippedLink>), io::Error> {
+    let link_responses = netlink_request_rtnl(
+        RtnlMessage::GetLink(LinkMessage::default()),
+        Some(NLM_F_DUMP | NLM_F_REQUEST),
+    )?;
+
+    let mut names = vec![];
+    let mut skipped = vec![];
+    for response in link_responses {
+        let result = match response {
+            NetlinkMessage {
+                payload: NetlinkPayload::InnerMessage(RtnlMessage::NewLink(link)),
+                ..
+            } => classify_link(&link),
+            _ => Some(Err(SkippedLink {
+                index: 0,
+                name: None,
+                reason: SkipReason::UnexpectedMessage,
+            })),
+        };
+        match result {
+            Some(Ok(name)) => names.push(name),
+            Some(Err(link)) => {
+                log::debug!("enumerate: skipped {}", link);
+                skipped.push(link);
+            },
+            None => {},
+        }
+    }
+
+    Ok((names, skipped))
+}
+
+/// Returns `None` for links that aren't (or don't claim to be) WireGuard interfaces.
+fn classify_link(link: &LinkMessage) -> Option<Result<InterfaceName, SkippedLink>> {
+    let name = link.nlas.iter().find_map(|nla| match nla {
+        link::nlas::Nla::IfName(name) => Some(name.clone()),
+        _ => None,
+    });
+    let infos = link.nlas.iter().find_map(|nla| match nla {
+        link::nlas::Nla::Info(infos) => Some(infos),
+        _ => None,
+    })?;
+    let skipped = |reason| {
+        Some(Err(SkippedLink {
+            index: link.header.index,
+            name: name.clone(),
+            reason,
+        }))
+    };
+
+    if !infos.iter().any(|info| matches!(info, Info::Kind(_))) {
+        return skipped(SkipReason::MissingKind);
+    }
+    if !infos
+        .iter()
+        .any(|info| info == &Info::Kind(InfoKind::Wireguard))
+    {
+        return None;
+    }
+
+    match name.as_deref().map(str::parse::<InterfaceName>) {
+        Some(Ok(name)) => Some(Ok(name)),
+        Some(Err(e)) => skipped(SkipReason::InvalidName(e)),
+        None => skipped(SkipReason::MissingName),
+    }
 }
 
 fn add_del(iface: &InterfaceName, add: bool) -> io::Result<()> {
@@ -217,8 +398,73 @@ fn add_del(iface: &InterfaceName, add: bool) -> io::Result<()> {
     }
 }
 
+/// What the running kernel's WireGuard module supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelCapabilities {
+    pub genl_family_id: u16,
+    /// The version of the `wireguard` generic netlink family.
+    pub genl_version: u32,
+}
+
+impl KernelCapabilities {
+    /// Whether the kernel speaks the netlink interface version this backend was tested
+    /// against. Other versions may add attributes or change semantics.
+    pub fn is_tested_version(&self) -> bool {
+        self.genl_version == TESTED_GENL_VERSION
+    }
+}
+
+/// Query the kernel's WireGuard support, failing if the module isn't loaded.
+pub fn kernel_capabilities() -> io::Result<KernelCapabilities> {
+    let family = resolve_genl_family("wireguard")?;
+    Ok(KernelCapabilities {
+        genl_family_id: family.id,
+        genl_version: family.version,
+    })
+}
+
+/// Warn, once per process, if the kernel's WireGuard netlink version isn't the tested one.
+fn check_genl_version() {
+    static CHECKED: Once = Once::new();
+    CHECKED.call_once(|| match kernel_capabilities() {
+        Ok(capabilities) if !capabilities.is_tested_version() => log::warn!(
+            "the kernel's WireGuard netlink interface is version {}, but this build was tested \
+             against version {}. Some operations may misbehave.",
+            capabilities.genl_version,
+            TESTED_GENL_VERSION
+        ),
+        Ok(_) => {},
+        Err(e) => log::debug!("couldn't check the WireGuard netlink version: {}", e),
+    });
+}
+
 pub fn apply(builder: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
-    add_del(iface, true)?;
+    check_genl_version();
+    if builder.create_interface {
+        add_del(iface, true)?;
+    }
+    send_set_device_messages(apply_messages(builder, iface)?, |message| {
+        netlink_request_genl(message, Some(NLM_F_REQUEST | NLM_F_ACK)).map(|_| ())
+    })
+    .map_err(|e| match e.raw_os_error() {
+        Some(libc::ENODEV) if !builder.create_interface => missing_interface_error(iface),
+        _ => e,
+    })?;
+    if let Some(mtu) = builder.mtu {
+        set_mtu(iface, mtu)?;
+    }
+    Ok(())
+}
+
+/// Serialize a [`DeviceUpdate`] into the `WG_CMD_SET_DEVICE` messages that apply it.
+///
+/// Updates that fit in [`MAX_GENL_PAYLOAD_LENGTH`] always produce exactly one message, so the
+/// kernel applies them atomically. Only larger updates are split across messages, including
+/// single peers with more allowed IPs than fit in one.
+pub(crate) fn apply_messages(
+    builder: &DeviceUpdate,
+    iface: &InterfaceName,
+) -> io::Result<Vec<GenlMessage<Wireguard>>> {
     let mut payload = ApplyPayload::new(iface);
     if let Some(Key(k)) = builder.private_key {
         payload.push(WgDeviceAttrs::PrivateKey(k))?;
@@ -236,11 +482,109 @@ pub fn apply(builder: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
     builder
         .peers
         .iter()
-        .map(|peer| payload.push_peer(peer.to_nla()))
+        .map(|peer| {
+            #[cfg(feature = "tracing")]
+            let _span =
+                tracing::debug_span!("peer", public_key = %peer.public_key.to_base64()).entered();
+            let nla = peer.to_nla();
+            #[cfg(feature = "tracing")]
+            tracing::debug!(flags = ?peer_flag_names(&nla), "encoded peer");
+            payload.push_peer(nla)
+        })
         .collect::<Result<Vec<_>, _>>()?;
 
-    for message in payload.finish() {
-        netlink_request_genl(message, Some(NLM_F_REQUEST | NLM_F_ACK))?;
+    let messages = payload.finish();
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        messages = messages.len(),
+        peers = builder.peers.len(),
+        "serialized SetDevice update"
+    );
+    Ok(messages)
+}
+
+/// The names of the flags set on an encoded peer, for logging.
+#[cfg(feature = "tracing")]
+fn peer_flag_names(peer: &WgPeer) -> Vec<&'static str> {
+    let flags = get_nla_value!(peer, WgPeerAttrs, Flags)
+        .copied()
+        .unwrap_or_default();
+    [
+        (WGPEER_F_REMOVE_ME, "REMOVE_ME"),
+        (WGPEER_F_REPLACE_ALLOWEDIPS, "REPLACE_ALLOWEDIPS"),
+    ]
+    .into_iter()
+    .filter(|(flag, _)| flags & flag != 0)
+    .map(|(_, name)| name)
+    .collect()
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EBUSY) | Some(libc::EAGAIN) | Some(libc::EINTR)
+    )
+}
+
+fn peer_count(message: &GenlMessage<Wireguard>) -> usize {
+    message
+        .payload
+        .nlas
+        .iter()
+        .map(|nla| match nla {
+            WgDeviceAttrs::Peers(peers) => peers.len(),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Send SetDevice messages in order, retrying transient failures with backoff.
+///
+/// Sending stops at the first message that ultimately fails, and the error reports which
+/// message it was and how many peers had already been applied by the ones before it.
+fn send_set_device_messages(
+    messages: Vec<GenlMessage<Wireguard>>,
+    mut send: impl FnMut(GenlMessage<Wireguard>) -> io::Result<()>,
+) -> io::Result<()> {
+    let total = messages.len();
+    let mut peers_applied = 0;
+    for (i, message) in messages.into_iter().enumerate() {
+        let peers = peer_count(&message);
+        let mut backoff = SET_DEVICE_RETRY_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match send(message.clone()) {
+                Ok(()) => break,
+                Err(e) if is_transient(&e) && attempt < SET_DEVICE_ATTEMPTS => {
+                    log::debug!(
+                        "SetDevice message {} of {} failed (attempt {}), retrying: {}",
+                        i + 1,
+                        total,
+                        attempt,
+                        e
+                    );
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                },
+                // Nothing has been applied yet, so the kernel's error is the most useful.
+                Err(e) if i == 0 => return Err(e),
+                Err(e) => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!(
+                            "SetDevice message {} of {} failed after {} attempt(s), with {} peer(s) already applied: {}",
+                            i + 1,
+                            total,
+                            attempt,
+                            peers_applied,
+                            e
+                        ),
+                    ))
+                },
+            }
+        }
+        peers_applied += peers;
     }
     Ok(())
 }
@@ -308,6 +652,24 @@ impl ApplyPayload {
     /// A helper function to assist in breaking up large peer lists across multiple netlink messages
     pub fn push_peer(&mut self, peer: WgPeer) -> io::Result<()> {
         const EMPTY_PEERS: WgDeviceAttrs = WgDeviceAttrs::Peers(vec![]);
+        let max_peer_len = MAX_GENL_PAYLOAD_LENGTH
+            - [WgDeviceAttrs::IfName(self.iface.clone())]
+                .as_slice()
+                .buffer_len()
+            - EMPTY_PEERS.buffer_len();
+        if peer.buffer_len() > max_peer_len {
+            let fragments = split_peer(peer, max_peer_len)?;
+            log::warn!(
+                "a peer has too many allowed IPs for one netlink message, so they're applied \
+                 in {} messages instead of atomically.",
+                fragments.len()
+            );
+            for fragment in fragments {
+                self.push_peer(fragment)?;
+            }
+            return Ok(());
+        }
+
         let mut needs_peer_nla = !self
             .nlas
             .iter()
@@ -358,7 +720,53 @@ impl ApplyPayload {
     }
 }
 
+/// Split a peer whose allowed IPs don't all fit in one netlink message into fragments of at
+/// most `max_len` bytes. The first fragment has all of the peer's other attributes (like a
+/// `WGPEER_F_REPLACE_ALLOWEDIPS` flag), and the rest only add allowed IPs to it.
+fn split_peer(peer: WgPeer, max_len: usize) -> io::Result<Vec<WgPeer>> {
+    let too_large = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "encoded peer is too large, even with one allowed IP per message",
+        )
+    };
+    let (mut allowed_ips, mut attrs) = (vec![], vec![]);
+    for attr in peer.0 {
+        match attr {
+            WgPeerAttrs::AllowedIps(ips) => allowed_ips.extend(ips),
+            attr => attrs.push(attr),
+        }
+    }
+    let public_key = get_nla_value!(attrs, WgPeerAttrs, PublicKey)
+        .copied()
+        .ok_or_else(too_large)?;
+
+    let mut fragments = vec![];
+    let mut allowed_ips = allowed_ips.into_iter().peekable();
+    while allowed_ips.peek().is_some() {
+        let mut fragment = match fragments.is_empty() {
+            true => std::mem::take(&mut attrs),
+            false => vec![WgPeerAttrs::PublicKey(public_key)],
+        };
+        fragment.push(WgPeerAttrs::AllowedIps(vec![]));
+        let mut len = WgPeer(fragment.clone()).buffer_len();
+        let mut chunk = vec![];
+        while let Some(ip) = allowed_ips.next_if(|ip| len + ip.buffer_len() <= max_len) {
+            len += ip.buffer_len();
+            chunk.push(ip);
+        }
+        if chunk.is_empty() {
+            return Err(too_large());
+        }
+        fragment.pop();
+        fragment.push(WgPeerAttrs::AllowedIps(chunk));
+        fragments.push(WgPeer(fragment));
+    }
+    Ok(fragments)
+}
+
 pub fn get_by_name(name: &InterfaceName) -> Result<Device, io::Error> {
+    check_genl_version();
     let genlmsg: GenlMessage<Wireguard> = GenlMessage::from_payload(Wireguard {
         cmd: WireguardCmd::GetDevice,
         nlas: vec![WgDeviceAttrs::IfName(name.as_str_lossy().to_string())],
@@ -369,8 +777,7 @@ pub fn get_by_name(name: &InterfaceName) -> Result<Device, io::Error> {
         responses.len()
     );
 
-    let nlas = responses.into_iter().fold(Ok(vec![]), |nlas_res, nlmsg| {
-        let mut nlas = nlas_res?;
+    let nlas = responses.into_iter().try_fold(vec![], |mut nlas, nlmsg| {
         let mut message = match nlmsg {
             NetlinkMessage {
                 payload: NetlinkPayload::InnerMessage(message),
@@ -395,10 +802,188 @@ pub fn get_by_name(name: &InterfaceName) -> Result<Device, io::Error> {
     Ok(device)
 }
 
+/// Parse only the peer with `public_key` out of a device dump's peer attributes, skipping the
+/// rest. A peer with more allowed IPs than fit in one message is split across several, each
+/// repeating its public key, so later fragments' allowed IPs are merged into the first.
+fn find_peer(
+    peers: impl IntoIterator<Item = WgPeer>,
+    public_key: &Key,
+) -> io::Result<Option<PeerInfo>> {
+    let mut found: Option<WgPeer> = None;
+    for peer in peers {
+        if get_nla_value!(peer, WgPeerAttrs, PublicKey) != Some(&public_key.0) {
+            continue;
+        }
+        match found {
+            None => found = Some(peer),
+            Some(ref mut found) => {
+                let more_ips = peer.0.into_iter().find_map(|attr| match attr {
+                    WgPeerAttrs::AllowedIps(ips) => Some(ips),
+                    _ => None,
+                });
+                let existing = found.0.iter_mut().find_map(|attr| match attr {
+                    WgPeerAttrs::AllowedIps(ips) => Some(ips),
+                    _ => None,
+                });
+                match (existing, more_ips) {
+                    (Some(existing), Some(mut more_ips)) => existing.append(&mut more_ips),
+                    (None, Some(more_ips)) => found.0.push(WgPeerAttrs::AllowedIps(more_ips)),
+                    (_, None) => {},
+                }
+            },
+        }
+    }
+    found.map(PeerInfo::try_from).transpose()
+}
+
+/// Read a single peer of the interface `name`, including its [`PeerStats`], without building
+/// a [`PeerInfo`] for each of the device's other peers like [`get_by_name`] does.
+///
+/// `WG_CMD_GET_DEVICE` can't be filtered to one peer (the kernel ignores any peers sent with
+/// it), so this still has to request the device's full dump.
+pub fn get_peer_by_name(name: &InterfaceName, public_key: &Key) -> io::Result<Option<PeerInfo>> {
+    check_genl_version();
+    let genlmsg: GenlMessage<Wireguard> = GenlMessage::from_payload(Wireguard {
+        cmd: WireguardCmd::GetDevice,
+        nlas: vec![WgDeviceAttrs::IfName(name.as_str_lossy().to_string())],
+    });
+    let responses = netlink_request_genl(genlmsg, Some(NLM_F_REQUEST | NLM_F_DUMP | NLM_F_ACK))?;
+
+    let mut peers = vec![];
+    for nlmsg in responses {
+        let message = match nlmsg {
+            NetlinkMessage {
+                payload: NetlinkPayload::InnerMessage(message),
+                ..
+            } => message,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected netlink payload: {:?}", nlmsg),
+                ))
+            },
+        };
+        peers.extend(
+            message
+                .payload
+                .nlas
+                .into_iter()
+                .filter_map(|nla| match nla {
+                    WgDeviceAttrs::Peers(peers) => Some(peers),
+                    _ => None,
+                }),
+        );
+    }
+    find_peer(peers.into_iter().flatten(), public_key)
+}
+
 pub fn delete_interface(iface: &InterfaceName) -> io::Result<()> {
     add_del(iface, false)
 }
 
+/// The kernel's index for the link named `name`, if there is one.
+fn link_index(name: &str) -> io::Result<Option<u32>> {
+    let link_responses = netlink_request_rtnl(
+        RtnlMessage::GetLink(LinkMessage::default()),
+        Some(NLM_F_DUMP | NLM_F_REQUEST),
+    )?;
+    Ok(link_responses
+        .into_iter()
+        .find_map(|response| match response {
+            NetlinkMessage {
+                payload: NetlinkPayload::InnerMessage(RtnlMessage::NewLink(link)),
+                ..
+            } if link
+                .nlas
+                .iter()
+                .any(|nla| matches!(nla, link::nlas::Nla::IfName(n) if n == name)) =>
+            {
+                Some(link.header.index)
+            },
+            _ => None,
+        }))
+}
+
+/// Rename an interface in place, keeping its keys, peers, and their stats and sessions,
+/// unlike deleting and recreating it.
+///
+/// Fails with [`io::ErrorKind::AlreadyExists`] if any link (WireGuard or not) is already
+/// named `new`. Older kernels only allow renaming interfaces that are down, and report
+/// `EBUSY` otherwise.
+pub fn rename_interface(old: &InterfaceName, new: &InterfaceName) -> io::Result<()> {
+    if link_index(&new.as_str_lossy())?.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("can't rename {} to {}: {} already exists", old, new, new),
+        ));
+    }
+    let index = link_index(&old.as_str_lossy())?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("can't rename {}: no such interface", old),
+        )
+    })?;
+
+    let mut message = LinkMessage::default();
+    message.header.index = index;
+    message
+        .nlas
+        .push(link::nlas::Nla::IfName(new.as_str_lossy().to_string()));
+    netlink_request_rtnl(
+        RtnlMessage::SetLink(message),
+        Some(NLM_F_REQUEST | NLM_F_ACK),
+    )?;
+    Ok(())
+}
+
+/// Send a `SetLink` for the existing link named `iface`, failing with
+/// [`io::ErrorKind::NotFound`] (saying it couldn't `action`) if there's no such link.
+fn set_link(iface: &InterfaceName, action: &str, mut message: LinkMessage) -> io::Result<()> {
+    message.header.index = link_index(&iface.as_str_lossy())?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("can't {} {}: no such interface", action, iface),
+        )
+    })?;
+    netlink_request_rtnl(
+        RtnlMessage::SetLink(message),
+        Some(NLM_F_REQUEST | NLM_F_ACK),
+    )?;
+    Ok(())
+}
+
+/// Set the MTU of the link named `iface`, which needn't be a kernel WireGuard interface.
+pub fn set_mtu(iface: &InterfaceName, mtu: u32) -> io::Result<()> {
+    let mut message = LinkMessage::default();
+    message.nlas.push(link::nlas::Nla::Mtu(mtu));
+    set_link(iface, "set the MTU of", message)?;
+    log::debug!("set the MTU of {} to {}", iface, mtu);
+    Ok(())
+}
+
+/// The `SetLink` message that brings a link up or down, leaving its other flags alone.
+fn up_down_message(up: bool) -> LinkMessage {
+    let mut message = LinkMessage::default();
+    message.header.flags = if up { IFF_UP } else { 0 };
+    message.header.change_mask = IFF_UP;
+    message
+}
+
+/// Bring the link named `iface` up. Links that are already up are left as they are.
+pub fn set_link_up(iface: &InterfaceName) -> io::Result<()> {
+    set_link(iface, "bring up", up_down_message(true))?;
+    log::debug!("brought {} up", iface);
+    Ok(())
+}
+
+/// Bring the link named `iface` down, keeping its configuration. Links that are already
+/// down are left as they are.
+pub fn set_link_down(iface: &InterfaceName) -> io::Result<()> {
+    set_link(iface, "bring down", up_down_message(false))?;
+    log::debug!("brought {} down", iface);
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -406,6 +991,327 @@ mod tests {
     use netlink_request::MAX_NETLINK_BUFFER_LENGTH;
     use std::str::FromStr;
 
+    fn link(index: u32, nlas: Vec<link::nlas::Nla>) -> LinkMessage {
+        let mut link = LinkMessage::default();
+        link.header.index = index;
+        link.nlas = nlas;
+        link
+    }
+
+    #[test]
+    fn test_parse_device_error() {
+        let missing_cidr = WgAllowedIp(vec![WgAllowedIpAttrs::IpAddr("10.0.0.1".parse().unwrap())]);
+        let err = AllowedIp::try_from(missing_cidr).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.get_ref()
+                .and_then(|e| e.downcast_ref::<ParseDeviceError>()),
+            Some(&ParseDeviceError::MissingCidr)
+        );
+        assert!(err.to_string().contains("prefix length"));
+
+        let err = PeerInfo::try_from(WgPeer(vec![WgPeerAttrs::RxBytes(1)])).unwrap_err();
+        assert!(err.to_string().contains("public key"));
+
+        let err = Device::try_from(&[WgDeviceAttrs::ListenPort(51820)][..]).unwrap_err();
+        assert!(err.to_string().contains("interface name"));
+    }
+
+    #[test]
+    fn test_unset_fwmark_parsed_as_none() {
+        let device = |fwmark| {
+            Device::try_from(
+                &[
+                    WgDeviceAttrs::IfName("wg0".into()),
+                    WgDeviceAttrs::Fwmark(fwmark),
+                ][..],
+            )
+            .unwrap()
+        };
+        assert_eq!(device(0).fwmark, None);
+        assert_eq!(device(0x51820).fwmark, Some(0x51820));
+    }
+
+    #[test]
+    fn test_unset_persistent_keepalive_sent() {
+        let keepalive = |builder: PeerConfigBuilder| {
+            get_nla_value!(builder.to_nla(), WgPeerAttrs, PersistentKeepalive).cloned()
+        };
+        let peer = PeerConfigBuilder::new(&Key::generate_private().get_public());
+
+        assert_eq!(keepalive(peer.clone()), None);
+        assert_eq!(
+            keepalive(peer.clone().unset_persistent_keepalive()),
+            Some(0)
+        );
+        assert_eq!(
+            keepalive(peer.set_persistent_keepalive_interval(25)),
+            Some(25)
+        );
+    }
+
+    #[test]
+    fn test_untouched_allowed_ips_not_sent() {
+        let has_allowed_ips = |builder: PeerConfigBuilder| {
+            builder
+                .to_nla()
+                .0
+                .iter()
+                .any(|attr| matches!(attr, WgPeerAttrs::AllowedIps(_)))
+        };
+        let key = Key::generate_private().get_public();
+        let endpoint_only =
+            PeerConfigBuilder::new(&key).set_endpoint("1.1.1.1:51820".parse().unwrap());
+
+        assert!(!has_allowed_ips(endpoint_only.clone()));
+        assert!(has_allowed_ips(endpoint_only.clone().replace_allowed_ips()));
+        assert!(has_allowed_ips(
+            endpoint_only
+                .clone()
+                .add_allowed_ip("10.0.0.1".parse().unwrap(), 32)
+        ));
+        assert!(!has_allowed_ips(
+            endpoint_only
+                .replace_allowed_ips()
+                .add_allowed_ip("10.0.0.1".parse().unwrap(), 32)
+                .keep_allowed_ips()
+        ));
+    }
+
+    #[test]
+    fn test_find_peer() {
+        let key = |byte| Key([byte; 32]);
+        let allowed_ip = |address: &str| {
+            AllowedIp {
+                address: address.parse().unwrap(),
+                cidr: 32,
+            }
+            .to_nla()
+        };
+        let peers = vec![
+            WgPeer(vec![
+                WgPeerAttrs::PublicKey(key(1).0),
+                WgPeerAttrs::AllowedIps(vec![allowed_ip("10.0.0.1")]),
+            ]),
+            WgPeer(vec![
+                WgPeerAttrs::PublicKey(key(2).0),
+                WgPeerAttrs::RxBytes(100),
+                WgPeerAttrs::TxBytes(200),
+                WgPeerAttrs::AllowedIps(vec![allowed_ip("10.0.0.2")]),
+            ]),
+            // The rest of peer 2's allowed IPs, split into another message.
+            WgPeer(vec![
+                WgPeerAttrs::PublicKey(key(2).0),
+                WgPeerAttrs::AllowedIps(vec![allowed_ip("10.0.0.3")]),
+            ]),
+        ];
+
+        let peer = find_peer(peers.clone(), &key(2)).unwrap().unwrap();
+        assert_eq!(peer.config.public_key, key(2));
+        assert_eq!((peer.stats.rx_bytes, peer.stats.tx_bytes), (100, 200));
+        assert_eq!(
+            peer.config
+                .allowed_ips
+                .iter()
+                .map(|ip| ip.address.to_string())
+                .collect::<Vec<_>>(),
+            vec!["10.0.0.2", "10.0.0.3"]
+        );
+        assert!(find_peer(peers, &key(3)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_kernel_capabilities_version() {
+        let capabilities = |genl_version| KernelCapabilities {
+            genl_family_id: 30,
+            genl_version,
+        };
+        assert!(capabilities(TESTED_GENL_VERSION).is_tested_version());
+        assert!(!capabilities(TESTED_GENL_VERSION + 1).is_tested_version());
+    }
+
+    #[test]
+    fn test_set_mtu_missing_interface() {
+        let missing: InterfaceName = "wgmtutest0".parse().unwrap();
+        assert_eq!(
+            set_mtu(&missing, 1280).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        // The MTU isn't a WireGuard attribute, so it adds nothing to the genl messages.
+        let messages = apply_messages(&DeviceUpdate::new().set_mtu(1280), &missing).unwrap();
+        assert_eq!(
+            messages,
+            apply_messages(&DeviceUpdate::new(), &missing).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_set_link_up_down() {
+        let up = up_down_message(true).header;
+        assert_eq!((up.flags, up.change_mask), (IFF_UP, IFF_UP));
+        let down = up_down_message(false).header;
+        assert_eq!((down.flags, down.change_mask), (0, IFF_UP));
+
+        let missing: InterfaceName = "wguptest0".parse().unwrap();
+        for result in [set_link_up(&missing), set_link_down(&missing)] {
+            let err = result.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+            assert!(err.to_string().contains("wguptest0"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_rename_interface_collision() {
+        // The loopback interface always exists, so this must fail before touching anything.
+        let missing: InterfaceName = "wgrenametest0".parse().unwrap();
+        let loopback: InterfaceName = "lo".parse().unwrap();
+        let err = rename_interface(&missing, &loopback).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        let err = rename_interface(&missing, &"wgrenametest1".parse().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_wireguard_link_names_lazy() {
+        use link::nlas::Nla;
+        let wireguard = |name: &str| {
+            NetlinkMessage::from(RtnlMessage::NewLink(link(
+                1,
+                vec![
+                    Nla::IfName(name.into()),
+                    Nla::Info(vec![Info::Kind(InfoKind::Wireguard)]),
+                ],
+            )))
+        };
+        let bridge = NetlinkMessage::from(RtnlMessage::NewLink(link(
+            2,
+            vec![
+                Nla::IfName("br0".into()),
+                Nla::Info(vec![Info::Kind(InfoKind::Bridge)]),
+            ],
+        )));
+
+        let responses = vec![bridge, wireguard("wg0"), wireguard("wg1")];
+        let mut names = wireguard_link_names(responses);
+        assert_eq!(names.next(), Some("wg0".parse().unwrap()));
+        assert_eq!(names.next(), Some("wg1".parse().unwrap()));
+        assert_eq!(names.next(), None);
+    }
+
+    #[test]
+    fn test_classify_link() {
+        use link::nlas::Nla;
+        let wireguard = || Nla::Info(vec![Info::Kind(InfoKind::Wireguard)]);
+
+        let ok = link(1, vec![Nla::IfName("wg0".into()), wireguard()]);
+        assert_eq!(classify_link(&ok), Some(Ok("wg0".parse().unwrap())));
+
+        let other_kind = link(
+            2,
+            vec![
+                Nla::IfName("br0".into()),
+                Nla::Info(vec![Info::Kind(InfoKind::Bridge)]),
+            ],
+        );
+        assert_eq!(classify_link(&other_kind), None);
+
+        let no_info = link(3, vec![Nla::IfName("eth0".into())]);
+        assert_eq!(classify_link(&no_info), None);
+
+        let missing_kind = link(4, vec![Nla::IfName("wg1".into()), Nla::Info(vec![])]);
+        assert_eq!(
+            classify_link(&missing_kind),
+            Some(Err(SkippedLink {
+                index: 4,
+                name: Some("wg1".into()),
+                reason: SkipReason::MissingKind,
+            }))
+        );
+
+        let missing_name = link(5, vec![wireguard()]);
+        assert_eq!(
+            classify_link(&missing_name),
+            Some(Err(SkippedLink {
+                index: 5,
+                name: None,
+                reason: SkipReason::MissingName,
+            }))
+        );
+
+        let invalid_name = link(6, vec![Nla::IfName("wg 2".into()), wireguard()]);
+        assert_eq!(
+            classify_link(&invalid_name),
+            Some(Err(SkippedLink {
+                index: 6,
+                name: Some("wg 2".into()),
+                reason: SkipReason::InvalidName(InvalidInterfaceName::InvalidChars),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_mapped_endpoint_normalized() {
+        let peer = |endpoint: &str| {
+            PeerInfo::try_from(WgPeer(vec![
+                WgPeerAttrs::PublicKey([2u8; 32]),
+                WgPeerAttrs::Endpoint(endpoint.parse().unwrap()),
+            ]))
+            .unwrap()
+            .config
+            .endpoint
+        };
+
+        assert_eq!(
+            peer("[::ffff:1.2.3.4]:51820"),
+            Some("1.2.3.4:51820".parse().unwrap())
+        );
+        assert_eq!(
+            peer("1.2.3.4:51820"),
+            Some("1.2.3.4:51820".parse().unwrap())
+        );
+        assert_eq!(
+            peer("[2001:db8::1]:51820"),
+            Some("[2001:db8::1]:51820".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_clear_peers_messages() {
+        let iface = InterfaceName::from_str("wg0").unwrap();
+        let update = DeviceUpdate::new()
+            .add_peer(PeerConfigBuilder::new(
+                &Key::generate_private().get_public(),
+            ))
+            .clear_peers();
+        let messages = apply_messages(&update, &iface).unwrap();
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(
+            messages[0].payload.nlas,
+            vec![
+                WgDeviceAttrs::IfName("wg0".into()),
+                WgDeviceAttrs::Flags(WGDEVICE_F_REPLACE_PEERS),
+            ]
+        );
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_peer_flag_names() {
+        let key = Key::generate_private().get_public();
+        let names = |builder: PeerConfigBuilder| peer_flag_names(&builder.to_nla());
+        assert!(names(PeerConfigBuilder::new(&key)).is_empty());
+        assert_eq!(
+            names(PeerConfigBuilder::new(&key).replace_allowed_ips()),
+            vec!["REPLACE_ALLOWEDIPS"]
+        );
+        assert_eq!(
+            names(PeerConfigBuilder::new(&key).remove().replace_allowed_ips()),
+            vec!["REMOVE_ME", "REPLACE_ALLOWEDIPS"]
+        );
+    }
+
     #[test]
     fn test_simple_payload() {
         let mut payload = ApplyPayload::new(&InterfaceName::from_str("wg0").unwrap());
@@ -431,6 +1337,147 @@ mod tests {
         assert_eq!(payload.finish().len(), 1);
     }
 
+    #[test]
+    fn test_small_update_is_one_message() {
+        let iface = InterfaceName::from_str("wg0").unwrap();
+        let mut update = DeviceUpdate::new()
+            .set_private_key(Key::generate_private())
+            .set_listen_port(51820)
+            .replace_peers();
+        for i in 0..8 {
+            update = update.add_peer(
+                PeerConfigBuilder::new(&Key::generate_private().get_public())
+                    .set_endpoint("1.1.1.1:51820".parse().unwrap())
+                    .add_allowed_ip([10, 1, 1, i].into(), 32),
+            );
+        }
+        let messages = apply_messages(&update, &iface).unwrap();
+
+        assert_eq!(messages.len(), 1);
+        let nlas = &messages[0].payload.nlas;
+        assert!(nlas
+            .iter()
+            .any(|nla| matches!(nla, WgDeviceAttrs::PrivateKey(_))));
+        assert!(nlas.contains(&WgDeviceAttrs::ListenPort(51820)));
+        assert!(nlas.contains(&WgDeviceAttrs::Flags(WGDEVICE_F_REPLACE_PEERS)));
+        assert_eq!(peer_count(&messages[0]), 8);
+    }
+
+    fn set_device_messages(peers_per_message: &[usize]) -> Vec<GenlMessage<Wireguard>> {
+        peers_per_message
+            .iter()
+            .map(|&count| {
+                GenlMessage::from_payload(Wireguard {
+                    cmd: WireguardCmd::SetDevice,
+                    nlas: vec![
+                        WgDeviceAttrs::IfName("wg0".into()),
+                        WgDeviceAttrs::Peers(vec![
+                            WgPeer(vec![WgPeerAttrs::PublicKey([2u8; 32])]);
+                            count
+                        ]),
+                    ],
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_set_device_failure_midway() {
+        let mut sent = 0;
+        let err = send_set_device_messages(set_device_messages(&[10, 20, 30]), |_| {
+            sent += 1;
+            if sent == 2 {
+                Err(io::Error::from_raw_os_error(libc::EINVAL))
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap_err();
+
+        // The third message must not be sent after the second one failed.
+        assert_eq!(sent, 2);
+        let message = err.to_string();
+        assert!(message.contains("message 2 of 3"), "{}", message);
+        assert!(
+            message.contains("10 peer(s) already applied"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_set_device_transient_retry() {
+        let mut sent = 0;
+        send_set_device_messages(set_device_messages(&[10, 20, 30]), |_| {
+            sent += 1;
+            if sent == 2 {
+                Err(io::Error::from_raw_os_error(libc::EBUSY))
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap();
+        assert_eq!(sent, 4);
+
+        let mut sent = 0;
+        let err = send_set_device_messages(set_device_messages(&[10, 20, 30]), |_| {
+            sent += 1;
+            if sent == 1 {
+                Ok(())
+            } else {
+                Err(io::Error::from_raw_os_error(libc::EBUSY))
+            }
+        })
+        .unwrap_err();
+        assert_eq!(sent, 1 + SET_DEVICE_ATTEMPTS as usize);
+        assert!(err.to_string().contains("message 2 of 3"));
+    }
+
+    #[test]
+    fn test_peer_with_huge_allowed_ips() {
+        let iface = InterfaceName::from_str("wg0").unwrap();
+        let key = Key::generate_private().get_public();
+        let mut peer = PeerConfigBuilder::new(&key)
+            .set_endpoint("1.1.1.1:51820".parse().unwrap())
+            .replace_allowed_ips();
+        for i in 0..50_000u32 {
+            peer = peer.add_allowed_ip(std::net::Ipv4Addr::from(0x0a00_0000 + i).into(), 32);
+        }
+        let messages = apply_messages(&DeviceUpdate::new().add_peer(peer), &iface).unwrap();
+        assert!(messages.len() > 1);
+
+        let fragments: Vec<_> = messages
+            .into_iter()
+            .inspect(|message| {
+                assert!(message.buffer_len() <= MAX_GENL_PAYLOAD_LENGTH);
+            })
+            .flat_map(|message| message.payload.nlas)
+            .filter_map(|nla| match nla {
+                WgDeviceAttrs::Peers(peers) => Some(peers),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        let flags = |peer: &WgPeer| get_nla_value!(peer, WgPeerAttrs, Flags).copied();
+        // Only the first fragment replaces the allowed IPs, the others add to them.
+        assert_eq!(flags(&fragments[0]), Some(WGPEER_F_REPLACE_ALLOWEDIPS));
+        assert!(get_nla_value!(fragments[0], WgPeerAttrs, Endpoint).is_some());
+        for fragment in &fragments[1..] {
+            assert_eq!(flags(fragment), None);
+            assert_eq!(fragment.len(), 2);
+        }
+        // Read back, it's one peer with all of its allowed IPs in order.
+        let peer = find_peer(fragments, &key).unwrap().unwrap();
+        assert_eq!(peer.config.allowed_ips.len(), 50_000);
+        assert_eq!(
+            peer.config.allowed_ips[49_999].address,
+            std::net::Ipv4Addr::from(0x0a00_0000 + 49_999)
+                .to_string()
+                .parse::<std::net::IpAddr>()
+                .unwrap()
+        );
+    }
+
     #[test]
     fn test_massive_payload() {
         let mut payload = ApplyPayload::new(&InterfaceName::from_str("wg0").unwrap());
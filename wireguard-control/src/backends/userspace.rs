@@ -1,4 +1,7 @@
-use crate::{Backend, Device, DeviceUpdate, InterfaceName, PeerConfig, PeerInfo, PeerStats};
+use crate::{
+    device::missing_interface_error, normalize_endpoint, Backend, Device, DeviceUpdate,
+    InterfaceName, PeerConfig, PeerInfo, PeerStats,
+};
 
 use crate::Key;
 
@@ -28,14 +31,14 @@ fn get_base_folder() -> io::Result<PathBuf> {
 }
 
 fn get_namefile(name: &InterfaceName) -> io::Result<PathBuf> {
-    Ok(get_base_folder()?.join(&format!("{}.name", name.as_str_lossy())))
+    Ok(get_base_folder()?.join(format!("{}.name", name.as_str_lossy())))
 }
 
 fn get_socketfile(name: &InterfaceName) -> io::Result<PathBuf> {
     if cfg!(target_os = "linux") {
-        Ok(get_base_folder()?.join(&format!("{}.sock", name)))
+        Ok(get_base_folder()?.join(format!("{}.sock", name)))
     } else {
-        Ok(get_base_folder()?.join(&format!("{}.sock", resolve_tun(name)?)))
+        Ok(get_base_folder()?.join(format!("{}.sock", resolve_tun(name)?)))
     }
 }
 
@@ -87,7 +90,6 @@ fn new_peer_info(public_key: Key) -> PeerInfo {
             endpoint: None,
             persistent_keepalive_interval: None,
             allowed_ips: vec![],
-            __cant_construct_me: (),
         },
         stats: PeerStats {
             last_handshake_time: None,
@@ -120,7 +122,6 @@ impl ConfigParser {
             peers: vec![],
             linked_name: resolve_tun(name).ok(),
             backend: Backend::Userspace,
-            __cant_construct_me: (),
         };
 
         Self {
@@ -215,7 +216,7 @@ impl ConfigParser {
                     .as_mut()
                     .ok_or(InvalidData)?
                     .config
-                    .endpoint = Some(value.parse().map_err(|_| InvalidData)?);
+                    .endpoint = Some(normalize_endpoint(value.parse().map_err(|_| InvalidData)?));
             },
             "errno" => {
                 // "errno" indicates an end of the stream, along with the error return code.
@@ -231,7 +232,22 @@ impl ConfigParser {
                     self.device_info.peers.push(finished_peer);
                 }
             },
-            "protocol_version" | "last_handshake_time_nsec" => {},
+            "last_handshake_time_nsec" => {
+                let handshake_nanos: u64 = value.parse().map_err(|_| InvalidData)?;
+
+                // Always sent right after the seconds, which are zero if there's no handshake.
+                if let Some(handshake) = self
+                    .current_peer
+                    .as_mut()
+                    .ok_or(InvalidData)?
+                    .stats
+                    .last_handshake_time
+                    .as_mut()
+                {
+                    *handshake += Duration::from_nanos(handshake_nanos);
+                }
+            },
+            "protocol_version" => {},
             _ => println!("got unsupported info: {}={}", key, value),
         }
 
@@ -267,23 +283,23 @@ pub fn get_by_name(name: &InterfaceName) -> Result<Device, io::Error> {
 /// wgctrl-rs will look for WG_USERSPACE_IMPLEMENTATION first, but will also
 /// respect the WG_QUICK_USERSPACE_IMPLEMENTATION choice if the former isn't
 /// available.
-fn get_userspace_implementation() -> String {
+pub(crate) fn get_userspace_implementation() -> String {
     std::env::var("WG_USERSPACE_IMPLEMENTATION")
         .or_else(|_| std::env::var("WG_QUICK_USERSPACE_IMPLEMENTATION"))
         .unwrap_or_else(|_| "wireguard-go".to_string())
 }
 
 fn start_userspace_wireguard(iface: &InterfaceName) -> io::Result<Output> {
-    let mut command = Command::new(&get_userspace_implementation());
+    let mut command = Command::new(get_userspace_implementation());
     let output = if cfg!(target_os = "linux") {
         command.args(&[iface.to_string()]).output()?
     } else {
         command
             .env(
                 "WG_TUN_NAME_FILE",
-                &format!("{}/{}.name", VAR_RUN_PATH, iface),
+                format!("{}/{}.name", VAR_RUN_PATH, iface),
             )
-            .args(&["utun"])
+            .args(["utun"])
             .output()?
     };
     if !output.status.success() {
@@ -296,6 +312,7 @@ fn start_userspace_wireguard(iface: &InterfaceName) -> io::Result<Output> {
 pub fn apply(builder: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
     // If we can't open a configuration socket to an existing interface, try starting it.
     let mut sock = match open_socket(iface) {
+        Err(_) if !builder.create_interface => return Err(missing_interface_error(iface)),
         Err(_) => {
             fs::create_dir_all(VAR_RUN_PATH)?;
             // Clear out any old namefiles if they didn't lead to a connected socket.
@@ -373,11 +390,108 @@ pub fn apply(builder: &DeviceUpdate, iface: &InterfaceName) -> io::Result<()> {
     reader.read_line(&mut line)?;
     let split: Vec<&str> = line.trim_end().splitn(2, '=').collect();
     match &split[..] {
-        ["errno", "0"] => Ok(()),
+        ["errno", "0"] => {},
         ["errno", val] => {
             println!("ERROR {}", val);
-            Err(io::ErrorKind::InvalidInput.into())
+            return Err(io::ErrorKind::InvalidInput.into());
         },
-        _ => Err(io::ErrorKind::Other.into()),
+        _ => return Err(io::ErrorKind::Other.into()),
+    }
+
+    // The userspace implementation's tun device is a link like any other.
+    if let Some(mtu) = builder.mtu {
+        #[cfg(target_os = "linux")]
+        crate::backends::kernel::set_mtu(iface, mtu)?;
+        #[cfg(not(target_os = "linux"))]
+        set_tun_mtu(iface, mtu)?;
+    }
+    Ok(())
+}
+
+/// Set the MTU of the tun device behind `iface` with ifconfig, like wg-quick(8) does on
+/// platforms without netlink.
+#[cfg(not(target_os = "linux"))]
+fn set_tun_mtu(iface: &InterfaceName, mtu: u32) -> io::Result<()> {
+    let tun = resolve_tun(iface)?;
+    let output = Command::new("ifconfig")
+        .args([tun.as_str(), "mtu", &mtu.to_string()])
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "failed to set the MTU of {} to {}: {}",
+                tun,
+                mtu,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_get_response() {
+        let name: InterfaceName = "wgtest0".parse().unwrap();
+        let private_key = Key::generate_private();
+        let peer_key = Key::generate_private().get_public();
+        let response = format!(
+            "private_key={}
+listen_port=51820
+public_key={}
+endpoint=1.1.1.1:51820
+last_handshake_time_sec=1600000000
+last_handshake_time_nsec=500000000
+tx_bytes=100
+rx_bytes=200
+persistent_keepalive_interval=25
+allowed_ip=10.0.0.2/32
+allowed_ip=fd00::2/128
+public_key={}
+last_handshake_time_sec=0
+last_handshake_time_nsec=0
+protocol_version=1
+errno=0",
+            hex::encode(private_key.as_bytes()),
+            hex::encode(peer_key.as_bytes()),
+            hex::encode(Key::generate_private().get_public().as_bytes()),
+        );
+
+        let mut parser = ConfigParser::new(&name);
+        for line in response.lines() {
+            parser.add_line(line).unwrap();
+        }
+        let device = Device::from(parser);
+
+        assert_eq!(device.backend, Backend::Userspace);
+        assert_eq!(device.public_key, Some(private_key.get_public()));
+        assert_eq!(device.listen_port, Some(51820));
+        assert_eq!(device.peers.len(), 2);
+
+        let peer = &device.peers[0];
+        assert_eq!(peer.config.public_key, peer_key);
+        assert_eq!(peer.config.allowed_ips.len(), 2);
+        assert_eq!(
+            peer.stats.last_handshake_time,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_millis(1_600_000_000_500))
+        );
+        assert_eq!((peer.stats.tx_bytes, peer.stats.rx_bytes), (100, 200));
+        assert_eq!(device.peers[1].stats.last_handshake_time, None);
+    }
+
+    #[test]
+    fn test_parse_errno() {
+        let name: InterfaceName = "wgtest0".parse().unwrap();
+        let mut parser = ConfigParser::new(&name);
+        let err = parser.add_line("errno=13").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(13));
+        assert!(
+            parser.add_line("tx_bytes=1").is_err(),
+            "no peer to attach stats to"
+        );
     }
 }
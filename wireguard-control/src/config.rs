@@ -69,7 +69,6 @@ impl PeerConfigBuilder {
             endpoint: self.endpoint,
             persistent_keepalive_interval: self.persistent_keepalive_interval,
             allowed_ips: self.allowed_ips,
-            __cant_construct_me: (),
         }
     }
 
@@ -122,13 +121,18 @@ impl PeerConfigBuilder {
     }
 
     /// Specifies the interval between keepalive packets to be sent to this peer.
+    ///
+    /// An interval of 0 turns keepalives off, like
+    /// [`unset_persistent_keepalive`](PeerConfigBuilder::unset_persistent_keepalive). Without
+    /// either, the peer's existing keepalive is left as it is.
     #[must_use]
     pub fn set_persistent_keepalive_interval(mut self, interval: u16) -> Self {
         self.persistent_keepalive_interval = Some(interval);
         self
     }
 
-    /// Specifies that this peer does not require keepalive packets.
+    /// Specifies that this peer does not require keepalive packets, turning off any
+    /// keepalive it already has (by sending an interval of 0).
     #[must_use]
     pub fn unset_persistent_keepalive(self) -> Self {
         self.set_persistent_keepalive_interval(0)
@@ -172,10 +176,60 @@ impl PeerConfigBuilder {
         self
     }
 
+    /// Specifies that this peer's allowed IPs should be left as they are, undoing any earlier
+    /// calls to add or replace them. This is the default for a new builder, and unlike
+    /// replacing them with an empty list, doesn't send any allowed IPs to the interface.
+    #[must_use]
+    pub fn keep_allowed_ips(mut self) -> Self {
+        self.allowed_ips.clear();
+        self.replace_allowed_ips = false;
+        self
+    }
+
+    /// Whether applying this builder leaves the peer's allowed IPs untouched.
+    pub(crate) fn keeps_allowed_ips(&self) -> bool {
+        self.allowed_ips.is_empty() && !self.replace_allowed_ips
+    }
+
     /// Mark peer for removal from interface.
     #[must_use]
     pub fn remove(mut self) -> Self {
         self.remove_me = true;
         self
     }
+
+    /// Whether applying this builder on top of `existing` would leave the peer unchanged.
+    pub(crate) fn is_noop_for(&self, existing: &PeerConfig) -> bool {
+        if self.remove_me || self.public_key != existing.public_key {
+            return false;
+        }
+
+        let preshared_key_unchanged = match &self.preshared_key {
+            Some(key) if key == &Key::zero() => {
+                existing.preshared_key.is_none() || existing.preshared_key.as_ref() == Some(key)
+            },
+            Some(key) => existing.preshared_key.as_ref() == Some(key),
+            None => true,
+        };
+        let endpoint_unchanged = self.endpoint.is_none() || self.endpoint == existing.endpoint;
+        let keepalive_unchanged = match self.persistent_keepalive_interval {
+            Some(0) => matches!(existing.persistent_keepalive_interval, None | Some(0)),
+            Some(interval) => existing.persistent_keepalive_interval == Some(interval),
+            None => true,
+        };
+        let allowed_ips_unchanged = self
+            .allowed_ips
+            .iter()
+            .all(|ip| existing.allowed_ips.contains(ip))
+            && (!self.replace_allowed_ips
+                || existing
+                    .allowed_ips
+                    .iter()
+                    .all(|ip| self.allowed_ips.contains(ip)));
+
+        preshared_key_unchanged
+            && endpoint_unchanged
+            && keepalive_unchanged
+            && allowed_ips_unchanged
+    }
 }
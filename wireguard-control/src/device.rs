@@ -4,11 +4,12 @@ use crate::{backends, key::Key, Backend, KeyPair, PeerConfigBuilder};
 
 use std::{
     borrow::Cow,
+    collections::HashMap,
     ffi::CStr,
     fmt, io,
     net::{IpAddr, SocketAddr},
     str::FromStr,
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
 /// Represents an IP address a peer is allowed to have, in CIDR notation.
@@ -26,26 +27,120 @@ impl fmt::Debug for AllowedIp {
     }
 }
 
+impl fmt::Display for AllowedIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.cidr)
+    }
+}
+
 impl std::str::FromStr for AllowedIp {
     type Err = ();
 
+    /// Parses CIDR notation such as `10.1.0.0/24` or `fd00::/64`, rejecting prefix lengths
+    /// longer than the address family allows.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts: Vec<_> = s.split('/').collect();
-        if parts.len() != 2 {
+        let (address, cidr) = s.split_once('/').ok_or(())?;
+        let address: IpAddr = address.parse().map_err(|_| ())?;
+        let cidr: u8 = cidr.parse().map_err(|_| ())?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        if cidr > max {
             return Err(());
         }
 
-        Ok(AllowedIp {
-            address: parts[0].parse().map_err(|_| ())?,
-            cidr: parts[1].parse().map_err(|_| ())?,
-        })
+        Ok(AllowedIp { address, cidr })
+    }
+}
+
+impl AllowedIp {
+    /// The minimal set of CIDRs covering `include` minus every one of `excludes`, for
+    /// configuring a route with holes in it (ex. `0.0.0.0/0` except `10.0.0.0/8`).
+    ///
+    /// Excludes of the other address family are ignored. The result is sorted by address.
+    pub fn subtract(include: &AllowedIp, excludes: &[AllowedIp]) -> Vec<AllowedIp> {
+        let width = include.width();
+        let mut nets = vec![(
+            include.bits() & prefix_mask(include.cidr, width),
+            include.cidr,
+        )];
+
+        for exclude in excludes.iter().filter(|e| e.width() == width) {
+            let exclude = (
+                exclude.bits() & prefix_mask(exclude.cidr, width),
+                exclude.cidr,
+            );
+            let mut remaining = Vec::with_capacity(nets.len());
+            for net in nets {
+                if net_contains(exclude, net, width) {
+                    // Entirely excluded.
+                } else if net_contains(net, exclude, width) {
+                    // Split the net in halves down to the excluded one, keeping every half
+                    // that doesn't contain it.
+                    let mut current = net;
+                    while current.1 < exclude.1 {
+                        let prefix = current.1 + 1;
+                        let low = (current.0, prefix);
+                        let high = (current.0 | 1 << (width - prefix), prefix);
+                        if net_contains(low, exclude, width) {
+                            remaining.push(high);
+                            current = low;
+                        } else {
+                            remaining.push(low);
+                            current = high;
+                        }
+                    }
+                } else {
+                    remaining.push(net);
+                }
+            }
+            nets = remaining;
+        }
+
+        nets.sort_unstable();
+        nets.into_iter()
+            .map(|(bits, cidr)| AllowedIp {
+                address: if width == 32 {
+                    IpAddr::from((bits as u32).to_be_bytes())
+                } else {
+                    IpAddr::from(bits.to_be_bytes())
+                },
+                cidr,
+            })
+            .collect()
+    }
+
+    fn width(&self) -> u8 {
+        if self.address.is_ipv4() {
+            32
+        } else {
+            128
+        }
+    }
+
+    fn bits(&self) -> u128 {
+        match self.address {
+            IpAddr::V4(ip) => u32::from(ip).into(),
+            IpAddr::V6(ip) => ip.into(),
+        }
+    }
+}
+
+fn prefix_mask(prefix: u8, width: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        _ => (u128::MAX << (128 - prefix)) >> (128 - width),
     }
 }
 
+/// Whether the `(network bits, prefix)` pair `outer` contains `inner`.
+fn net_contains(outer: (u128, u8), inner: (u128, u8), width: u8) -> bool {
+    outer.1 <= inner.1 && inner.0 & prefix_mask(outer.1, width) == outer.0
+}
+
 /// Represents a single peer's configuration (i.e. persistent attributes).
 ///
 /// These are the attributes that don't change over time and are part of the configuration.
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[non_exhaustive]
 pub struct PeerConfig {
     /// The public key of the peer.
     pub public_key: Key,
@@ -57,7 +152,33 @@ pub struct PeerConfig {
     pub persistent_keepalive_interval: Option<u16>,
     /// The IP addresses this peer is allowed to have.
     pub allowed_ips: Vec<AllowedIp>,
-    pub(crate) __cant_construct_me: (),
+}
+
+/// Rewrite an IPv4-mapped IPv6 endpoint (`[::ffff:1.2.3.4]:51820`) to plain IPv4.
+///
+/// Dual-stack listen sockets report IPv4 peers in the mapped form, so endpoints read back
+/// from an interface are normalized with this to compare equal to the addresses peers
+/// report for themselves.
+pub fn normalize_endpoint(endpoint: SocketAddr) -> SocketAddr {
+    match endpoint {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(v4.into(), v6.port()),
+            None => endpoint,
+        },
+        SocketAddr::V4(_) => endpoint,
+    }
+}
+
+/// The error for applying an update to an interface that doesn't exist when
+/// [`DeviceUpdate::create_interface`] is off.
+pub(crate) fn missing_interface_error(iface: &InterfaceName) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "interface {} doesn't exist, and creating it is disabled",
+            iface
+        ),
+    )
 }
 
 /// Represents a single peer's current statistics (i.e. the data from the current session).
@@ -74,6 +195,23 @@ pub struct PeerStats {
     pub tx_bytes: u64,
 }
 
+impl PeerStats {
+    /// How long ago the last handshake with this peer was, or `None` if there hasn't been
+    /// one. A handshake that appears to be in the future (ex. after the clock was set back)
+    /// counts as having just happened.
+    pub fn time_since_handshake(&self) -> Option<Duration> {
+        self.last_handshake_time
+            .map(|time| time.elapsed().unwrap_or_default())
+    }
+
+    /// Whether the last handshake with this peer was more than `threshold` ago. Peers that
+    /// have never completed a handshake are always stale.
+    pub fn is_stale(&self, threshold: Duration) -> bool {
+        self.time_since_handshake()
+            .is_none_or(|since| since > threshold)
+    }
+}
+
 /// Represents the complete status of a peer.
 ///
 /// This struct simply combines [`PeerInfo`](PeerInfo) and [`PeerStats`](PeerStats)
@@ -84,6 +222,49 @@ pub struct PeerInfo {
     pub stats: PeerStats,
 }
 
+impl PeerInfo {
+    pub fn allowed_ip_count(&self) -> usize {
+        self.config.allowed_ips.len()
+    }
+
+    pub fn allowed_ip_counts(&self) -> AllowedIpCounts {
+        AllowedIpCounts::of(&self.config.allowed_ips)
+    }
+}
+
+/// How many allowed IPs (each a route in the kernel) are IPv4 and IPv6, for spotting peers
+/// whose allowed IP lists have grown large enough to bloat the routing table.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct AllowedIpCounts {
+    pub ipv4: usize,
+    pub ipv6: usize,
+}
+
+impl AllowedIpCounts {
+    pub fn of(allowed_ips: &[AllowedIp]) -> Self {
+        let ipv4 = allowed_ips.iter().filter(|ip| ip.address.is_ipv4()).count();
+        Self {
+            ipv4,
+            ipv6: allowed_ips.len() - ipv4,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.ipv4 + self.ipv6
+    }
+}
+
+impl std::ops::Add for AllowedIpCounts {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            ipv4: self.ipv4 + other.ipv4,
+            ipv6: self.ipv6 + other.ipv6,
+        }
+    }
+}
+
 /// Represents all available information about a WireGuard device (interface).
 ///
 /// This struct contains the current configuration of the device
@@ -91,6 +272,7 @@ pub struct PeerInfo {
 /// The peer statistics are retrieved once at construction time,
 /// and need to be updated manually by calling [`get_by_name`](DeviceInfo::get_by_name).
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[non_exhaustive]
 pub struct Device {
     /// The interface name of this device
     pub name: InterfaceName,
@@ -98,7 +280,8 @@ pub struct Device {
     pub public_key: Option<Key>,
     /// The private encryption key of this interface (if present)
     pub private_key: Option<Key>,
-    /// The [fwmark](https://www.linux.org/docs/man8/tc-fw.html) of this interface
+    /// The [fwmark](https://www.linux.org/docs/man8/tc-fw.html) of this interface, or `None`
+    /// if it doesn't have one (which WireGuard treats the same as a fwmark of 0)
     pub fwmark: Option<u32>,
     /// The port to listen for incoming connections on
     pub listen_port: Option<u16>,
@@ -108,12 +291,138 @@ pub struct Device {
     pub linked_name: Option<String>,
     /// The backend the device exists on (userspace or kernel).
     pub backend: Backend,
+}
+
+/// Shown in place of secret keys when displaying or serializing devices.
+const REDACTED: &str = "(hidden)";
+
+impl fmt::Display for PeerInfo {
+    /// A `wg show`-like, multi-line summary of the peer. The preshared key is redacted.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let config = &self.config;
+        writeln!(f, "peer: {}", config.public_key.to_base64())?;
+        if config.preshared_key.is_some() {
+            writeln!(f, "  preshared key: {}", REDACTED)?;
+        }
+        if let Some(endpoint) = config.endpoint {
+            writeln!(f, "  endpoint: {}", endpoint)?;
+        }
+        let allowed_ips: Vec<_> = config.allowed_ips.iter().map(|ip| ip.to_string()).collect();
+        writeln!(f, "  allowed ips: {}", allowed_ips.join(", "))?;
+        if let Some(elapsed) = self
+            .stats
+            .last_handshake_time
+            .and_then(|time| time.elapsed().ok())
+        {
+            writeln!(f, "  latest handshake: {}s ago", elapsed.as_secs())?;
+        }
+        writeln!(
+            f,
+            "  transfer: {} B received, {} B sent",
+            self.stats.rx_bytes, self.stats.tx_bytes
+        )?;
+        if let Some(interval) = config.persistent_keepalive_interval {
+            writeln!(f, "  persistent keepalive: every {}s", interval)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Device {
+    /// A `wg show`-like, multi-line summary of the device and its peers. Secret keys are redacted.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "interface: {} ({})", self.name, self.backend)?;
+        if let Some(linked_name) = &self.linked_name {
+            writeln!(f, "  linked name: {}", linked_name)?;
+        }
+        if let Some(public_key) = &self.public_key {
+            writeln!(f, "  public key: {}", public_key.to_base64())?;
+        }
+        if self.private_key.is_some() {
+            writeln!(f, "  private key: {}", REDACTED)?;
+        }
+        if let Some(listen_port) = self.listen_port {
+            writeln!(f, "  listening port: {}", listen_port)?;
+        }
+        if let Some(fwmark) = self.fwmark.filter(|fwmark| *fwmark != 0) {
+            writeln!(f, "  fwmark: {:#x}", fwmark)?;
+        }
+        for peer in &self.peers {
+            writeln!(f)?;
+            write!(f, "{}", peer)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for PeerInfo {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let config = &self.config;
+        let allowed_ips: Vec<_> = config.allowed_ips.iter().map(|ip| ip.to_string()).collect();
+        let last_handshake_time = self
+            .stats
+            .last_handshake_time
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs());
 
-    pub(crate) __cant_construct_me: (),
+        let mut state = serializer.serialize_struct("PeerInfo", 8)?;
+        state.serialize_field("public_key", &config.public_key.to_base64())?;
+        state.serialize_field(
+            "preshared_key",
+            &config.preshared_key.as_ref().map(|_| REDACTED),
+        )?;
+        state.serialize_field("endpoint", &config.endpoint.map(|e| e.to_string()))?;
+        state.serialize_field(
+            "persistent_keepalive_interval",
+            &config.persistent_keepalive_interval,
+        )?;
+        state.serialize_field("allowed_ips", &allowed_ips)?;
+        state.serialize_field("last_handshake_time", &last_handshake_time)?;
+        state.serialize_field("rx_bytes", &self.stats.rx_bytes)?;
+        state.serialize_field("tx_bytes", &self.stats.tx_bytes)?;
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Device {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut state = serializer.serialize_struct("Device", 8)?;
+        state.serialize_field("name", &self.name.to_string())?;
+        state.serialize_field("backend", &self.backend.to_string())?;
+        state.serialize_field("public_key", &self.public_key.as_ref().map(Key::to_base64))?;
+        state.serialize_field("private_key", &self.private_key.as_ref().map(|_| REDACTED))?;
+        state.serialize_field("fwmark", &self.fwmark)?;
+        state.serialize_field("listen_port", &self.listen_port)?;
+        state.serialize_field("linked_name", &self.linked_name)?;
+        state.serialize_field("peers", &self.peers)?;
+        state.end()
+    }
 }
 
 type RawInterfaceName = [c_char; libc::IFNAMSIZ];
 
+/// The longest interface name accepted, leaving room for a trailing NUL.
+///
+/// On Linux this is the kernel's limit. On other platforms the name is only used to find the
+/// userspace implementation's socket (the system picks the real `utunN` name), but it's still
+/// kept in an `IFNAMSIZ` buffer.
+pub const MAX_INTERFACE_NAME_LEN: usize = libc::IFNAMSIZ - 1;
+
+/// Whether a byte can appear in an interface name on this platform.
+fn is_valid_interface_name_byte(b: u8) -> bool {
+    let valid = b != 0 && b != b'/' && !b.is_ascii_whitespace();
+    // The kernel uses ':' to separate an interface from its aliases.
+    #[cfg(target_os = "linux")]
+    let valid = valid && b != b':';
+    valid
+}
+
 /// The name of a Wireguard interface device.
 #[derive(PartialEq, Eq, Clone, Copy)]
 pub struct InterfaceName(RawInterfaceName);
@@ -121,24 +430,29 @@ pub struct InterfaceName(RawInterfaceName);
 impl FromStr for InterfaceName {
     type Err = InvalidInterfaceName;
 
-    /// Attempts to parse a Rust string as a valid Linux interface name.
+    /// Attempts to parse a Rust string as a valid interface name for this platform.
     ///
     /// Extra validation logic ported from [iproute2](https://git.kernel.org/pub/scm/network/iproute2/iproute2.git/tree/lib/utils.c#n827)
+    /// and the kernel's `dev_valid_name`.
     fn from_str(name: &str) -> Result<Self, InvalidInterfaceName> {
         let len = name.len();
         if len == 0 {
             return Err(InvalidInterfaceName::Empty);
         }
 
-        // Ensure its short enough to include a trailing NUL
-        if len > (libc::IFNAMSIZ - 1) {
+        if len > MAX_INTERFACE_NAME_LEN {
             return Err(InvalidInterfaceName::TooLong);
         }
 
+        #[cfg(target_os = "linux")]
+        if name == "." || name == ".." {
+            return Err(InvalidInterfaceName::InvalidChars);
+        }
+
         let mut buf = [c_char::default(); libc::IFNAMSIZ];
         // Check for interior NULs and other invalid characters.
         for (out, b) in buf.iter_mut().zip(name.as_bytes().iter()) {
-            if *b == 0 || *b == b'/' || b.is_ascii_whitespace() {
+            if !is_valid_interface_name_byte(*b) {
                 return Err(InvalidInterfaceName::InvalidChars);
             }
 
@@ -188,7 +502,8 @@ pub enum InvalidInterfaceName {
     // get that far: https://git.kernel.org/pub/scm/network/iproute2/iproute2.git/tree/lib/utils.c?id=1f420318bda3cc62156e89e1b56d60cc744b48ad#n827.
     /// Interface name was an empty string.
     Empty,
-    /// Interface name contained a nul, `/` or whitespace character.
+    /// Interface name contained a nul, `/` or whitespace character, or (on Linux) a `:` or
+    /// was `.` or `..`.
     InvalidChars,
 }
 
@@ -198,9 +513,12 @@ impl fmt::Display for InvalidInterfaceName {
             Self::TooLong => write!(
                 f,
                 "interface name longer than system max of {} chars",
-                libc::IFNAMSIZ
+                MAX_INTERFACE_NAME_LEN
             ),
             Self::Empty => f.write_str("an empty interface name was provided"),
+            Self::InvalidChars if cfg!(target_os = "linux") => {
+                f.write_str("interface name contained slash, colon, or space characters, or was \".\" or \"..\"")
+            },
             Self::InvalidChars => f.write_str("interface name contained slash or space characters"),
         }
     }
@@ -221,6 +539,7 @@ impl Device {
     /// You can use [`get_by_name`](DeviceInfo::get_by_name) to retrieve more
     /// detailed information on each interface.
     pub fn list(backend: Backend) -> Result<Vec<InterfaceName>, std::io::Error> {
+        log::debug!("listing interfaces via the {} backend", backend);
         match backend {
             #[cfg(target_os = "linux")]
             Backend::Kernel => backends::kernel::enumerate(),
@@ -229,6 +548,7 @@ impl Device {
     }
 
     pub fn get(name: &InterfaceName, backend: Backend) -> Result<Self, std::io::Error> {
+        log::debug!("reading {} via the {} backend", name, backend);
         match backend {
             #[cfg(target_os = "linux")]
             Backend::Kernel => backends::kernel::get_by_name(name),
@@ -236,13 +556,100 @@ impl Device {
         }
     }
 
+    /// Read a single peer of the interface `name`, or `None` if it has no peer with
+    /// `public_key`. The kernel backend only parses that peer, which is much cheaper than
+    /// [`Device::get`] on interfaces with thousands of peers.
+    pub fn get_peer(
+        name: &InterfaceName,
+        backend: Backend,
+        public_key: &Key,
+    ) -> Result<Option<PeerInfo>, std::io::Error> {
+        match backend {
+            #[cfg(target_os = "linux")]
+            Backend::Kernel => backends::kernel::get_peer_by_name(name, public_key),
+            Backend::Userspace => Ok(backends::userspace::get_by_name(name)?
+                .peers
+                .into_iter()
+                .find(|peer| &peer.config.public_key == public_key)),
+        }
+    }
+
     pub fn delete(self) -> Result<(), std::io::Error> {
+        log::debug!("deleting {} via the {} backend", self.name, self.backend);
         match self.backend {
             #[cfg(target_os = "linux")]
             Backend::Kernel => backends::kernel::delete_interface(&self.name),
             Backend::Userspace => backends::userspace::delete_interface(&self.name),
         }
     }
+
+    /// The number of allowed IPs across all of the interface's peers.
+    pub fn total_allowed_ips(&self) -> usize {
+        self.peers.iter().map(PeerInfo::allowed_ip_count).sum()
+    }
+
+    /// The allowed IPs across all of the interface's peers, counted by address family.
+    pub fn allowed_ip_counts(&self) -> AllowedIpCounts {
+        self.peers
+            .iter()
+            .map(PeerInfo::allowed_ip_counts)
+            .fold(AllowedIpCounts::default(), |total, counts| total + counts)
+    }
+
+    /// The peers whose handshake time or transfer counters changed since `previous`, an
+    /// earlier snapshot of this interface, including peers that weren't on it yet.
+    ///
+    /// Useful for stats pollers on interfaces with many mostly-idle peers.
+    pub fn peers_changed_since<'a>(&'a self, previous: &Device) -> Vec<&'a PeerInfo> {
+        let previous: HashMap<&[u8; 32], &PeerStats> = previous
+            .peers
+            .iter()
+            .map(|peer| (&peer.config.public_key.0, &peer.stats))
+            .collect();
+
+        self.peers
+            .iter()
+            .filter(|peer| previous.get(&peer.config.public_key.0) != Some(&&peer.stats))
+            .collect()
+    }
+
+    /// The peer with `public_key`, if it's on the interface.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use wireguard_control::*;
+    /// let peer_key = KeyPair::generate().public;
+    /// let config = format!("[Peer]\nPublicKey = {}\nAllowedIPs = 10.0.0.2/32", peer_key.to_base64());
+    /// let device = Device::from_wg_quick_config(&"wg0".parse().unwrap(), &config)?;
+    ///
+    /// let peer = device.find_peer(&peer_key).unwrap();
+    /// assert_eq!(peer.config.allowed_ips[0].to_string(), "10.0.0.2/32");
+    /// assert!(!device.has_peer(&KeyPair::generate().public));
+    /// assert_eq!(device.peer_count(), 1);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn find_peer(&self, public_key: &Key) -> Option<&PeerInfo> {
+        self.peers
+            .iter()
+            .find(|peer| &peer.config.public_key == public_key)
+    }
+
+    /// Like [`Device::find_peer`], but for changing the peer's information in place.
+    pub fn find_peer_mut(&mut self, public_key: &Key) -> Option<&mut PeerInfo> {
+        self.peers
+            .iter_mut()
+            .find(|peer| &peer.config.public_key == public_key)
+    }
+
+    /// Whether a peer with `public_key` is on the interface.
+    pub fn has_peer(&self, public_key: &Key) -> bool {
+        self.find_peer(public_key).is_some()
+    }
+
+    /// The number of peers on the interface.
+    pub fn peer_count(&self) -> usize {
+        self.peers.len()
+    }
 }
 
 /// Builds and represents a configuration that can be applied to a WireGuard interface.
@@ -285,8 +692,11 @@ pub struct DeviceUpdate {
     pub(crate) private_key: Option<Key>,
     pub(crate) fwmark: Option<u32>,
     pub(crate) listen_port: Option<u16>,
+    pub(crate) mtu: Option<u32>,
     pub(crate) peers: Vec<PeerConfigBuilder>,
     pub(crate) replace_peers: bool,
+    pub(crate) preserved_peers: Option<Vec<PeerConfig>>,
+    pub(crate) create_interface: bool,
 }
 
 impl DeviceUpdate {
@@ -298,8 +708,11 @@ impl DeviceUpdate {
             private_key: None,
             fwmark: None,
             listen_port: None,
+            mtu: None,
             peers: vec![],
             replace_peers: false,
+            preserved_peers: None,
+            create_interface: true,
         }
     }
 
@@ -340,14 +753,17 @@ impl DeviceUpdate {
         self.set_private_key(Key::zero())
     }
 
-    /// Specifies the fwmark value that should be applied to packets coming from the interface.
+    /// Specifies the fwmark value that should be applied to packets coming from the interface,
+    /// replacing any it already has. Without this (or [`unset_fwmark`](DeviceUpdate::unset_fwmark)),
+    /// the interface's fwmark is left as it is.
     #[must_use]
     pub fn set_fwmark(mut self, fwmark: u32) -> Self {
         self.fwmark = Some(fwmark);
         self
     }
 
-    /// Specifies that fwmark should not be set on packets from the interface.
+    /// Specifies that fwmark should not be set on packets from the interface, clearing any it
+    /// already has. This sends a fwmark of 0, which WireGuard treats as disabling it.
     #[must_use]
     pub fn unset_fwmark(self) -> Self {
         self.set_fwmark(0)
@@ -410,6 +826,56 @@ impl DeviceUpdate {
         self
     }
 
+    /// Like [`replace_peers`](DeviceUpdate::replace_peers), but diffed against the current
+    /// peers of `device` rather than flushing every peer from the interface.
+    ///
+    /// Peers whose configuration wouldn't change are left untouched, keeping their rx/tx
+    /// and handshake stats, and peers on the device missing from this update are removed.
+    #[must_use]
+    pub fn replace_peers_preserving(mut self, device: &Device) -> Self {
+        self.replace_peers = false;
+        self.preserved_peers = Some(device.peers.iter().map(|p| p.config.clone()).collect());
+        self
+    }
+
+    /// Reconcile the interface's peers with `desired`, given its `current` state: peers
+    /// missing from `desired` are removed, new or changed ones are added or updated, and
+    /// ones whose configuration already matches aren't sent at all.
+    ///
+    /// Unlike [`PeerConfigBuilder::from_peer_config`], desired peers without a preshared key
+    /// or keepalive have them unset, so the peers end up exactly as desired (except for
+    /// endpoints, which can't be unset).
+    #[must_use]
+    pub fn sync_peers(self, current: &Device, desired: &[PeerConfig]) -> Self {
+        let peers: Vec<_> = desired
+            .iter()
+            .map(|config| {
+                let mut peer = PeerConfigBuilder::from_peer_config(config.clone());
+                if config.preshared_key.is_none() {
+                    peer = peer.unset_preshared_key();
+                }
+                if config.persistent_keepalive_interval.is_none() {
+                    peer = peer.unset_persistent_keepalive();
+                }
+                peer
+            })
+            .collect();
+        self.add_peers(&peers)
+            .replace_peers_preserving(current)
+            .without_unchanged_peers()
+    }
+
+    /// Remove every peer from the interface, leaving its keys, listen port and fwmark as
+    /// they are. Any peers previously added to this update are dropped.
+    ///
+    /// On the kernel backend this is a single `WG_CMD_SET_DEVICE` message.
+    #[must_use]
+    pub fn clear_peers(mut self) -> Self {
+        self.peers.clear();
+        self.preserved_peers = None;
+        self.replace_peers()
+    }
+
     /// Specifies that the peer with this public key should be removed from the interface.
     #[must_use]
     pub fn remove_peer_by_key(self, public_key: &Key) -> Self {
@@ -418,15 +884,84 @@ impl DeviceUpdate {
         self.add_peer(peer)
     }
 
+    /// Sets the interface's MTU. Unlike the rest of the update, the MTU is a property of the
+    /// network link rather than of WireGuard, so it's set separately after everything else
+    /// (with netlink on Linux, or ifconfig on other platforms).
+    #[must_use]
+    pub fn set_mtu(mut self, mtu: u32) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Whether [`apply`](DeviceUpdate::apply) creates the interface if it doesn't exist
+    /// (the default).
+    ///
+    /// Turn this off when something else (ex. systemd-networkd or a CNI plugin) owns the
+    /// interface's lifecycle, so applying to a missing interface fails instead.
+    #[must_use]
+    pub fn create_interface(mut self, create: bool) -> Self {
+        self.create_interface = create;
+        self
+    }
+
     /// Build and apply the configuration to a WireGuard interface by name.
     ///
-    /// An interface with the provided name will be created if one does not exist already.
+    /// An interface with the provided name will be created if one does not exist already,
+    /// unless disabled with [`create_interface`](DeviceUpdate::create_interface).
     pub fn apply(self, iface: &InterfaceName, backend: Backend) -> io::Result<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("apply", interface = %iface, %backend).entered();
+        let update = self.without_unchanged_peers();
+        log::debug!(
+            "applying update to {} ({} peer change(s)) via the {} backend",
+            iface,
+            update.peers.len(),
+            backend
+        );
         match backend {
             #[cfg(target_os = "linux")]
-            Backend::Kernel => backends::kernel::apply(&self, iface),
-            Backend::Userspace => backends::userspace::apply(&self, iface),
+            Backend::Kernel => backends::kernel::apply(&update, iface),
+            Backend::Userspace => backends::userspace::apply(&update, iface),
+        }
+    }
+
+    /// Like [`apply`](DeviceUpdate::apply), then read the interface back, ex. to learn the
+    /// `listen_port` that was picked at random when the update didn't set one.
+    pub fn apply_and_fetch(self, iface: &InterfaceName, backend: Backend) -> io::Result<Device> {
+        self.apply(iface, backend)?;
+        Device::get(iface, backend)
+    }
+
+    /// The `WG_CMD_SET_DEVICE` messages that [`apply`](DeviceUpdate::apply) would send to the
+    /// kernel backend, without sending them, ex. to preview what an update would change.
+    ///
+    /// Creating the interface (see [`create_interface`](DeviceUpdate::create_interface)) is a
+    /// separate rtnetlink request, so it isn't included.
+    #[cfg(target_os = "linux")]
+    pub fn build_messages(
+        self,
+        iface: &InterfaceName,
+    ) -> io::Result<Vec<netlink_packet_generic::GenlMessage<netlink_packet_wireguard::Wireguard>>>
+    {
+        backends::kernel::apply_messages(&self.without_unchanged_peers(), iface)
+    }
+
+    /// Resolve [`replace_peers_preserving`](DeviceUpdate::replace_peers_preserving) into
+    /// the individual peer additions and removals actually needed.
+    fn without_unchanged_peers(mut self) -> Self {
+        if let Some(existing) = self.preserved_peers.take() {
+            let removed: Vec<_> = existing
+                .iter()
+                .filter(|config| !self.peers.iter().any(|p| p.public_key == config.public_key))
+                .map(|config| config.public_key.clone())
+                .collect();
+            self.peers
+                .retain(|peer| !existing.iter().any(|config| peer.is_noop_for(config)));
+            for public_key in &removed {
+                self = self.remove_peer_by_key(public_key);
+            }
         }
+        self
     }
 }
 
@@ -443,9 +978,20 @@ mod tests {
     const TEST_INTERFACE: &str = "wgctrl-test";
     use super::*;
 
+    /// Tests that bring up a userspace interface need root and a userspace WireGuard
+    /// implementation (ex. wireguard-go), and are skipped without them.
+    fn can_create_userspace_interfaces() -> bool {
+        let is_root = unsafe { libc::getuid() } == 0;
+        is_root
+            && std::process::Command::new(crate::backends::userspace::get_userspace_implementation())
+                .arg("--version")
+                .output()
+                .is_ok()
+    }
+
     #[test]
     fn test_add_peers() {
-        if unsafe { libc::getuid() } != 0 {
+        if !can_create_userspace_interfaces() {
             return;
         }
 
@@ -469,6 +1015,498 @@ mod tests {
         device.delete().unwrap();
     }
 
+    #[test]
+    fn test_clear_peers() {
+        if !can_create_userspace_interfaces() {
+            return;
+        }
+
+        // A separate interface, so this doesn't race test_add_peers.
+        let interface = "wgctrl-clear".parse().unwrap();
+        let keypair = KeyPair::generate();
+        DeviceUpdate::new()
+            .set_keypair(keypair.clone())
+            .set_listen_port(51999)
+            .add_peer(PeerConfigBuilder::new(&KeyPair::generate().public))
+            .add_peer(PeerConfigBuilder::new(&KeyPair::generate().public))
+            .apply(&interface, Backend::Userspace)
+            .unwrap();
+
+        DeviceUpdate::new()
+            .clear_peers()
+            .apply(&interface, Backend::Userspace)
+            .unwrap();
+
+        let device = Device::get(&interface, Backend::Userspace).unwrap();
+        assert!(device.peers.is_empty());
+        assert_eq!(device.public_key, Some(keypair.public));
+        assert_eq!(device.private_key, Some(keypair.private));
+        assert_eq!(device.listen_port, Some(51999));
+
+        device.delete().unwrap();
+    }
+
+    #[test]
+    fn test_apply_without_creating_interface() {
+        let iface = "wgctrl-nocreate".parse().unwrap();
+        let err = DeviceUpdate::new()
+            .create_interface(false)
+            .apply(&iface, Backend::Userspace)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("creating it is disabled"));
+
+        // Nothing is read back when applying fails.
+        let err = DeviceUpdate::new()
+            .create_interface(false)
+            .apply_and_fetch(&iface, Backend::Userspace)
+            .unwrap_err();
+        assert!(err.to_string().contains("creating it is disabled"));
+    }
+
+    #[test]
+    fn test_find_peer() {
+        let keys: Vec<_> = (0..3).map(|_| KeyPair::generate().public).collect();
+        let info = |key: &Key| PeerInfo {
+            config: PeerConfigBuilder::new(key).into_peer_config(),
+            stats: Default::default(),
+        };
+        let mut device = Device {
+            name: TEST_INTERFACE.parse().unwrap(),
+            public_key: None,
+            private_key: None,
+            fwmark: None,
+            listen_port: None,
+            peers: vec![info(&keys[0]), info(&keys[1])],
+            linked_name: None,
+            backend: Backend::Userspace,
+        };
+
+        assert_eq!(device.peer_count(), 2);
+        assert_eq!(device.find_peer(&keys[1]), Some(&device.peers[1]));
+        assert!(device.has_peer(&keys[0]));
+        assert!(!device.has_peer(&keys[2]));
+        assert_eq!(device.find_peer(&keys[2]), None);
+
+        device.find_peer_mut(&keys[0]).unwrap().stats.rx_bytes = 100;
+        assert_eq!(device.peers[0].stats.rx_bytes, 100);
+        assert!(device.find_peer_mut(&keys[2]).is_none());
+    }
+
+    #[test]
+    fn test_peers_changed_since() {
+        let keys: Vec<_> = (0..4).map(|_| KeyPair::generate().public).collect();
+        let info = |key: &Key, rx_bytes: u64, last_handshake_time: Option<SystemTime>| PeerInfo {
+            config: PeerConfigBuilder::new(key).into_peer_config(),
+            stats: PeerStats {
+                last_handshake_time,
+                rx_bytes,
+                tx_bytes: 0,
+            },
+        };
+        let device = |peers: Vec<PeerInfo>| Device {
+            name: TEST_INTERFACE.parse().unwrap(),
+            public_key: None,
+            private_key: None,
+            fwmark: None,
+            listen_port: None,
+            peers,
+            linked_name: None,
+            backend: Backend::Userspace,
+        };
+        let handshake = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        let previous = device(vec![
+            info(&keys[0], 100, Some(handshake)),
+            info(&keys[1], 100, Some(handshake)),
+            info(&keys[2], 100, None),
+        ]);
+        let current = device(vec![
+            info(&keys[0], 100, Some(handshake)),
+            info(&keys[1], 200, Some(handshake)),
+            info(&keys[2], 100, Some(handshake)),
+            info(&keys[3], 0, None),
+        ]);
+
+        let changed: Vec<_> = current
+            .peers_changed_since(&previous)
+            .into_iter()
+            .map(|peer| peer.config.public_key.clone())
+            .collect();
+        assert_eq!(changed, &keys[1..]);
+        assert!(current.peers_changed_since(&current).is_empty());
+    }
+
+    #[test]
+    fn test_replace_peers_preserving() {
+        let unchanged = KeyPair::generate().public;
+        let changed = KeyPair::generate().public;
+        let stale = KeyPair::generate().public;
+        let added = KeyPair::generate().public;
+
+        let peer = |key: &Key, endpoint: &str, ip: &str| {
+            PeerConfigBuilder::new(key)
+                .set_endpoint(endpoint.parse().unwrap())
+                .replace_allowed_ips()
+                .add_allowed_ip(ip.parse().unwrap(), 32)
+        };
+        let info = |builder: PeerConfigBuilder| PeerInfo {
+            config: builder.into_peer_config(),
+            stats: PeerStats {
+                last_handshake_time: Some(SystemTime::now()),
+                rx_bytes: 1024,
+                tx_bytes: 2048,
+            },
+        };
+
+        let device = Device {
+            name: TEST_INTERFACE.parse().unwrap(),
+            public_key: None,
+            private_key: None,
+            fwmark: None,
+            listen_port: None,
+            peers: vec![
+                info(peer(&unchanged, "1.1.1.1:51820", "10.0.0.1")),
+                info(peer(&changed, "1.1.1.2:51820", "10.0.0.2")),
+                info(peer(&stale, "1.1.1.3:51820", "10.0.0.3")),
+            ],
+            linked_name: None,
+            backend: Backend::Userspace,
+        };
+
+        let update = DeviceUpdate::new()
+            .replace_peers_preserving(&device)
+            .add_peer(peer(&unchanged, "1.1.1.1:51820", "10.0.0.1"))
+            .add_peer(peer(&changed, "1.1.1.2:51820", "10.0.0.22"))
+            .add_peer(peer(&added, "1.1.1.4:51820", "10.0.0.4"))
+            .without_unchanged_peers();
+
+        assert!(!update.replace_peers);
+        assert!(!update.peers.iter().any(|p| p.public_key == unchanged));
+        assert!(update
+            .peers
+            .iter()
+            .any(|p| p.public_key == changed && !p.remove_me));
+        assert!(update
+            .peers
+            .iter()
+            .any(|p| p.public_key == added && !p.remove_me));
+        assert!(update
+            .peers
+            .iter()
+            .any(|p| p.public_key == stale && p.remove_me));
+        assert_eq!(update.peers.len(), 3);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_build_messages() {
+        let device = test_device();
+        let iface = device.name;
+        let added = PeerConfigBuilder::new(&KeyPair::generate().public)
+            .add_allowed_ip("10.0.0.3".parse().unwrap(), 32)
+            .into_peer_config();
+
+        let messages = DeviceUpdate::new()
+            .set_listen_port(51821)
+            .sync_peers(&device, &[device.peers[0].config.clone(), added])
+            .build_messages(&iface)
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        let peers: usize = messages[0]
+            .payload
+            .nlas
+            .iter()
+            .map(|nla| match nla {
+                netlink_packet_wireguard::nlas::WgDeviceAttrs::Peers(peers) => peers.len(),
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(peers, 1, "only the added peer should be sent");
+
+        // Many peers are split across messages, like they would be when applied.
+        let many: Vec<_> = (0..2000)
+            .map(|_| {
+                PeerConfigBuilder::new(&KeyPair::generate().public)
+                    .add_allowed_ip("10.0.0.4".parse().unwrap(), 32)
+            })
+            .collect();
+        let messages = DeviceUpdate::new()
+            .add_peers(&many)
+            .build_messages(&iface)
+            .unwrap();
+        assert!(messages.len() > 1);
+    }
+
+    #[test]
+    fn test_allowed_ip_counts() {
+        let mut device = test_device();
+        assert_eq!(device.peers[0].allowed_ip_count(), 2);
+
+        let mut extra = device.peers[0].clone();
+        extra.config.allowed_ips.truncate(1);
+        device.peers.push(extra);
+        assert_eq!(device.total_allowed_ips(), 3);
+        assert_eq!(
+            device.allowed_ip_counts(),
+            AllowedIpCounts { ipv4: 2, ipv6: 1 }
+        );
+        assert_eq!(device.allowed_ip_counts().total(), 3);
+    }
+
+    #[test]
+    fn test_sync_peers() {
+        let device = test_device();
+        let existing = device.peers[0].config.clone();
+        let added = PeerConfigBuilder::new(&KeyPair::generate().public)
+            .add_allowed_ip("10.0.0.3".parse().unwrap(), 32)
+            .into_peer_config();
+
+        let update = DeviceUpdate::new().sync_peers(&device, &[existing.clone(), added.clone()]);
+        assert_eq!(
+            update.peers.len(),
+            1,
+            "the unchanged peer shouldn't be sent"
+        );
+        assert_eq!(update.peers[0].public_key, added.public_key);
+        assert_eq!(update.peers[0].preshared_key, Some(Key::zero()));
+
+        // Dropping the preshared key is a change, and peers that aren't desired are removed.
+        let without_psk = PeerConfig {
+            preshared_key: None,
+            ..existing.clone()
+        };
+        let update = DeviceUpdate::new().sync_peers(&device, &[without_psk]);
+        assert_eq!(update.peers.len(), 1);
+        assert!(!update.peers[0].remove_me);
+        assert_eq!(update.peers[0].preshared_key, Some(Key::zero()));
+
+        let update = DeviceUpdate::new().sync_peers(&device, &[]);
+        assert_eq!(update.peers.len(), 1);
+        assert!(update.peers[0].remove_me);
+        assert_eq!(update.peers[0].public_key, existing.public_key);
+
+        assert!(DeviceUpdate::new()
+            .sync_peers(&device, &[existing])
+            .peers
+            .is_empty());
+    }
+
+    #[test]
+    fn test_peer_stats_staleness() {
+        let stats = |last_handshake_time| PeerStats {
+            last_handshake_time,
+            ..Default::default()
+        };
+        let threshold = Duration::from_secs(180);
+
+        let never = stats(None);
+        assert_eq!(never.time_since_handshake(), None);
+        assert!(never.is_stale(threshold));
+
+        let recent = stats(Some(SystemTime::now() - Duration::from_secs(10)));
+        let since = recent.time_since_handshake().unwrap();
+        assert!(since >= Duration::from_secs(10) && since < threshold);
+        assert!(!recent.is_stale(threshold));
+
+        let old = stats(Some(SystemTime::now() - Duration::from_secs(600)));
+        assert!(old.is_stale(threshold));
+
+        let future = stats(Some(SystemTime::now() + Duration::from_secs(600)));
+        assert_eq!(future.time_since_handshake(), Some(Duration::ZERO));
+        assert!(!future.is_stale(threshold));
+    }
+
+    #[test]
+    fn test_sync_peers_between_snapshots() {
+        let keys: Vec<_> = (0..3).map(|_| KeyPair::generate().public).collect();
+        let snapshot = |moved_endpoint: &str| {
+            let config = format!(
+                "[Interface]
+                ListenPort = 51820
+
+                [Peer]
+                PublicKey = {}
+                AllowedIPs = 10.0.0.2/32
+                Endpoint = 192.0.2.2:51820
+                PersistentKeepalive = 25
+
+                [Peer]
+                PublicKey = {}
+                AllowedIPs = 10.0.0.3/32, 10.0.3.0/24
+                Endpoint = {}
+
+                [Peer]
+                PublicKey = {}
+                AllowedIPs = 10.0.0.4/32",
+                keys[0].to_base64(),
+                keys[1].to_base64(),
+                moved_endpoint,
+                keys[2].to_base64()
+            );
+            Device::from_wg_quick_config(&"wg0".parse().unwrap(), &config).unwrap()
+        };
+        let current = snapshot("192.0.2.3:51820");
+        let desired = snapshot("198.51.100.3:51820");
+        let desired: Vec<_> = desired.peers.into_iter().map(|peer| peer.config).collect();
+
+        // Only the peer whose endpoint moved is sent, and nothing is removed.
+        let update = DeviceUpdate::new().sync_peers(&current, &desired);
+        assert_eq!(update.peers.len(), 1);
+        assert_eq!(update.peers[0].public_key, keys[1]);
+        assert_eq!(
+            update.peers[0].endpoint,
+            Some("198.51.100.3:51820".parse().unwrap())
+        );
+        assert!(!update.peers[0].remove_me);
+
+        let unchanged: Vec<_> = current
+            .peers
+            .iter()
+            .map(|peer| peer.config.clone())
+            .collect();
+        assert!(DeviceUpdate::new()
+            .sync_peers(&current, &unchanged)
+            .peers
+            .is_empty());
+    }
+
+    fn test_device() -> Device {
+        let private_key = Key::generate_private();
+        let peer = PeerConfigBuilder::new(&Key::zero())
+            .set_preshared_key(Key::generate_preshared())
+            .set_endpoint("1.1.1.1:51820".parse().unwrap())
+            .set_persistent_keepalive_interval(25)
+            .add_allowed_ip("10.0.0.2".parse().unwrap(), 32)
+            .add_allowed_ip("fd00::2".parse().unwrap(), 128);
+        Device {
+            name: TEST_INTERFACE.parse().unwrap(),
+            public_key: Some(private_key.get_public()),
+            private_key: Some(private_key),
+            fwmark: Some(0x51820),
+            listen_port: Some(51820),
+            peers: vec![PeerInfo {
+                config: peer.into_peer_config(),
+                stats: PeerStats {
+                    last_handshake_time: None,
+                    rx_bytes: 1024,
+                    tx_bytes: 2048,
+                },
+            }],
+            linked_name: None,
+            backend: Backend::Userspace,
+        }
+    }
+
+    #[test]
+    fn test_device_display() {
+        let device = test_device();
+        let display = device.to_string();
+
+        assert_eq!(
+            display,
+            format!(
+                "interface: wgctrl-test (userspace)\n  public key: {}\n  private key: (hidden)\n  \
+                 listening port: 51820\n  fwmark: 0x51820\n\npeer: {}\n  preshared key: (hidden)\n  \
+                 endpoint: 1.1.1.1:51820\n  allowed ips: 10.0.0.2/32, fd00::2/128\n  \
+                 transfer: 1024 B received, 2048 B sent\n  persistent keepalive: every 25s\n",
+                device.public_key.as_ref().unwrap().to_base64(),
+                Key::zero().to_base64(),
+            )
+        );
+        assert!(!display.contains(&device.private_key.as_ref().unwrap().to_base64()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_device_serialize() {
+        let device = test_device();
+        let json = serde_json::to_value(&device).unwrap();
+
+        assert_eq!(json["name"], "wgctrl-test");
+        assert_eq!(json["private_key"], "(hidden)");
+        assert_eq!(
+            json["public_key"],
+            device.public_key.as_ref().unwrap().to_base64()
+        );
+        assert_eq!(json["peers"][0]["preshared_key"], "(hidden)");
+        assert_eq!(json["peers"][0]["endpoint"], "1.1.1.1:51820");
+        assert_eq!(
+            json["peers"][0]["allowed_ips"],
+            serde_json::json!(["10.0.0.2/32", "fd00::2/128"])
+        );
+        assert_eq!(
+            json["peers"][0]["last_handshake_time"],
+            serde_json::Value::Null
+        );
+    }
+
+    #[test]
+    fn test_allowed_ip_parse() {
+        for cidr in [
+            "10.1.0.0/24",
+            "10.1.2.3/32",
+            "0.0.0.0/0",
+            "fd00::/64",
+            "::/0",
+        ] {
+            let allowed_ip: AllowedIp = cidr.parse().unwrap();
+            assert_eq!(allowed_ip.to_string(), cidr);
+        }
+        assert_eq!(
+            "fd00::1/128".parse(),
+            Ok(AllowedIp {
+                address: "fd00::1".parse().unwrap(),
+                cidr: 128
+            })
+        );
+
+        assert_eq!("10.1.0.0".parse::<AllowedIp>(), Err(()));
+        assert_eq!("10.1.0/24".parse::<AllowedIp>(), Err(()));
+        assert_eq!("10.1.0.0/x".parse::<AllowedIp>(), Err(()));
+        assert_eq!("10.1.0.0/24/1".parse::<AllowedIp>(), Err(()));
+        assert_eq!("10.1.0.0/33".parse::<AllowedIp>(), Err(()));
+        assert_eq!("fd00::/129".parse::<AllowedIp>(), Err(()));
+    }
+
+    #[test]
+    fn test_allowed_ip_subtract() {
+        let ip = |s: &str| s.parse::<AllowedIp>().unwrap();
+        let ips = |list: &[&str]| list.iter().map(|s| ip(s)).collect::<Vec<_>>();
+
+        assert_eq!(
+            AllowedIp::subtract(&ip("0.0.0.0/0"), &[ip("10.0.0.0/8")]),
+            ips(&[
+                "0.0.0.0/5",
+                "8.0.0.0/7",
+                "11.0.0.0/8",
+                "12.0.0.0/6",
+                "16.0.0.0/4",
+                "32.0.0.0/3",
+                "64.0.0.0/2",
+                "128.0.0.0/1",
+            ])
+        );
+        assert_eq!(
+            AllowedIp::subtract(
+                &ip("10.0.0.0/24"),
+                &[ip("10.0.0.0/26"), ip("10.0.0.128/26"), ip("10.0.0.130/32")]
+            ),
+            ips(&["10.0.0.64/26", "10.0.0.192/26"])
+        );
+        assert_eq!(
+            AllowedIp::subtract(&ip("fd00::/64"), &[ip("fd00::/65"), ip("10.0.0.0/8")]),
+            ips(&["fd00::8000:0:0:0/65"])
+        );
+
+        // Host bits in the include are ignored, and disjoint excludes change nothing.
+        assert_eq!(
+            AllowedIp::subtract(&ip("10.1.2.3/16"), &[ip("10.2.0.0/16")]),
+            ips(&["10.1.0.0/16"])
+        );
+        assert!(AllowedIp::subtract(&ip("10.1.0.0/16"), &[ip("10.0.0.0/8")]).is_empty());
+    }
+
     #[test]
     fn test_interface_names() {
         assert_eq!(
@@ -490,5 +1528,38 @@ mod tests {
         for (name, expected) in invalid_names {
             assert!(name.parse::<InterfaceName>().as_ref() == Err(expected))
         }
+
+        // The longest allowed name still fits.
+        let longest = "w".repeat(MAX_INTERFACE_NAME_LEN);
+        assert_eq!(
+            longest.parse::<InterfaceName>().unwrap().to_string(),
+            longest
+        );
+        assert_eq!(
+            format!("{}g", longest).parse::<InterfaceName>(),
+            Err(InvalidInterfaceName::TooLong)
+        );
+        assert_eq!(
+            InvalidInterfaceName::TooLong.to_string(),
+            "interface name longer than system max of 15 chars"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_linux_interface_names() {
+        assert_eq!(
+            "wg0:1".parse::<InterfaceName>(),
+            Err(InvalidInterfaceName::InvalidChars)
+        );
+        assert_eq!(
+            ".".parse::<InterfaceName>(),
+            Err(InvalidInterfaceName::InvalidChars)
+        );
+        assert_eq!(
+            "..".parse::<InterfaceName>(),
+            Err(InvalidInterfaceName::InvalidChars)
+        );
+        assert!("wg.0".parse::<InterfaceName>().is_ok());
     }
 }
@@ -26,9 +26,19 @@ impl From<NulError> for InvalidKey {
 ///
 /// This means that you need to be careful when working with
 /// `Key`s, especially ones created from external data.
-#[derive(PartialEq, Eq, Clone)]
+///
+/// Equality is checked in constant time, since most keys are secrets.
+#[derive(Eq, Clone)]
 pub struct Key(pub [u8; 32]);
 
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        use subtle::ConstantTimeEq;
+
+        self.0.ct_eq(&other.0).into()
+    }
+}
+
 impl Key {
     /// Generates and returns a new private key.
     pub fn generate_private() -> Self {
@@ -45,6 +55,8 @@ impl Key {
     }
 
     /// Generates and returns a new preshared key.
+    ///
+    /// Unlike [`Key::generate_private`], the bytes are used as-is, without clamping.
     #[must_use]
     pub fn generate_preshared() -> Self {
         use rand_core::{OsRng, RngCore};
@@ -79,7 +91,7 @@ impl Key {
 
     /// Converts the key to a standardized base64 representation, as used by the `wg` utility and `wg-quick`.
     pub fn to_base64(&self) -> String {
-        base64::encode(&self.0)
+        base64::encode(self.0)
     }
 
     /// Converts a base64 representation of the key to the raw bytes.
@@ -98,11 +110,26 @@ impl Key {
         Ok(Self(key_bytes))
     }
 
+    /// Converts the key to lowercase hex, as used by the userspace configuration protocol.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    /// Converts a hex representation of the key to the raw bytes, failing with
+    /// `Err(InvalidKey)` unless it's exactly 32 bytes of valid hex.
     pub fn from_hex(hex_str: &str) -> Result<Self, crate::InvalidKey> {
         let mut sized_bytes = [0u8; 32];
         hex::decode_to_slice(hex_str, &mut sized_bytes).map_err(|_| InvalidKey)?;
         Ok(Self(sized_bytes))
     }
+
+    /// Parses a key that's either base64 (tried first) or hex encoded.
+    ///
+    /// The two can't be confused: 32 bytes of hex is 64 characters, which as base64 would
+    /// decode to 48 bytes.
+    pub fn parse_any(key: &str) -> Result<Self, crate::InvalidKey> {
+        Self::from_base64(key).or_else(|_| Self::from_hex(key))
+    }
 }
 
 #[cfg(test)]
@@ -141,6 +168,16 @@ mod test {
             assert!(key.as_bytes() != [0u8; 32]);
         }
     }
+
+    #[test]
+    fn test_preshared_not_clamped() {
+        // A clamped key always has the low three bits of its first byte cleared, and the
+        // top bit of its last byte cleared. 100 random keys all looking clamped would be
+        // astronomically unlikely.
+        let looks_clamped = |key: &Key| key.0[0] & 0b111 == 0 && key.0[31] & 0x80 == 0;
+        assert!(!(0..100).all(|_| looks_clamped(&Key::generate_preshared())));
+        assert!((0..100).all(|_| looks_clamped(&Key::generate_private())));
+    }
 }
 
 /// Represents a pair of private and public keys.
@@ -198,6 +235,38 @@ mod tests {
         assert_eq!(key, key_new);
     }
 
+    #[test]
+    fn test_hex_and_parse_any() {
+        use crate::key::{InvalidKey, Key};
+
+        let key = Key::generate_private();
+        let key_hex = key.to_hex();
+        assert_eq!(key_hex.len(), 64);
+        assert_eq!(Key::from_hex(&key_hex), Ok(key.clone()));
+        assert_eq!(Key::parse_any(&key_hex), Ok(key.clone()));
+        assert_eq!(Key::parse_any(&key.to_base64()), Ok(key));
+
+        // Valid encodings of the wrong number of bytes are rejected.
+        assert_eq!(Key::from_hex(&key_hex[..62]), Err(InvalidKey));
+        assert_eq!(Key::parse_any(&format!("{}00", key_hex)), Err(InvalidKey));
+        assert_eq!(Key::parse_any(&base64::encode([0u8; 31])), Err(InvalidKey));
+        assert_eq!(Key::parse_any("not a key"), Err(InvalidKey));
+    }
+
+    #[test]
+    fn test_key_eq() {
+        use crate::key::Key;
+
+        let key = Key::generate_preshared();
+        assert_eq!(key, key.clone());
+
+        // Keys differing only in their last byte must still compare unequal.
+        let mut other = key.0;
+        other[31] ^= 1;
+        assert_ne!(key, Key(other));
+        assert_ne!(key, Key::generate_preshared());
+    }
+
     #[test]
     fn test_invalid_key() {
         use crate::key::{InvalidKey, Key};
@@ -2,6 +2,7 @@ pub mod backends;
 mod config;
 mod device;
 mod key;
+mod wg_quick;
 
 use std::{
     fmt::{self, Display, Formatter},
@@ -10,27 +11,15 @@ use std::{
 
 pub use crate::{config::*, device::*, key::*};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum Backend {
     #[cfg(target_os = "linux")]
+    #[default]
     Kernel,
+    #[cfg_attr(not(target_os = "linux"), default)]
     Userspace,
 }
 
-impl Default for Backend {
-    fn default() -> Self {
-        #[cfg(target_os = "linux")]
-        {
-            Self::Kernel
-        }
-
-        #[cfg(not(target_os = "linux"))]
-        {
-            Self::Userspace
-        }
-    }
-}
-
 impl Display for Backend {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
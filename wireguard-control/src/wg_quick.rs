@@ -0,0 +1,340 @@
+//! Loading and saving devices as `wg showconf`/wg-quick style configuration files.
+
+use crate::{normalize_endpoint, Backend, Device, InterfaceName, Key, PeerConfig, PeerInfo};
+use std::{fmt::Write, io};
+
+/// Keys only wg-quick uses, which have nothing to do with the device itself.
+const WG_QUICK_KEYS: &[&str] = &[
+    "address",
+    "dns",
+    "mtu",
+    "table",
+    "preup",
+    "postup",
+    "predown",
+    "postdown",
+    "saveconfig",
+];
+
+enum Section {
+    None,
+    Interface,
+    Peer,
+}
+
+impl Device {
+    /// Load a device named `name` from the text of a `wg showconf` snapshot or a wg-quick
+    /// configuration file, without touching any interface. The result looks the same as
+    /// what [`Device::get`] returns, except that peers have no handshakes or transfer yet.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use wireguard_control::*;
+    /// let config = "
+    ///     [Interface]
+    ///     PrivateKey = 8OfBuaqbTnfnNbgjFpNJa8PZQP7OYqZyoOPIbSDz1k8=
+    ///     ListenPort = 51820
+    ///
+    ///     [Peer]
+    ///     PublicKey = 4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=
+    ///     AllowedIPs = 10.0.0.2/32
+    ///     Endpoint = 192.0.2.1:51820
+    /// ";
+    /// let device = Device::from_wg_quick_config(&"wg0".parse().unwrap(), config)?;
+    /// assert_eq!(device.listen_port, Some(51820));
+    /// let peer_key = Key::from_base64("4CNZorWVtohO64n6AAaH/JyFjIIgBFrfJK2SGtKjzEE=").unwrap();
+    /// assert!(device.has_peer(&peer_key));
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn from_wg_quick_config(name: &InterfaceName, config: &str) -> io::Result<Self> {
+        let mut device = Device {
+            name: *name,
+            public_key: None,
+            private_key: None,
+            fwmark: None,
+            listen_port: None,
+            peers: vec![],
+            linked_name: None,
+            backend: Backend::default(),
+        };
+        let mut section = Section::None;
+
+        for (i, line) in config.lines().enumerate() {
+            let invalid = |message: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {}", i + 1, message),
+                )
+            };
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                section = match line.to_ascii_lowercase().as_str() {
+                    "[interface]" => Section::Interface,
+                    "[peer]" => {
+                        device.peers.push(PeerInfo {
+                            config: PeerConfig {
+                                // Replaced by the peer's PublicKey, which is checked for below.
+                                public_key: Key::zero(),
+                                preshared_key: None,
+                                endpoint: None,
+                                persistent_keepalive_interval: None,
+                                allowed_ips: vec![],
+                            },
+                            stats: Default::default(),
+                        });
+                        Section::Peer
+                    },
+                    _ => return Err(invalid(format!("unknown section {}", line))),
+                };
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .map(|(key, value)| (key.trim().to_ascii_lowercase(), value.trim()))
+                .ok_or_else(|| invalid("expected \"Key = Value\"".into()))?;
+            let parse_key =
+                |value: &str| Key::from_base64(value).map_err(|_| invalid("invalid key".into()));
+            match (&section, key.as_str()) {
+                (Section::Interface, "privatekey") => {
+                    let private_key = parse_key(value)?;
+                    device.public_key = Some(private_key.get_public());
+                    device.private_key = Some(private_key);
+                },
+                (Section::Interface, "listenport") => {
+                    device.listen_port = Some(
+                        value
+                            .parse()
+                            .map_err(|_| invalid("invalid listen port".into()))?,
+                    );
+                },
+                (Section::Interface, "fwmark") => {
+                    let fwmark = match value.strip_prefix("0x") {
+                        _ if value == "off" => Ok(0),
+                        Some(hex) => u32::from_str_radix(hex, 16),
+                        None => value.parse(),
+                    }
+                    .map_err(|_| invalid("invalid fwmark".into()))?;
+                    device.fwmark = Some(fwmark).filter(|fwmark| *fwmark != 0);
+                },
+                (Section::Interface, key) if WG_QUICK_KEYS.contains(&key) => {},
+                (Section::Peer, key) => {
+                    // Only ever in the peer section after pushing a peer.
+                    let peer = &mut device.peers.last_mut().unwrap().config;
+                    match key {
+                        "publickey" => peer.public_key = parse_key(value)?,
+                        "presharedkey" => peer.preshared_key = Some(parse_key(value)?),
+                        "allowedips" => {
+                            for allowed_ip in value.split(',').map(str::trim) {
+                                peer.allowed_ips.push(allowed_ip.parse().map_err(|_| {
+                                    invalid(format!("invalid allowed IP {}", allowed_ip))
+                                })?);
+                            }
+                        },
+                        "endpoint" => {
+                            peer.endpoint =
+                                Some(normalize_endpoint(value.parse().map_err(|_| {
+                                    invalid("endpoints must be IP addresses with a port".into())
+                                })?));
+                        },
+                        "persistentkeepalive" => {
+                            let interval = match value {
+                                "off" => 0,
+                                value => value
+                                    .parse()
+                                    .map_err(|_| invalid("invalid keepalive interval".into()))?,
+                            };
+                            peer.persistent_keepalive_interval =
+                                Some(interval).filter(|interval| *interval != 0);
+                        },
+                        _ => return Err(invalid(format!("unknown peer key {}", key))),
+                    }
+                },
+                (Section::Interface, key) => {
+                    return Err(invalid(format!("unknown interface key {}", key)))
+                },
+                (Section::None, _) => return Err(invalid("expected a section first".into())),
+            }
+        }
+
+        if device
+            .peers
+            .iter()
+            .any(|peer| peer.config.public_key == Key::zero())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "every peer needs a PublicKey",
+            ));
+        }
+        Ok(device)
+    }
+
+    /// The device's configuration in the format [`Device::from_wg_quick_config`] reads,
+    /// which plain WireGuard tools also understand. Unlike displaying the device, this
+    /// includes the private and preshared keys, so treat the output as a secret.
+    pub fn to_wg_quick_config(&self) -> String {
+        let mut out = String::from("[Interface]\n");
+        if let Some(private_key) = &self.private_key {
+            let _ = writeln!(out, "PrivateKey = {}", private_key.to_base64());
+        }
+        if let Some(listen_port) = self.listen_port {
+            let _ = writeln!(out, "ListenPort = {}", listen_port);
+        }
+        if let Some(fwmark) = self.fwmark {
+            let _ = writeln!(out, "FwMark = {:#x}", fwmark);
+        }
+
+        for peer in &self.peers {
+            let config = &peer.config;
+            let _ = writeln!(out, "\n[Peer]");
+            let _ = writeln!(out, "PublicKey = {}", config.public_key.to_base64());
+            if let Some(preshared_key) = &config.preshared_key {
+                let _ = writeln!(out, "PresharedKey = {}", preshared_key.to_base64());
+            }
+            if !config.allowed_ips.is_empty() {
+                let allowed_ips: Vec<_> =
+                    config.allowed_ips.iter().map(ToString::to_string).collect();
+                let _ = writeln!(out, "AllowedIPs = {}", allowed_ips.join(", "));
+            }
+            if let Some(endpoint) = config.endpoint {
+                let _ = writeln!(out, "Endpoint = {}", endpoint);
+            }
+            if let Some(interval) = config.persistent_keepalive_interval {
+                let _ = writeln!(out, "PersistentKeepalive = {}", interval);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AllowedIp, KeyPair, PeerConfigBuilder, PeerStats};
+
+    #[test]
+    fn test_from_wg_quick_config() {
+        let interface = KeyPair::generate();
+        let peers: Vec<_> = (0..2).map(|_| KeyPair::generate().public).collect();
+        let psk = Key::generate_preshared();
+        let config = format!(
+            "
+            [Interface]
+            # wg-quick's own settings are skipped.
+            Address = 10.0.0.1/24
+            PrivateKey = {}
+            ListenPort = 51820
+            FwMark = 0x1f
+
+            [Peer]
+            PublicKey = {}
+            PresharedKey = {}
+            AllowedIPs = 10.0.0.2/32, fd00::2/128
+            Endpoint = [::ffff:192.0.2.1]:51820
+            PersistentKeepalive = 25
+
+            [peer]
+            publickey = {}
+            ",
+            interface.private.to_base64(),
+            peers[0].to_base64(),
+            psk.to_base64(),
+            peers[1].to_base64()
+        );
+        let name = "wg0".parse().unwrap();
+        let device = Device::from_wg_quick_config(&name, &config).unwrap();
+        assert_eq!(device.public_key, Some(interface.public));
+        assert_eq!(device.listen_port, Some(51820));
+        assert_eq!(device.fwmark, Some(0x1f));
+        assert_eq!(device.peer_count(), 2);
+
+        let peer = device.find_peer(&peers[0]).unwrap();
+        assert_eq!(peer.config.preshared_key, Some(psk));
+        assert_eq!(
+            peer.config.allowed_ips,
+            vec![
+                "10.0.0.2/32".parse::<AllowedIp>().unwrap(),
+                "fd00::2/128".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            peer.config.endpoint,
+            Some("192.0.2.1:51820".parse().unwrap())
+        );
+        assert_eq!(peer.config.persistent_keepalive_interval, Some(25));
+        assert_eq!(peer.stats, Default::default());
+        assert_eq!(
+            device.find_peer(&peers[1]).unwrap().config.allowed_ips,
+            vec![]
+        );
+
+        for invalid in [
+            "ListenPort = 51820",
+            "[Interface]\nListenPort = lots",
+            "[Interface]\nColor = blue",
+            "[Peer]\nAllowedIPs = 10.0.0.2/32",
+            "[Peer]\nPublicKey = nope",
+            "[Tunnel]",
+        ] {
+            assert!(
+                Device::from_wg_quick_config(&name, invalid).is_err(),
+                "{}",
+                invalid
+            );
+        }
+        let error = Device::from_wg_quick_config(&name, "[Interface]\n\nListenPort = x")
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("line 3:"), "{}", error);
+    }
+
+    #[test]
+    fn test_wg_quick_config_round_trip() {
+        let keys: Vec<_> = (0..2).map(|_| KeyPair::generate().public).collect();
+        let mut device = Device {
+            name: "wg0".parse().unwrap(),
+            public_key: None,
+            private_key: None,
+            fwmark: Some(51820),
+            listen_port: Some(51820),
+            peers: vec![
+                PeerInfo {
+                    config: PeerConfigBuilder::new(&keys[0])
+                        .set_preshared_key(Key::generate_preshared())
+                        .add_allowed_ip("10.0.0.2".parse().unwrap(), 32)
+                        .add_allowed_ip("fd00::2".parse().unwrap(), 128)
+                        .set_endpoint("[2001:db8::1]:51820".parse().unwrap())
+                        .set_persistent_keepalive_interval(25)
+                        .into_peer_config(),
+                    stats: PeerStats {
+                        last_handshake_time: Some(std::time::SystemTime::now()),
+                        rx_bytes: 987_654_321,
+                        tx_bytes: 123_456_789,
+                    },
+                },
+                PeerInfo {
+                    config: PeerConfigBuilder::new(&keys[1]).into_peer_config(),
+                    stats: Default::default(),
+                },
+            ],
+            linked_name: None,
+            backend: Backend::default(),
+        };
+        let private_key = Key::generate_private();
+        device.public_key = Some(private_key.get_public());
+        device.private_key = Some(private_key);
+
+        let config = device.to_wg_quick_config();
+        assert!(config.contains("FwMark = 0xca6c\n"), "{}", config);
+        assert!(!config.contains("987654321") && !config.contains("123456789"));
+        let parsed = Device::from_wg_quick_config(&device.name, &config).unwrap();
+        for peer in &mut device.peers {
+            peer.stats = Default::default();
+        }
+        assert_eq!(parsed, device);
+    }
+}